@@ -0,0 +1,67 @@
+//! FLV demuxer/muxer throughput
+//!
+//! Run with `cargo bench --bench flv_throughput`.
+
+use bytes::Bytes;
+use rtmp_streaming_server::flv::{FlvDemuxer, FlvHeader, FlvMuxer, FlvTag, TagType};
+use std::time::Instant;
+
+/// Size of the generated FLV stream
+const STREAM_SIZE: usize = 64 * 1024 * 1024;
+
+/// Size of the chunks fed to the demuxer (typical HTTP body chunk)
+const CHUNK_SIZE: usize = 16 * 1024;
+
+fn build_stream() -> (Bytes, usize) {
+    let mut muxer = FlvMuxer::new();
+    let mut out = muxer.header(&FlvHeader::new(true, true)).to_vec();
+    let mut count = 0;
+    let mut timestamp = 0;
+    while out.len() < STREAM_SIZE {
+        // Mix of large video frames and small audio frames
+        let video_size = if count % 30 == 0 { 60_000 } else { 8_000 };
+        let video = FlvTag::new(TagType::Video, timestamp, Bytes::from(vec![0x27; video_size]));
+        let audio = FlvTag::new(TagType::Audio, timestamp, Bytes::from(vec![0xaf; 400]));
+        out.extend_from_slice(&muxer.tags([&video, &audio]));
+        timestamp += 33;
+        count += 2;
+    }
+    (Bytes::from(out), count)
+}
+
+fn report(name: &str, bytes: usize, tags: usize, start: Instant) {
+    let secs = start.elapsed().as_secs_f64();
+    println!(
+        "{:<8} {:>8.1} MB/s {:>10.0} tags/s ({} tags in {:.3}s)",
+        name,
+        bytes as f64 / secs / (1024.0 * 1024.0),
+        tags as f64 / secs,
+        tags,
+        secs
+    );
+}
+
+fn main() {
+    let (data, expected) = build_stream();
+
+    let start = Instant::now();
+    let mut demuxer = FlvDemuxer::new();
+    let mut tags = Vec::with_capacity(expected);
+    for chunk in data.chunks(CHUNK_SIZE) {
+        demuxer.push(chunk);
+        while let Some(tag) = demuxer.next_tag().unwrap() {
+            tags.push(tag);
+        }
+    }
+    assert_eq!(tags.len(), expected);
+    report("demux", data.len(), tags.len(), start);
+
+    let start = Instant::now();
+    let mut muxer = FlvMuxer::new();
+    let mut written = muxer.header(&FlvHeader::new(true, true)).len();
+    for tag in &tags {
+        written += muxer.tag(tag).len();
+    }
+    assert_eq!(written, data.len());
+    report("mux", written, tags.len(), start);
+}
@@ -0,0 +1,317 @@
+//! RTMP chunk stream
+//!
+//! [`ChunkDecoder`] reassembles messages from interleaved chunks and
+//! [`ChunkEncoder`] splits outgoing messages into chunks.
+
+use crate::error::{Error, Result};
+use crate::protocol::{
+    constants::DEFAULT_CHUNK_SIZE, utils, ChunkHeader, ChunkHeaderFormat, Message, MessageType,
+};
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use tracing::debug;
+
+/// Largest chunk size allowed by the specification
+pub const MAX_CHUNK_SIZE: u32 = 0x7fff_ffff;
+
+/// Per chunk stream decoding state
+#[derive(Debug, Default)]
+struct ChunkStreamState {
+    /// Absolute timestamp of the current message
+    timestamp: u32,
+    /// Last timestamp delta (or absolute timestamp after a type 0 header)
+    delta: u32,
+    /// Length of the current message
+    length: u32,
+    /// Raw message type ID
+    type_id: u8,
+    /// Message stream ID
+    stream_id: u32,
+    /// Whether the last header carried an extended timestamp
+    extended: bool,
+    /// Payload received so far for the current message, grown chunk by
+    /// chunk rather than reserved from the peer-declared length
+    payload: BytesMut,
+}
+
+/// Reassembles RTMP messages from chunks
+#[derive(Debug)]
+pub struct ChunkDecoder {
+    /// Incoming chunk size
+    chunk_size: usize,
+    /// State per chunk stream ID
+    streams: HashMap<u32, ChunkStreamState>,
+}
+
+impl ChunkDecoder {
+    /// Create a decoder with the default chunk size
+    pub fn new() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE as usize,
+            streams: HashMap::new(),
+        }
+    }
+
+    /// Set the incoming chunk size (SetChunkSize from the peer)
+    pub fn set_chunk_size(&mut self, size: u32) -> Result<()> {
+        if size == 0 || size > MAX_CHUNK_SIZE {
+            return Err(Error::BadChunk(format!("Invalid chunk size: {}", size)));
+        }
+        self.chunk_size = size as usize;
+        Ok(())
+    }
+
+    /// Drop a partially received message (AbortMessage from the peer)
+    pub fn abort(&mut self, chunk_stream_id: u32) {
+        if let Some(state) = self.streams.get_mut(&chunk_stream_id) {
+            state.payload.clear();
+        }
+    }
+
+    /// Decode the next complete message from `buf`
+    ///
+    /// Consumes whole chunks only; returns `Ok(None)` when more data is
+    /// needed. Messages with unknown type IDs are skipped.
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Message>> {
+        loop {
+            match self.decode_chunk(buf)? {
+                ChunkResult::Incomplete => return Ok(None),
+                ChunkResult::Partial => continue,
+                ChunkResult::Complete(Some(message)) => return Ok(Some(message)),
+                ChunkResult::Complete(None) => continue,
+            }
+        }
+    }
+
+    fn decode_chunk(&mut self, buf: &mut BytesMut) -> Result<ChunkResult> {
+        // Basic header
+        if buf.is_empty() {
+            return Ok(ChunkResult::Incomplete);
+        }
+        let fmt = buf[0] >> 6;
+        let (chunk_stream_id, mut pos) = match buf[0] & 0x3f {
+            0 => {
+                if buf.len() < 2 {
+                    return Ok(ChunkResult::Incomplete);
+                }
+                (64 + buf[1] as u32, 2)
+            }
+            1 => {
+                if buf.len() < 3 {
+                    return Ok(ChunkResult::Incomplete);
+                }
+                (64 + buf[1] as u32 + ((buf[2] as u32) << 8), 3)
+            }
+            id => (id as u32, 1),
+        };
+        let format = match fmt {
+            0 => ChunkHeaderFormat::Format0,
+            1 => ChunkHeaderFormat::Format1,
+            2 => ChunkHeaderFormat::Format2,
+            _ => ChunkHeaderFormat::Format3,
+        };
+
+        // Message header
+        let header_size = utils::chunk_header_size(&format);
+        if buf.len() < pos + header_size {
+            return Ok(ChunkResult::Incomplete);
+        }
+        let h = &buf[pos..pos + header_size];
+        let state = self.streams.entry(chunk_stream_id).or_default();
+        let starting = state.payload.is_empty();
+        if !starting && !matches!(format, ChunkHeaderFormat::Format3) {
+            return Err(Error::BadChunk(format!(
+                "Chunk stream {} got a new header in the middle of a message",
+                chunk_stream_id
+            )));
+        }
+
+        let mut timestamp_field = None;
+        let (mut length, mut type_id, mut stream_id) = (state.length, state.type_id, state.stream_id);
+        match format {
+            ChunkHeaderFormat::Format0 => {
+                timestamp_field = Some(read_u24(&h[0..3]));
+                length = read_u24(&h[3..6]);
+                type_id = h[6];
+                stream_id = u32::from_le_bytes([h[7], h[8], h[9], h[10]]);
+            }
+            ChunkHeaderFormat::Format1 => {
+                timestamp_field = Some(read_u24(&h[0..3]));
+                length = read_u24(&h[3..6]);
+                type_id = h[6];
+            }
+            ChunkHeaderFormat::Format2 => {
+                timestamp_field = Some(read_u24(&h[0..3]));
+            }
+            ChunkHeaderFormat::Format3 => {}
+        }
+        pos += header_size;
+
+        // Extended timestamp
+        let extended = match timestamp_field {
+            Some(field) => field == 0xFF_FFFF,
+            None => state.extended,
+        };
+        let mut timestamp_value = timestamp_field.unwrap_or(0);
+        if extended {
+            if buf.len() < pos + 4 {
+                return Ok(ChunkResult::Incomplete);
+            }
+            timestamp_value = u32::from_be_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]]);
+            pos += 4;
+        }
+
+        // Chunk payload
+        let received = if starting { 0 } else { state.payload.len() };
+        let remaining = (length as usize).saturating_sub(received);
+        let chunk_len = remaining.min(self.chunk_size);
+        if buf.len() < pos + chunk_len {
+            return Ok(ChunkResult::Incomplete);
+        }
+
+        // The whole chunk is available: commit the header
+        state.extended = extended;
+        if starting {
+            match format {
+                ChunkHeaderFormat::Format0 => {
+                    state.timestamp = timestamp_value;
+                    state.delta = timestamp_value;
+                }
+                ChunkHeaderFormat::Format1 | ChunkHeaderFormat::Format2 => {
+                    state.delta = timestamp_value;
+                    state.timestamp = state.timestamp.wrapping_add(timestamp_value);
+                }
+                ChunkHeaderFormat::Format3 => {
+                    state.timestamp = state.timestamp.wrapping_add(state.delta);
+                }
+            }
+            state.length = length;
+            state.type_id = type_id;
+            state.stream_id = stream_id;
+        }
+
+        let _ = buf.split_to(pos);
+        state.payload.extend_from_slice(&buf.split_to(chunk_len));
+
+        if state.payload.len() < state.length as usize {
+            return Ok(ChunkResult::Partial);
+        }
+
+        let payload = state.payload.split().freeze();
+        match MessageType::try_from(state.type_id) {
+            Ok(message_type) => Ok(ChunkResult::Complete(Some(Message::new(
+                message_type,
+                state.stream_id,
+                state.timestamp,
+                payload,
+            )))),
+            Err(_) => {
+                debug!(
+                    "Skipping message with unknown type {} on chunk stream {}",
+                    state.type_id, chunk_stream_id
+                );
+                Ok(ChunkResult::Complete(None))
+            }
+        }
+    }
+}
+
+impl Default for ChunkDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Outcome of decoding a single chunk
+enum ChunkResult {
+    /// Not enough buffered data for the next chunk
+    Incomplete,
+    /// A chunk was consumed, the message is not complete yet
+    Partial,
+    /// A message was completed (`None` if it was skipped)
+    Complete(Option<Message>),
+}
+
+/// Splits RTMP messages into chunks
+#[derive(Debug)]
+pub struct ChunkEncoder {
+    /// Outgoing chunk size
+    chunk_size: usize,
+}
+
+impl ChunkEncoder {
+    /// Create an encoder with the default chunk size
+    pub fn new() -> Self {
+        Self {
+            chunk_size: DEFAULT_CHUNK_SIZE as usize,
+        }
+    }
+
+    /// Set the outgoing chunk size (after sending SetChunkSize)
+    pub fn set_chunk_size(&mut self, size: u32) {
+        self.chunk_size = size.clamp(1, MAX_CHUNK_SIZE) as usize;
+    }
+
+    /// Append the chunks of `message` on `chunk_stream_id` to `out`
+    ///
+    /// The first chunk carries a type 0 header, continuation chunks use
+    /// type 3.
+    pub fn encode(&mut self, chunk_stream_id: u32, message: &Message, out: &mut BytesMut) {
+        let header = ChunkHeader::new(
+            ChunkHeaderFormat::Format0,
+            chunk_stream_id,
+            message.timestamp,
+            message.payload.len() as u32,
+            message.message_type,
+            message.message_stream_id,
+        );
+        let extended = utils::needs_extended_timestamp(header.timestamp);
+        let chunks = message.payload.len().div_ceil(self.chunk_size).max(1);
+        out.reserve(message.payload.len() + chunks * 8 + 11);
+
+        write_basic_header(out, 0, chunk_stream_id);
+        out.put_slice(&(if extended { 0xFF_FFFF } else { header.timestamp }).to_be_bytes()[1..]);
+        out.put_slice(&header.message_length.to_be_bytes()[1..]);
+        out.put_u8(header.message_type as u8);
+        out.put_u32_le(header.message_stream_id);
+        if extended {
+            out.put_u32(header.timestamp);
+        }
+
+        for (i, chunk) in message.payload.chunks(self.chunk_size).enumerate() {
+            if i > 0 {
+                write_basic_header(out, 3, chunk_stream_id);
+                if extended {
+                    out.put_u32(header.timestamp);
+                }
+            }
+            out.put_slice(chunk);
+        }
+    }
+}
+
+impl Default for ChunkEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn write_basic_header(out: &mut BytesMut, fmt: u8, chunk_stream_id: u32) {
+    match chunk_stream_id {
+        2..=63 => out.put_u8((fmt << 6) | chunk_stream_id as u8),
+        64..=319 => {
+            out.put_u8(fmt << 6);
+            out.put_u8((chunk_stream_id - 64) as u8);
+        }
+        _ => {
+            let id = chunk_stream_id - 64;
+            out.put_u8((fmt << 6) | 1);
+            out.put_u8((id & 0xff) as u8);
+            out.put_u8((id >> 8) as u8);
+        }
+    }
+}
+
+fn read_u24(b: &[u8]) -> u32 {
+    u32::from_be_bytes([0, b[0], b[1], b[2]])
+}
@@ -0,0 +1,467 @@
+//! RTMP client
+//!
+//! Connects to another RTMP server to publish or play a stream. Used by the
+//! push relays to re-stream local streams upstream, and by edge mode to pull
+//! streams from an origin. `rtmpt://` URLs tunnel the connection over HTTP
+//! for networks that only let HTTP through.
+//!
+//! Applications use [`RtmpClient`] the same way to push to or pull from any
+//! RTMP server: media goes both ways as [`FlvTag`]s, audio, video and
+//! metadata, with millisecond timestamps.
+
+use crate::chunk::{ChunkDecoder, ChunkEncoder};
+use crate::command::{self, Command};
+use crate::error::{Error, Result};
+use crate::flow::FlowControl;
+use crate::flv::{FlvTag, TagType};
+use crate::handshake;
+use crate::protocol::{constants::*, utils, Message, MessageType, UserControl};
+use crate::rtmpt;
+use amf::amf0::{self, Value};
+use bytes::{Buf, Bytes, BytesMut};
+use futures::FutureExt;
+use std::collections::VecDeque;
+use std::fmt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// A parsed `rtmp://host[:port]/app/stream` (or `rtmpt://`, `rtmps://`) URL
+///
+/// IPv6 hosts are written in brackets, as in `rtmp://[::1]/live/s`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RtmpUrl {
+    /// Tunnel over HTTP (RTMPT) instead of a plain TCP connection
+    pub tunneled: bool,
+    /// RTMP over TLS (RTMPS), connected with [`RtmpClient::connect_with`]
+    pub tls: bool,
+    /// Server host, without brackets
+    pub host: String,
+    /// Server port
+    pub port: u16,
+    /// Application name
+    pub app: String,
+    /// Stream name, including any query string
+    pub stream: String,
+}
+
+impl RtmpUrl {
+    /// Parse an RTMP URL
+    pub fn parse(url: &str) -> Result<Self> {
+        let (scheme, rest) = url
+            .split_once("://")
+            .ok_or_else(|| Error::InvalidInput(format!("Not an rtmp:// URL: {}", url)))?;
+        let (tunneled, tls, default_port) = match scheme {
+            "rtmp" => (false, false, crate::DEFAULT_RTMP_PORT),
+            "rtmpt" => (true, false, 80),
+            "rtmps" => (false, true, 443),
+            _ => return Err(Error::InvalidInput(format!("Not an rtmp:// URL: {}", url))),
+        };
+        let (authority, path) = rest
+            .split_once('/')
+            .ok_or_else(|| Error::InvalidInput(format!("Missing app in URL: {}", url)))?;
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| Error::InvalidInput(format!("Unclosed IPv6 host in URL: {}", url)))?;
+                match port {
+                    "" => (host, None),
+                    port => (host, Some(port.strip_prefix(':').unwrap_or(port))),
+                }
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| Error::InvalidInput(format!("Invalid port in URL: {}", url)))?,
+            None => default_port,
+        };
+        let (app, stream) = path.split_once('/').unwrap_or((path, ""));
+        if host.is_empty() || app.is_empty() || stream.is_empty() {
+            return Err(Error::InvalidInput(format!("Incomplete RTMP URL: {}", url)));
+        }
+        Ok(Self {
+            tunneled,
+            tls,
+            host: host.to_string(),
+            port,
+            app: app.to_string(),
+            stream: stream.to_string(),
+        })
+    }
+
+    /// `tcUrl` sent in the connect command
+    pub fn tc_url(&self) -> String {
+        let scheme = match (self.tunneled, self.tls) {
+            (true, _) => "rtmpt",
+            (false, true) => "rtmps",
+            (false, false) => "rtmp",
+        };
+        if self.host.contains(':') {
+            format!("{}://[{}]:{}/{}", scheme, self.host, self.port, self.app)
+        } else {
+            format!("{}://{}:{}/{}", scheme, self.host, self.port, self.app)
+        }
+    }
+}
+
+impl fmt::Display for RtmpUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.tc_url(), self.stream)
+    }
+}
+
+/// Byte stream an RTMP client runs over
+trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+/// RTMP client connection
+///
+/// [`connect`](Self::connect) performs the handshake and connects to the
+/// URL's app; [`publish`](Self::publish) or [`play`](Self::play) then
+/// create a stream. Control messages from the server (chunk size, windows,
+/// pings) are handled while tags are sent and read.
+pub struct RtmpClient {
+    /// TCP stream, or the pipe of an RTMPT tunnel
+    stream: Box<dyn Transport>,
+    /// Bytes read but not yet decoded
+    read_buf: BytesMut,
+    /// Encoded chunks waiting to be written
+    write_buf: BytesMut,
+    /// Incoming chunk stream
+    decoder: ChunkDecoder,
+    /// Outgoing chunk stream
+    encoder: ChunkEncoder,
+    /// Acknowledgement windows and byte counters
+    flow: FlowControl,
+    /// Next command transaction ID
+    transaction_id: f64,
+    /// Message stream ID from createStream
+    stream_id: u32,
+    /// Sub-messages of an aggregate not returned yet
+    pending: VecDeque<Message>,
+}
+
+impl RtmpClient {
+    /// Connect to the application of `url`
+    ///
+    /// `rtmps://` URLs are refused: the TLS connection, with the trust
+    /// roots it needs, goes through [`connect_with`](Self::connect_with).
+    pub async fn connect(url: &RtmpUrl) -> Result<Self> {
+        if url.tls {
+            return Err(Error::InvalidInput(format!("{} needs a TLS stream, see connect_with", url)));
+        }
+        if url.tunneled {
+            return Self::connect_with(rtmpt::connect(&url.host, url.port).await?, url).await;
+        }
+        let stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+        stream.set_nodelay(true)?;
+        Self::connect_with(stream, url).await
+    }
+
+    /// Connect to the application of `url` over an established byte
+    /// stream, such as a TLS connection
+    ///
+    /// The host and port of `url` only go into the `tcUrl`.
+    pub async fn connect_with<S>(stream: S, url: &RtmpUrl) -> Result<Self>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let mut stream: Box<dyn Transport> = Box::new(stream);
+        handshake::client_handshake(&mut stream).await?;
+
+        let mut client = Self {
+            stream,
+            read_buf: BytesMut::with_capacity(64 * 1024),
+            write_buf: BytesMut::new(),
+            decoder: ChunkDecoder::new(),
+            encoder: ChunkEncoder::new(),
+            flow: FlowControl::new(),
+            transaction_id: 1.0,
+            stream_id: 0,
+            pending: VecDeque::new(),
+        };
+
+        let chunk_size = SERVER_CHUNK_SIZE.to_be_bytes();
+        client.send_message(
+            CSID_PROTOCOL_CONTROL,
+            Message::new(MessageType::SetChunkSize, 0, 0, Bytes::copy_from_slice(&chunk_size)),
+        );
+        client.encoder.set_chunk_size(SERVER_CHUNK_SIZE);
+
+        let properties = command::object(vec![
+            ("app", amf0::string(url.app.as_str())),
+            ("type", amf0::string("nonprivate")),
+            ("flashVer", amf0::string("FMLE/3.0 (compatible; rtmp-streaming-server)")),
+            ("tcUrl", amf0::string(url.tc_url())),
+        ]);
+        client.call("connect", properties, Vec::new()).await?;
+        debug!("Connected to {}", url.tc_url());
+        Ok(client)
+    }
+
+    /// Create a stream and start publishing `name` on it
+    pub async fn publish(&mut self, name: &str) -> Result<()> {
+        self.create_stream().await?;
+        let args = vec![amf0::string(name), amf0::string("live")];
+        self.send_command(self.stream_id, Command::new("publish", 0.0, Value::Null, args));
+        self.wait_status("NetStream.Publish.Start").await
+    }
+
+    /// Create a stream and start playing `name` on it
+    pub async fn play(&mut self, name: &str) -> Result<()> {
+        self.create_stream().await?;
+        let args = vec![amf0::string(name)];
+        self.send_command(self.stream_id, Command::new("play", 0.0, Value::Null, args));
+        self.wait_status("NetStream.Play.Start").await
+    }
+
+    /// Send a media or data tag on the published stream
+    ///
+    /// Script tags carry AMF0 data such as `onMetaData` and its properties.
+    /// Waits for acknowledgements while the server's bandwidth window is
+    /// full.
+    pub async fn send_tag(&mut self, tag: &FlvTag) -> Result<()> {
+        let csid = match tag.tag_type {
+            TagType::Audio => CSID_AUDIO,
+            TagType::Video => CSID_VIDEO,
+            TagType::Script => CSID_DATA,
+        };
+        self.wait_window().await?;
+        self.send_message(csid, Message::from_tag(tag, self.stream_id));
+        self.flush().await
+    }
+
+    /// Read the next media or data tag of the played stream
+    ///
+    /// AMF3 data arrives as an AMF0 script tag. Returns `None` when the
+    /// connection closes or the stream is unpublished.
+    ///
+    /// Cancel safe: it can race a timer in `select!` without losing tags
+    /// or corrupting the outgoing chunk stream.
+    pub async fn read_tag(&mut self) -> Result<Option<FlvTag>> {
+        while let Some(message) = self.read_message().await? {
+            if let Some(command) = Command::from_message(&message)? {
+                if status_code(&command) == Some("NetStream.Play.UnpublishNotify") {
+                    return Ok(None);
+                }
+                continue;
+            }
+            if matches!(message.message_type, MessageType::DataAmf0 | MessageType::DataAmf3) {
+                let values = command::data_values(&message)?;
+                // Player permissions, not stream data
+                if values.first().and_then(|v| v.try_as_str()) == Some("|RtmpSampleAccess") {
+                    continue;
+                }
+                let data = match message.message_type {
+                    MessageType::DataAmf3 => command::encode_values(&values),
+                    _ => message.payload,
+                };
+                return Ok(Some(FlvTag::new(TagType::Script, message.timestamp, data)));
+            }
+            if let Some(tag) = message.to_tag() {
+                return Ok(Some(tag));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Delete the published or played stream and close the connection
+    pub async fn close(mut self) -> Result<()> {
+        if self.stream_id != 0 {
+            let args = vec![amf0::number(self.stream_id)];
+            self.send_command(0, Command::new("deleteStream", 0.0, Value::Null, args));
+        }
+        self.flush().await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Send `createStream` and remember the returned stream ID
+    async fn create_stream(&mut self) -> Result<()> {
+        let result = self.call("createStream", Value::Null, Vec::new()).await?;
+        let stream_id = result.args.first().and_then(|v| v.try_as_f64()).ok_or_else(|| {
+            Error::Protocol("createStream result without a stream ID".to_string())
+        })?;
+        self.stream_id = stream_id as u32;
+        Ok(())
+    }
+
+    /// Send a command and wait for its `_result`
+    async fn call(&mut self, name: &str, command_object: Value, args: Vec<Value>) -> Result<Command> {
+        let transaction_id = self.transaction_id;
+        self.transaction_id += 1.0;
+        self.send_command(0, Command::new(name, transaction_id, command_object, args));
+        self.flush().await?;
+
+        while let Some(message) = self.read_message().await? {
+            let Some(command) = Command::from_message(&message)? else {
+                continue;
+            };
+            if command.transaction_id != transaction_id {
+                continue;
+            }
+            return match command.name.as_str() {
+                "_result" => Ok(command),
+                "_error" => Err(Error::Protocol(format!(
+                    "{} failed: {}",
+                    name,
+                    status_code(&command).unwrap_or("unknown error")
+                ))),
+                _ => continue,
+            };
+        }
+        Err(Error::Network(format!("Connection closed during {}", name)))
+    }
+
+    /// Wait for an onStatus with `code`; error statuses fail
+    async fn wait_status(&mut self, code: &str) -> Result<()> {
+        self.flush().await?;
+        while let Some(message) = self.read_message().await? {
+            let Some(command) = Command::from_message(&message)? else {
+                continue;
+            };
+            if command.name != "onStatus" {
+                continue;
+            }
+            let info = command.args.first().unwrap_or(&Value::Null);
+            let received = status_code(&command).unwrap_or_default();
+            if received == code {
+                return Ok(());
+            }
+            if command::property(info, "level").and_then(|v| v.try_as_str()) == Some("error") {
+                let description = command::property(info, "description")
+                    .and_then(|v| v.try_as_str())
+                    .unwrap_or_default();
+                return Err(Error::Stream(format!("{}: {}", received, description)));
+            }
+        }
+        Err(Error::Network(format!("Connection closed waiting for {}", code)))
+    }
+
+    /// Apply the control messages that already arrived, then wait for
+    /// acknowledgements while the peer's bandwidth window is full
+    ///
+    /// Other messages are kept for the next read.
+    async fn wait_window(&mut self) -> Result<()> {
+        loop {
+            self.decode_messages()?;
+            let open = if self.flow.can_send() {
+                // Take what is already there, without waiting for more
+                match self.fill().now_or_never() {
+                    Some(open) => open?,
+                    None => return Ok(()),
+                }
+            } else {
+                self.flush().await?;
+                self.fill().await?
+            };
+            if !open {
+                return Err(Error::Network("Connection closed while sending".to_string()));
+            }
+        }
+    }
+
+    /// Read the next message, applying protocol control messages and
+    /// splitting aggregates
+    async fn read_message(&mut self) -> Result<Option<Message>> {
+        loop {
+            self.decode_messages()?;
+            if let Some(message) = self.pending.pop_front() {
+                return Ok(Some(message));
+            }
+            self.flush().await?;
+            if !self.fill().await? {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Decode the buffered messages: control messages are applied, the
+    /// others (and the parts of aggregates) queued in `pending`
+    fn decode_messages(&mut self) -> Result<()> {
+        while let Some(message) = self.decoder.decode(&mut self.read_buf)? {
+            match message.message_type {
+                MessageType::SetChunkSize => {
+                    self.decoder.set_chunk_size(utils::read_u32(&message.payload)? & 0x7fff_ffff)?;
+                }
+                MessageType::AbortMessage => {
+                    self.decoder.abort(utils::read_u32(&message.payload)?);
+                }
+                MessageType::UserControl => {
+                    if let Ok(UserControl::PingRequest(timestamp)) = UserControl::decode(&message.payload) {
+                        self.send_message(CSID_PROTOCOL_CONTROL, UserControl::PingResponse(timestamp).to_message());
+                    }
+                }
+                MessageType::Acknowledgement
+                | MessageType::WindowAcknowledgementSize
+                | MessageType::SetPeerBandwidth => {
+                    if let Some(reply) = self.flow.process(&message)? {
+                        self.send_message(CSID_PROTOCOL_CONTROL, reply);
+                    }
+                }
+                MessageType::Aggregate => self.pending.extend(message.split_aggregate()?),
+                _ => self.pending.push_back(message),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read more bytes, acknowledging them when a window is full; `false`
+    /// once the connection is closed
+    ///
+    /// Cancel safe, like the read it wraps.
+    async fn fill(&mut self) -> Result<bool> {
+        let read = self.stream.read_buf(&mut self.read_buf).await?;
+        if read == 0 {
+            return Ok(false);
+        }
+        if let Some(ack) = self.flow.received(read) {
+            self.send_message(CSID_PROTOCOL_CONTROL, ack);
+        }
+        Ok(true)
+    }
+
+    /// Queue a command message
+    fn send_command(&mut self, message_stream_id: u32, command: Command) {
+        let message = Message::new(MessageType::CommandAmf0, message_stream_id, 0, command.encode());
+        self.send_message(CSID_COMMAND, message);
+    }
+
+    /// Queue a message on a chunk stream
+    fn send_message(&mut self, chunk_stream_id: u32, message: Message) {
+        self.encoder.encode(chunk_stream_id, &message, &mut self.write_buf);
+    }
+
+    /// Write queued chunks to the socket
+    ///
+    /// Written bytes leave the queue as soon as each write returns, so a
+    /// flush cancelled halfway never sends them twice.
+    async fn flush(&mut self) -> Result<()> {
+        while !self.write_buf.is_empty() {
+            let written = self.stream.write(&self.write_buf).await?;
+            if written == 0 {
+                return Err(Error::Network("Connection closed while writing".to_string()));
+            }
+            self.write_buf.advance(written);
+            self.flow.sent(written);
+        }
+        Ok(())
+    }
+}
+
+/// `code` of the info object carried by a status or error command
+fn status_code(command: &Command) -> Option<&str> {
+    command
+        .args
+        .first()
+        .and_then(|info| command::property(info, "code"))
+        .and_then(|v| v.try_as_str())
+}
+
@@ -0,0 +1,310 @@
+//! RTMP command messages
+//!
+//! AMF0 encoding and decoding of command messages (`connect`, `publish`,
+//! `play`...) and the status objects sent back to clients.
+//!
+//! Clients that negotiate `objectEncoding: 3` send AMF3 command and data
+//! messages: a format byte, then AMF0 values that may switch to AMF3 with
+//! the AVM+ marker. AMF3 values are decoded (with their string, object and
+//! trait references resolved) and turned into their AMF0 equivalents, so
+//! the rest of the server handles both encodings alike. Replies to such
+//! clients go the other way: their objects are written as AMF3.
+
+use crate::error::{Error, Result};
+use crate::protocol::{Message, MessageType};
+use amf::amf0::{self, Value};
+use amf::{amf3, Pair};
+use bytes::Bytes;
+use std::io::Cursor;
+
+/// Format byte in front of the body of AMF3 command and data messages
+pub const AMF3_FORMAT: u8 = 0;
+
+/// Smallest AMF3 integer (29 bits, signed)
+const AMF3_INT_MIN: f64 = -(1 << 28) as f64;
+
+/// Largest AMF3 integer
+const AMF3_INT_MAX: f64 = ((1 << 28) - 1) as f64;
+
+/// A decoded command message
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    /// Command name
+    pub name: String,
+    /// Transaction ID
+    pub transaction_id: f64,
+    /// Command object (`Null` when absent)
+    pub command_object: Value,
+    /// Additional arguments
+    pub args: Vec<Value>,
+}
+
+impl Command {
+    /// Create a new command
+    pub fn new(name: &str, transaction_id: f64, command_object: Value, args: Vec<Value>) -> Self {
+        Self {
+            name: name.to_string(),
+            transaction_id,
+            command_object,
+            args,
+        }
+    }
+
+    /// Decode an AMF0 command payload
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let mut values = decode_values(payload)?.into_iter();
+        let name = match values.next() {
+            Some(Value::String(name)) => name,
+            other => {
+                return Err(Error::Protocol(format!(
+                    "Command name expected, got {:?}",
+                    other
+                )))
+            }
+        };
+        let transaction_id = values.next().and_then(|v| v.try_as_f64()).unwrap_or(0.0);
+        let command_object = values.next().unwrap_or(Value::Null);
+        Ok(Self {
+            name,
+            transaction_id,
+            command_object,
+            args: values.collect(),
+        })
+    }
+
+    /// Decode the command of an AMF0 or AMF3 command message; `None` for
+    /// other messages
+    pub fn from_message(message: &Message) -> Result<Option<Self>> {
+        match message.message_type {
+            MessageType::CommandAmf0 => Self::decode(&message.payload).map(Some),
+            MessageType::CommandAmf3 => Self::decode(amf3_body(&message.payload)).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Encode as an AMF0 command payload
+    pub fn encode(&self) -> Bytes {
+        let mut values = vec![
+            amf0::string(self.name.as_str()),
+            amf0::number(self.transaction_id),
+            self.command_object.clone(),
+        ];
+        values.extend(self.args.iter().cloned());
+        encode_values(&values)
+    }
+
+    /// Encode as the payload of an AMF3 command message
+    ///
+    /// Name and transaction ID stay AMF0, as Flash Player writes them; the
+    /// command object and arguments switch to AMF3.
+    pub fn encode_amf3(&self) -> Bytes {
+        let mut values = vec![
+            amf0::string(self.name.as_str()),
+            amf0::number(self.transaction_id),
+            Value::AvmPlus(to_amf3(self.command_object.clone())),
+        ];
+        values.extend(self.args.iter().cloned().map(|value| Value::AvmPlus(to_amf3(value))));
+        let mut payload = vec![AMF3_FORMAT];
+        payload.extend_from_slice(&encode_values(&values));
+        Bytes::from(payload)
+    }
+
+    /// String argument at `index`, if present
+    pub fn arg_str(&self, index: usize) -> Option<&str> {
+        self.args.get(index).and_then(|v| v.try_as_str())
+    }
+}
+
+/// Decode consecutive AMF0 values until the payload is exhausted
+///
+/// Values switched to AMF3 are returned as AMF0 where AMF0 has an
+/// equivalent.
+pub fn decode_values(payload: &[u8]) -> Result<Vec<Value>> {
+    let mut cursor = Cursor::new(payload);
+    let mut values = Vec::new();
+    while (cursor.position() as usize) < payload.len() {
+        let value = Value::read_from(&mut cursor)
+            .map_err(|e| Error::Protocol(format!("AMF0 decode error: {}", e)))?;
+        values.push(to_amf0(value));
+    }
+    Ok(values)
+}
+
+/// Decode the values of an AMF0 or AMF3 data message
+pub fn data_values(message: &Message) -> Result<Vec<Value>> {
+    match message.message_type {
+        MessageType::DataAmf3 => decode_values(amf3_body(&message.payload)),
+        _ => decode_values(&message.payload),
+    }
+}
+
+/// Values of an AMF3 command or data message, after the format byte
+pub fn amf3_body(payload: &[u8]) -> &[u8] {
+    payload.strip_prefix(&[AMF3_FORMAT]).unwrap_or(payload)
+}
+
+/// Replace AMF3 values inside `value` with their AMF0 equivalents
+///
+/// Byte arrays and dictionaries have none and stay AMF3.
+fn to_amf0(value: Value) -> Value {
+    let pairs = |entries: Vec<Pair<String, Value>>| {
+        entries
+            .into_iter()
+            .map(|p| Pair {
+                key: p.key,
+                value: to_amf0(p.value),
+            })
+            .collect()
+    };
+    match value {
+        Value::AvmPlus(value) => from_amf3(value),
+        Value::Object { class_name, entries } => Value::Object {
+            class_name,
+            entries: pairs(entries),
+        },
+        Value::EcmaArray { entries } => Value::EcmaArray { entries: pairs(entries) },
+        Value::Array { entries } => Value::Array {
+            entries: entries.into_iter().map(to_amf0).collect(),
+        },
+        value => value,
+    }
+}
+
+/// AMF0 equivalent of an AMF3 value
+fn from_amf3(value: amf3::Value) -> Value {
+    let pairs = |entries: Vec<Pair<String, amf3::Value>>| {
+        entries
+            .into_iter()
+            .map(|p| Pair {
+                key: p.key,
+                value: from_amf3(p.value),
+            })
+            .collect::<Vec<_>>()
+    };
+    let numbers = |entries: Vec<f64>| Value::Array {
+        entries: entries.into_iter().map(Value::Number).collect(),
+    };
+    match value {
+        amf3::Value::Undefined => Value::Undefined,
+        amf3::Value::Null => Value::Null,
+        amf3::Value::Boolean(value) => Value::Boolean(value),
+        amf3::Value::Integer(value) => Value::Number(value.into()),
+        amf3::Value::Double(value) => Value::Number(value),
+        amf3::Value::String(value) => Value::String(value),
+        amf3::Value::XmlDocument(value) | amf3::Value::Xml(value) => Value::XmlDocument(value),
+        amf3::Value::Date { unix_time } => Value::Date { unix_time, time_zone: 0 },
+        // Arrays with named entries are ECMA arrays, the dense part indexed
+        amf3::Value::Array {
+            assoc_entries,
+            dense_entries,
+        } if assoc_entries.is_empty() => Value::Array {
+            entries: dense_entries.into_iter().map(from_amf3).collect(),
+        },
+        amf3::Value::Array {
+            assoc_entries,
+            dense_entries,
+        } => {
+            let mut entries = pairs(assoc_entries);
+            entries.extend(dense_entries.into_iter().enumerate().map(|(i, value)| Pair {
+                key: i.to_string(),
+                value: from_amf3(value),
+            }));
+            Value::EcmaArray { entries }
+        }
+        amf3::Value::Object { class_name, entries, .. } => Value::Object {
+            class_name,
+            entries: pairs(entries),
+        },
+        amf3::Value::IntVector { entries, .. } => numbers(entries.into_iter().map(f64::from).collect()),
+        amf3::Value::UintVector { entries, .. } => numbers(entries.into_iter().map(f64::from).collect()),
+        amf3::Value::DoubleVector { entries, .. } => numbers(entries),
+        amf3::Value::ObjectVector { entries, .. } => Value::Array {
+            entries: entries.into_iter().map(from_amf3).collect(),
+        },
+        value @ (amf3::Value::ByteArray(_) | amf3::Value::Dictionary { .. }) => Value::AvmPlus(value),
+    }
+}
+
+/// AMF3 equivalent of an AMF0 value
+///
+/// Whole numbers in the 29-bit range become AMF3 integers; the time zone
+/// of dates is dropped, as AMF3 has none.
+fn to_amf3(value: Value) -> amf3::Value {
+    let pairs = |entries: Vec<Pair<String, Value>>| {
+        entries
+            .into_iter()
+            .map(|p| Pair {
+                key: p.key,
+                value: to_amf3(p.value),
+            })
+            .collect::<Vec<_>>()
+    };
+    match value {
+        Value::Undefined => amf3::Value::Undefined,
+        Value::Null => amf3::Value::Null,
+        Value::Boolean(value) => amf3::Value::Boolean(value),
+        Value::Number(value) if value.fract() == 0.0 && (AMF3_INT_MIN..=AMF3_INT_MAX).contains(&value) => {
+            amf3::Value::Integer(value as i32)
+        }
+        Value::Number(value) => amf3::Value::Double(value),
+        Value::String(value) => amf3::Value::String(value),
+        Value::XmlDocument(value) => amf3::Value::XmlDocument(value),
+        Value::Date { unix_time, .. } => amf3::Value::Date { unix_time },
+        Value::Array { entries } => amf3::Value::Array {
+            assoc_entries: Vec::new(),
+            dense_entries: entries.into_iter().map(to_amf3).collect(),
+        },
+        Value::EcmaArray { entries } => amf3::Value::Array {
+            assoc_entries: pairs(entries),
+            dense_entries: Vec::new(),
+        },
+        Value::Object { class_name, entries } => amf3::Value::Object {
+            class_name,
+            sealed_count: 0,
+            entries: pairs(entries),
+        },
+        Value::AvmPlus(value) => value,
+    }
+}
+
+/// Encode AMF0 values back to back
+pub fn encode_values(values: &[Value]) -> Bytes {
+    let mut out = Vec::new();
+    for value in values {
+        // Writing into a Vec cannot fail
+        let _ = value.write_to(&mut out);
+    }
+    Bytes::from(out)
+}
+
+/// Look up a property of an AMF0 object or ECMA array
+pub fn property<'a>(object: &'a Value, key: &str) -> Option<&'a Value> {
+    let entries = match object {
+        Value::Object { entries, .. } | Value::EcmaArray { entries } => entries,
+        _ => return None,
+    };
+    entries.iter().find(|p| p.key == key).map(|p| &p.value)
+}
+
+/// Build an anonymous AMF0 object from key/value pairs
+pub fn object(entries: Vec<(&str, Value)>) -> Value {
+    Value::Object {
+        class_name: None,
+        entries: entries
+            .into_iter()
+            .map(|(key, value)| Pair {
+                key: key.to_string(),
+                value,
+            })
+            .collect(),
+    }
+}
+
+/// Build a status info object (`level`, `code`, `description`)
+pub fn status(level: &str, code: &str, description: &str) -> Value {
+    object(vec![
+        ("level", amf0::string(level)),
+        ("code", amf0::string(code)),
+        ("description", amf0::string(description)),
+    ])
+}
@@ -0,0 +1,146 @@
+//! Edge mode
+//!
+//! When a viewer asks for a stream that is not published locally and the
+//! app has an [`EdgeConfig`], the stream is pulled from the origin server
+//! (over RTMP or HTTP-FLV) and published locally, so every local viewer
+//! shares one upstream connection. The pull stops once the stream has had
+//! no viewers for the configured idle timeout.
+
+use crate::client::{RtmpClient, RtmpUrl};
+use crate::config::EdgeConfig;
+use crate::error::{Error, Result};
+use crate::flv::{FlvDemuxer, FlvTag};
+use crate::stream::{Publisher, StreamManager};
+use futures::StreamExt;
+use hyper::{Body, Client};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How often a pull checks whether it still has viewers
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Streams currently pulled from an origin
+#[derive(Clone, Default)]
+pub struct EdgeManager {
+    pulling: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl EdgeManager {
+    /// Create an empty edge manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if `name` of `app` is being pulled from an origin
+    pub fn is_pulling(&self, app: &str, name: &str) -> bool {
+        self.pulling.lock().unwrap().contains(&(app.to_string(), name.to_string()))
+    }
+
+    /// Start pulling `name` unless it is already pulled or published
+    pub(crate) async fn start(&self, streams: &StreamManager, app: &str, name: &str, edge: &EdgeConfig) {
+        let key = (app.to_string(), name.to_string());
+        if !self.pulling.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        let origin = edge.origin_for(streams.config().app_name(app), name);
+        let publisher_id = format!("edge-{}", uuid::Uuid::new_v4().simple());
+        let publisher = match streams.start_publish(app, name, publisher_id).await {
+            Ok(publisher) => publisher,
+            Err(e) => {
+                warn!("Not pulling {} from {}: {}", name, origin, e);
+                self.pulling.lock().unwrap().remove(&key);
+                return;
+            }
+        };
+
+        info!("Pulling {} from origin {}", name, origin);
+        let manager = self.clone();
+        let name = name.to_string();
+        let idle_timeout = edge.idle_timeout();
+        tokio::spawn(async move {
+            match pull(&origin, publisher, idle_timeout).await {
+                Ok(()) => info!("Stopped pulling {} from {}", name, origin),
+                Err(e) => warn!("Pulling {} from {} failed: {}", name, origin, e),
+            }
+            manager.pulling.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+/// An upstream connection delivering FLV tags
+enum Origin {
+    /// RTMP play session
+    Rtmp(RtmpClient),
+    /// HTTP-FLV response body
+    Http(Body, FlvDemuxer),
+}
+
+impl Origin {
+    /// Connect to an `rtmp://`, `rtmpt://` or `http://` origin URL
+    async fn connect(url: &str) -> Result<Self> {
+        if url.starts_with("rtmp://") || url.starts_with("rtmpt://") {
+            let url = RtmpUrl::parse(url)?;
+            let mut client = RtmpClient::connect(&url).await?;
+            client.play(&url.stream).await?;
+            return Ok(Origin::Rtmp(client));
+        }
+        if url.starts_with("http://") {
+            let uri = url
+                .parse()
+                .map_err(|e| Error::InvalidInput(format!("Invalid origin URL {}: {}", url, e)))?;
+            let response = Client::new()
+                .get(uri)
+                .await
+                .map_err(|e| Error::Network(e.to_string()))?;
+            if !response.status().is_success() {
+                return Err(Error::Stream(format!("Origin answered {}", response.status())));
+            }
+            return Ok(Origin::Http(response.into_body(), FlvDemuxer::new()));
+        }
+        Err(Error::InvalidInput(format!("Unsupported origin URL: {}", url)))
+    }
+
+    /// Next tag from the origin, or `None` when the origin stream ended
+    async fn next_tag(&mut self) -> Result<Option<FlvTag>> {
+        match self {
+            Origin::Rtmp(client) => client.read_tag().await,
+            Origin::Http(body, demuxer) => loop {
+                if let Some(tag) = demuxer.next_tag()? {
+                    return Ok(Some(tag));
+                }
+                match body.next().await {
+                    Some(Ok(chunk)) => demuxer.push(&chunk),
+                    Some(Err(e)) => return Err(Error::Network(e.to_string())),
+                    None => return Ok(None),
+                }
+            },
+        }
+    }
+}
+
+/// Pull from `url` into the local stream until the origin ends or the
+/// stream stays without viewers for `idle_timeout`
+async fn pull(url: &str, publisher: Publisher, idle_timeout: Duration) -> Result<()> {
+    let mut origin = Origin::connect(url).await?;
+    let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    let mut idle_since: Option<Instant> = None;
+
+    loop {
+        // Both origins read cancel safely, so the tick may interrupt a read
+        tokio::select! {
+            tag = origin.next_tag() => match tag? {
+                Some(tag) => publisher.publish(tag).await?,
+                None => return Ok(()),
+            },
+            _ = idle_check.tick() => {
+                if publisher.stream().subscriber_count() > 0 {
+                    idle_since = None;
+                } else if idle_since.get_or_insert_with(Instant::now).elapsed() >= idle_timeout {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
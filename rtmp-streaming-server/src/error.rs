@@ -1,5 +1,11 @@
 //! Error types for the RTMP streaming server
+//!
+//! Besides the broad categories, errors a client can act on have their own
+//! kind. [`Error::http_status`] and [`Error::rtmp_status`] turn them into
+//! the answer each protocol expects, so a rejected client learns why
+//! instead of seeing its socket dropped.
 
+use crate::protocol::CommandType;
 use thiserror::Error;
 
 /// Result type for the RTMP streaming server
@@ -20,6 +26,30 @@ pub enum Error {
     #[error("Protocol error: {0}")]
     Protocol(String),
 
+    /// RTMP handshake failed
+    #[error("Handshake failed: {0}")]
+    Handshake(String),
+
+    /// Malformed RTMP chunk stream
+    #[error("Bad chunk: {0}")]
+    BadChunk(String),
+
+    /// Command the server does not implement
+    #[error("Unknown command: {0}")]
+    UnknownCommand(String),
+
+    /// Client is not allowed to connect, publish or play
+    #[error("Access denied: {0}")]
+    AuthDenied(String),
+
+    /// Stream is already being published
+    #[error("Stream busy: {0}")]
+    StreamBusy(String),
+
+    /// Stream or resource does not exist
+    #[error("Not found: {0}")]
+    NotFound(String),
+
     /// Stream errors
     #[error("Stream error: {0}")]
     Stream(String),
@@ -41,14 +71,40 @@ pub enum Error {
     Internal(String),
 }
 
-impl From<&str> for Error {
-    fn from(s: &str) -> Self {
-        Error::Internal(s.to_string())
+impl Error {
+    /// HTTP status code to answer a request that failed with this error
+    pub fn http_status(&self) -> u16 {
+        match self {
+            Error::Protocol(_)
+            | Error::Handshake(_)
+            | Error::BadChunk(_)
+            | Error::UnknownCommand(_)
+            | Error::InvalidInput(_) => 400,
+            Error::AuthDenied(_) => 403,
+            Error::NotFound(_) => 404,
+            Error::StreamBusy(_) | Error::Stream(_) => 409,
+            Error::ResourceLimit(_) => 429,
+            Error::Network(_) => 503,
+            Error::Io(_) | Error::Config(_) | Error::Internal(_) => 500,
+        }
     }
-}
 
-impl From<String> for Error {
-    fn from(s: String) -> Self {
-        Error::Internal(s)
+    /// RTMP status code to answer `command` with
+    ///
+    /// Connect failures use `NetConnection.Connect.*`, publish and play
+    /// failures `NetStream.Publish.*` and `NetStream.Play.*`; any other
+    /// command gets `NetConnection.Call.Failed`.
+    pub fn rtmp_status(&self, command: &CommandType) -> &'static str {
+        match (command, self) {
+            (CommandType::Connect, Error::AuthDenied(_) | Error::ResourceLimit(_)) => "NetConnection.Connect.Rejected",
+            (CommandType::Connect, Error::NotFound(_) | Error::InvalidInput(_)) => "NetConnection.Connect.InvalidApp",
+            (CommandType::Connect, _) => "NetConnection.Connect.Failed",
+            (CommandType::Publish, Error::StreamBusy(_) | Error::InvalidInput(_)) => "NetStream.Publish.BadName",
+            (CommandType::Publish, Error::AuthDenied(_) | Error::ResourceLimit(_)) => "NetStream.Publish.Denied",
+            (CommandType::Publish, _) => "NetStream.Publish.Failed",
+            (CommandType::Play, Error::NotFound(_)) => "NetStream.Play.StreamNotFound",
+            (CommandType::Play, _) => "NetStream.Play.Failed",
+            _ => "NetConnection.Call.Failed",
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,135 @@
+//! Stream lifecycle events
+//!
+//! The [`EventBus`] of a [`StreamManager`](crate::StreamManager) reports
+//! publishers and subscribers coming and going and codec changes, so a
+//! control plane can follow the server without polling. Listeners that
+//! fall behind miss events instead of slowing streams down. The same
+//! events are served as Server-Sent Events at `GET /api/events`.
+
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+/// Events kept for listeners that have not read them yet
+const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened to a stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// A publisher started publishing a stream
+    PublishStarted {
+        /// Application name
+        app: String,
+        /// Stream name
+        stream: String,
+        /// Publisher ID
+        publisher: String,
+    },
+    /// A publisher stopped or was replaced
+    PublishStopped {
+        /// Application name
+        app: String,
+        /// Stream name
+        stream: String,
+        /// Publisher ID
+        publisher: String,
+    },
+    /// A subscriber started receiving a stream
+    SubscriberJoined {
+        /// Application name
+        app: String,
+        /// Stream name
+        stream: String,
+        /// Subscriber ID
+        subscriber: String,
+    },
+    /// A subscriber went away
+    SubscriberLeft {
+        /// Application name
+        app: String,
+        /// Stream name
+        stream: String,
+        /// Subscriber ID
+        subscriber: String,
+    },
+    /// A new sequence header changed the codec or its configuration
+    CodecChanged {
+        /// Application name
+        app: String,
+        /// Stream name
+        stream: String,
+        /// `video` or `audio`
+        media: String,
+        /// Codec name, such as `Avc` or `Aac`
+        codec: String,
+    },
+    /// A recording of a stream was closed
+    ///
+    /// Emitted for the recordings of apps with a `record_dir`; recorders
+    /// built on the library can report their files with [`EventBus::emit`].
+    RecordingFinished {
+        /// Application name
+        app: String,
+        /// Stream name
+        stream: String,
+        /// Path of the recorded file
+        path: String,
+    },
+}
+
+impl Event {
+    /// Event name, as used in the SSE `event:` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PublishStarted { .. } => "publish_started",
+            Event::PublishStopped { .. } => "publish_stopped",
+            Event::SubscriberJoined { .. } => "subscriber_joined",
+            Event::SubscriberLeft { .. } => "subscriber_left",
+            Event::CodecChanged { .. } => "codec_changed",
+            Event::RecordingFinished { .. } => "recording_finished",
+        }
+    }
+
+    /// App and stream the event is about
+    pub fn stream(&self) -> (&str, &str) {
+        match self {
+            Event::PublishStarted { app, stream, .. }
+            | Event::PublishStopped { app, stream, .. }
+            | Event::SubscriberJoined { app, stream, .. }
+            | Event::SubscriberLeft { app, stream, .. }
+            | Event::CodecChanged { app, stream, .. }
+            | Event::RecordingFinished { app, stream, .. } => (app, stream),
+        }
+    }
+}
+
+/// Broadcasts [`Event`]s to every listener; clones share the channel
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    /// Create a bus without listeners
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender }
+    }
+
+    /// Listen to events from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    /// Send an event to the current listeners
+    pub fn emit(&self, event: Event) {
+        // Nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,129 @@
+//! RTMP flow control
+//!
+//! Each side announces a window with WindowAcknowledgementSize, and the
+//! peer sends an Acknowledgement every time that many bytes have arrived.
+//! SetPeerBandwidth caps how much unacknowledged data the receiver may
+//! have in flight; strict encoders stop sending when their window is not
+//! acknowledged.
+
+use crate::error::{Error, Result};
+use crate::protocol::constants::*;
+use crate::protocol::{utils, Message, MessageType};
+use bytes::Bytes;
+use tracing::debug;
+
+/// Byte counters and windows of one connection
+///
+/// Counters are sequence numbers as on the wire, so they wrap at 4 GiB.
+pub(crate) struct FlowControl {
+    /// Bytes received since the handshake
+    received: u32,
+    /// `received` when the last acknowledgement was sent
+    acknowledged: u32,
+    /// Window the peer wants acknowledgements for
+    ack_window: u32,
+    /// Window last announced to the peer
+    announced: u32,
+    /// Bytes sent since the handshake
+    sent: u32,
+    /// Last sequence number acknowledged by the peer
+    peer_acknowledged: u32,
+    /// Unacknowledged bytes the peer lets us send, once it set a limit
+    bandwidth: Option<(u32, u8)>,
+}
+
+impl FlowControl {
+    /// Counters for a new connection, announcing the default window
+    pub(crate) fn new() -> Self {
+        Self {
+            received: 0,
+            acknowledged: 0,
+            ack_window: DEFAULT_WINDOW_ACK_SIZE,
+            announced: DEFAULT_WINDOW_ACK_SIZE,
+            sent: 0,
+            peer_acknowledged: 0,
+            bandwidth: None,
+        }
+    }
+
+    /// Count bytes read from the peer; returns an Acknowledgement once a
+    /// window's worth arrived since the last one
+    pub(crate) fn received(&mut self, bytes: usize) -> Option<Message> {
+        self.received = self.received.wrapping_add(bytes as u32);
+        if self.ack_window == 0 || self.received.wrapping_sub(self.acknowledged) < self.ack_window {
+            return None;
+        }
+        self.acknowledged = self.received;
+        Some(control(MessageType::Acknowledgement, &self.received.to_be_bytes()))
+    }
+
+    /// Count bytes written to the peer
+    pub(crate) fn sent(&mut self, bytes: usize) {
+        self.sent = self.sent.wrapping_add(bytes as u32);
+    }
+
+    /// Whether the peer's bandwidth limit allows sending more data
+    pub(crate) fn can_send(&self) -> bool {
+        let Some((window, _)) = self.bandwidth else {
+            return true;
+        };
+        let unacknowledged = self.sent.wrapping_sub(self.peer_acknowledged);
+        // A peer counting the handshake acknowledges "ahead" of us
+        unacknowledged > i32::MAX as u32 || unacknowledged < window
+    }
+
+    /// Apply a flow control message from the peer
+    ///
+    /// Returns the WindowAcknowledgementSize reply a new peer bandwidth
+    /// calls for. Other message types are ignored.
+    pub(crate) fn process(&mut self, message: &Message) -> Result<Option<Message>> {
+        match message.message_type {
+            MessageType::Acknowledgement => {
+                self.peer_acknowledged = utils::read_u32(&message.payload)?;
+            }
+            MessageType::WindowAcknowledgementSize => {
+                self.ack_window = utils::read_u32(&message.payload)?;
+                debug!("Peer window acknowledgement size {}", self.ack_window);
+            }
+            MessageType::SetPeerBandwidth => {
+                let window = utils::read_u32(&message.payload)?;
+                let limit_type = *message
+                    .payload
+                    .get(4)
+                    .ok_or_else(|| Error::Protocol("SetPeerBandwidth without limit type".to_string()))?;
+                self.bandwidth = match (limit_type, self.bandwidth) {
+                    (BANDWIDTH_LIMIT_HARD, _) => Some((window, BANDWIDTH_LIMIT_HARD)),
+                    (BANDWIDTH_LIMIT_SOFT, Some((current, _))) if current < window => self.bandwidth,
+                    (BANDWIDTH_LIMIT_SOFT, _) => Some((window, BANDWIDTH_LIMIT_SOFT)),
+                    // Dynamic limits only count after a hard one
+                    (BANDWIDTH_LIMIT_DYNAMIC, Some((_, BANDWIDTH_LIMIT_HARD))) => Some((window, BANDWIDTH_LIMIT_HARD)),
+                    (BANDWIDTH_LIMIT_DYNAMIC, _) => self.bandwidth,
+                    _ => return Err(Error::Protocol(format!("Unknown bandwidth limit type {}", limit_type))),
+                };
+                debug!("Peer bandwidth {} (limit type {})", window, limit_type);
+                if window != self.announced {
+                    self.announced = window;
+                    return Ok(Some(control(MessageType::WindowAcknowledgementSize, &window.to_be_bytes())));
+                }
+            }
+            _ => {}
+        }
+        Ok(None)
+    }
+}
+
+/// Window messages a server sends after connect: our acknowledgement
+/// window, then the bandwidth we allow the peer
+pub(crate) fn server_windows() -> [Message; 2] {
+    let mut bandwidth = DEFAULT_PEER_BANDWIDTH.to_be_bytes().to_vec();
+    bandwidth.push(BANDWIDTH_LIMIT_DYNAMIC);
+    [
+        control(MessageType::WindowAcknowledgementSize, &DEFAULT_WINDOW_ACK_SIZE.to_be_bytes()),
+        control(MessageType::SetPeerBandwidth, &bandwidth),
+    ]
+}
+
+/// Protocol control message on stream 0
+fn control(message_type: MessageType, payload: &[u8]) -> Message {
+    Message::new(message_type, 0, 0, Bytes::copy_from_slice(payload))
+}
@@ -0,0 +1,594 @@
+//! FLV container support
+//!
+//! An incremental demuxer that splits an FLV byte stream into typed tags
+//! without copying tag payloads, and a muxer that turns tags back into an
+//! FLV byte stream. Both the HTTP-FLV and RTMP paths exchange media as
+//! [`FlvTag`]s, since an RTMP audio/video/data message body is exactly an
+//! FLV tag body.
+
+use crate::error::{Error, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+
+/// FLV file header size (without PreviousTagSize0)
+pub const FLV_HEADER_SIZE: usize = 9;
+
+/// Largest FLV header accepted, extension bytes included
+const MAX_FLV_HEADER_SIZE: usize = FLV_HEADER_SIZE + 1024;
+
+/// FLV tag header size
+pub const TAG_HEADER_SIZE: usize = 11;
+
+/// Size of the PreviousTagSize field following every tag
+pub const PREV_TAG_SIZE_LEN: usize = 4;
+
+/// FLV header flag: stream contains audio
+pub const FLAG_AUDIO: u8 = 0x01;
+
+/// FLV header flag: stream contains video
+pub const FLAG_VIDEO: u8 = 0x04;
+
+/// FourCC of Opus audio in Enhanced RTMP tags
+pub const OPUS_FOURCC: [u8; 4] = *b"Opus";
+
+/// FLV tag types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TagType {
+    /// Audio tag
+    Audio = 8,
+    /// Video tag
+    Video = 9,
+    /// Script data tag (onMetaData etc.)
+    Script = 18,
+}
+
+impl TryFrom<u8> for TagType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value & 0x1f {
+            8 => Ok(TagType::Audio),
+            9 => Ok(TagType::Video),
+            18 => Ok(TagType::Script),
+            other => Err(Error::Protocol(format!("Invalid FLV tag type: {}", other))),
+        }
+    }
+}
+
+/// Video frame types
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameType {
+    /// Key frame (seekable)
+    Keyframe,
+    /// Inter frame
+    Inter,
+    /// Disposable inter frame (H.263 only)
+    Disposable,
+    /// Generated key frame (server use only)
+    Generated,
+    /// Video info/command frame
+    Command,
+    /// Reserved value
+    Other(u8),
+}
+
+impl From<u8> for FrameType {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => FrameType::Keyframe,
+            2 => FrameType::Inter,
+            3 => FrameType::Disposable,
+            4 => FrameType::Generated,
+            5 => FrameType::Command,
+            other => FrameType::Other(other),
+        }
+    }
+}
+
+/// Video codec IDs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VideoCodec {
+    /// Sorenson H.263
+    SorensonH263,
+    /// Screen video
+    ScreenVideo,
+    /// On2 VP6
+    Vp6,
+    /// On2 VP6 with alpha channel
+    Vp6Alpha,
+    /// Screen video version 2
+    ScreenVideo2,
+    /// AVC (H.264)
+    Avc,
+    /// HEVC (H.265, non-standard codec ID 12)
+    Hevc,
+    /// Unknown codec ID
+    Other(u8),
+}
+
+impl From<u8> for VideoCodec {
+    fn from(value: u8) -> Self {
+        match value {
+            2 => VideoCodec::SorensonH263,
+            3 => VideoCodec::ScreenVideo,
+            4 => VideoCodec::Vp6,
+            5 => VideoCodec::Vp6Alpha,
+            6 => VideoCodec::ScreenVideo2,
+            7 => VideoCodec::Avc,
+            12 => VideoCodec::Hevc,
+            other => VideoCodec::Other(other),
+        }
+    }
+}
+
+/// Audio codec IDs (SoundFormat)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioCodec {
+    /// Linear PCM, platform endian
+    Pcm,
+    /// ADPCM
+    Adpcm,
+    /// MP3
+    Mp3,
+    /// Linear PCM, little endian
+    PcmLe,
+    /// G.711 A-law
+    G711A,
+    /// G.711 mu-law
+    G711U,
+    /// AAC
+    Aac,
+    /// Speex
+    Speex,
+    /// Enhanced RTMP: packet type in the low nibble, codec as a FourCC
+    ExHeader,
+    /// Unknown sound format
+    Other(u8),
+}
+
+impl From<u8> for AudioCodec {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => AudioCodec::Pcm,
+            1 => AudioCodec::Adpcm,
+            2 => AudioCodec::Mp3,
+            3 => AudioCodec::PcmLe,
+            7 => AudioCodec::G711A,
+            8 => AudioCodec::G711U,
+            10 => AudioCodec::Aac,
+            11 => AudioCodec::Speex,
+            9 => AudioCodec::ExHeader,
+            other => AudioCodec::Other(other),
+        }
+    }
+}
+
+/// Packet type of AVC/HEVC video and AAC audio tags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    /// Decoder configuration (AVCDecoderConfigurationRecord / AudioSpecificConfig)
+    SequenceHeader,
+    /// Coded frames (NALUs / raw AAC)
+    Data,
+    /// End of sequence (AVC/HEVC only)
+    EndOfSequence,
+}
+
+/// FLV file header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlvHeader {
+    /// FLV version (always 1)
+    pub version: u8,
+    /// Stream declares audio
+    pub has_audio: bool,
+    /// Stream declares video
+    pub has_video: bool,
+}
+
+impl FlvHeader {
+    /// Create a version 1 header
+    pub fn new(has_audio: bool, has_video: bool) -> Self {
+        Self {
+            version: 1,
+            has_audio,
+            has_video,
+        }
+    }
+
+    /// Flags byte as written in the header
+    pub fn flags(&self) -> u8 {
+        let mut flags = 0;
+        if self.has_audio {
+            flags |= FLAG_AUDIO;
+        }
+        if self.has_video {
+            flags |= FLAG_VIDEO;
+        }
+        flags
+    }
+}
+
+/// A single FLV tag
+///
+/// `data` is the tag body (everything after the 11-byte tag header), which
+/// is also the payload of the corresponding RTMP media message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlvTag {
+    /// Tag type
+    pub tag_type: TagType,
+    /// Timestamp in milliseconds (32-bit, including the extended byte)
+    pub timestamp: u32,
+    /// Tag body
+    pub data: Bytes,
+}
+
+impl FlvTag {
+    /// Create a new tag
+    pub fn new(tag_type: TagType, timestamp: u32, data: Bytes) -> Self {
+        Self {
+            tag_type,
+            timestamp,
+            data,
+        }
+    }
+
+    /// Check if this is an audio tag
+    pub fn is_audio(&self) -> bool {
+        self.tag_type == TagType::Audio
+    }
+
+    /// Check if this is a video tag
+    pub fn is_video(&self) -> bool {
+        self.tag_type == TagType::Video
+    }
+
+    /// Check if this is a script data tag
+    pub fn is_script(&self) -> bool {
+        self.tag_type == TagType::Script
+    }
+
+    /// Video frame type
+    pub fn frame_type(&self) -> Option<FrameType> {
+        match (self.tag_type, self.data.first()) {
+            (TagType::Video, Some(b)) => Some(FrameType::from(b >> 4)),
+            _ => None,
+        }
+    }
+
+    /// Check if this is a video key frame (sequence headers included)
+    pub fn is_keyframe(&self) -> bool {
+        self.frame_type() == Some(FrameType::Keyframe)
+    }
+
+    /// Video codec
+    pub fn video_codec(&self) -> Option<VideoCodec> {
+        match (self.tag_type, self.data.first()) {
+            (TagType::Video, Some(b)) => Some(VideoCodec::from(b & 0x0f)),
+            _ => None,
+        }
+    }
+
+    /// Audio codec
+    pub fn audio_codec(&self) -> Option<AudioCodec> {
+        match (self.tag_type, self.data.first()) {
+            (TagType::Audio, Some(b)) => Some(AudioCodec::from(b >> 4)),
+            _ => None,
+        }
+    }
+
+    /// FourCC of Enhanced RTMP audio
+    pub fn audio_fourcc(&self) -> Option<&[u8]> {
+        match self.audio_codec() {
+            Some(AudioCodec::ExHeader) => self.data.get(1..5),
+            _ => None,
+        }
+    }
+
+    /// Packet type for AVC/HEVC video and AAC or Enhanced RTMP audio, `None`
+    /// for other codecs
+    pub fn packet_type(&self) -> Option<PacketType> {
+        if self.audio_codec() == Some(AudioCodec::ExHeader) {
+            return match self.data[0] & 0x0f {
+                0 => Some(PacketType::SequenceHeader),
+                1 => Some(PacketType::Data),
+                _ => None,
+            };
+        }
+        let raw = *self.data.get(1)?;
+        let has_packet_type = matches!(
+            self.video_codec(),
+            Some(VideoCodec::Avc) | Some(VideoCodec::Hevc)
+        ) || self.audio_codec() == Some(AudioCodec::Aac);
+        if !has_packet_type {
+            return None;
+        }
+        match raw {
+            0 => Some(PacketType::SequenceHeader),
+            1 => Some(PacketType::Data),
+            2 if self.is_video() => Some(PacketType::EndOfSequence),
+            _ => None,
+        }
+    }
+
+    /// Check if this is an AVC/HEVC or AAC sequence header
+    pub fn is_sequence_header(&self) -> bool {
+        self.packet_type() == Some(PacketType::SequenceHeader)
+    }
+
+    /// Size of this tag once muxed, including the PreviousTagSize field
+    pub fn encoded_len(&self) -> usize {
+        TAG_HEADER_SIZE + self.data.len() + PREV_TAG_SIZE_LEN
+    }
+}
+
+/// Incremental FLV demuxer
+///
+/// Bytes are appended with [`FlvDemuxer::push`] as they arrive and complete
+/// tags are taken out with [`FlvDemuxer::next_tag`]. Tag bodies are split
+/// off the internal buffer and frozen, so they share its allocation instead
+/// of being copied.
+#[derive(Debug, Default)]
+pub struct FlvDemuxer {
+    /// Pending bytes
+    buf: BytesMut,
+    /// Parsed file header
+    header: Option<FlvHeader>,
+}
+
+impl FlvDemuxer {
+    /// Create a new demuxer expecting an FLV file header first
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a demuxer for a bare tag stream (no file header)
+    pub fn without_header() -> Self {
+        Self {
+            buf: BytesMut::new(),
+            header: Some(FlvHeader::new(true, true)),
+        }
+    }
+
+    /// Append received bytes
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// File header, once it has been parsed
+    pub fn header(&self) -> Option<&FlvHeader> {
+        self.header.as_ref()
+    }
+
+    /// Number of buffered bytes not yet consumed
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Parse the file header if it has not been parsed yet
+    ///
+    /// Returns `Ok(None)` while fewer than 13 bytes are buffered.
+    pub fn read_header(&mut self) -> Result<Option<FlvHeader>> {
+        if let Some(header) = self.header {
+            return Ok(Some(header));
+        }
+        if self.buf.len() < FLV_HEADER_SIZE + PREV_TAG_SIZE_LEN {
+            return Ok(None);
+        }
+        if &self.buf[..3] != b"FLV" {
+            return Err(Error::Protocol("Missing FLV signature".to_string()));
+        }
+        let version = self.buf[3];
+        let flags = self.buf[4];
+        let data_offset =
+            u32::from_be_bytes([self.buf[5], self.buf[6], self.buf[7], self.buf[8]]) as usize;
+        if data_offset < FLV_HEADER_SIZE {
+            return Err(Error::Protocol(format!(
+                "Invalid FLV header size: {}",
+                data_offset
+            )));
+        }
+        if data_offset > MAX_FLV_HEADER_SIZE {
+            return Err(Error::InvalidInput(format!("FLV header size {} is too large", data_offset)));
+        }
+        let skip = data_offset + PREV_TAG_SIZE_LEN;
+        if self.buf.len() < skip {
+            return Ok(None);
+        }
+        let _ = self.buf.split_to(skip);
+
+        let header = FlvHeader {
+            version,
+            has_audio: flags & FLAG_AUDIO != 0,
+            has_video: flags & FLAG_VIDEO != 0,
+        };
+        self.header = Some(header);
+        Ok(Some(header))
+    }
+
+    /// Take the next complete tag out of the buffer
+    ///
+    /// Returns `Ok(None)` when more data is needed. Tags with an unknown
+    /// type are skipped.
+    pub fn next_tag(&mut self) -> Result<Option<FlvTag>> {
+        if self.read_header()?.is_none() {
+            return Ok(None);
+        }
+        loop {
+            if self.buf.len() < TAG_HEADER_SIZE {
+                return Ok(None);
+            }
+            let b = &self.buf[..TAG_HEADER_SIZE];
+            let raw_type = b[0];
+            let data_size = u32::from_be_bytes([0, b[1], b[2], b[3]]) as usize;
+            let timestamp = u32::from_be_bytes([b[7], b[4], b[5], b[6]]);
+            let total = TAG_HEADER_SIZE + data_size + PREV_TAG_SIZE_LEN;
+            if self.buf.len() < total {
+                self.buf.reserve(total - self.buf.len());
+                return Ok(None);
+            }
+
+            let mut raw = self.buf.split_to(total).freeze();
+            let data = raw.split_off(TAG_HEADER_SIZE).slice(..data_size);
+            match TagType::try_from(raw_type) {
+                Ok(tag_type) => return Ok(Some(FlvTag::new(tag_type, timestamp, data))),
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+/// FLV muxer
+///
+/// Serializes headers and tags into FLV bytes, reusing one internal buffer.
+#[derive(Debug, Default)]
+pub struct FlvMuxer {
+    /// Scratch buffer, split off for every output chunk
+    buf: BytesMut,
+}
+
+impl FlvMuxer {
+    /// Create a new muxer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encode the file header followed by PreviousTagSize0
+    pub fn header(&mut self, header: &FlvHeader) -> Bytes {
+        self.buf.reserve(FLV_HEADER_SIZE + PREV_TAG_SIZE_LEN);
+        self.buf.put_slice(b"FLV");
+        self.buf.put_u8(header.version);
+        self.buf.put_u8(header.flags());
+        self.buf.put_u32(FLV_HEADER_SIZE as u32);
+        self.buf.put_u32(0);
+        self.buf.split().freeze()
+    }
+
+    /// Encode a tag including its trailing PreviousTagSize
+    pub fn tag(&mut self, tag: &FlvTag) -> Bytes {
+        self.write_tag(tag);
+        self.buf.split().freeze()
+    }
+
+    /// Encode several tags into a single chunk
+    pub fn tags<'a>(&mut self, tags: impl IntoIterator<Item = &'a FlvTag>) -> Bytes {
+        for tag in tags {
+            self.write_tag(tag);
+        }
+        self.buf.split().freeze()
+    }
+
+    fn write_tag(&mut self, tag: &FlvTag) {
+        let data_size = tag.data.len() as u32;
+        let ts = tag.timestamp.to_be_bytes();
+        self.buf.reserve(tag.encoded_len());
+        self.buf.put_u8(tag.tag_type as u8);
+        self.buf.put_slice(&data_size.to_be_bytes()[1..]);
+        self.buf.put_slice(&ts[1..]);
+        self.buf.put_u8(ts[0]);
+        self.buf.put_slice(&[0, 0, 0]);
+        self.buf.put_slice(&tag.data);
+        self.buf.put_u32(TAG_HEADER_SIZE as u32 + data_size);
+    }
+}
+
+/// Packs H.264 access units into AVC video tags
+///
+/// Used by the MPEG-TS and RTP ingest paths. SPS and PPS NAL units are
+/// kept out of the frames and turned into a sequence header whenever they
+/// change; frames before the first SPS/PPS are dropped.
+#[derive(Debug, Default)]
+pub(crate) struct AvcPacker {
+    sps: Option<Bytes>,
+    pps: Option<Bytes>,
+    /// Last AVCDecoderConfigurationRecord sent in a sequence header
+    config: Option<Bytes>,
+}
+
+/// H.264 NAL unit types
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+
+impl AvcPacker {
+    /// Tags for one access unit (NAL units without start codes): a
+    /// sequence header if the parameter sets changed, then the frame
+    pub(crate) fn pack(&mut self, timestamp: u32, composition: u32, nals: Vec<Bytes>) -> Vec<FlvTag> {
+        let mut frame = BytesMut::new();
+        let mut keyframe = false;
+        for nal in nals.into_iter().filter(|nal| !nal.is_empty()) {
+            match nal[0] & 0x1f {
+                NAL_SPS => self.sps = Some(nal),
+                NAL_PPS => self.pps = Some(nal),
+                NAL_AUD => {}
+                nal_type => {
+                    keyframe |= nal_type == NAL_IDR;
+                    frame.put_u32(nal.len() as u32);
+                    frame.put_slice(&nal);
+                }
+            }
+        }
+
+        let mut tags = Vec::with_capacity(2);
+        if let (Some(sps), Some(pps)) = (&self.sps, &self.pps) {
+            let config = avc_decoder_configuration(sps, pps);
+            if self.config.as_ref() != Some(&config) {
+                let mut data = BytesMut::with_capacity(5 + config.len());
+                data.put_slice(&[0x17, 0x00, 0, 0, 0]);
+                data.put_slice(&config);
+                tags.push(FlvTag::new(TagType::Video, timestamp, data.freeze()));
+                self.config = Some(config);
+            }
+        }
+        // Frames before the first SPS/PPS cannot be decoded
+        if frame.is_empty() || self.config.is_none() {
+            return tags;
+        }
+
+        let mut data = BytesMut::with_capacity(5 + frame.len());
+        data.put_u8(if keyframe { 0x17 } else { 0x27 });
+        data.put_u8(0x01);
+        data.put_slice(&composition.to_be_bytes()[1..]);
+        data.put_slice(&frame);
+        tags.push(FlvTag::new(TagType::Video, timestamp, data.freeze()));
+        tags
+    }
+}
+
+/// Enhanced RTMP Opus tag: the `OpusHead` identification header when
+/// `sequence_start`, otherwise one Opus packet
+pub(crate) fn opus_tag(timestamp: u32, sequence_start: bool, body: &[u8]) -> FlvTag {
+    let mut data = BytesMut::with_capacity(5 + body.len());
+    data.put_u8(0x90 | if sequence_start { 0 } else { 1 });
+    data.put_slice(&OPUS_FOURCC);
+    data.put_slice(body);
+    FlvTag::new(TagType::Audio, timestamp, data.freeze())
+}
+
+/// `OpusHead` of a 48 kHz stream without channel mapping (RFC 7845)
+pub(crate) fn opus_head(channels: u8) -> Bytes {
+    let mut head = BytesMut::with_capacity(19);
+    head.put_slice(b"OpusHead");
+    head.put_u8(1);
+    head.put_u8(channels);
+    // Pre-skip of 6.5 ms, as libopus encoders use
+    head.put_u16_le(312);
+    head.put_u32_le(48_000);
+    head.put_i16_le(0);
+    head.put_u8(0);
+    head.freeze()
+}
+
+/// AVCDecoderConfigurationRecord for one SPS and PPS
+fn avc_decoder_configuration(sps: &[u8], pps: &[u8]) -> Bytes {
+    let mut config = BytesMut::with_capacity(11 + sps.len() + pps.len());
+    config.put_u8(1);
+    config.put_slice(sps.get(1..4).unwrap_or(&[0, 0, 0]));
+    config.put_u8(0xff);
+    config.put_u8(0xe1);
+    config.put_u16(sps.len() as u16);
+    config.put_slice(sps);
+    config.put_u8(1);
+    config.put_u16(pps.len() as u16);
+    config.put_slice(pps);
+    config.freeze()
+}
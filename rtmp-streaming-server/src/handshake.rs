@@ -0,0 +1,83 @@
+//! RTMP handshake
+//!
+//! Implements the simple (non-digest) handshake: C0/C1 → S0/S1/S2 → C2,
+//! for both the server and the client side.
+
+use crate::error::{Error, Result};
+use crate::protocol::{HANDSHAKE_SIZE, RTMP_VERSION};
+use rand::RngCore;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+/// Build a C1/S1 packet: time (4), zero (4), random (1528)
+fn handshake_packet(time: u32) -> Vec<u8> {
+    let mut packet = vec![0u8; HANDSHAKE_SIZE];
+    packet[..4].copy_from_slice(&time.to_be_bytes());
+    rand::thread_rng().fill_bytes(&mut packet[8..]);
+    packet
+}
+
+/// Perform the server side of the handshake
+pub async fn server_handshake<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // C0 + C1
+    let mut c0c1 = vec![0u8; 1 + HANDSHAKE_SIZE];
+    stream.read_exact(&mut c0c1).await?;
+    if c0c1[0] != RTMP_VERSION {
+        return Err(Error::Handshake(format!(
+            "Unsupported RTMP version: {}",
+            c0c1[0]
+        )));
+    }
+    debug!("Received C0+C1");
+
+    // S0 + S1 + S2 (S2 echoes C1)
+    let mut response = Vec::with_capacity(1 + 2 * HANDSHAKE_SIZE);
+    response.push(RTMP_VERSION);
+    response.extend_from_slice(&handshake_packet(0));
+    response.extend_from_slice(&c0c1[1..]);
+    stream.write_all(&response).await?;
+    stream.flush().await?;
+    debug!("Sent S0+S1+S2");
+
+    // C2
+    let mut c2 = vec![0u8; HANDSHAKE_SIZE];
+    stream.read_exact(&mut c2).await?;
+    debug!("Received C2, handshake completed");
+
+    Ok(())
+}
+
+/// Perform the client side of the handshake
+pub async fn client_handshake<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // C0 + C1
+    let mut c0c1 = Vec::with_capacity(1 + HANDSHAKE_SIZE);
+    c0c1.push(RTMP_VERSION);
+    c0c1.extend_from_slice(&handshake_packet(0));
+    stream.write_all(&c0c1).await?;
+    stream.flush().await?;
+    debug!("Sent C0+C1");
+
+    // S0 + S1 + S2
+    let mut s0s1s2 = vec![0u8; 1 + 2 * HANDSHAKE_SIZE];
+    stream.read_exact(&mut s0s1s2).await?;
+    if s0s1s2[0] != RTMP_VERSION {
+        return Err(Error::Handshake(format!(
+            "Unsupported RTMP version: {}",
+            s0s1s2[0]
+        )));
+    }
+    debug!("Received S0+S1+S2");
+
+    // C2 echoes S1
+    stream.write_all(&s0s1s2[1..1 + HANDSHAKE_SIZE]).await?;
+    stream.flush().await?;
+    debug!("Sent C2, handshake completed");
+
+    Ok(())
+}
@@ -0,0 +1,148 @@
+//! HLS output
+//!
+//! Published streams of apps with `hls = true` are cut into MPEG-TS
+//! segments in memory, each starting at a video keyframe once the last one
+//! is `hls_segment_ms` long (streams without video are cut at any audio
+//! frame). The newest segments are listed in a live playlist at
+//! `/hls/{app}/{stream}.m3u8` and served at `/hls/{app}/{stream}/{n}.ts`
+//! (see [`crate::http`]). Like a recording, the segmenter subscribes
+//! without counting as a viewer; the playlist goes away with the stream.
+
+use crate::mpegts::TsMuxer;
+use crate::stream::{Stream, Subscription};
+use crate::timestamp::TimestampRebaser;
+use bytes::{Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::info;
+
+/// Segments listed in a playlist
+const PLAYLIST_LENGTH: usize = 5;
+
+/// Segments kept beyond the playlist, for players still fetching them
+const EXTRA_SEGMENTS: usize = 2;
+
+/// A finished segment
+struct Segment {
+    sequence: u64,
+    /// Duration in seconds
+    duration: f64,
+    data: Bytes,
+}
+
+/// Kept segments of one stream, oldest first
+type Segments = VecDeque<Segment>;
+
+/// Running segmenters and their latest segments, keyed by app and stream
+#[derive(Clone, Default)]
+pub struct HlsManager {
+    playlists: Arc<Mutex<HashMap<(String, String), Segments>>>,
+}
+
+impl HlsManager {
+    /// Create an empty HLS manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start segmenting `stream` into segments of at least `segment`,
+    /// unless it is already segmented (e.g. when a publisher reconnects)
+    pub(crate) async fn start(&self, app: &str, name: &str, stream: &Stream, segment: Duration) {
+        let key = (app.to_string(), name.to_string());
+        {
+            let mut playlists = self.playlists.lock().unwrap();
+            if playlists.contains_key(&key) {
+                return;
+            }
+            playlists.insert(key.clone(), Segments::new());
+        }
+        info!("Segmenting {}/{} for HLS", app, name);
+        let subscription = stream.subscribe_internal(format!("hls-{}/{}", app, name)).await;
+        tokio::spawn(self.clone().run(key, subscription, segment));
+    }
+
+    /// Live playlist of `name` in `app`, once it has a segment
+    ///
+    /// `query` (such as `token=...`) is added to the segment URLs.
+    pub fn playlist(&self, app: &str, name: &str, query: Option<&str>) -> Option<String> {
+        let playlists = self.playlists.lock().unwrap();
+        let segments = playlists.get(&(app.to_string(), name.to_string()))?;
+        let listed: Vec<&Segment> = segments.iter().skip(segments.len().saturating_sub(PLAYLIST_LENGTH)).collect();
+        let first = listed.first()?;
+        let target = listed.iter().map(|segment| segment.duration.ceil() as u64).max().unwrap_or(1).max(1);
+
+        let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+        let _ = writeln!(playlist, "#EXT-X-TARGETDURATION:{}", target);
+        let _ = writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first.sequence);
+        let query = query.map(|query| format!("?{}", query)).unwrap_or_default();
+        for segment in listed {
+            let _ = writeln!(playlist, "#EXTINF:{:.3},", segment.duration);
+            let _ = writeln!(playlist, "{}/{}.ts{}", name, segment.sequence, query);
+        }
+        Some(playlist)
+    }
+
+    /// Segment `sequence` of `name` in `app`, while it is kept
+    pub fn segment(&self, app: &str, name: &str, sequence: u64) -> Option<Bytes> {
+        let playlists = self.playlists.lock().unwrap();
+        let segments = playlists.get(&(app.to_string(), name.to_string()))?;
+        segments
+            .iter()
+            .find(|segment| segment.sequence == sequence)
+            .map(|segment| segment.data.clone())
+    }
+
+    /// Segmenter task: cut segments until the stream ends
+    async fn run(self, key: (String, String), mut subscription: Subscription, target: Duration) {
+        let target_ms = target.as_millis() as u32;
+        let mut muxer = TsMuxer::new();
+        let mut rebaser = TimestampRebaser::new(format!("{}/{}/hls", key.0, key.1));
+        let mut sequence = 0;
+        // Start time and packets of the open segment
+        let mut current: Option<(u32, BytesMut)> = None;
+
+        while let Ok(Some(mut tag)) = subscription.recv().await {
+            tag.timestamp = rebaser.rebase(tag.timestamp);
+            let boundary = !tag.is_sequence_header()
+                && if muxer.has_video() {
+                    tag.is_video() && tag.is_keyframe()
+                } else {
+                    tag.is_audio()
+                };
+            if boundary {
+                match current.take() {
+                    Some((start, data)) if tag.timestamp.saturating_sub(start) >= target_ms => {
+                        let duration = tag.timestamp.saturating_sub(start) as f64 / 1000.0;
+                        self.push(&key, sequence, duration, data.freeze());
+                        sequence += 1;
+                        current = Some((tag.timestamp, BytesMut::from(&muxer.tables()[..])));
+                    }
+                    Some(open) => current = Some(open),
+                    None => current = Some((tag.timestamp, BytesMut::from(&muxer.tables()[..]))),
+                }
+            }
+            // Before the first segment only the sequence headers matter
+            let packets = muxer.tag(&tag);
+            if let Some((_, data)) = &mut current {
+                data.extend_from_slice(&packets);
+            }
+        }
+
+        info!("HLS for {}/{} stopped", key.0, key.1);
+        self.playlists.lock().unwrap().remove(&key);
+    }
+
+    /// Add a finished segment, dropping the oldest beyond those kept
+    fn push(&self, key: &(String, String), sequence: u64, duration: f64, data: Bytes) {
+        let mut playlists = self.playlists.lock().unwrap();
+        let Some(segments) = playlists.get_mut(key) else {
+            return;
+        };
+        segments.push_back(Segment { sequence, duration, data });
+        while segments.len() > PLAYLIST_LENGTH + EXTRA_SEGMENTS {
+            segments.pop_front();
+        }
+    }
+}
@@ -0,0 +1,690 @@
+//! HTTP-FLV server
+//!
+//! Serves plain HTTP, or HTTPS with [`HttpFlvServer::with_tls`]; the routes
+//! are the same either way.
+//!
+//! - POST /{app}/{stream}: publish a raw FLV byte stream
+//! - GET  /{app}/{stream}: play; the FLV header, cached metadata and sequence
+//!   headers are sent first so players can start decoding right away.
+//!   `?offset=-{seconds}` starts that far in the past, for apps with a
+//!   time-shift buffer
+//! - GET  /api/relays: status of the push relays, as JSON
+//! - GET  /api/transcodes: status of the transcode workers, as JSON
+//! - GET  /api/recordings: running recordings, as JSON
+//! - GET  /api/limits: rejections by the connection limits, as JSON
+//! - GET  /api/sessions: open RTMP sessions with their ping RTT, as JSON
+//! - GET  /api/events: stream lifecycle events as Server-Sent Events;
+//!   `?app={app}` limits them to one app and `?stream={name}` to one
+//!   stream of that app (of `live` if no app is given)
+//! - GET  /snapshot/{app}/{stream}.jpg: latest keyframe of a live stream
+//!   as JPEG; `/snapshot/{stream}.jpg` is a stream of the `live` app
+//! - GET  /hls/{app}/{stream}.m3u8, /hls/{app}/{stream}/{n}.ts: live HLS
+//!   playlist and segments, for apps with HLS (see [`crate::hls`])
+//! - POST /open, /send, /idle, /close: RTMPT tunnels (see [`crate::rtmpt`])
+//! - POST /whip/{app}/{stream}, /whep/{app}/{stream}: WebRTC publish and
+//!   play with an SDP offer (see [`crate::webrtc`]); DELETE on the returned
+//!   `Location` ends the session. `/whip/{stream}` and `/whep/{stream}` are
+//!   a stream of the `live` app
+//!
+//! The `Host` header picks the virtual host of the app, if one is
+//! configured for it. Requests to apps, /whip and /whep count against the
+//! per-IP connection limits, and playback and publishing against the app
+//! limits; rejected requests get `429 Too Many Requests`. Apps that need
+//! a token want it as `?token=`, and answer `403 Forbidden` without.
+
+use crate::config::{self, Access};
+use crate::error::{Error, Result};
+use crate::flv::{FlvDemuxer, FlvMuxer};
+use crate::limits::ConnectionGuard;
+use crate::rtmpt::RtmptTunnels;
+use crate::session::HANDSHAKE_TIMEOUT;
+use crate::stream::{Publisher, StreamManager};
+use crate::timestamp::TimestampRebaser;
+use crate::tls::TlsAcceptor;
+use crate::webrtc::WebRtcSessions;
+use async_stream::stream;
+use bytes::Bytes;
+use futures::StreamExt;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::server::conn::AddrStream;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio_rustls::server::TlsStream;
+use tracing::{debug, info, warn};
+
+/// Time between keepalive comments on an idle event stream
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// HTTP-FLV server
+pub struct HttpFlvServer {
+    /// Address to bind to
+    address: SocketAddr,
+    /// Stream manager
+    stream_manager: StreamManager,
+    /// Serve HTTPS instead of plain HTTP
+    tls: Option<TlsAcceptor>,
+    /// Sessions of the WebRTC server, for WHIP and WHEP
+    webrtc: Option<WebRtcSessions>,
+    /// Time a client has for the TLS handshake
+    handshake_timeout: Duration,
+}
+
+impl HttpFlvServer {
+    /// Create a new HTTP-FLV server
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            stream_manager: StreamManager::new(),
+            tls: None,
+            webrtc: None,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    /// Share a stream manager with other servers (e.g. RTMP)
+    pub fn with_stream_manager(mut self, stream_manager: StreamManager) -> Self {
+        self.stream_manager = stream_manager;
+        self
+    }
+
+    /// Serve HTTPS: every connection starts with a TLS handshake
+    pub fn with_tls(mut self, tls: TlsAcceptor) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Drop HTTPS clients that take longer than `timeout` for the TLS
+    /// handshake
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Answer WHIP and WHEP offers with sessions of a WebRTC server
+    pub fn with_webrtc(mut self, sessions: WebRtcSessions) -> Self {
+        self.webrtc = Some(sessions);
+        self
+    }
+
+    /// Run the server
+    pub async fn run(self) -> Result<()> {
+        let listener = std::net::TcpListener::bind(self.address)?;
+        self.serve(listener).await
+    }
+
+    /// Serve requests from an already bound listener
+    pub async fn serve(self, listener: std::net::TcpListener) -> Result<()> {
+        listener.set_nonblocking(true)?;
+        let scheme = if self.tls.is_some() { "HTTPS" } else { "HTTP" };
+        info!("Starting {}-FLV server on {}", scheme, listener.local_addr()?);
+
+        let streams = self.stream_manager;
+        let tunnels = RtmptTunnels::new();
+        let webrtc = self.webrtc;
+        let service = move |streams: StreamManager, tunnels: RtmptTunnels, remote_addr: SocketAddr| {
+            let webrtc = webrtc.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    handle_http(req, streams.clone(), tunnels.clone(), webrtc.clone(), remote_addr)
+                }))
+            }
+        };
+
+        let handshake_timeout = self.handshake_timeout;
+        let Some(tls) = self.tls else {
+            return Server::from_tcp(listener)
+                .map_err(|e| Error::Network(e.to_string()))?
+                .serve(make_service_fn(move |conn: &AddrStream| {
+                    service(streams.clone(), tunnels.clone(), conn.remote_addr())
+                }))
+                .await
+                .map_err(|e| Error::Network(e.to_string()));
+        };
+
+        // Handshakes run concurrently so a slow client cannot stall accept
+        let listener = tokio::net::TcpListener::from_std(listener)?;
+        let (tx, mut rx) = tokio::sync::mpsc::channel(64);
+        tokio::spawn(async move {
+            loop {
+                let (socket, addr) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Error accepting HTTPS connection: {}", e);
+                        continue;
+                    }
+                };
+                let (tls, tx) = (tls.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match tls.accept(socket, handshake_timeout).await {
+                        Ok(stream) => {
+                            let _ = tx.send(stream).await;
+                        }
+                        Err(e) => debug!("HTTPS connection from {} dropped: {}", addr, e),
+                    }
+                });
+            }
+        });
+        let incoming = stream! {
+            while let Some(stream) = rx.recv().await {
+                yield Ok::<_, std::io::Error>(stream);
+            }
+        };
+
+        Server::builder(hyper::server::accept::from_stream(incoming))
+            .serve(make_service_fn(move |conn: &TlsStream<TcpStream>| {
+                let remote_addr = conn.get_ref().0.peer_addr().unwrap_or_else(|_| ([0, 0, 0, 0], 0).into());
+                service(streams.clone(), tunnels.clone(), remote_addr)
+            }))
+            .await
+            .map_err(|e| Error::Network(e.to_string()))
+    }
+}
+
+/// HTTP request handling
+async fn handle_http(
+    req: Request<Body>,
+    streams: StreamManager,
+    tunnels: RtmptTunnels,
+    webrtc: Option<WebRtcSessions>,
+    remote_addr: SocketAddr,
+) -> std::result::Result<Response<Body>, hyper::Error> {
+    let path = req.uri().path().to_string();
+    if RtmptTunnels::is_rtmpt_path(&path) {
+        return Ok(tunnels.handle(req, remote_addr, &streams).await);
+    }
+    if path.starts_with("/whip/") || path.starts_with("/whep/") {
+        // Offers count against the connection rate only, as the media
+        // flows over the WebRTC port
+        if req.method() == Method::POST {
+            if let Err(e) = streams.limits().connect(remote_addr.ip()) {
+                return Ok(error_response(&e));
+            }
+        }
+        return handle_webrtc(req, &streams, webrtc.as_ref()).await;
+    }
+    if req.method() == Method::GET {
+        match path.as_str() {
+            "/api/relays" => return Ok(json(&streams.relays().statuses())),
+            "/api/transcodes" => return Ok(json(&streams.transcodes().statuses())),
+            "/api/recordings" => return Ok(json(&streams.recordings().statuses())),
+            "/api/limits" => return Ok(json(&streams.limits().rejections())),
+            "/api/sessions" => return Ok(json(&streams.sessions().list())),
+            "/api/events" => return Ok(handle_events(&req, &streams)),
+            _ => {}
+        }
+        if let Some(path) = path.strip_prefix("/hls/") {
+            return Ok(handle_hls(&req, &streams, path));
+        }
+        if let Some(path) = path.strip_prefix("/snapshot/").and_then(|p| p.strip_suffix(".jpg")) {
+            let (app, name) = path.rsplit_once('/').unwrap_or(("live", path));
+            return Ok(match app_id(&req, &streams, app) {
+                Ok(app) => handle_snapshot(&streams, &app, name).await,
+                Err(e) => error_response(&e),
+            });
+        }
+    }
+
+    // Otherwise /{app}/{stream} is served
+    let parts: Vec<&str> = path.split('/').collect();
+    if parts.len() != 3 || parts[1].is_empty() || parts[2].is_empty() {
+        return Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not Found"))
+            .unwrap());
+    }
+    let app = match app_id(&req, &streams, parts[1]) {
+        Ok(app) => app,
+        Err(e) => return Ok(error_response(&e)),
+    };
+    let stream_name = parts[2].to_string();
+
+    if !matches!(*req.method(), Method::POST | Method::GET) {
+        return Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::from("Method Not Allowed"))
+            .unwrap());
+    }
+    let access = if req.method() == Method::POST { Access::Publish } else { Access::Play };
+    if let Err(e) = streams.authorize(&app, access, req.uri().query().and_then(config::token)) {
+        return Ok(error_response(&e));
+    }
+    let guard = match streams.limits().connect(remote_addr.ip()) {
+        Ok(guard) => guard,
+        Err(e) => return Ok(error_response(&e)),
+    };
+
+    match *req.method() {
+        Method::POST => handle_publish(req, streams, app, stream_name, guard).await,
+        Method::GET => {
+            let window = streams.config().app(&app).timeshift();
+            let offset = match timeshift_offset(req.uri().query(), window) {
+                Ok(offset) => offset,
+                Err(e) => return Ok(error_response(&e)),
+            };
+            handle_play(streams, app, stream_name, offset, guard).await
+        }
+        _ => Ok(Response::builder()
+            .status(StatusCode::METHOD_NOT_ALLOWED)
+            .body(Body::from("Method Not Allowed"))
+            .unwrap()),
+    }
+}
+
+/// Publisher: demux the request body into tags and publish them
+async fn handle_publish(
+    req: Request<Body>,
+    streams: StreamManager,
+    app: String,
+    stream_name: String,
+    _guard: ConnectionGuard,
+) -> std::result::Result<Response<Body>, hyper::Error> {
+    let publisher_id = format!("http-{}", uuid::Uuid::new_v4().simple());
+    let mut publisher = match streams.start_publish(&app, &stream_name, publisher_id).await {
+        Ok(publisher) => publisher,
+        Err(e) => return Ok(error_response(&e)),
+    };
+
+    let mut body = req.into_body();
+    let mut demuxer = FlvDemuxer::new();
+    let mut header_logged = false;
+    let mut replaced = false;
+
+    loop {
+        tokio::select! {
+            chunk = body.next() => match chunk {
+                Some(Ok(bytes)) => {
+                    demuxer.push(&bytes);
+                    if let Err(e) = publish_tags(&stream_name, &publisher, &mut demuxer, &mut header_logged).await {
+                        warn!("Publisher [{}]: invalid FLV data: {}", stream_name, e);
+                        break;
+                    }
+                }
+                Some(Err(e)) => {
+                    warn!("Error reading publisher body for {}: {}", stream_name, e);
+                    break;
+                }
+                None => break,
+            },
+            _ = publisher.evicted() => {
+                info!("Publisher [{}]: replaced by a new publisher", stream_name);
+                replaced = true;
+                break;
+            }
+        }
+    }
+    if demuxer.buffered() > 0 {
+        debug!("Publisher [{}]: {} trailing bytes discarded", stream_name, demuxer.buffered());
+    }
+
+    // Publisher gone: the stream is dropped after the app's grace period
+    drop(publisher);
+
+    if replaced {
+        return Ok(Response::builder()
+            .status(StatusCode::CONFLICT)
+            .body(Body::from("Replaced by another publisher"))
+            .unwrap());
+    }
+    Ok(Response::new(Body::from("OK")))
+}
+
+/// Publish every complete tag buffered in the demuxer
+async fn publish_tags(
+    stream_name: &str,
+    publisher: &Publisher,
+    demuxer: &mut FlvDemuxer,
+    header_logged: &mut bool,
+) -> Result<()> {
+    while let Some(tag) = demuxer.next_tag()? {
+        if !*header_logged {
+            if let Some(header) = demuxer.header() {
+                let sent = publisher.stream().flv_header();
+                info!(
+                    "Publisher [{}]: received FLV header flags=0x{:02x} ({}), subscribers get flags=0x{:02x} ({})",
+                    stream_name,
+                    header.flags(),
+                    flag_bits(header.flags()),
+                    sent.flags(),
+                    flag_bits(sent.flags())
+                );
+                *header_logged = true;
+            }
+        }
+
+        if tag.is_video() && tag.is_sequence_header() {
+            info!(
+                "Publisher [{}]: detected video sequence header ({} bytes), preview: {}",
+                stream_name,
+                tag.data.len(),
+                hex_preview(&tag.data, 32)
+            );
+        } else {
+            debug!(
+                "Publisher [{}]: forwarding tag type={:?} data_size={}",
+                stream_name,
+                tag.tag_type,
+                tag.data.len()
+            );
+        }
+        publisher.publish(tag).await?;
+    }
+    Ok(())
+}
+
+/// Subscriber: FLV header and cached tags first, then live tags
+///
+/// Timestamps are rebased per subscriber, so playback starts near 0 even
+/// when joining a stream that has been live for hours. A viewer that falls
+/// behind skips to the next keyframe, and is disconnected if it keeps
+/// lagging beyond the app's limit.
+async fn handle_play(
+    streams: StreamManager,
+    app: String,
+    stream_name: String,
+    offset: Option<Duration>,
+    guard: ConnectionGuard,
+) -> std::result::Result<Response<Body>, hyper::Error> {
+    // Created if needed so a later publisher feeds this viewer, or pulled
+    // from the origin in edge mode
+    let stream = streams.get_or_pull(&app, &stream_name).await;
+    if let Err(e) = streams.admit_subscriber(&app, &stream).await {
+        return Ok(error_response(&e));
+    }
+    let header = stream.flv_header();
+    let subscriber_id = format!("http-{}", uuid::Uuid::new_v4().simple());
+    let mut rebaser = TimestampRebaser::new(format!("{}/{}", stream_name, subscriber_id));
+    // A time-shifted subscription brings the headers of its start point
+    let (subscription, cached) = match offset {
+        Some(offset) => match stream.subscribe_at(subscriber_id, offset).await {
+            Ok(subscription) => (subscription, Vec::new()),
+            Err(e) => return Ok(error_response(&e)),
+        },
+        None => {
+            let cached = stream.sequence_headers().await;
+            (stream.subscribe(subscriber_id).await, cached)
+        }
+    };
+    let mut subscription = subscription.with_lag_policy(streams.config().app(&app).lag_policy());
+    info!(
+        "Subscriber connected to '{}' (subscribers: {}) cached tags: {}",
+        stream_name,
+        stream.subscriber_count(),
+        cached.len()
+    );
+    // Only the publisher may keep the channel open
+    drop(stream);
+
+    let body_stream = stream! {
+        // The viewer counts as connected until the body is dropped
+        let _guard = guard;
+        let mut muxer = FlvMuxer::new();
+        yield Ok::<Bytes, Infallible>(muxer.header(&header));
+        if !cached.is_empty() {
+            let cached: Vec<_> = cached
+                .into_iter()
+                .map(|mut tag| {
+                    tag.timestamp = rebaser.position();
+                    tag
+                })
+                .collect();
+            yield Ok(muxer.tags(&cached));
+        }
+        loop {
+            match subscription.recv().await {
+                Ok(Some(mut tag)) => {
+                    tag.timestamp = rebaser.rebase(tag.timestamp);
+                    yield Ok(muxer.tag(&tag));
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Subscriber [{}]: {}", stream_name, e);
+                    break;
+                }
+            }
+        }
+    };
+
+    Ok(Response::builder()
+        .header("Content-Type", "video/x-flv")
+        .status(StatusCode::OK)
+        .body(Body::wrap_stream(body_stream))
+        .unwrap())
+}
+
+/// WHIP/WHEP: POST an offer to create a session, DELETE it to end it
+async fn handle_webrtc(
+    req: Request<Body>,
+    streams: &StreamManager,
+    webrtc: Option<&WebRtcSessions>,
+) -> std::result::Result<Response<Body>, hyper::Error> {
+    let response = |status: StatusCode, body: String| Response::builder().status(status).body(Body::from(body)).unwrap();
+    let Some(sessions) = webrtc else {
+        return Ok(response(StatusCode::SERVICE_UNAVAILABLE, "WebRTC is not enabled".to_string()));
+    };
+    let path = req.uri().path().to_string();
+    let parts: Vec<&str> = path.split('/').collect();
+    match (req.method().clone(), parts.as_slice()) {
+        (Method::POST, ["", kind, app @ .., name]) if app.len() <= 1 && !app.contains(&"") && !name.is_empty() => {
+            let app = match app_id(&req, streams, app.first().copied().unwrap_or("live")) {
+                Ok(app) => app,
+                Err(e) => return Ok(error_response(&e)),
+            };
+            let token = req.uri().query().and_then(config::token).map(str::to_string);
+            let offer = hyper::body::to_bytes(req.into_body()).await?;
+            let offer = String::from_utf8_lossy(&offer);
+            let result = match *kind {
+                "whip" => sessions.whip(streams, &app, name, token.as_deref(), &offer).await,
+                _ => sessions.whep(streams, &app, name, token.as_deref(), &offer).await,
+            };
+            Ok(match result {
+                Ok((id, answer)) => Response::builder()
+                    .status(StatusCode::CREATED)
+                    .header("Content-Type", "application/sdp")
+                    .header("Location", format!("{}/{}", path, id))
+                    .body(Body::from(answer))
+                    .unwrap(),
+                Err(e) => {
+                    debug!("{} offer for '{}/{}' refused: {}", kind.to_uppercase(), app, name, e);
+                    error_response(&e)
+                }
+            })
+        }
+        (Method::DELETE, ["", _, _, id] | ["", _, _, _, id]) => Ok(match sessions.delete(id) {
+            true => response(StatusCode::OK, "OK".to_string()),
+            false => response(StatusCode::NOT_FOUND, "No such session".to_string()),
+        }),
+        (Method::POST | Method::DELETE, _) => Ok(response(StatusCode::NOT_FOUND, "Not Found".to_string())),
+        _ => Ok(response(StatusCode::METHOD_NOT_ALLOWED, "Method Not Allowed".to_string())),
+    }
+}
+
+/// Events: one SSE message per event, with a comment line as keepalive
+/// so idle connections survive proxies
+fn handle_events(req: &Request<Body>, streams: &StreamManager) -> Response<Body> {
+    let param = |name: &str| {
+        req.uri()
+            .query()
+            .into_iter()
+            .flat_map(|query| query.split('&'))
+            .find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+            .map(str::to_string)
+    };
+    let stream_filter = param("stream");
+    // A stream is only named within its app, `live` unless given
+    let app_filter = match param("app").or_else(|| stream_filter.as_ref().map(|_| "live".to_string())) {
+        Some(app) => match app_id(req, streams, &app) {
+            Ok(app) => Some(app),
+            Err(e) => return error_response(&e),
+        },
+        None => None,
+    };
+    let mut events = streams.events().subscribe();
+    let body_stream = stream! {
+        let mut keepalive = tokio::time::interval(EVENT_KEEPALIVE);
+        keepalive.tick().await;
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => {
+                        let (app, stream) = event.stream();
+                        if app_filter.as_deref().is_some_and(|filter| filter != app)
+                            || stream_filter.as_deref().is_some_and(|filter| filter != stream)
+                        {
+                            continue;
+                        }
+                        let data = serde_json::to_string(&event).unwrap_or_default();
+                        yield Ok::<Bytes, Infallible>(Bytes::from(format!("event: {}\ndata: {}\n\n", event.name(), data)));
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        yield Ok(Bytes::from(format!(": missed {} events\n\n", missed)));
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keepalive.tick() => yield Ok(Bytes::from_static(b": keepalive\n\n")),
+            }
+        }
+    };
+    Response::builder()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-cache")
+        .body(Body::wrap_stream(body_stream))
+        .unwrap()
+}
+
+/// Answer a failed request with the status of its error kind
+fn error_response(e: &Error) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::from_u16(e.http_status()).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+        .body(Body::from(e.to_string()))
+        .unwrap()
+}
+
+/// Time-shift offset of a play request: `offset` in seconds, 0 or
+/// negative and no further back than the app's `window`; `None` for live
+fn timeshift_offset(query: Option<&str>, window: Option<Duration>) -> Result<Option<Duration>> {
+    let Some(offset) = query
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("offset="))
+    else {
+        return Ok(None);
+    };
+    let bad = || Error::InvalidInput(format!("Bad time-shift offset '{}'", offset));
+    match offset.parse::<f64>() {
+        Ok(0.0) => Ok(None),
+        Ok(secs) if secs < 0.0 => {
+            let offset = Duration::try_from_secs_f64(-secs).map_err(|_| bad())?;
+            match window {
+                Some(window) if offset > window => Err(Error::InvalidInput(format!(
+                    "Time-shift offset '{}' is beyond the {} s window",
+                    secs,
+                    window.as_secs()
+                ))),
+                _ => Ok(Some(offset)),
+            }
+        }
+        _ => Err(bad()),
+    }
+}
+
+/// App ID of `app` for the virtual host the request is addressed to
+fn app_id(req: &Request<Body>, streams: &StreamManager, app: &str) -> Result<String> {
+    let host = req
+        .headers()
+        .get(hyper::header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host());
+    streams.config().app_id(host, app)
+}
+
+/// HLS playlist or segment of a stream, 404 while there is none
+fn handle_hls(req: &Request<Body>, streams: &StreamManager, path: &str) -> Response<Body> {
+    let not_found = || {
+        Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("Not Found"))
+            .unwrap()
+    };
+    let Some((app, file)) = path.split_once('/') else {
+        return not_found();
+    };
+    let app = match app_id(req, streams, app) {
+        Ok(app) => app,
+        Err(e) => return error_response(&e),
+    };
+    let token = req.uri().query().and_then(config::token);
+    if let Err(e) = streams.authorize(&app, Access::Play, token) {
+        return error_response(&e);
+    }
+
+    if let Some(name) = file.strip_suffix(".m3u8") {
+        let query = token.map(|token| format!("token={}", token));
+        return match streams.hls().playlist(&app, name, query.as_deref()) {
+            Some(playlist) => Response::builder()
+                .header("Content-Type", "application/vnd.apple.mpegurl")
+                .header("Cache-Control", "no-cache")
+                .body(Body::from(playlist))
+                .unwrap(),
+            None => not_found(),
+        };
+    }
+    let segment = file
+        .strip_suffix(".ts")
+        .and_then(|file| file.split_once('/'))
+        .and_then(|(name, sequence)| streams.hls().segment(&app, name, sequence.parse().ok()?));
+    match segment {
+        Some(data) => Response::builder()
+            .header("Content-Type", "video/mp2t")
+            .body(Body::from(data))
+            .unwrap(),
+        None => not_found(),
+    }
+}
+
+/// Snapshot: JPEG of the latest keyframe, 404 while there is none
+async fn handle_snapshot(streams: &StreamManager, app: &str, stream_name: &str) -> Response<Body> {
+    match streams.snapshots().get(streams, app, stream_name).await {
+        Ok(jpeg) => Response::builder()
+            .header("Content-Type", "image/jpeg")
+            .header("Cache-Control", "no-cache")
+            .body(Body::from(jpeg))
+            .unwrap(),
+        Err(e @ Error::NotFound(_)) => error_response(&e),
+        Err(e) => {
+            warn!("Snapshot of {} failed: {}", stream_name, e);
+            Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::from(e.to_string()))
+                .unwrap()
+        }
+    }
+}
+
+/// JSON response for the admin API
+fn json<T: serde::Serialize>(value: &T) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(value).unwrap_or_default()))
+        .unwrap()
+}
+
+/// Hex preview of the first `max` bytes (for logging)
+fn hex_preview(data: &[u8], max: usize) -> String {
+    data.iter()
+        .take(max)
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Readable FLV header flags (A audio, R reserved, V video)
+fn flag_bits(b: u8) -> String {
+    let audio = if b & 0x01 != 0 { "A" } else { "-" };
+    let reserved = if b & 0x02 != 0 { "R" } else { "-" };
+    let video = if b & 0x04 != 0 { "V" } else { "-" };
+    format!("{}{}{}", audio, reserved, video)
+}
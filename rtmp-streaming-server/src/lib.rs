@@ -2,15 +2,59 @@
 //!
 //! This crate provides a simple RTMP streaming server implementation.
 
+mod chunk;
+mod client;
+mod command;
+pub mod config;
+mod edge;
 mod error;
-mod protocol;
+mod events;
+mod flow;
+pub mod flv;
+mod handshake;
+mod hls;
+mod http;
+mod limits;
+mod mpegts;
+pub mod protocol;
+mod recording;
+mod relay;
+mod rtmpt;
+mod rtsp;
+mod rtsp_server;
 mod server;
 mod session;
+mod shared_object;
+mod snapshot;
+mod srt;
+mod srtp;
 mod stream;
+pub mod timestamp;
+mod timeshift;
+mod tls;
+mod transcode;
+mod webrtc;
 
+pub use client::{RtmpClient, RtmpUrl};
+pub use edge::EdgeManager;
 pub use error::{Error, Result};
-pub use server::RtmpServer;
-pub use stream::{Stream, StreamManager};
+pub use events::{Event, EventBus};
+pub use hls::HlsManager;
+pub use http::HttpFlvServer;
+pub use limits::{ConnectionGuard, Limiter, Rejections};
+pub use recording::{RecordingManager, RecordingStatus};
+pub use relay::{RelayManager, RelayState, RelayStatus};
+pub use rtsp::RtspManager;
+pub use rtsp_server::RtspServer;
+pub use server::{RtmpServer, ServerConfig};
+pub use session::{RtmpSession, SessionRegistry, SessionStats};
+pub use shared_object::{SharedObjectEvent, SharedObjectMessage, SharedObjects};
+pub use snapshot::SnapshotCache;
+pub use srt::SrtServer;
+pub use stream::{LagPolicy, Publisher, Stream, StreamData, StreamManager, SubscriberStats, Subscription};
+pub use tls::TlsAcceptor;
+pub use transcode::{TranscodeManager, TranscodeStatus, WorkerState};
+pub use webrtc::{WebRtcServer, WebRtcSessions};
 
 /// Library version
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -18,8 +62,14 @@ pub const VERSION: &str = env!("CARGO_PKG_VERSION");
 /// Default RTMP port
 pub const DEFAULT_RTMP_PORT: u16 = 1935;
 
+/// Default HTTP-FLV port
+pub const DEFAULT_HTTP_PORT: u16 = 8080;
+
+/// Default RTSP port
+pub const DEFAULT_RTSP_PORT: u16 = 8554;
+
 /// Maximum number of concurrent connections
 pub const MAX_CONNECTIONS: usize = 1000;
 
 /// Maximum stream buffer size in bytes
-pub const MAX_STREAM_BUFFER_SIZE: usize = 10 * 1024 * 1024; // 10MB
\ No newline at end of file
+pub const MAX_STREAM_BUFFER_SIZE: usize = 10 * 1024 * 1024; // 10MB
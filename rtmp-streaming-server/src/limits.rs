@@ -0,0 +1,191 @@
+//! Connection, publisher and subscriber limits
+//!
+//! Per-IP limits come from the `[limits]` section: concurrent connections
+//! and a token bucket on new connections. Publishers per app and viewers
+//! per stream come from the app settings. Every rejection is logged,
+//! counted, and returned as [`Error::ResourceLimit`]; the counters are
+//! served at `GET /api/limits`.
+
+use crate::config::LimitsConfig;
+use crate::error::{Error, Result};
+use crate::stream::Stream;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tracing::warn;
+
+/// Token buckets kept before full (idle) ones are pruned
+const MAX_BUCKETS: usize = 4096;
+
+/// Rejections since startup, by limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Rejections {
+    /// Too many concurrent connections from one IP
+    pub connections_per_ip: u64,
+    /// New connections from one IP arrived too fast
+    pub connection_rate: u64,
+    /// Too many publishers in one app
+    pub publishers_per_app: u64,
+    /// Too many viewers on one stream
+    pub subscribers_per_stream: u64,
+}
+
+/// New-connection allowance of one IP
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Default)]
+struct State {
+    /// Open connections per IP
+    connections: HashMap<IpAddr, usize>,
+    /// Connection rate per IP
+    buckets: HashMap<IpAddr, Bucket>,
+    /// Active publishers per app
+    publishers: HashMap<String, usize>,
+    rejected: Rejections,
+}
+
+/// Enforces the configured limits; clones share state
+#[derive(Clone, Default)]
+pub struct Limiter {
+    config: LimitsConfig,
+    state: Arc<Mutex<State>>,
+}
+
+impl Limiter {
+    /// Create a limiter for the per-IP limits in `config`
+    pub fn new(config: LimitsConfig) -> Self {
+        Self {
+            config,
+            state: Arc::default(),
+        }
+    }
+
+    /// Admit a new connection from `ip`
+    ///
+    /// The connection counts against the per-IP limit until the returned
+    /// guard is dropped. Rejected attempts still use up the rate, so a
+    /// client retrying in a loop stays throttled.
+    pub fn connect(&self, ip: IpAddr) -> Result<ConnectionGuard> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(rate) = self.config.connections_per_second.filter(|rate| *rate > 0.0) {
+            let burst = self.config.connection_burst.map_or(rate.max(1.0), f64::from);
+            let now = Instant::now();
+            if state.buckets.len() >= MAX_BUCKETS && !state.buckets.contains_key(&ip) {
+                state
+                    .buckets
+                    .retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+            }
+            let bucket = state.buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now });
+            bucket.tokens = (bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate).min(burst);
+            bucket.updated = now;
+            if bucket.tokens < 1.0 {
+                state.rejected.connection_rate += 1;
+                warn!("Rejected connection from {}: more than {}/s", ip, rate);
+                return Err(Error::ResourceLimit(format!("Too many new connections from {}", ip)));
+            }
+            bucket.tokens -= 1.0;
+        }
+
+        let open = state.connections.get(&ip).copied().unwrap_or(0);
+        if let Some(max) = self.config.max_connections_per_ip {
+            if open >= max {
+                state.rejected.connections_per_ip += 1;
+                warn!("Rejected connection from {}: {} already open", ip, open);
+                return Err(Error::ResourceLimit(format!("Too many connections from {}", ip)));
+            }
+        }
+        state.connections.insert(ip, open + 1);
+
+        Ok(ConnectionGuard {
+            ip,
+            state: self.state.clone(),
+        })
+    }
+
+    /// Admit a publisher into `app`, allowing at most `max` at once
+    pub(crate) fn publish(&self, app: &str, publisher_id: &str, max: Option<usize>) -> Result<PublishGuard> {
+        let mut state = self.state.lock().unwrap();
+        let active = state.publishers.get(app).copied().unwrap_or(0);
+        if let Some(max) = max {
+            if active >= max {
+                state.rejected.publishers_per_app += 1;
+                warn!("Rejected publisher {} in '{}': {} already publishing", publisher_id, app, active);
+                return Err(Error::ResourceLimit(format!("Too many publishers in '{}'", app)));
+            }
+        }
+        state.publishers.insert(app.to_string(), active + 1);
+
+        Ok(PublishGuard {
+            app: app.to_string(),
+            state: self.state.clone(),
+        })
+    }
+
+    /// Check that `stream` has room for one more viewer, allowing at most
+    /// `max`
+    pub(crate) async fn subscribe(&self, stream: &Stream, max: Option<usize>) -> Result<()> {
+        let Some(max) = max else {
+            return Ok(());
+        };
+        let viewers = stream.subscriber_count();
+        if viewers < max {
+            return Ok(());
+        }
+        self.state.lock().unwrap().rejected.subscribers_per_stream += 1;
+        let name = stream.name().await;
+        warn!("Rejected viewer of '{}': {} already watching", name, viewers);
+        Err(Error::ResourceLimit(format!("Too many viewers on '{}'", name)))
+    }
+
+    /// Rejection counters
+    pub fn rejections(&self) -> Rejections {
+        self.state.lock().unwrap().rejected
+    }
+
+    /// Open connections from `ip`
+    pub fn connections(&self, ip: IpAddr) -> usize {
+        self.state.lock().unwrap().connections.get(&ip).copied().unwrap_or(0)
+    }
+}
+
+/// Open connection counted by a [`Limiter`]
+pub struct ConnectionGuard {
+    ip: IpAddr,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(open) = state.connections.get_mut(&self.ip) {
+            *open -= 1;
+            if *open == 0 {
+                state.connections.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Active publisher counted by a [`Limiter`]
+pub(crate) struct PublishGuard {
+    app: String,
+    state: Arc<Mutex<State>>,
+}
+
+impl Drop for PublishGuard {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(active) = state.publishers.get_mut(&self.app) {
+            *active -= 1;
+            if *active == 0 {
+                state.publishers.remove(&self.app);
+            }
+        }
+    }
+}
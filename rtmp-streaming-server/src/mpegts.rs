@@ -0,0 +1,855 @@
+//! MPEG-TS demuxing into FLV tags, and muxing for HLS
+//!
+//! Used by ingest protocols that carry MPEG transport streams (SRT). The
+//! demuxer follows the PAT and PMT to the first H.264 and AAC (ADTS)
+//! elementary streams, reassembles their PES packets and converts them to
+//! the tags RTMP and HTTP-FLV subscribers expect: AVC and AAC sequence
+//! headers whenever the codec configuration changes, AVCC video frames
+//! and raw AAC frames. Timestamps are milliseconds from the first PES.
+//! [`TsMuxer`] goes the other way for HLS segments.
+
+use crate::error::{Error, Result};
+use crate::flv::{AudioCodec, AvcPacker, FlvTag, PacketType, TagType, VideoCodec};
+use crate::rtsp_server::Media;
+use bytes::{BufMut, Bytes, BytesMut};
+use std::collections::{HashMap, VecDeque};
+
+/// Size of a transport stream packet
+pub const TS_PACKET_SIZE: usize = 188;
+
+/// First byte of every transport stream packet
+const SYNC_BYTE: u8 = 0x47;
+
+/// PID of the program association table
+const PAT_PID: u16 = 0;
+
+/// PID of the program map table [`TsMuxer`] writes
+const PMT_PID: u16 = 0x1000;
+
+/// PIDs of the elementary streams [`TsMuxer`] writes
+const VIDEO_PID: u16 = 0x100;
+const AUDIO_PID: u16 = 0x101;
+
+/// PES stream IDs of the first video and audio stream
+const STREAM_ID_VIDEO: u8 = 0xe0;
+const STREAM_ID_AUDIO: u8 = 0xc0;
+
+/// Added to muxed timestamps so frames shown before their decode time
+/// never go negative (90 kHz)
+const PTS_OFFSET: u64 = 90_000;
+
+/// H.264 access unit delimiter
+const NAL_AUD: u8 = 9;
+
+/// PMT stream type of H.264 video
+const STREAM_TYPE_H264: u8 = 0x1b;
+
+/// PMT stream type of AAC audio in ADTS framing
+const STREAM_TYPE_AAC: u8 = 0x0f;
+
+/// AAC frames carry this many samples
+const AAC_SAMPLES_PER_FRAME: u64 = 1024;
+
+/// ADTS sampling frequency table
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+/// A PES packet being reassembled
+#[derive(Debug, Default)]
+struct PesBuffer {
+    data: BytesMut,
+    /// Declared PES packet length (0 for unbounded video PES)
+    expected: usize,
+}
+
+/// Incremental MPEG-TS demuxer
+///
+/// Bytes are appended with [`TsDemuxer::push`] in any chunking; tags are
+/// taken out with [`TsDemuxer::next_tag`], like [`crate::flv::FlvDemuxer`].
+#[derive(Debug, Default)]
+pub struct TsDemuxer {
+    /// Bytes not yet forming a whole TS packet
+    buf: BytesMut,
+    pmt_pid: Option<u16>,
+    video_pid: Option<u16>,
+    audio_pid: Option<u16>,
+    video_pes: PesBuffer,
+    audio_pes: PesBuffer,
+    /// H.264 parameter sets and frame packing
+    avc: AvcPacker,
+    /// Last AudioSpecificConfig sent in a sequence header
+    audio_config: Option<[u8; 2]>,
+    /// First DTS seen, in 90 kHz units; timestamps are relative to it
+    base: Option<u64>,
+    /// Last unwrapped DTS, to extend the 33-bit clock across wraparound
+    last_dts: Option<u64>,
+    /// Tags ready to be taken
+    ready: VecDeque<FlvTag>,
+}
+
+impl TsDemuxer {
+    /// Create a new demuxer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Append received bytes
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Next complete tag, or `None` until more bytes arrive
+    ///
+    /// Garbage before a sync byte is skipped; malformed tables and PES
+    /// headers are errors.
+    pub fn next_tag(&mut self) -> Result<Option<FlvTag>> {
+        loop {
+            if let Some(tag) = self.ready.pop_front() {
+                return Ok(Some(tag));
+            }
+            match self.buf.iter().position(|&b| b == SYNC_BYTE) {
+                Some(0) => {}
+                Some(skip) => {
+                    let _ = self.buf.split_to(skip);
+                }
+                None => {
+                    self.buf.clear();
+                    return Ok(None);
+                }
+            }
+            if self.buf.len() < TS_PACKET_SIZE {
+                return Ok(None);
+            }
+            let packet = self.buf.split_to(TS_PACKET_SIZE).freeze();
+            self.packet(&packet)?;
+        }
+    }
+
+    /// Emit the PES packets still being reassembled (end of stream)
+    pub fn flush(&mut self) -> Result<()> {
+        self.flush_pes(true)?;
+        self.flush_pes(false)
+    }
+
+    /// Handle one transport stream packet
+    fn packet(&mut self, packet: &[u8]) -> Result<()> {
+        let unit_start = packet[1] & 0x40 != 0;
+        let pid = u16::from_be_bytes([packet[1] & 0x1f, packet[2]]);
+        let adaptation = (packet[3] >> 4) & 0x03;
+
+        let mut offset = 4;
+        if adaptation & 0x02 != 0 {
+            offset += 1 + packet[4] as usize;
+        }
+        if adaptation & 0x01 == 0 || offset >= TS_PACKET_SIZE {
+            return Ok(());
+        }
+        let payload = &packet[offset..];
+
+        if pid == PAT_PID {
+            if unit_start {
+                self.pat(section(payload)?)?;
+            }
+        } else if Some(pid) == self.pmt_pid {
+            if unit_start {
+                self.pmt(section(payload)?)?;
+            }
+        } else if Some(pid) == self.video_pid || Some(pid) == self.audio_pid {
+            let video = Some(pid) == self.video_pid;
+            if unit_start {
+                self.flush_pes(video)?;
+                if payload.len() < 6 || payload[..3] != [0, 0, 1] {
+                    return Err(Error::Protocol("Invalid PES start code".to_string()));
+                }
+                // Unbounded video PES complete late; the clock starts with
+                // whichever PES starts first
+                if self.base.is_none() {
+                    if let Ok((_, dts, _)) = parse_pes(&Bytes::copy_from_slice(payload)) {
+                        self.base = Some(self.unwrap_dts(dts));
+                    }
+                }
+            }
+            let pes = if video { &mut self.video_pes } else { &mut self.audio_pes };
+            if unit_start {
+                pes.expected = u16::from_be_bytes([payload[4], payload[5]]) as usize;
+            } else if pes.data.is_empty() {
+                // Joined mid-PES: wait for the next unit start
+                return Ok(());
+            }
+            pes.data.extend_from_slice(payload);
+            if pes.expected > 0 && pes.data.len() >= pes.expected + 6 {
+                self.flush_pes(video)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Program association table: remember the first program's PMT PID
+    fn pat(&mut self, section: &[u8]) -> Result<()> {
+        for program in section.chunks_exact(4) {
+            let number = u16::from_be_bytes([program[0], program[1]]);
+            if number != 0 {
+                self.pmt_pid = Some(u16::from_be_bytes([program[2] & 0x1f, program[3]]));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Program map table: find the H.264 and AAC elementary streams
+    fn pmt(&mut self, section: &[u8]) -> Result<()> {
+        if section.len() < 4 {
+            return Err(Error::Protocol("PMT too short".to_string()));
+        }
+        let info_len = u16::from_be_bytes([section[2] & 0x0f, section[3]]) as usize;
+        let mut streams = section.get(4 + info_len..).unwrap_or_default();
+        while streams.len() >= 5 {
+            let stream_type = streams[0];
+            let pid = u16::from_be_bytes([streams[1] & 0x1f, streams[2]]);
+            let es_info_len = u16::from_be_bytes([streams[3] & 0x0f, streams[4]]) as usize;
+            match stream_type {
+                STREAM_TYPE_H264 if self.video_pid.is_none() => self.video_pid = Some(pid),
+                STREAM_TYPE_AAC if self.audio_pid.is_none() => self.audio_pid = Some(pid),
+                _ => {}
+            }
+            streams = streams.get(5 + es_info_len..).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    /// Convert a reassembled PES packet into tags
+    fn flush_pes(&mut self, video: bool) -> Result<()> {
+        let pes = if video { &mut self.video_pes } else { &mut self.audio_pes };
+        if pes.data.is_empty() {
+            return Ok(());
+        }
+        let data = pes.data.split().freeze();
+        let (pts, dts, payload) = parse_pes(&data)?;
+        let dts = self.unwrap_dts(dts);
+        let pts = dts + (pts.wrapping_sub(dts) & 0x1_ffff_ffff);
+        let base = *self.base.get_or_insert(dts);
+        let timestamp = (dts.saturating_sub(base) / 90) as u32;
+
+        if video {
+            let composition = (pts.saturating_sub(dts) / 90) as u32;
+            self.ready.extend(self.avc.pack(timestamp, composition, nal_units(&payload)));
+        } else {
+            self.audio(timestamp, payload)?;
+        }
+        Ok(())
+    }
+
+    /// Extend a 33-bit DTS into a monotonic 64-bit clock
+    fn unwrap_dts(&mut self, dts: u64) -> u64 {
+        let dts = match self.last_dts {
+            Some(last) => {
+                let delta = dts.wrapping_sub(last & 0x1_ffff_ffff) & 0x1_ffff_ffff;
+                // Deltas over half the clock range are small steps backwards
+                if delta < 1 << 32 {
+                    last + delta
+                } else {
+                    last.saturating_sub((1 << 33) - delta)
+                }
+            }
+            None => dts + (1 << 33),
+        };
+        self.last_dts = Some(dts);
+        dts
+    }
+
+    /// ADTS frames: sequence header on a new configuration, then raw frames
+    fn audio(&mut self, timestamp: u32, mut payload: Bytes) -> Result<()> {
+        let mut frame_index = 0u64;
+        while payload.len() >= 7 {
+            if payload[0] != 0xff || payload[1] & 0xf0 != 0xf0 {
+                return Err(Error::Protocol("Invalid ADTS sync word".to_string()));
+            }
+            let header_len = if payload[1] & 0x01 == 0 { 9 } else { 7 };
+            let object_type = (payload[2] >> 6) + 1;
+            let rate_index = (payload[2] >> 2) & 0x0f;
+            let channels = ((payload[2] & 0x01) << 2) | (payload[3] >> 6);
+            let frame_len =
+                ((payload[3] as usize & 0x03) << 11) | ((payload[4] as usize) << 3) | (payload[5] as usize >> 5);
+            let Some(&rate) = AAC_SAMPLE_RATES.get(rate_index as usize) else {
+                return Err(Error::Protocol(format!("Invalid ADTS sampling index {}", rate_index)));
+            };
+            if frame_len < header_len || frame_len > payload.len() {
+                return Err(Error::Protocol("Truncated ADTS frame".to_string()));
+            }
+
+            let config = [
+                (object_type << 3) | (rate_index >> 1),
+                ((rate_index & 0x01) << 7) | (channels << 3),
+            ];
+            let offset = (frame_index * AAC_SAMPLES_PER_FRAME * 1000 / rate as u64) as u32;
+            if self.audio_config != Some(config) {
+                self.audio_config = Some(config);
+                let data = Bytes::copy_from_slice(&[0xaf, 0x00, config[0], config[1]]);
+                self.ready.push_back(FlvTag::new(TagType::Audio, timestamp + offset, data));
+            }
+
+            let mut data = BytesMut::with_capacity(2 + frame_len - header_len);
+            data.put_slice(&[0xaf, 0x01]);
+            data.put_slice(&payload[header_len..frame_len]);
+            self.ready.push_back(FlvTag::new(TagType::Audio, timestamp + offset, data.freeze()));
+
+            let _ = payload.split_to(frame_len);
+            frame_index += 1;
+        }
+        Ok(())
+    }
+}
+
+/// FLV tags muxed into an MPEG transport stream, for HLS segments
+///
+/// H.264 frames become Annex B access units with an access unit
+/// delimiter, and keyframes carry the parameter sets of the last sequence
+/// header; AAC frames get ADTS headers. Tags of other codecs are skipped.
+/// Every segment starts with [`TsMuxer::tables`].
+#[derive(Debug, Default)]
+pub struct TsMuxer {
+    /// SPS, PPS and NAL length size from the AVC sequence header
+    avc: Option<(Bytes, Bytes, usize)>,
+    /// AudioSpecificConfig from the AAC sequence header
+    aac: Option<[u8; 2]>,
+    /// Continuity counters per PID
+    counters: HashMap<u16, u8>,
+    /// Packets being written
+    out: BytesMut,
+}
+
+impl TsMuxer {
+    /// Create a new muxer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if an H.264 sequence header has been seen
+    pub fn has_video(&self) -> bool {
+        self.avc.is_some()
+    }
+
+    /// PAT and PMT for the streams whose sequence headers have been seen
+    pub fn tables(&mut self) -> Bytes {
+        let pat = [0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xe0 | (PMT_PID >> 8) as u8, PMT_PID as u8];
+        self.psi(PAT_PID, &pat);
+
+        let mut streams = Vec::new();
+        if self.avc.is_some() {
+            streams.push((STREAM_TYPE_H264, VIDEO_PID));
+        }
+        if self.aac.is_some() {
+            streams.push((STREAM_TYPE_AAC, AUDIO_PID));
+        }
+        let pcr_pid = if self.avc.is_some() { VIDEO_PID } else { AUDIO_PID };
+        let length = 13 + 5 * streams.len();
+        let mut pmt = vec![0x02, 0xb0 | (length >> 8) as u8, length as u8, 0x00, 0x01, 0xc1, 0x00, 0x00];
+        pmt.extend_from_slice(&[0xe0 | (pcr_pid >> 8) as u8, pcr_pid as u8, 0xf0, 0x00]);
+        for (stream_type, pid) in streams {
+            pmt.extend_from_slice(&[stream_type, 0xe0 | (pid >> 8) as u8, pid as u8, 0xf0, 0x00]);
+        }
+        self.psi(PMT_PID, &pmt);
+        self.out.split().freeze()
+    }
+
+    /// Packets of `tag`; sequence headers are only remembered, so they
+    /// give none
+    pub fn tag(&mut self, tag: &FlvTag) -> Bytes {
+        match (tag.video_codec(), tag.audio_codec(), tag.packet_type()) {
+            (Some(VideoCodec::Avc), _, Some(PacketType::SequenceHeader)) => {
+                if let Some(Media::H264 { sps, pps, length_size }) = Media::h264(tag) {
+                    self.avc = Some((sps, pps, length_size));
+                }
+            }
+            (_, Some(AudioCodec::Aac), Some(PacketType::SequenceHeader)) => {
+                if let Some(config) = tag.data.get(2..4) {
+                    self.aac = Some([config[0], config[1]]);
+                }
+            }
+            (Some(VideoCodec::Avc), _, Some(PacketType::Data)) => self.video(tag),
+            (_, Some(AudioCodec::Aac), Some(PacketType::Data)) => self.audio(tag),
+            _ => {}
+        }
+        self.out.split().freeze()
+    }
+
+    /// One H.264 access unit
+    fn video(&mut self, tag: &FlvTag) {
+        let Some((sps, pps, length_size)) = &self.avc else {
+            return;
+        };
+        if tag.data.len() < 5 {
+            return;
+        }
+        // Signed 24-bit composition time offset
+        let composition = (i32::from_be_bytes([0, tag.data[2], tag.data[3], tag.data[4]]) << 8) >> 8;
+        let dts = PTS_OFFSET + tag.timestamp as u64 * 90;
+        let pts = dts.saturating_add_signed(composition as i64 * 90);
+
+        let keyframe = tag.is_keyframe();
+        let mut es = BytesMut::with_capacity(tag.data.len() + 64);
+        es.put_slice(&[0, 0, 0, 1, NAL_AUD, 0xf0]);
+        if keyframe {
+            for nal in [sps, pps] {
+                es.put_slice(&[0, 0, 0, 1]);
+                es.put_slice(nal);
+            }
+        }
+        let mut payload = &tag.data[5..];
+        while payload.len() >= *length_size {
+            let len = payload[..*length_size].iter().fold(0usize, |len, byte| len << 8 | *byte as usize);
+            payload = &payload[*length_size..];
+            if len == 0 || len > payload.len() {
+                break;
+            }
+            let nal = &payload[..len];
+            payload = &payload[len..];
+            if nal[0] & 0x1f != NAL_AUD {
+                es.put_slice(&[0, 0, 0, 1]);
+                es.put_slice(nal);
+            }
+        }
+        let pes = pes(STREAM_ID_VIDEO, pts, Some(dts), &es);
+        self.packets(VIDEO_PID, &pes, Some(dts), keyframe);
+    }
+
+    /// One raw AAC frame, behind an ADTS header
+    fn audio(&mut self, tag: &FlvTag) {
+        let Some(config) = self.aac else {
+            return;
+        };
+        let frame = tag.data.get(2..).unwrap_or_default();
+        let object_type = (config[0] >> 3).max(1);
+        let rate_index = ((config[0] & 0x07) << 1) | (config[1] >> 7);
+        let channels = (config[1] >> 3) & 0x0f;
+        let len = frame.len() + 7;
+        let mut es = Vec::with_capacity(len);
+        es.extend_from_slice(&[
+            0xff,
+            0xf1,
+            ((object_type - 1) << 6) | (rate_index << 2) | (channels >> 2),
+            ((channels & 0x03) << 6) | (len >> 11) as u8,
+            (len >> 3) as u8,
+            ((len & 0x07) << 5) as u8 | 0x1f,
+            0xfc,
+        ]);
+        es.extend_from_slice(frame);
+        let pts = PTS_OFFSET + tag.timestamp as u64 * 90;
+        let pcr = (self.avc.is_none()).then_some(pts);
+        let pes = pes(STREAM_ID_AUDIO, pts, None, &es);
+        self.packets(AUDIO_PID, &pes, pcr, false);
+    }
+
+    /// A PSI section with its CRC, in one packet
+    fn psi(&mut self, pid: u16, section: &[u8]) {
+        let counter = self.counter(pid);
+        let start = self.out.len();
+        self.out.put_slice(&[SYNC_BYTE, 0x40 | (pid >> 8) as u8, pid as u8, 0x10 | counter, 0x00]);
+        self.out.put_slice(section);
+        self.out.put_u32(crc32(section));
+        self.out.resize(start + TS_PACKET_SIZE, 0xff);
+    }
+
+    /// A PES packet split into transport stream packets; the first one
+    /// carries `pcr` and marks a random access point on keyframes
+    fn packets(&mut self, pid: u16, mut pes: &[u8], pcr: Option<u64>, random_access: bool) {
+        let mut first = true;
+        while !pes.is_empty() {
+            // Adaptation field after its length byte
+            let mut adaptation = None;
+            if first && (pcr.is_some() || random_access) {
+                let mut field = vec![if random_access { 0x40 } else { 0x00 }];
+                if let Some(pcr) = pcr {
+                    field[0] |= 0x10;
+                    field.extend_from_slice(&[
+                        (pcr >> 25) as u8,
+                        (pcr >> 17) as u8,
+                        (pcr >> 9) as u8,
+                        (pcr >> 1) as u8,
+                        ((pcr & 1) << 7) as u8 | 0x7e,
+                        0x00,
+                    ]);
+                }
+                adaptation = Some(field);
+            }
+            let space = TS_PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| 1 + field.len());
+            // The last payload is padded with stuffing bytes
+            if pes.len() < space {
+                let stuffing = space - pes.len();
+                let field = adaptation.get_or_insert_with(Vec::new);
+                if field.is_empty() {
+                    if stuffing > 1 {
+                        field.push(0x00);
+                        field.resize(stuffing - 1, 0xff);
+                    }
+                } else {
+                    field.resize(field.len() + stuffing, 0xff);
+                }
+            }
+
+            let counter = self.counter(pid);
+            let unit_start = if first { 0x40 } else { 0x00 };
+            let control = if adaptation.is_some() { 0x30 } else { 0x10 };
+            self.out.put_slice(&[SYNC_BYTE, unit_start | (pid >> 8) as u8, pid as u8, control | counter]);
+            if let Some(field) = &adaptation {
+                self.out.put_u8(field.len() as u8);
+                self.out.put_slice(field);
+            }
+            let size = (TS_PACKET_SIZE - 4 - adaptation.as_ref().map_or(0, |field| 1 + field.len())).min(pes.len());
+            self.out.put_slice(&pes[..size]);
+            pes = &pes[size..];
+            first = false;
+        }
+    }
+
+    /// Next continuity counter of `pid`
+    fn counter(&mut self, pid: u16) -> u8 {
+        let counter = self.counters.entry(pid).or_default();
+        let current = *counter;
+        *counter = (current + 1) & 0x0f;
+        current
+    }
+}
+
+/// Table section of a PSI payload, without header and CRC
+fn section(payload: &[u8]) -> Result<&[u8]> {
+    let pointer = *payload.first().unwrap_or(&0) as usize;
+    let table = payload
+        .get(1 + pointer..)
+        .filter(|table| table.len() >= 8)
+        .ok_or_else(|| Error::Protocol("PSI section too short".to_string()))?;
+    let length = u16::from_be_bytes([table[1] & 0x0f, table[2]]) as usize;
+    // Header up to last_section_number is 8 bytes; the CRC takes 4
+    table
+        .get(8..3 + length.saturating_sub(4).max(5))
+        .ok_or_else(|| Error::Protocol("PSI section truncated".to_string()))
+}
+
+/// PTS, DTS (PTS if absent) and payload of a PES packet
+fn parse_pes(data: &Bytes) -> Result<(u64, u64, Bytes)> {
+    if data.len() < 9 {
+        return Err(Error::Protocol("PES header too short".to_string()));
+    }
+    let flags = data[7] >> 6;
+    let header_len = 9 + data[8] as usize;
+    if data.len() < header_len || flags & 0x02 == 0 || header_len < 14 {
+        return Err(Error::Protocol("PES packet without PTS".to_string()));
+    }
+    let pts = timestamp(&data[9..14]);
+    let dts = if flags == 0x03 && header_len >= 19 {
+        timestamp(&data[14..19])
+    } else {
+        pts
+    };
+    let end = match u16::from_be_bytes([data[4], data[5]]) as usize {
+        0 => data.len(),
+        length => (6 + length).min(data.len()),
+    };
+    Ok((pts, dts, data.slice(header_len..end.max(header_len))))
+}
+
+/// 33-bit PES timestamp
+fn timestamp(b: &[u8]) -> u64 {
+    (((b[0] as u64 >> 1) & 0x07) << 30)
+        | ((b[1] as u64) << 22)
+        | (((b[2] as u64) >> 1) << 15)
+        | ((b[3] as u64) << 7)
+        | ((b[4] as u64) >> 1)
+}
+
+/// PES packet of `es`; video PES are left unbounded
+fn pes(stream_id: u8, pts: u64, dts: Option<u64>, es: &[u8]) -> Vec<u8> {
+    let mut pes = Vec::with_capacity(es.len() + 19);
+    pes.extend_from_slice(&[0, 0, 1, stream_id, 0, 0, 0x80]);
+    match dts.filter(|&dts| dts != pts) {
+        Some(dts) => {
+            pes.extend_from_slice(&[0xc0, 10]);
+            pes.extend_from_slice(&encode_timestamp(0x3, pts));
+            pes.extend_from_slice(&encode_timestamp(0x1, dts));
+        }
+        None => {
+            pes.extend_from_slice(&[0x80, 5]);
+            pes.extend_from_slice(&encode_timestamp(0x2, pts));
+        }
+    }
+    pes.extend_from_slice(es);
+    let length = pes.len() - 6;
+    if stream_id != STREAM_ID_VIDEO && length <= u16::MAX as usize {
+        pes[4..6].copy_from_slice(&(length as u16).to_be_bytes());
+    }
+    pes
+}
+
+/// 33-bit PES timestamp behind its 4-bit prefix
+fn encode_timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+    [
+        (prefix << 4) | ((ts >> 29) as u8 & 0x0e) | 1,
+        (ts >> 22) as u8,
+        ((ts >> 14) as u8 & 0xfe) | 1,
+        (ts >> 7) as u8,
+        ((ts << 1) as u8 & 0xfe) | 1,
+    ]
+}
+
+/// CRC-32/MPEG-2 of a PSI section
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= (byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ 0x04c1_1db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// NAL units of an Annex B byte stream
+fn nal_units(data: &Bytes) -> Vec<Bytes> {
+    let mut starts = Vec::new();
+    let mut i = 0;
+    while i + 3 <= data.len() {
+        if data[i..i + 3] == [0, 0, 1] {
+            starts.push(i + 3);
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    let mut units = Vec::with_capacity(starts.len());
+    for (n, &start) in starts.iter().enumerate() {
+        let mut end = starts.get(n + 1).map_or(data.len(), |next| next - 3);
+        // Four-byte start codes and trailing zeros belong to no NAL unit
+        while end > start && data[end - 1] == 0 {
+            end -= 1;
+        }
+        if end > start {
+            units.push(data.slice(start..end));
+        }
+    }
+    units
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPS: &[u8] = &[0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80];
+    const PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+    const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x21, 0x7f];
+    const SLICE: &[u8] = &[0x41, 0x9a, 0x22, 0x6c];
+
+    /// Minimal MPEG-TS muxer for one H.264 and one AAC stream
+    #[derive(Default)]
+    struct RawMuxer {
+        out: BytesMut,
+        counters: std::collections::HashMap<u16, u8>,
+    }
+
+    impl RawMuxer {
+        fn tables(&mut self) {
+            // PAT: program 1 -> PMT on PID 0x1000
+            let pat = [0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00, 0, 0, 0, 0];
+            self.packet(0, true, &pat);
+            // PMT: H.264 on 0x100, AAC on 0x101
+            let pmt = [
+                0x02, 0xb0, 0x17, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe1, 0x00, 0xf0, 0x00, 0x1b, 0xe1, 0x00, 0xf0,
+                0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00, 0, 0, 0, 0,
+            ];
+            self.packet(0x1000, true, &pmt);
+        }
+
+        /// One access unit of Annex B NAL units
+        fn video(&mut self, dts_ms: u64, cts_ms: u64, nals: &[&[u8]]) {
+            let mut es = vec![0, 0, 0, 1, 0x09, 0xf0];
+            for nal in nals {
+                es.extend_from_slice(&[0, 0, 0, 1]);
+                es.extend_from_slice(nal);
+            }
+            let dts = 900_000 + dts_ms * 90;
+            self.pes(VIDEO_PID, 0xe0, dts + cts_ms * 90, Some(dts), &es);
+        }
+
+        /// AAC-LC 44.1 kHz stereo frames in ADTS framing
+        fn audio(&mut self, pts_ms: u64, frames: &[&[u8]]) {
+            let mut es = Vec::new();
+            for frame in frames {
+                let len = frame.len() + 7;
+                es.extend_from_slice(&[
+                    0xff,
+                    0xf1,
+                    0x50,
+                    0x80 | (len >> 11) as u8,
+                    (len >> 3) as u8,
+                    ((len & 0x07) << 5) as u8 | 0x1f,
+                    0xfc,
+                ]);
+                es.extend_from_slice(frame);
+            }
+            self.pes(AUDIO_PID, 0xc0, 900_000 + pts_ms * 90, None, &es);
+        }
+
+        fn pes(&mut self, pid: u16, stream_id: u8, pts: u64, dts: Option<u64>, es: &[u8]) {
+            let mut pes = vec![0, 0, 1, stream_id, 0, 0, 0x80];
+            match dts {
+                Some(dts) => {
+                    pes.extend_from_slice(&[0xc0, 10]);
+                    pes.extend_from_slice(&encode_timestamp(0x3, pts));
+                    pes.extend_from_slice(&encode_timestamp(0x1, dts));
+                }
+                None => {
+                    pes.extend_from_slice(&[0x80, 5]);
+                    pes.extend_from_slice(&encode_timestamp(0x2, pts));
+                }
+            }
+            pes.extend_from_slice(es);
+            // Video PES are unbounded, audio PES carry their length
+            if dts.is_none() {
+                let len = (pes.len() - 6) as u16;
+                pes[4..6].copy_from_slice(&len.to_be_bytes());
+            }
+            for (i, chunk) in pes.chunks(184).enumerate() {
+                self.packet(pid, i == 0, chunk);
+            }
+        }
+
+        fn packet(&mut self, pid: u16, unit_start: bool, payload: &[u8]) {
+            let counter = self.counters.entry(pid).or_default();
+            let mut packet = vec![0x47, (pid >> 8) as u8 | if unit_start { 0x40 } else { 0 }, pid as u8];
+            let is_psi = pid == 0 || pid == 0x1000;
+            if payload.len() < 184 && !is_psi {
+                // Stuff with an adaptation field
+                packet.push(0x30 | *counter);
+                let stuffing = 183 - payload.len();
+                packet.push(stuffing as u8);
+                if stuffing > 0 {
+                    packet.push(0x00);
+                    packet.resize(packet.len() + stuffing - 1, 0xff);
+                }
+            } else {
+                packet.push(0x10 | *counter);
+            }
+            if is_psi {
+                packet.push(0); // pointer field
+            }
+            packet.extend_from_slice(payload);
+            packet.resize(TS_PACKET_SIZE, 0xff);
+            *counter = (*counter + 1) & 0x0f;
+            self.out.put_slice(&packet);
+        }
+    }
+
+    /// The tag the demuxer should produce for `nals` in a frame
+    fn avc_frame(keyframe: bool, cts: u8, nals: &[&[u8]]) -> Bytes {
+        let mut data = vec![if keyframe { 0x17 } else { 0x27 }, 0x01, 0, 0, cts];
+        for nal in nals {
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        Bytes::from(data)
+    }
+
+    fn avc_sequence_header() -> Bytes {
+        let mut data = vec![0x17, 0x00, 0, 0, 0, 0x01, SPS[1], SPS[2], SPS[3], 0xff, 0xe1, 0, SPS.len() as u8];
+        data.extend_from_slice(SPS);
+        data.extend_from_slice(&[0x01, 0, PPS.len() as u8]);
+        data.extend_from_slice(PPS);
+        Bytes::from(data)
+    }
+
+    #[test]
+    fn test_ts_demuxer() {
+        let mut muxer = RawMuxer::default();
+        muxer.tables();
+        muxer.video(0, 0, &[SPS, PPS, IDR]);
+        muxer.audio(10, &[&[0x21, 0x10, 0x05], &[0x21, 0x10, 0x06]]);
+        muxer.video(40, 80, &[SLICE]);
+        let mut data = vec![0x00, 0x12];
+        data.extend_from_slice(&muxer.out);
+
+        // Odd chunking and leading garbage are fine
+        let mut demuxer = TsDemuxer::new();
+        let mut tags = Vec::new();
+        for chunk in data.chunks(100) {
+            demuxer.push(chunk);
+            while let Some(tag) = demuxer.next_tag().unwrap() {
+                tags.push(tag);
+            }
+        }
+        demuxer.flush().unwrap();
+        while let Some(tag) = demuxer.next_tag().unwrap() {
+            tags.push(tag);
+        }
+
+        let video: Vec<_> = tags.iter().filter(|tag| tag.tag_type == TagType::Video).collect();
+        assert_eq!(video.len(), 3);
+        assert_eq!(video[0].data, avc_sequence_header());
+        assert_eq!(video[1].data, avc_frame(true, 0, &[IDR]));
+        assert_eq!((video[1].timestamp, video[2].timestamp), (0, 40));
+        assert_eq!(video[2].data, avc_frame(false, 80, &[SLICE]));
+
+        let audio: Vec<_> = tags.iter().filter(|tag| tag.tag_type == TagType::Audio).collect();
+        assert_eq!(audio.len(), 3);
+        assert_eq!(&audio[0].data[..], &[0xaf, 0x00, 0x12, 0x10]);
+        assert_eq!(&audio[1].data[..], &[0xaf, 0x01, 0x21, 0x10, 0x05]);
+        assert_eq!(&audio[2].data[..], &[0xaf, 0x01, 0x21, 0x10, 0x06]);
+        // The second AAC frame is 1024 samples later
+        assert_eq!((audio[1].timestamp, audio[2].timestamp), (10, 33));
+    }
+
+    #[test]
+    fn test_ts_muxer_round_trip() {
+        let mut muxer = TsMuxer::new();
+        let tags = [
+            FlvTag::new(TagType::Video, 0, avc_sequence_header()),
+            FlvTag::new(TagType::Audio, 0, Bytes::from_static(&[0xaf, 0x00, 0x12, 0x10])),
+            FlvTag::new(TagType::Video, 0, avc_frame(true, 0, &[IDR])),
+            FlvTag::new(TagType::Audio, 10, Bytes::from_static(&[0xaf, 0x01, 0x21, 0x10, 0x05])),
+            FlvTag::new(TagType::Video, 40, avc_frame(false, 80, &[SLICE])),
+        ];
+        // Sequence headers give no packets until the tables are written
+        let mut data = BytesMut::new();
+        for tag in &tags[..2] {
+            assert!(muxer.tag(tag).is_empty());
+        }
+        assert!(muxer.has_video());
+        data.extend_from_slice(&muxer.tables());
+        for tag in &tags[2..] {
+            data.extend_from_slice(&muxer.tag(tag));
+        }
+        assert_eq!(data.len() % TS_PACKET_SIZE, 0);
+
+        let mut demuxer = TsDemuxer::new();
+        demuxer.push(&data);
+        let mut demuxed = Vec::new();
+        while let Some(tag) = demuxer.next_tag().unwrap() {
+            demuxed.push(tag);
+        }
+        demuxer.flush().unwrap();
+        while let Some(tag) = demuxer.next_tag().unwrap() {
+            demuxed.push(tag);
+        }
+        let video: Vec<_> = demuxed.iter().filter(|tag| tag.tag_type == TagType::Video).collect();
+        assert_eq!(video.len(), 3);
+        assert_eq!(video[0].data, tags[0].data);
+        assert_eq!(video[1].data, tags[2].data);
+        assert_eq!(video[2].data, tags[4].data);
+        assert_eq!((video[1].timestamp, video[2].timestamp), (0, 40));
+
+        let audio: Vec<_> = demuxed.iter().filter(|tag| tag.tag_type == TagType::Audio).collect();
+        assert_eq!(audio.len(), 2);
+        assert_eq!(audio[0].data, tags[1].data);
+        assert_eq!(audio[1].data, tags[3].data);
+        assert_eq!(audio[1].timestamp, 10);
+    }
+
+    #[test]
+    fn test_ts_muxer_truncated_sequence_header() {
+        let mut muxer = TsMuxer::new();
+        for len in 2..12 {
+            let data = Bytes::copy_from_slice(&avc_sequence_header()[..len]);
+            assert!(muxer.tag(&FlvTag::new(TagType::Video, 0, data)).is_empty());
+        }
+        assert!(!muxer.has_video());
+    }
+}
@@ -1,6 +1,8 @@
 //! RTMP protocol definitions and utilities
 
 use crate::error::{Error, Result};
+use crate::flv::{FlvTag, TagType};
+use bytes::Bytes;
 
 /// RTMP protocol version
 pub const RTMP_VERSION: u8 = 3;
@@ -68,6 +70,28 @@ impl TryFrom<u8> for MessageType {
     }
 }
 
+impl MessageType {
+    /// FLV tag type carried by this message, if it is a media or data message
+    pub fn tag_type(self) -> Option<TagType> {
+        match self {
+            MessageType::Audio => Some(TagType::Audio),
+            MessageType::Video => Some(TagType::Video),
+            MessageType::DataAmf0 => Some(TagType::Script),
+            _ => None,
+        }
+    }
+}
+
+impl From<TagType> for MessageType {
+    fn from(tag_type: TagType) -> Self {
+        match tag_type {
+            TagType::Audio => MessageType::Audio,
+            TagType::Video => MessageType::Video,
+            TagType::Script => MessageType::DataAmf0,
+        }
+    }
+}
+
 /// RTMP chunk header format
 #[derive(Debug, Clone)]
 pub enum ChunkHeaderFormat {
@@ -132,7 +156,7 @@ pub struct Message {
     /// Timestamp
     pub timestamp: u32,
     /// Payload
-    pub payload: Vec<u8>,
+    pub payload: Bytes,
 }
 
 impl Message {
@@ -141,7 +165,7 @@ impl Message {
         message_type: MessageType,
         message_stream_id: u32,
         timestamp: u32,
-        payload: Vec<u8>,
+        payload: Bytes,
     ) -> Self {
         Self {
             message_type,
@@ -150,6 +174,149 @@ impl Message {
             payload,
         }
     }
+
+    /// Wrap an FLV tag as a media or data message
+    pub fn from_tag(tag: &FlvTag, message_stream_id: u32) -> Self {
+        Self::new(
+            tag.tag_type.into(),
+            message_stream_id,
+            tag.timestamp,
+            tag.data.clone(),
+        )
+    }
+
+    /// Convert a media or data message into an FLV tag
+    pub fn to_tag(&self) -> Option<FlvTag> {
+        let tag_type = self.message_type.tag_type()?;
+        Some(FlvTag::new(tag_type, self.timestamp, self.payload.clone()))
+    }
+
+    /// Split an aggregate message into its sub-messages
+    ///
+    /// The body is a run of FLV tags, each followed by a back pointer. The
+    /// first sub-message gets the aggregate's timestamp and the others keep
+    /// their offset from it; all of them belong to the aggregate's message
+    /// stream.
+    pub fn split_aggregate(&self) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        let mut rest = self.payload.clone();
+        let mut first_timestamp = None;
+        while !rest.is_empty() {
+            if rest.len() < AGGREGATE_TAG_HEADER_SIZE {
+                return Err(Error::Protocol("Truncated aggregate sub-message header".to_string()));
+            }
+            let message_type = MessageType::try_from(rest[0])?;
+            let size = u32::from_be_bytes([0, rest[1], rest[2], rest[3]]) as usize;
+            let timestamp = u32::from_be_bytes([rest[7], rest[4], rest[5], rest[6]]);
+            let end = AGGREGATE_TAG_HEADER_SIZE + size + AGGREGATE_BACK_POINTER_SIZE;
+            if rest.len() < end {
+                return Err(Error::Protocol("Truncated aggregate sub-message".to_string()));
+            }
+            let first = *first_timestamp.get_or_insert(timestamp);
+            messages.push(Message::new(
+                message_type,
+                self.message_stream_id,
+                self.timestamp.wrapping_add(timestamp.wrapping_sub(first)),
+                rest.slice(AGGREGATE_TAG_HEADER_SIZE..AGGREGATE_TAG_HEADER_SIZE + size),
+            ));
+            rest = rest.slice(end..);
+        }
+        Ok(messages)
+    }
+
+    /// Combine messages into one aggregate message on `message_stream_id`
+    ///
+    /// The aggregate carries the first message's timestamp.
+    pub fn aggregate(messages: &[Message], message_stream_id: u32) -> Self {
+        let mut payload = Vec::with_capacity(
+            messages
+                .iter()
+                .map(|m| m.payload.len() + AGGREGATE_TAG_HEADER_SIZE + AGGREGATE_BACK_POINTER_SIZE)
+                .sum(),
+        );
+        for message in messages {
+            let size = message.payload.len() as u32;
+            let timestamp = message.timestamp.to_be_bytes();
+            payload.push(message.message_type as u8);
+            payload.extend_from_slice(&size.to_be_bytes()[1..]);
+            payload.extend_from_slice(&[timestamp[1], timestamp[2], timestamp[3], timestamp[0]]);
+            payload.extend_from_slice(&[0, 0, 0]);
+            payload.extend_from_slice(&message.payload);
+            payload.extend_from_slice(&(size + AGGREGATE_TAG_HEADER_SIZE as u32).to_be_bytes());
+        }
+        let timestamp = messages.first().map_or(0, |m| m.timestamp);
+        Self::new(MessageType::Aggregate, message_stream_id, timestamp, Bytes::from(payload))
+    }
+}
+
+/// FLV tag header in front of each aggregate sub-message
+const AGGREGATE_TAG_HEADER_SIZE: usize = 11;
+
+/// Back pointer after each aggregate sub-message
+const AGGREGATE_BACK_POINTER_SIZE: usize = 4;
+
+/// User control event (message type 4)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserControl {
+    /// A stream became functional and can be used (stream ID)
+    StreamBegin(u32),
+    /// Playback of a stream is over (stream ID)
+    StreamEof(u32),
+    /// A stream has no more data for now (stream ID)
+    StreamDry(u32),
+    /// Client buffer size (stream ID, buffer length in milliseconds)
+    SetBufferLength(u32, u32),
+    /// A stream is recorded (stream ID)
+    StreamIsRecorded(u32),
+    /// Liveness probe carrying the sender's timestamp
+    PingRequest(u32),
+    /// Reply to a ping, echoing its timestamp
+    PingResponse(u32),
+}
+
+impl UserControl {
+    /// Decode a user control message payload
+    pub fn decode(payload: &[u8]) -> Result<Self> {
+        let event = payload
+            .get(..2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .ok_or_else(|| Error::Protocol("Truncated user control message".to_string()))?;
+        let value = utils::read_u32(&payload[2..])?;
+        Ok(match event {
+            0 => UserControl::StreamBegin(value),
+            1 => UserControl::StreamEof(value),
+            2 => UserControl::StreamDry(value),
+            3 => UserControl::SetBufferLength(value, utils::read_u32(&payload[6..])?),
+            4 => UserControl::StreamIsRecorded(value),
+            6 => UserControl::PingRequest(value),
+            7 => UserControl::PingResponse(value),
+            _ => return Err(Error::Protocol(format!("Unknown user control event: {}", event))),
+        })
+    }
+
+    /// Encode as a user control message payload
+    pub fn encode(&self) -> Bytes {
+        let (event, value): (u16, u32) = match *self {
+            UserControl::StreamBegin(id) => (0, id),
+            UserControl::StreamEof(id) => (1, id),
+            UserControl::StreamDry(id) => (2, id),
+            UserControl::SetBufferLength(id, _) => (3, id),
+            UserControl::StreamIsRecorded(id) => (4, id),
+            UserControl::PingRequest(timestamp) => (6, timestamp),
+            UserControl::PingResponse(timestamp) => (7, timestamp),
+        };
+        let mut payload = event.to_be_bytes().to_vec();
+        payload.extend_from_slice(&value.to_be_bytes());
+        if let UserControl::SetBufferLength(_, length) = *self {
+            payload.extend_from_slice(&length.to_be_bytes());
+        }
+        Bytes::from(payload)
+    }
+
+    /// Wrap as a protocol control message
+    pub fn to_message(&self) -> Message {
+        Message::new(MessageType::UserControl, 0, 0, self.encode())
+    }
 }
 
 /// RTMP command types
@@ -185,6 +352,8 @@ pub enum CommandType {
     FCUnpublish,
     /// Get stream length command
     GetStreamLength,
+    /// Any other command
+    Unknown,
 }
 
 impl From<&str> for CommandType {
@@ -205,7 +374,7 @@ impl From<&str> for CommandType {
             "FCPublish" => CommandType::FCPublish,
             "FCUnpublish" => CommandType::FCUnpublish,
             "getStreamLength" => CommandType::GetStreamLength,
-            _ => CommandType::Connect, // Default
+            _ => CommandType::Unknown,
         }
     }
 }
@@ -228,6 +397,15 @@ pub mod utils {
     pub fn needs_extended_timestamp(timestamp: u32) -> bool {
         timestamp >= 0xFFFFFF
     }
+
+    /// Read a big-endian u32 from the start of a control message payload
+    pub fn read_u32(payload: &[u8]) -> Result<u32> {
+        let bytes: [u8; 4] = payload
+            .get(..4)
+            .and_then(|b| b.try_into().ok())
+            .ok_or_else(|| Error::Protocol("Truncated control message".to_string()))?;
+        Ok(u32::from_be_bytes(bytes))
+    }
 }
 
 /// Protocol constants
@@ -235,6 +413,24 @@ pub mod constants {
     /// Default chunk size
     pub const DEFAULT_CHUNK_SIZE: u32 = 128;
 
+    /// Chunk size the server switches to after connect
+    pub const SERVER_CHUNK_SIZE: u32 = 4096;
+
+    /// Chunk stream ID for protocol control messages
+    pub const CSID_PROTOCOL_CONTROL: u32 = 2;
+
+    /// Chunk stream ID for command messages
+    pub const CSID_COMMAND: u32 = 3;
+
+    /// Chunk stream ID for audio messages
+    pub const CSID_AUDIO: u32 = 4;
+
+    /// Chunk stream ID for data messages
+    pub const CSID_DATA: u32 = 5;
+
+    /// Chunk stream ID for video messages
+    pub const CSID_VIDEO: u32 = 6;
+
     /// Default window acknowledgement size
     pub const DEFAULT_WINDOW_ACK_SIZE: u32 = 2500000;
 
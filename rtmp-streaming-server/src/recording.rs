@@ -0,0 +1,173 @@
+//! FLV recordings
+//!
+//! Every published stream of an app with a `record_dir` is written to
+//! `{record_dir}/{app}/{stream}-{unix time}.flv` while it is live. The
+//! recorder subscribes like a push relay, so it costs the publisher
+//! nothing and never counts as a viewer. Once the stream ends the file is
+//! closed and [`Event::RecordingFinished`] names it.
+
+use crate::error::Result;
+use crate::events::Event;
+use crate::flv::{FlvHeader, FlvMuxer};
+use crate::stream::{Stream, StreamManager, Subscription};
+use crate::timestamp::TimestampRebaser;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tracing::{info, warn};
+
+/// Status of one running recording
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RecordingStatus {
+    /// Local application name
+    pub app: String,
+    /// Stream name
+    pub stream: String,
+    /// File being written
+    pub path: String,
+    /// Bytes written so far
+    pub bytes: u64,
+}
+
+/// Running recordings, keyed by app and stream
+#[derive(Clone, Default)]
+pub struct RecordingManager {
+    recordings: Arc<Mutex<HashMap<(String, String), RecordingStatus>>>,
+}
+
+impl RecordingManager {
+    /// Create an empty recording manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Status of every running recording, ordered by file
+    pub fn statuses(&self) -> Vec<RecordingStatus> {
+        let mut statuses: Vec<_> = self.recordings.lock().unwrap().values().cloned().collect();
+        statuses.sort_by(|a, b| a.path.cmp(&b.path));
+        statuses
+    }
+
+    /// Start recording `stream` into `dir`, unless it is already recorded
+    /// (e.g. when a publisher reconnects)
+    pub(crate) async fn start(&self, streams: &StreamManager, app: &str, name: &str, stream: &Stream, dir: &str) {
+        let Some(path) = recording_path(Path::new(dir), app, name) else {
+            warn!("Not recording '{}' of '{}': invalid name", name, app);
+            return;
+        };
+        let key = (app.to_string(), name.to_string());
+        {
+            let mut recordings = self.recordings.lock().unwrap();
+            if recordings.contains_key(&key) {
+                return;
+            }
+            recordings.insert(
+                key.clone(),
+                RecordingStatus {
+                    app: app.to_string(),
+                    stream: name.to_string(),
+                    path: path.display().to_string(),
+                    bytes: 0,
+                },
+            );
+        }
+
+        info!("Recording {}/{} to {}", app, name, path.display());
+        let subscription = stream.subscribe_internal(format!("record-{}", path.display())).await;
+        let recorder = Recorder {
+            manager: self.clone(),
+            streams: streams.clone(),
+            key,
+            path,
+        };
+        tokio::spawn(recorder.run(stream.clone(), subscription));
+    }
+
+    /// Apply `f` to the status of a recording
+    fn update(&self, key: &(String, String), f: impl FnOnce(&mut RecordingStatus)) {
+        if let Some(status) = self.recordings.lock().unwrap().get_mut(key) {
+            f(status);
+        }
+    }
+}
+
+/// Writer of one recording
+struct Recorder {
+    manager: RecordingManager,
+    streams: StreamManager,
+    key: (String, String),
+    path: PathBuf,
+}
+
+impl Recorder {
+    /// Record until the stream ends, then report the file
+    async fn run(self, stream: Stream, subscription: Subscription) {
+        let header = stream.flv_header();
+        // Only the subscription is kept, so the stream can still end
+        drop(stream);
+        let (app, name) = self.key.clone();
+        match self.record(&header, subscription).await {
+            Ok(()) => info!("Recording {}/{} finished: {}", app, name, self.path.display()),
+            Err(e) => warn!("Recording {}/{} to {} failed: {}", app, name, self.path.display(), e),
+        }
+        self.manager.recordings.lock().unwrap().remove(&self.key);
+        // A file that could not be created is not reported
+        if fs::metadata(&self.path).await.is_ok() {
+            self.streams.events().emit(Event::RecordingFinished {
+                app,
+                stream: name,
+                path: self.path.display().to_string(),
+            });
+        }
+    }
+
+    /// Write the FLV file; a new file never replaces an older one
+    async fn record(&self, header: &FlvHeader, mut subscription: Subscription) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let file = OpenOptions::new().write(true).create_new(true).open(&self.path).await?;
+        let mut file = BufWriter::new(file);
+        let mut muxer = FlvMuxer::new();
+        let mut rebaser = TimestampRebaser::new(format!("{}/{}/record", self.key.0, self.key.1));
+
+        let header = muxer.header(header);
+        file.write_all(&header).await?;
+        let mut written = header.len() as u64;
+        let result = async {
+            while let Some(mut tag) = subscription.recv().await? {
+                tag.timestamp = rebaser.rebase(tag.timestamp);
+                let bytes = muxer.tag(&tag);
+                file.write_all(&bytes).await?;
+                written += bytes.len() as u64;
+                self.manager.update(&self.key, |status| status.bytes = written);
+            }
+            Ok(())
+        }
+        .await;
+        // Whatever was recorded before an error is kept
+        file.flush().await?;
+        result
+    }
+}
+
+/// File for a new recording of `name` in `app`; `None` when a part of
+/// the names would leave `dir`
+fn recording_path(dir: &Path, app: &str, name: &str) -> Option<PathBuf> {
+    let mut path = dir.to_path_buf();
+    let parts: Vec<&str> = app.split('/').chain(name.split('/')).collect();
+    for part in &parts {
+        if part.is_empty() || *part == "." || *part == ".." || part.contains('\\') {
+            return None;
+        }
+    }
+    let (file, dirs) = parts.split_last()?;
+    path.extend(dirs);
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    path.push(format!("{}-{}.flv", file, started));
+    Some(path)
+}
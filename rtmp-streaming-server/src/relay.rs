@@ -0,0 +1,184 @@
+//! Static push relays
+//!
+//! Every published stream that matches a configured [`PushTarget`] is
+//! re-streamed to the target over RTMP. Each target runs its own task which
+//! subscribes to the local stream, reconnects with exponential backoff and
+//! ends once the stream is gone.
+
+use crate::client::{RtmpClient, RtmpUrl};
+use crate::config::PushTarget;
+use crate::error::Result;
+use crate::stream::{Stream, Subscription};
+use crate::timestamp::TimestampRebaser;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Delay before the first reconnect attempt
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the reconnect delay
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Connection state of a push relay
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayState {
+    /// Connecting to the upstream server
+    Connecting,
+    /// Publishing to the upstream server
+    Connected,
+    /// Waiting before the next reconnect attempt
+    Backoff,
+}
+
+/// Status of one push target for one stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct RelayStatus {
+    /// Local application name
+    pub app: String,
+    /// Local stream name
+    pub stream: String,
+    /// Upstream URL
+    pub url: String,
+    /// Connection state
+    pub state: RelayState,
+    /// Successful connections so far
+    pub connects: u64,
+    /// Tags sent over all connections
+    pub tags_sent: u64,
+    /// Last connection or publish error
+    pub last_error: Option<String>,
+}
+
+/// Running push relays, keyed by stream and upstream URL
+#[derive(Clone, Default)]
+pub struct RelayManager {
+    relays: Arc<Mutex<HashMap<(String, String), RelayStatus>>>,
+}
+
+impl RelayManager {
+    /// Create an empty relay manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Status of every running relay, ordered by stream and URL
+    pub fn statuses(&self) -> Vec<RelayStatus> {
+        let mut statuses: Vec<_> = self.relays.lock().unwrap().values().cloned().collect();
+        statuses.sort_by(|a, b| (&a.stream, &a.url).cmp(&(&b.stream, &b.url)));
+        statuses
+    }
+
+    /// Start the relays `targets` define for `stream`, skipping those
+    /// already running (e.g. when a publisher reconnects)
+    ///
+    /// `app` is the app ID, `app_name` the name upstream URLs use.
+    pub(crate) async fn start(&self, app: &str, app_name: &str, name: &str, stream: &Stream, targets: &[PushTarget]) {
+        for target in targets {
+            let Some(url) = target.url_for(app_name, name) else {
+                continue;
+            };
+            let upstream = match RtmpUrl::parse(&url) {
+                Ok(upstream) => upstream,
+                Err(e) => {
+                    warn!("Skipping push target for {}: {}", name, e);
+                    continue;
+                }
+            };
+
+            let key = (format!("{}/{}", app, name), url.clone());
+            {
+                let mut relays = self.relays.lock().unwrap();
+                if relays.contains_key(&key) {
+                    continue;
+                }
+                relays.insert(
+                    key.clone(),
+                    RelayStatus {
+                        app: app.to_string(),
+                        stream: name.to_string(),
+                        url: url.clone(),
+                        state: RelayState::Connecting,
+                        connects: 0,
+                        tags_sent: 0,
+                        last_error: None,
+                    },
+                );
+            }
+
+            info!("Starting push relay {} -> {}", name, url);
+            let subscription = stream.subscribe_internal(format!("relay-{}", url)).await;
+            tokio::spawn(self.clone().run(key, upstream, subscription));
+        }
+    }
+
+    /// Relay task: push until the stream ends, reconnecting on errors
+    async fn run(self, key: (String, String), upstream: RtmpUrl, mut subscription: Subscription) {
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            self.update(&key, |status| status.state = RelayState::Connecting);
+            match self.push(&key, &upstream, &mut subscription, &mut backoff).await {
+                Ok(()) => break,
+                Err(e) => {
+                    warn!("Push relay {} -> {} failed: {}; retrying in {:?}", key.0, key.1, e, backoff);
+                    self.update(&key, |status| {
+                        status.state = RelayState::Backoff;
+                        status.last_error = Some(e.to_string());
+                    });
+                }
+            }
+
+            // Keep draining the stream while waiting so its end is noticed
+            if !subscription.skip_for(backoff).await {
+                break;
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+        self.finish(&key);
+    }
+
+    /// One upstream connection; `Ok` means the local stream ended
+    async fn push(
+        &self,
+        key: &(String, String),
+        upstream: &RtmpUrl,
+        subscription: &mut Subscription,
+        backoff: &mut Duration,
+    ) -> Result<()> {
+        let mut client = RtmpClient::connect(upstream).await?;
+        client.publish(&upstream.stream).await?;
+        info!("Push relay {} -> {} connected", key.0, key.1);
+        *backoff = INITIAL_BACKOFF;
+        self.update(key, |status| {
+            status.state = RelayState::Connected;
+            status.connects += 1;
+            status.last_error = None;
+        });
+
+        // A new upstream session starts at a keyframe with fresh headers
+        subscription.resync().await;
+        let mut rebaser = TimestampRebaser::new(format!("{}/relay", key.0));
+        while let Some(mut tag) = subscription.recv().await? {
+            tag.timestamp = rebaser.rebase(tag.timestamp);
+            client.send_tag(&tag).await?;
+            self.update(key, |status| status.tags_sent += 1);
+        }
+        Ok(())
+    }
+
+    /// Apply `f` to the status of a relay
+    fn update(&self, key: &(String, String), f: impl FnOnce(&mut RelayStatus)) {
+        if let Some(status) = self.relays.lock().unwrap().get_mut(key) {
+            f(status);
+        }
+    }
+
+    /// Forget a relay whose stream has ended
+    fn finish(&self, key: &(String, String)) {
+        info!("Push relay {} -> {} stopped", key.0, key.1);
+        self.relays.lock().unwrap().remove(key);
+    }
+}
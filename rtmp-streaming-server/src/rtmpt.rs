@@ -0,0 +1,325 @@
+//! RTMPT: RTMP tunneled over HTTP
+//!
+//! Clients behind proxies that only allow HTTP wrap the RTMP byte stream in
+//! POST requests to the HTTP server:
+//!
+//! - POST /open/1: create a tunnel; the response body is its session ID
+//! - POST /send/{id}/{seq}: RTMP bytes from the client
+//! - POST /idle/{id}/{seq}: poll for RTMP bytes from the server
+//! - POST /close/{id}/{seq}: close the tunnel
+//!
+//! Responses to send and idle start with one byte telling the client how
+//! long to wait before polling again, followed by the pending server bytes.
+//! Every tunnel drives a regular [`RtmpSession`] over an in-memory pipe, so
+//! publishing and playback behave exactly as over TCP. [`connect`] is the
+//! client side, used for `rtmpt://` URLs.
+
+use crate::error::{Error, Result};
+use crate::session::RtmpSession;
+use crate::stream::StreamManager;
+use bytes::{Bytes, BytesMut};
+use hyper::{Body, Client, Method, Request, Response, StatusCode};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::Notify;
+use tracing::{debug, info, warn};
+
+/// Content type of every RTMPT request and response
+const CONTENT_TYPE: &str = "application/x-fcs";
+
+/// Capacity of the pipe between a tunnel and its session
+const PIPE_CAPACITY: usize = 64 * 1024;
+
+/// Server bytes buffered for a tunnel before the session is held back
+const MAX_PENDING_OUTPUT: usize = 4 * 1024 * 1024;
+
+/// Tunnels that are not polled for this long are closed
+const TUNNEL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a send waits for the session to answer before responding
+const RESPONSE_WAIT: Duration = Duration::from_millis(50);
+
+/// Largest polling interval handed to clients
+const MAX_INTERVAL: u8 = 0x21;
+
+/// Client side: delay per unit of the server's polling interval
+const POLL_UNIT: Duration = Duration::from_millis(10);
+
+/// Server bytes waiting for the client's next request
+#[derive(Default)]
+struct Output {
+    buf: Mutex<BytesMut>,
+    /// The session has ended; nothing more will be added
+    closed: Mutex<bool>,
+    /// Woken when bytes are added or the session ends
+    filled: Notify,
+    /// Woken when a request takes bytes out
+    drained: Notify,
+}
+
+/// One open tunnel
+struct Tunnel {
+    /// Pipe into the session; dropping it ends the session
+    input: tokio::sync::Mutex<DuplexStream>,
+    output: Arc<Output>,
+    last_seen: Mutex<Instant>,
+    interval: Mutex<u8>,
+}
+
+impl Tunnel {
+    /// Pending server bytes behind the polling interval byte
+    ///
+    /// The interval drops to 1 while data flows and doubles with every
+    /// empty poll, up to [`MAX_INTERVAL`].
+    fn take_output(&self) -> Bytes {
+        let data = self.output.buf.lock().unwrap().split();
+        self.output.drained.notify_one();
+
+        let mut interval = self.interval.lock().unwrap();
+        *interval = if data.is_empty() {
+            interval.saturating_mul(2).min(MAX_INTERVAL)
+        } else {
+            1
+        };
+        let mut response = BytesMut::with_capacity(1 + data.len());
+        response.extend_from_slice(&[*interval]);
+        response.extend_from_slice(&data);
+        response.freeze()
+    }
+
+    fn is_finished(&self) -> bool {
+        *self.output.closed.lock().unwrap() && self.output.buf.lock().unwrap().is_empty()
+    }
+}
+
+/// Open RTMPT tunnels, keyed by session ID
+#[derive(Clone, Default)]
+pub(crate) struct RtmptTunnels {
+    tunnels: Arc<Mutex<HashMap<String, Arc<Tunnel>>>>,
+}
+
+impl RtmptTunnels {
+    /// Create an empty tunnel table
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if `path` is an RTMPT command
+    pub(crate) fn is_rtmpt_path(path: &str) -> bool {
+        ["/open/", "/send/", "/idle/", "/close/"]
+            .iter()
+            .any(|prefix| path.starts_with(prefix))
+    }
+
+    /// Handle an RTMPT request
+    pub(crate) async fn handle(
+        &self,
+        req: Request<Body>,
+        remote_addr: SocketAddr,
+        streams: &StreamManager,
+    ) -> Response<Body> {
+        let path = req.uri().path().to_string();
+        let parts: Vec<&str> = path.split('/').skip(1).collect();
+        if req.method() != Method::POST {
+            return status(StatusCode::METHOD_NOT_ALLOWED);
+        }
+        if parts[0] == "open" {
+            return self.open(remote_addr, streams);
+        }
+        let Some(id) = parts.get(1).map(|id| id.to_string()) else {
+            return status(StatusCode::NOT_FOUND);
+        };
+        let Some(tunnel) = self.tunnels.lock().unwrap().get(&id).cloned() else {
+            return status(StatusCode::NOT_FOUND);
+        };
+        *tunnel.last_seen.lock().unwrap() = Instant::now();
+
+        match parts[0] {
+            "send" => {
+                let body = match hyper::body::to_bytes(req.into_body()).await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        debug!("RTMPT {}: failed to read request body: {}", id, e);
+                        return status(StatusCode::BAD_REQUEST);
+                    }
+                };
+                // A session that has ended shows up as a finished tunnel below
+                let _ = tunnel.input.lock().await.write_all(&body).await;
+
+                // Give the session a moment so the reply rides on this response
+                let filled = tunnel.output.filled.notified();
+                if tunnel.output.buf.lock().unwrap().is_empty() {
+                    let _ = tokio::time::timeout(RESPONSE_WAIT, filled).await;
+                }
+                self.respond(&id, &tunnel)
+            }
+            "idle" => self.respond(&id, &tunnel),
+            "close" => {
+                self.tunnels.lock().unwrap().remove(&id);
+                info!("RTMPT tunnel {} closed by the client", id);
+                fcs(Bytes::from_static(&[0]))
+            }
+            _ => status(StatusCode::NOT_FOUND),
+        }
+    }
+
+    /// Start a session for a new tunnel
+    fn open(&self, remote_addr: SocketAddr, streams: &StreamManager) -> Response<Body> {
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        // One pipe per direction, so either side closing is seen as EOF
+        let (input, session_input) = tokio::io::duplex(PIPE_CAPACITY);
+        let (session_output, output_pipe) = tokio::io::duplex(PIPE_CAPACITY);
+        let output = Arc::new(Output::default());
+        let tunnel = Arc::new(Tunnel {
+            input: tokio::sync::Mutex::new(input),
+            output: Arc::clone(&output),
+            last_seen: Mutex::new(Instant::now()),
+            interval: Mutex::new(1),
+        });
+        self.tunnels.lock().unwrap().insert(id.clone(), Arc::clone(&tunnel));
+        info!("RTMPT tunnel {} opened from {}", id, remote_addr);
+
+        let pipe = tokio::io::join(session_input, session_output);
+        let mut session = RtmpSession::new(pipe, remote_addr, streams.clone());
+        tokio::spawn(async move {
+            if let Err(e) = session.handle().await {
+                warn!("Session {} from {} failed: {}", session.session_id(), remote_addr, e);
+            }
+        });
+        tokio::spawn(collect_output(output_pipe, output));
+        tokio::spawn(expire(self.clone(), id.clone(), Arc::downgrade(&tunnel)));
+
+        fcs(Bytes::from(format!("{}\n", id)))
+    }
+
+    /// Respond with the pending output, or 404 once the session has ended
+    /// and everything was delivered
+    fn respond(&self, id: &str, tunnel: &Tunnel) -> Response<Body> {
+        if tunnel.is_finished() {
+            self.tunnels.lock().unwrap().remove(id);
+            debug!("RTMPT tunnel {} finished", id);
+            return status(StatusCode::NOT_FOUND);
+        }
+        fcs(tunnel.take_output())
+    }
+}
+
+/// Move server bytes from the session pipe into the tunnel's output
+async fn collect_output(mut reader: DuplexStream, output: Arc<Output>) {
+    let mut buf = BytesMut::with_capacity(PIPE_CAPACITY);
+    loop {
+        // Hold the session back while the client is not collecting
+        while output.buf.lock().unwrap().len() >= MAX_PENDING_OUTPUT {
+            let drained = output.drained.notified();
+            let _ = tokio::time::timeout(TUNNEL_TIMEOUT, drained).await;
+        }
+        match reader.read_buf(&mut buf).await {
+            Ok(n) if n > 0 => {
+                output.buf.lock().unwrap().extend_from_slice(&buf.split());
+                output.filled.notify_one();
+            }
+            _ => break,
+        }
+    }
+    *output.closed.lock().unwrap() = true;
+    output.filled.notify_one();
+}
+
+/// Close a tunnel once the client stops polling it
+async fn expire(tunnels: RtmptTunnels, id: String, tunnel: Weak<Tunnel>) {
+    loop {
+        tokio::time::sleep(TUNNEL_TIMEOUT / 4).await;
+        let Some(tunnel) = tunnel.upgrade() else {
+            return;
+        };
+        if tunnel.last_seen.lock().unwrap().elapsed() >= TUNNEL_TIMEOUT {
+            tunnels.tunnels.lock().unwrap().remove(&id);
+            info!("RTMPT tunnel {} timed out", id);
+            return;
+        }
+    }
+}
+
+/// RTMPT response with `body`
+fn fcs(body: Bytes) -> Response<Body> {
+    Response::builder()
+        .header("Content-Type", CONTENT_TYPE)
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    Response::builder().status(code).body(Body::empty()).unwrap()
+}
+
+/// Open a tunnel to the RTMPT server at `host:port`
+///
+/// The returned pipe carries the RTMP byte stream; a background task sends
+/// what is written to it and polls the server for the other direction.
+pub(crate) async fn connect(host: &str, port: u16) -> Result<DuplexStream> {
+    let base = format!("http://{}:{}", host, port);
+    let client = Client::new();
+    let id = post(&client, &format!("{}/open/1", base), Bytes::new()).await?;
+    let id = String::from_utf8_lossy(&id).trim().to_string();
+    if id.is_empty() {
+        return Err(Error::Protocol("RTMPT server sent no session ID".to_string()));
+    }
+    debug!("RTMPT tunnel {} opened to {}", id, base);
+
+    let (local, tunnel) = tokio::io::duplex(PIPE_CAPACITY);
+    tokio::spawn(async move {
+        if let Err(e) = pump(&client, &base, &id, tunnel).await {
+            debug!("RTMPT tunnel {} to {} ended: {}", id, base, e);
+        }
+    });
+    Ok(local)
+}
+
+/// Exchange bytes between the local pipe and the server until either ends
+async fn pump(client: &Client<hyper::client::HttpConnector>, base: &str, id: &str, tunnel: DuplexStream) -> Result<()> {
+    let (mut reader, mut writer) = tokio::io::split(tunnel);
+    let mut buf = BytesMut::with_capacity(PIPE_CAPACITY);
+    let mut seq = 1u64;
+    let mut delay = POLL_UNIT;
+
+    loop {
+        let url = match tokio::time::timeout(delay, reader.read_buf(&mut buf)).await {
+            Ok(Ok(0)) | Ok(Err(_)) => {
+                let _ = post(client, &format!("{}/close/{}/{}", base, id, seq), Bytes::new()).await;
+                return Ok(());
+            }
+            Ok(Ok(_)) => format!("{}/send/{}/{}", base, id, seq),
+            Err(_) => format!("{}/idle/{}/{}", base, id, seq),
+        };
+        seq += 1;
+
+        let response = post(client, &url, buf.split().freeze()).await?;
+        let Some((&interval, data)) = response.split_first() else {
+            return Err(Error::Protocol("Empty RTMPT response".to_string()));
+        };
+        delay = POLL_UNIT * u32::from(interval.max(1));
+        writer.write_all(data).await?;
+    }
+}
+
+/// POST `body` to an RTMPT URL and return the response body
+async fn post(client: &Client<hyper::client::HttpConnector>, url: &str, body: Bytes) -> Result<Bytes> {
+    let request = Request::post(url)
+        .header("Content-Type", CONTENT_TYPE)
+        .body(Body::from(body))
+        .map_err(|e| Error::InvalidInput(format!("Invalid RTMPT URL {}: {}", url, e)))?;
+    let response = client
+        .request(request)
+        .await
+        .map_err(|e| Error::Network(e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(Error::Network(format!("RTMPT server answered {}", response.status())));
+    }
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| Error::Network(e.to_string()))
+}
@@ -0,0 +1,1035 @@
+//! RTSP pull from IP cameras
+//!
+//! Cameras are configured per app ([`CameraConfig`]). When a viewer asks
+//! for a camera's stream and it is not published, the camera is pulled
+//! over RTSP, with RTP interleaved on the RTSP connection or on UDP ports,
+//! and republished locally so RTMP and HTTP-FLV viewers can watch it.
+//! H.264 (single NAL units, STAP-A, FU-A), AAC (RFC 3640), G.711 and Opus
+//! are depacketized; the AVC sequence header is built from the SDP
+//! `sprop-parameter-sets` or from parameter sets sent in-band. A lost
+//! camera connection is retried with backoff while the stream has viewers;
+//! the pull stops once it has had none for the idle timeout.
+
+use crate::config::{CameraConfig, RtspTransport};
+use crate::error::{Error, Result};
+use crate::flv::{opus_head, opus_tag, AvcPacker, FlvTag, TagType};
+use crate::stream::{Publisher, StreamManager};
+use base64::Engine;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// How often a pull checks whether it still has viewers
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Delay before the first reconnect
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the reconnect delay
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Upper bound for connecting and for each RTSP response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A camera that sends no RTP for this long is reconnected
+const RTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Session timeout when the camera does not announce one
+const DEFAULT_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest RTSP response header block
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// FLV sound format bytes for G.711: A-law/µ-law, 16-bit, mono
+const FLV_PCMA: u8 = 0x72;
+const FLV_PCMU: u8 = 0x82;
+
+const USER_AGENT: &str = concat!("rtmp-streaming-server/", env!("CARGO_PKG_VERSION"));
+
+/// Cameras currently pulled over RTSP
+#[derive(Clone, Default)]
+pub struct RtspManager {
+    pulling: Arc<Mutex<HashSet<(String, String)>>>,
+}
+
+impl RtspManager {
+    /// Create an empty RTSP manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Check if `name` of `app` is being pulled from a camera
+    pub fn is_pulling(&self, app: &str, name: &str) -> bool {
+        self.pulling.lock().unwrap().contains(&(app.to_string(), name.to_string()))
+    }
+
+    /// Start pulling `name` unless it is already pulled or published
+    pub(crate) async fn start(&self, streams: &StreamManager, app: &str, name: &str, camera: &CameraConfig) {
+        let key = (app.to_string(), name.to_string());
+        if !self.pulling.lock().unwrap().insert(key.clone()) {
+            return;
+        }
+        let publisher_id = format!("rtsp-{}", uuid::Uuid::new_v4().simple());
+        let publisher = match streams.start_publish(app, name, publisher_id).await {
+            Ok(publisher) => publisher,
+            Err(e) => {
+                warn!("Not pulling camera {}: {}", name, e);
+                self.pulling.lock().unwrap().remove(&key);
+                return;
+            }
+        };
+
+        info!("Pulling camera {} from {}", name, redact(&camera.url));
+        let manager = self.clone();
+        let name = name.to_string();
+        let camera = camera.clone();
+        tokio::spawn(async move {
+            run(&camera, &name, publisher).await;
+            info!("Stopped pulling camera {}", name);
+            manager.pulling.lock().unwrap().remove(&key);
+        });
+    }
+}
+
+/// Pull until the stream has no viewers, reconnecting on failures. The
+/// publisher is kept while reconnecting, so viewers stay attached.
+async fn run(camera: &CameraConfig, name: &str, publisher: Publisher) {
+    let mut idle = IdleTimer::new(camera.idle_timeout());
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match pull(camera, &publisher, &mut idle, &mut backoff).await {
+            Ok(()) => return,
+            Err(e) if publisher.is_evicted() => {
+                info!("Camera {} replaced by another publisher: {}", name, e);
+                return;
+            }
+            Err(e) => warn!("Camera {} failed: {}; retrying in {:?}", name, e, backoff),
+        }
+
+        let retry_at = Instant::now() + backoff;
+        while Instant::now() < retry_at {
+            if idle.expired(&publisher) {
+                return;
+            }
+            tokio::time::sleep(IDLE_CHECK_INTERVAL).await;
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Tracks how long a stream has been without viewers
+struct IdleTimer {
+    timeout: Duration,
+    since: Option<Instant>,
+}
+
+impl IdleTimer {
+    fn new(timeout: Duration) -> Self {
+        Self { timeout, since: None }
+    }
+
+    fn expired(&mut self, publisher: &Publisher) -> bool {
+        if publisher.stream().subscriber_count() > 0 {
+            self.since = None;
+            return false;
+        }
+        self.since.get_or_insert_with(Instant::now).elapsed() >= self.timeout
+    }
+}
+
+/// One RTSP session: set up the tracks, play, and publish what arrives.
+/// Returns `Ok` when the stream went idle.
+async fn pull(camera: &CameraConfig, publisher: &Publisher, idle: &mut IdleTimer, backoff: &mut Duration) -> Result<()> {
+    let url = RtspUrl::parse(&camera.url)?;
+    let mut conn = Connection::connect(&url).await?;
+
+    let options = conn.request("OPTIONS", &url.uri, &[]).await?;
+    let get_parameter = options.header("Public").is_some_and(|public| public.contains("GET_PARAMETER"));
+    let describe = conn.request("DESCRIBE", &url.uri, &[("Accept", "application/sdp")]).await?;
+    let base = describe
+        .header("Content-Base")
+        .or_else(|| describe.header("Content-Location"))
+        .unwrap_or(&url.uri)
+        .to_string();
+    let sdp = Sdp::parse(&String::from_utf8_lossy(&describe.body), &base);
+    if sdp.tracks.is_empty() {
+        return Err(Error::Stream("Camera offers no H.264, AAC or G.711 track".to_string()));
+    }
+
+    // UDP: RTP of every track is forwarded into one channel
+    let (udp_tx, mut udp_rx) = mpsc::channel::<(usize, Bytes)>(256);
+    let mut udp_tasks = AbortOnDrop::default();
+    for (index, track) in sdp.tracks.iter().enumerate() {
+        let transport = match camera.transport {
+            RtspTransport::Tcp => format!("RTP/AVP/TCP;unicast;interleaved={}-{}", 2 * index, 2 * index + 1),
+            RtspTransport::Udp => {
+                let (rtp, rtcp) = bind_udp_pair().await?;
+                let header = format!(
+                    "RTP/AVP;unicast;client_port={}-{}",
+                    rtp.local_addr()?.port(),
+                    rtcp.local_addr()?.port()
+                );
+                udp_tasks.0.push(tokio::spawn(forward_udp(index, rtp, rtcp, udp_tx.clone())));
+                header
+            }
+        };
+        conn.request("SETUP", &track.control, &[("Transport", &transport)]).await?;
+    }
+    conn.request("PLAY", &sdp.control, &[("Range", "npt=0.000-")]).await?;
+    *backoff = INITIAL_BACKOFF;
+    info!("Camera {} playing ({} track(s))", redact(&camera.url), sdp.tracks.len());
+
+    let keepalive_method = if get_parameter { "GET_PARAMETER" } else { "OPTIONS" };
+    let mut keepalive = tokio::time::interval(conn.session_timeout / 2);
+    keepalive.tick().await;
+    let mut idle_check = tokio::time::interval(IDLE_CHECK_INTERVAL);
+    let mut receiver = RtpReceiver::new(sdp.tracks.iter().map(|track| (track.clock_rate, track.codec.clone())));
+    let mut last_rtp = Instant::now();
+
+    loop {
+        let (track, data) = tokio::select! {
+            message = conn.read_message() => match message? {
+                Message::Interleaved { channel, data } if channel % 2 == 0 => ((channel / 2) as usize, data),
+                // RTCP, keepalive answers and requests from the camera
+                _ => continue,
+            },
+            Some(packet) = udp_rx.recv() => packet,
+            _ = keepalive.tick() => {
+                conn.send(keepalive_method, &url.uri, &[]).await?;
+                continue;
+            }
+            _ = idle_check.tick() => {
+                if idle.expired(publisher) {
+                    let _ = conn.send("TEARDOWN", &sdp.control, &[]).await;
+                    return Ok(());
+                }
+                if last_rtp.elapsed() > RTP_TIMEOUT {
+                    return Err(Error::Network(format!("No RTP from the camera for {:?}", RTP_TIMEOUT)));
+                }
+                continue;
+            }
+        };
+        last_rtp = Instant::now();
+        for tag in receiver.receive(track, data) {
+            publisher.publish(tag).await?;
+        }
+    }
+}
+
+/// Bind an RTP/RTCP socket pair, on consecutive ports when possible
+pub(crate) async fn bind_udp_pair() -> Result<(UdpSocket, UdpSocket)> {
+    for _ in 0..16 {
+        let rtp = UdpSocket::bind("0.0.0.0:0").await?;
+        let port = rtp.local_addr()?.port();
+        if port % 2 != 0 || port == u16::MAX {
+            continue;
+        }
+        if let Ok(rtcp) = UdpSocket::bind(("0.0.0.0", port + 1)).await {
+            return Ok((rtp, rtcp));
+        }
+    }
+    Ok((UdpSocket::bind("0.0.0.0:0").await?, UdpSocket::bind("0.0.0.0:0").await?))
+}
+
+/// Forward RTP datagrams of one track; the RTCP socket is only held open
+async fn forward_udp(track: usize, rtp: UdpSocket, _rtcp: UdpSocket, packets: mpsc::Sender<(usize, Bytes)>) {
+    let mut buf = vec![0u8; 65536];
+    while let Ok(len) = rtp.recv(&mut buf).await {
+        if packets.send((track, Bytes::copy_from_slice(&buf[..len]))).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Tasks aborted with the session
+#[derive(Default)]
+pub(crate) struct AbortOnDrop(pub(crate) Vec<JoinHandle<()>>);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        for task in &self.0 {
+            task.abort();
+        }
+    }
+}
+
+/// Camera URL without its credentials, for logs
+fn redact(url: &str) -> String {
+    RtspUrl::parse(url).map(|url| url.uri).unwrap_or_else(|_| url.to_string())
+}
+
+/// Parsed `rtsp://` URL
+struct RtspUrl {
+    /// URL without credentials, used in requests
+    uri: String,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl RtspUrl {
+    fn parse(url: &str) -> Result<Self> {
+        let invalid = || Error::InvalidInput(format!("Invalid RTSP URL: {}", url));
+        let rest = url.strip_prefix("rtsp://").ok_or_else(invalid)?;
+        let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+        let (credentials, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (Some((user.to_string(), password.to_string())), host_port)
+            }
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().map_err(|_| invalid())?),
+            _ => (host_port, 554),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            uri: format!("rtsp://{}{}", host_port, path),
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port,
+            credentials,
+        })
+    }
+}
+
+/// Something read from the RTSP connection
+enum Message {
+    /// `$`-framed RTP or RTCP
+    Interleaved { channel: u8, data: Bytes },
+    Response(Response),
+    /// A request from the camera (ignored)
+    Request,
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Credentials answer to a `WWW-Authenticate` challenge
+enum Auth {
+    Basic,
+    Digest {
+        realm: String,
+        nonce: String,
+        opaque: Option<String>,
+        qop: bool,
+        count: u32,
+    },
+}
+
+/// RTSP control connection
+struct Connection {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    buf: BytesMut,
+    cseq: u32,
+    credentials: Option<(String, String)>,
+    auth: Option<Auth>,
+    session: Option<String>,
+    session_timeout: Duration,
+}
+
+impl Connection {
+    async fn connect(url: &RtspUrl) -> Result<Self> {
+        let socket = tokio::time::timeout(RESPONSE_TIMEOUT, TcpStream::connect((url.host.as_str(), url.port)))
+            .await
+            .map_err(|_| Error::Network(format!("Connecting to {}:{} timed out", url.host, url.port)))??;
+        socket.set_nodelay(true)?;
+        let (reader, writer) = socket.into_split();
+        Ok(Self {
+            reader,
+            writer,
+            buf: BytesMut::with_capacity(64 * 1024),
+            cseq: 0,
+            credentials: url.credentials.clone(),
+            auth: None,
+            session: None,
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
+        })
+    }
+
+    /// Send a request and wait for its successful response, answering one
+    /// authentication challenge on the way
+    async fn request(&mut self, method: &str, uri: &str, headers: &[(&str, &str)]) -> Result<Response> {
+        for attempt in 0..2 {
+            let cseq = self.send(method, uri, headers).await?;
+            let response = tokio::time::timeout(RESPONSE_TIMEOUT, self.response(cseq))
+                .await
+                .map_err(|_| Error::Network(format!("RTSP {} timed out", method)))??;
+            if response.status == 401 && attempt == 0 && self.credentials.is_some() {
+                if let Some(auth) = challenge(&response) {
+                    self.auth = Some(auth);
+                    continue;
+                }
+            }
+            if !(200..300).contains(&response.status) {
+                return Err(Error::Stream(format!("RTSP {} answered {}", method, response.status)));
+            }
+            if let Some(session) = response.header("Session") {
+                let mut parts = session.split(';');
+                self.session = parts.next().map(|id| id.trim().to_string());
+                for part in parts {
+                    if let Some(Ok(seconds)) = part.trim().strip_prefix("timeout=").map(str::parse::<u64>) {
+                        self.session_timeout = Duration::from_secs(seconds.max(2));
+                    }
+                }
+            }
+            return Ok(response);
+        }
+        Err(Error::AuthDenied(format!("RTSP {}: authentication failed", method)))
+    }
+
+    /// Send a request without waiting for the response; returns its CSeq
+    async fn send(&mut self, method: &str, uri: &str, headers: &[(&str, &str)]) -> Result<u32> {
+        self.cseq += 1;
+        let mut request = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\nUser-Agent: {}\r\n", method, uri, self.cseq, USER_AGENT);
+        if let Some(authorization) = self.authorization(method, uri) {
+            request.push_str(&format!("Authorization: {}\r\n", authorization));
+        }
+        if let Some(session) = &self.session {
+            request.push_str(&format!("Session: {}\r\n", session));
+        }
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        self.writer.write_all(request.as_bytes()).await?;
+        Ok(self.cseq)
+    }
+
+    /// Read until the response with `cseq`
+    async fn response(&mut self, cseq: u32) -> Result<Response> {
+        loop {
+            if let Message::Response(response) = self.read_message().await? {
+                let matches = response
+                    .header("CSeq")
+                    .and_then(|value| value.trim().parse::<u32>().ok())
+                    .is_none_or(|value| value == cseq);
+                if matches {
+                    return Ok(response);
+                }
+            }
+        }
+    }
+
+    /// Next message from the camera (cancel safe)
+    async fn read_message(&mut self) -> Result<Message> {
+        loop {
+            if let Some(message) = self.parse_message()? {
+                return Ok(message);
+            }
+            if self.reader.read_buf(&mut self.buf).await? == 0 {
+                return Err(Error::Network("Camera closed the connection".to_string()));
+            }
+        }
+    }
+
+    fn parse_message(&mut self) -> Result<Option<Message>> {
+        if self.buf.first() == Some(&b'$') {
+            if self.buf.len() < 4 {
+                return Ok(None);
+            }
+            let len = u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize;
+            if self.buf.len() < 4 + len {
+                return Ok(None);
+            }
+            let frame = self.buf.split_to(4 + len).freeze();
+            return Ok(Some(Message::Interleaved {
+                channel: frame[1],
+                data: frame.slice(4..),
+            }));
+        }
+
+        let Some(end) = self.buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+            if self.buf.len() > MAX_HEADER_SIZE {
+                return Err(Error::Protocol("RTSP header too large".to_string()));
+            }
+            return Ok(None);
+        };
+        let head = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+        let mut lines = head.split("\r\n");
+        let start_line = lines.next().unwrap_or_default();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+            .unwrap_or(0);
+        if self.buf.len() < end + 4 + content_length {
+            return Ok(None);
+        }
+        let _ = self.buf.split_to(end + 4);
+        let body = self.buf.split_to(content_length).freeze();
+
+        let Some(status) = start_line.strip_prefix("RTSP/1.0 ") else {
+            debug!("Ignoring RTSP request from camera: {}", start_line);
+            return Ok(Some(Message::Request));
+        };
+        let status = status
+            .split(' ')
+            .next()
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| Error::Protocol(format!("Invalid RTSP status line: {}", start_line)))?;
+        Ok(Some(Message::Response(Response { status, headers, body })))
+    }
+
+    /// `Authorization` header for a request, once challenged
+    fn authorization(&mut self, method: &str, uri: &str) -> Option<String> {
+        let (user, password) = self.credentials.as_ref()?;
+        match self.auth.as_mut()? {
+            Auth::Basic => {
+                let token = base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password));
+                Some(format!("Basic {}", token))
+            }
+            Auth::Digest {
+                realm,
+                nonce,
+                opaque,
+                qop,
+                count,
+            } => {
+                let ha1 = md5_hex(&format!("{}:{}:{}", user, realm, password));
+                let ha2 = md5_hex(&format!("{}:{}", method, uri));
+                let mut header = format!(r#"Digest username="{}", realm="{}", nonce="{}", uri="{}""#, user, realm, nonce, uri);
+                let response = if *qop {
+                    *count += 1;
+                    let cnonce = format!("{:016x}", rand::random::<u64>());
+                    header.push_str(&format!(r#", qop=auth, nc={:08x}, cnonce="{}""#, count, cnonce));
+                    md5_hex(&format!("{}:{}:{:08x}:{}:auth:{}", ha1, nonce, count, cnonce, ha2))
+                } else {
+                    md5_hex(&format!("{}:{}:{}", ha1, nonce, ha2))
+                };
+                header.push_str(&format!(r#", response="{}""#, response));
+                if let Some(opaque) = opaque {
+                    header.push_str(&format!(r#", opaque="{}""#, opaque));
+                }
+                Some(header)
+            }
+        }
+    }
+}
+
+/// Pick the strongest scheme offered in a 401 response
+fn challenge(response: &Response) -> Option<Auth> {
+    let offers: Vec<&str> = response
+        .headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("WWW-Authenticate"))
+        .map(|(_, value)| value.as_str())
+        .collect();
+    for offer in &offers {
+        if let Some(params) = offer.strip_prefix("Digest ") {
+            let params = auth_params(params);
+            let param = |name: &str| params.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+            return Some(Auth::Digest {
+                realm: param("realm")?,
+                nonce: param("nonce")?,
+                opaque: param("opaque"),
+                qop: param("qop").is_some_and(|qop| qop.split(',').any(|value| value.trim() == "auth")),
+                count: 0,
+            });
+        }
+    }
+    offers.iter().any(|offer| offer.starts_with("Basic")).then_some(Auth::Basic)
+}
+
+/// `key=value` and `key="value"` pairs of an authentication header
+fn auth_params(mut input: &str) -> Vec<(String, String)> {
+    let mut params = Vec::new();
+    loop {
+        input = input.trim_start_matches([',', ' ']);
+        let Some((key, rest)) = input.split_once('=') else {
+            return params;
+        };
+        let (value, rest) = match rest.strip_prefix('"') {
+            Some(quoted) => quoted.split_once('"').unwrap_or((quoted, "")),
+            None => rest.split_once(',').unwrap_or((rest, "")),
+        };
+        params.push((key.trim().to_string(), value.to_string()));
+        input = rest;
+    }
+}
+
+fn md5_hex(input: &str) -> String {
+    format!("{:x}", md5::compute(input))
+}
+
+/// Codec of a track we can republish
+#[derive(Debug, Clone)]
+pub(crate) enum Codec {
+    H264 { parameter_sets: Vec<Bytes> },
+    Aac { config: Bytes, size_length: u32, index_length: u32 },
+    G711 { sound_format: u8 },
+    Opus { channels: u8 },
+}
+
+#[derive(Debug, Clone)]
+struct Track {
+    /// Absolute control URL for SETUP
+    control: String,
+    clock_rate: u32,
+    codec: Codec,
+}
+
+/// The parts of a session description we use
+struct Sdp {
+    /// Aggregate control URL for PLAY and TEARDOWN
+    control: String,
+    /// At most one video and one audio track
+    tracks: Vec<Track>,
+}
+
+impl Sdp {
+    fn parse(sdp: &str, base: &str) -> Self {
+        let mut control = base.to_string();
+        let mut tracks = Vec::new();
+        let mut has_video = false;
+        let mut has_audio = false;
+
+        // Split into the session section and one section per m= line
+        let mut sections: Vec<Vec<&str>> = vec![Vec::new()];
+        for line in sdp.lines().map(str::trim) {
+            if line.starts_with("m=") {
+                sections.push(Vec::new());
+            }
+            sections.last_mut().unwrap().push(line);
+        }
+        if let Some(url) = sections[0].iter().find_map(|line| line.strip_prefix("a=control:")) {
+            control = resolve(base, url);
+        }
+
+        for media in &sections[1..] {
+            let mut fields = media[0].trim_start_matches("m=").split_whitespace();
+            let kind = fields.next().unwrap_or_default();
+            let Some(payload_type) = fields.nth(2).and_then(|pt| pt.parse::<u8>().ok()) else {
+                continue;
+            };
+            let attribute = |name: &str| {
+                let prefix = format!("a={}:", name);
+                media.iter().find_map(|line| {
+                    let value = line.strip_prefix(&prefix)?;
+                    match name {
+                        "control" => Some(value.to_string()),
+                        _ => value
+                            .strip_prefix(&payload_type.to_string())
+                            .filter(|rest| rest.starts_with(' '))
+                            .map(|rest| rest.trim().to_string()),
+                    }
+                })
+            };
+            let rtpmap = attribute("rtpmap").unwrap_or_default();
+            let mut rtpmap_parts = rtpmap.split('/');
+            let encoding = rtpmap_parts.next().unwrap_or_default().to_ascii_uppercase();
+            let clock_rate = rtpmap_parts.next().and_then(|rate| rate.parse().ok());
+            let channels = rtpmap_parts.next().and_then(|channels| channels.parse().ok());
+            let fmtp = attribute("fmtp").unwrap_or_default();
+            let fmtp_param = |name: &str| {
+                fmtp.split(';').find_map(|param| {
+                    let (key, value) = param.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+                })
+            };
+
+            let codec = match (kind, encoding.as_str(), payload_type) {
+                ("video", "H264", _) if !has_video => {
+                    has_video = true;
+                    let parameter_sets = fmtp_param("sprop-parameter-sets")
+                        .unwrap_or_default()
+                        .split(',')
+                        .filter_map(|set| base64::engine::general_purpose::STANDARD.decode(set).ok())
+                        .map(Bytes::from)
+                        .collect();
+                    Codec::H264 { parameter_sets }
+                }
+                ("audio", "MPEG4-GENERIC", _) if !has_audio => {
+                    let Some(config) = fmtp_param("config").and_then(|hex| decode_hex(&hex)) else {
+                        continue;
+                    };
+                    has_audio = true;
+                    Codec::Aac {
+                        config: Bytes::from(config),
+                        size_length: fmtp_param("sizelength").and_then(|v| v.parse().ok()).unwrap_or(13),
+                        index_length: fmtp_param("indexlength").and_then(|v| v.parse().ok()).unwrap_or(3),
+                    }
+                }
+                ("audio", "PCMA", _) | ("audio", "", 8) if !has_audio => {
+                    has_audio = true;
+                    Codec::G711 { sound_format: FLV_PCMA }
+                }
+                ("audio", "PCMU", _) | ("audio", "", 0) if !has_audio => {
+                    has_audio = true;
+                    Codec::G711 { sound_format: FLV_PCMU }
+                }
+                ("audio", "OPUS", _) if !has_audio => {
+                    has_audio = true;
+                    Codec::Opus {
+                        channels: channels.unwrap_or(2),
+                    }
+                }
+                _ => {
+                    debug!("Skipping {} track {} ({})", kind, payload_type, encoding);
+                    continue;
+                }
+            };
+            let clock_rate = clock_rate.unwrap_or(match codec {
+                Codec::H264 { .. } => 90_000,
+                _ => 8_000,
+            });
+            tracks.push(Track {
+                control: attribute("control").map_or_else(|| base.to_string(), |url| resolve(base, &url)),
+                clock_rate,
+                codec,
+            });
+        }
+        Self { control, tracks }
+    }
+}
+
+/// Resolve a control attribute against the base URL
+fn resolve(base: &str, control: &str) -> String {
+    if control.starts_with("rtsp://") {
+        control.to_string()
+    } else if control == "*" {
+        base.to_string()
+    } else {
+        format!("{}/{}", base.trim_end_matches('/'), control)
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len() / 2 * 2)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// An RTP packet
+struct RtpPacket {
+    marker: bool,
+    seq: u16,
+    timestamp: u32,
+    payload: Bytes,
+}
+
+impl RtpPacket {
+    fn parse(mut data: Bytes) -> Option<Self> {
+        if data.len() < 12 || data[0] >> 6 != 2 {
+            return None;
+        }
+        let padding = data[0] & 0x20 != 0;
+        let extension = data[0] & 0x10 != 0;
+        let csrc_count = (data[0] & 0x0f) as usize;
+        let marker = data[1] & 0x80 != 0;
+        let seq = u16::from_be_bytes([data[2], data[3]]);
+        let timestamp = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+
+        let mut header_len = 12 + 4 * csrc_count;
+        if extension {
+            let words = data.get(header_len + 2..header_len + 4)?;
+            header_len += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+        }
+        let mut end = data.len();
+        if padding {
+            end = end.checked_sub(*data.last()? as usize)?;
+        }
+        if header_len > end {
+            return None;
+        }
+        data.truncate(end);
+        data.advance(header_len);
+        Some(Self {
+            marker,
+            seq,
+            timestamp,
+            payload: data,
+        })
+    }
+}
+
+/// RTP timestamps of one track as milliseconds on the stream clock
+///
+/// Tracks start at the wall-clock time their first packet arrived, as the
+/// cameras' RTCP sender reports are not used.
+struct RtpClock {
+    rate: u64,
+    /// Arrival of the first packet (ms since the session started)
+    offset: u64,
+    last: Option<u32>,
+    /// Ticks since the first packet, extended past 32-bit wraparound
+    ticks: i64,
+}
+
+impl RtpClock {
+    fn ms(&mut self, timestamp: u32, started: Instant) -> u32 {
+        match self.last {
+            None => self.offset = started.elapsed().as_millis() as u64,
+            Some(last) => self.ticks += timestamp.wrapping_sub(last) as i32 as i64,
+        }
+        self.last = Some(timestamp);
+        (self.offset + self.ticks.max(0) as u64 * 1000 / self.rate) as u32
+    }
+}
+
+/// Turns RTP packets of the session's tracks into FLV tags
+pub(crate) struct RtpReceiver {
+    started: Instant,
+    tracks: Vec<(RtpClock, Depacketizer)>,
+}
+
+impl RtpReceiver {
+    /// Receiver for tracks given by clock rate and codec
+    pub(crate) fn new(tracks: impl IntoIterator<Item = (u32, Codec)>) -> Self {
+        Self {
+            started: Instant::now(),
+            tracks: tracks
+                .into_iter()
+                .map(|(clock_rate, codec)| {
+                    let clock = RtpClock {
+                        rate: clock_rate.max(1) as u64,
+                        offset: 0,
+                        last: None,
+                        ticks: 0,
+                    };
+                    (clock, Depacketizer::new(&codec))
+                })
+                .collect(),
+        }
+    }
+
+    /// Tags completed by an RTP packet of `track`
+    pub(crate) fn receive(&mut self, track: usize, data: Bytes) -> Vec<FlvTag> {
+        let (Some((clock, depacketizer)), Some(packet)) = (self.tracks.get_mut(track), RtpPacket::parse(data)) else {
+            return Vec::new();
+        };
+        depacketizer.push(packet, clock, self.started)
+    }
+}
+
+enum Depacketizer {
+    H264 {
+        packer: AvcPacker,
+        /// SPS/PPS from the SDP, sent ahead of the first access unit
+        parameter_sets: Vec<Bytes>,
+        /// NAL units of the access unit being received
+        nals: Vec<Bytes>,
+        timestamp: Option<u32>,
+        /// FU-A fragments of the NAL unit being received
+        fragment: Option<BytesMut>,
+        next_seq: Option<u16>,
+    },
+    Aac {
+        config: Bytes,
+        size_length: u32,
+        index_length: u32,
+        config_sent: bool,
+    },
+    G711 {
+        sound_format: u8,
+    },
+    Opus {
+        channels: u8,
+        head_sent: bool,
+    },
+}
+
+impl Depacketizer {
+    fn new(codec: &Codec) -> Self {
+        match codec.clone() {
+            Codec::H264 { parameter_sets } => Depacketizer::H264 {
+                packer: AvcPacker::default(),
+                parameter_sets,
+                nals: Vec::new(),
+                timestamp: None,
+                fragment: None,
+                next_seq: None,
+            },
+            Codec::Aac {
+                config,
+                size_length,
+                index_length,
+            } => Depacketizer::Aac {
+                config,
+                size_length,
+                index_length,
+                config_sent: false,
+            },
+            Codec::G711 { sound_format } => Depacketizer::G711 { sound_format },
+            Codec::Opus { channels } => Depacketizer::Opus {
+                channels,
+                head_sent: false,
+            },
+        }
+    }
+
+    fn push(&mut self, packet: RtpPacket, clock: &mut RtpClock, started: Instant) -> Vec<FlvTag> {
+        match self {
+            Depacketizer::H264 {
+                packer,
+                parameter_sets,
+                nals,
+                timestamp,
+                fragment,
+                next_seq,
+            } => {
+                let mut units = Vec::new();
+                if *timestamp != Some(packet.timestamp) && !nals.is_empty() {
+                    units.push((timestamp.unwrap_or(packet.timestamp), std::mem::take(nals)));
+                }
+                if next_seq.is_some_and(|expected| expected != packet.seq) {
+                    // A lost fragment spoils the whole NAL unit
+                    *fragment = None;
+                }
+                *next_seq = Some(packet.seq.wrapping_add(1));
+                *timestamp = Some(packet.timestamp);
+                depacketize_h264(packet.payload, nals, fragment);
+                if packet.marker && !nals.is_empty() {
+                    units.push((packet.timestamp, std::mem::take(nals)));
+                }
+
+                let mut tags = Vec::new();
+                for (rtp_timestamp, unit) in units {
+                    // In-band parameter sets come later in the unit and win
+                    let unit = std::mem::take(parameter_sets).into_iter().chain(unit).collect();
+                    tags.extend(packer.pack(clock.ms(rtp_timestamp, started), 0, unit));
+                }
+                tags
+            }
+            Depacketizer::Aac {
+                config,
+                size_length,
+                index_length,
+                config_sent,
+            } => {
+                let timestamp = clock.ms(packet.timestamp, started);
+                let mut tags = Vec::new();
+                if !*config_sent {
+                    *config_sent = true;
+                    let mut data = BytesMut::with_capacity(2 + config.len());
+                    data.put_slice(&[0xaf, 0x00]);
+                    data.put_slice(config);
+                    tags.push(FlvTag::new(TagType::Audio, timestamp, data.freeze()));
+                }
+                let frames = aac_frames(packet.payload, *size_length, *index_length);
+                for (i, frame) in frames.into_iter().enumerate() {
+                    let offset = (i as u64 * 1024 * 1000 / clock.rate) as u32;
+                    let mut data = BytesMut::with_capacity(2 + frame.len());
+                    data.put_slice(&[0xaf, 0x01]);
+                    data.put_slice(&frame);
+                    tags.push(FlvTag::new(TagType::Audio, timestamp + offset, data.freeze()));
+                }
+                tags
+            }
+            Depacketizer::G711 { sound_format } => {
+                let mut data = BytesMut::with_capacity(1 + packet.payload.len());
+                data.put_u8(*sound_format);
+                data.put_slice(&packet.payload);
+                vec![FlvTag::new(TagType::Audio, clock.ms(packet.timestamp, started), data.freeze())]
+            }
+            // One Opus packet per RTP packet (RFC 7587)
+            Depacketizer::Opus { channels, head_sent } => {
+                let timestamp = clock.ms(packet.timestamp, started);
+                let mut tags = Vec::new();
+                if !*head_sent {
+                    *head_sent = true;
+                    tags.push(opus_tag(timestamp, true, &opus_head(*channels)));
+                }
+                if !packet.payload.is_empty() {
+                    tags.push(opus_tag(timestamp, false, &packet.payload));
+                }
+                tags
+            }
+        }
+    }
+}
+
+/// Collect the NAL units of one H.264 RTP payload (RFC 6184)
+fn depacketize_h264(mut payload: Bytes, nals: &mut Vec<Bytes>, fragment: &mut Option<BytesMut>) {
+    let Some(&indicator) = payload.first() else {
+        return;
+    };
+    match indicator & 0x1f {
+        1..=23 => nals.push(payload),
+        // STAP-A: 16-bit size before every NAL unit
+        24 => {
+            payload.advance(1);
+            while payload.len() >= 2 {
+                let size = payload.get_u16() as usize;
+                if size > payload.len() {
+                    break;
+                }
+                nals.push(payload.split_to(size));
+            }
+        }
+        // FU-A: a NAL unit split over several packets
+        28 if payload.len() >= 2 => {
+            let header = payload[1];
+            if header & 0x80 != 0 {
+                let mut nal = BytesMut::with_capacity(payload.len() * 4);
+                nal.put_u8((indicator & 0xe0) | (header & 0x1f));
+                *fragment = Some(nal);
+            }
+            if let Some(nal) = fragment.as_mut() {
+                nal.put_slice(&payload[2..]);
+            }
+            if header & 0x40 != 0 {
+                if let Some(nal) = fragment.take() {
+                    nals.push(nal.freeze());
+                }
+            }
+        }
+        packet_type => debug!("Unsupported H.264 RTP packet type {}", packet_type),
+    }
+}
+
+/// Access units of an AAC payload in RFC 3640 `AAC-hbr`/`AAC-lbr` mode
+fn aac_frames(mut payload: Bytes, size_length: u32, index_length: u32) -> Vec<Bytes> {
+    if payload.len() < 2 || size_length == 0 {
+        return Vec::new();
+    }
+    let header_bits = payload.get_u16() as usize;
+    let header_bytes = header_bits.div_ceil(8);
+    if header_bytes > payload.len() {
+        return Vec::new();
+    }
+    let headers = payload.split_to(header_bytes);
+    let au_bits = (size_length + index_length) as usize;
+
+    let mut frames = Vec::new();
+    let mut bit = 0;
+    while bit + au_bits <= header_bits {
+        let mut size = 0usize;
+        for i in bit..bit + size_length as usize {
+            size = (size << 1) | ((headers[i / 8] >> (7 - i % 8)) & 1) as usize;
+        }
+        bit += au_bits;
+        if size > payload.len() {
+            break;
+        }
+        frames.push(payload.split_to(size));
+    }
+    frames
+}
@@ -0,0 +1,1006 @@
+//! RTSP server output
+//!
+//! Every stream in the registry can be played at
+//! `rtsp://host:8554/{app}/{stream}`. DESCRIBE answers with an SDP built
+//! from the cached sequence headers (H.264, AAC or Opus). Each track is then set
+//! up with RTP interleaved on the RTSP connection or sent to the client's
+//! UDP ports. H.264 is packetized per RFC 6184 as single NAL units and
+//! FU-A fragments, with SPS/PPS repeated in front of every keyframe; AAC
+//! is sent one frame per packet with an RFC 3640 AU header, Opus (from
+//! Enhanced RTMP tags) per RFC 7587. Apps with a play token want it as
+//! `?token=` on the URL.
+
+use crate::config::{self, Access};
+use crate::error::{Error, Result};
+use crate::flv::{AudioCodec, FlvTag, PacketType, VideoCodec, OPUS_FOURCC};
+use crate::rtsp::{bind_udp_pair, AbortOnDrop};
+use crate::stream::{StreamManager, Subscription};
+use crate::timestamp::TimestampRebaser;
+use base64::Engine;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// How long DESCRIBE waits for a starting stream's sequence headers
+const DESCRIBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sessions over UDP without a request for this long are closed
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest RTP payload, to stay below common MTUs
+const MAX_PAYLOAD: usize = 1400;
+
+/// Largest request header accepted
+const MAX_HEADER_SIZE: usize = 64 * 1024;
+
+/// Responses and interleaved packets queued for the connection writer
+const WRITE_QUEUE: usize = 512;
+
+/// Dynamic RTP payload types
+const PT_H264: u8 = 96;
+const PT_AAC: u8 = 97;
+const PT_OPUS: u8 = 98;
+
+/// RTP clock of video tracks
+const VIDEO_CLOCK: u32 = 90_000;
+
+/// RTP clock of Opus, whatever the input rate (RFC 7587)
+const OPUS_CLOCK: u32 = 48_000;
+
+/// Largest AAC frame an AU header with a 13-bit size can describe
+const MAX_AAC_FRAME: usize = 0x1fff;
+
+/// H.264 NAL unit types
+const NAL_IDR: u8 = 5;
+const NAL_SPS: u8 = 7;
+const NAL_PPS: u8 = 8;
+const NAL_AUD: u8 = 9;
+const NAL_FU_A: u8 = 28;
+
+/// AAC sampling frequencies by AudioSpecificConfig index
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+const SERVER: &str = concat!("rtmp-streaming-server/", env!("CARGO_PKG_VERSION"));
+
+/// RTSP server
+pub struct RtspServer {
+    /// Address to bind to
+    address: SocketAddr,
+    /// Stream manager
+    stream_manager: StreamManager,
+}
+
+impl RtspServer {
+    /// Create a new RTSP server
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            stream_manager: StreamManager::new(),
+        }
+    }
+
+    /// Share a stream manager with other servers (e.g. RTMP)
+    pub fn with_stream_manager(mut self, stream_manager: StreamManager) -> Self {
+        self.stream_manager = stream_manager;
+        self
+    }
+
+    /// Run the server
+    pub async fn run(self) -> Result<()> {
+        info!("Starting RTSP server on {}", self.address);
+
+        let listener = TcpListener::bind(self.address).await?;
+        self.serve(listener).await
+    }
+
+    /// Serve clients on an already bound listener
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        info!("RTSP listening on {}", listener.local_addr()?);
+
+        loop {
+            let (socket, peer) = listener.accept().await?;
+            let Ok(guard) = self.stream_manager.limits().connect(peer.ip()) else {
+                continue;
+            };
+            let streams = self.stream_manager.clone();
+            tokio::spawn(async move {
+                let _guard = guard;
+                if let Err(e) = handle_connection(streams, socket, peer).await {
+                    debug!("RTSP client {}: {}", peer, e);
+                }
+                debug!("RTSP client {} disconnected", peer);
+            });
+        }
+    }
+}
+
+async fn handle_connection(streams: StreamManager, socket: TcpStream, peer: SocketAddr) -> Result<()> {
+    socket.set_nodelay(true)?;
+    let local = socket.local_addr()?;
+    let (mut reader, mut writer) = socket.into_split();
+
+    // Responses and interleaved RTP share the socket
+    let (tx, mut rx) = mpsc::channel::<Bytes>(WRITE_QUEUE);
+    let _writer = AbortOnDrop(vec![tokio::spawn(async move {
+        while let Some(data) = rx.recv().await {
+            if writer.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+    })]);
+
+    let mut client = Client {
+        streams,
+        peer,
+        local,
+        writer: tx,
+        session: None,
+    };
+    let mut buf = BytesMut::with_capacity(4096);
+    loop {
+        while let Some(request) = parse_request(&mut buf)? {
+            let response = client.handle(&request).await;
+            client
+                .writer
+                .send(response)
+                .await
+                .map_err(|_| Error::Network("RTSP connection closed".to_string()))?;
+        }
+        // Interleaved sessions end with the connection; UDP ones need keepalives
+        let timeout = match &client.session {
+            Some(session) if session.udp() => SESSION_TIMEOUT,
+            _ => Duration::MAX,
+        };
+        match tokio::time::timeout(timeout, reader.read_buf(&mut buf)).await {
+            Ok(Ok(0)) => return Ok(()),
+            Ok(Ok(_)) => {}
+            Ok(Err(e)) => return Err(e.into()),
+            Err(_) => return Err(Error::Network("RTSP session timed out".to_string())),
+        }
+    }
+}
+
+/// An RTSP request from a client
+struct Request {
+    method: String,
+    uri: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Next request in `buf`; interleaved frames from the client (RTCP
+/// receiver reports) are skipped
+fn parse_request(buf: &mut BytesMut) -> Result<Option<Request>> {
+    loop {
+        if buf.first() == Some(&b'$') {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+            if buf.len() < 4 + len {
+                return Ok(None);
+            }
+            buf.advance(4 + len);
+            continue;
+        }
+
+        let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+            if buf.len() > MAX_HEADER_SIZE {
+                return Err(Error::Protocol("RTSP header too large".to_string()));
+            }
+            return Ok(None);
+        };
+        let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+        let mut lines = head.split("\r\n");
+        let request_line = lines.next().unwrap_or_default();
+        let headers: Vec<(String, String)> = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+            .collect();
+        let content_length = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("Content-Length"))
+            .and_then(|(_, value)| value.parse::<usize>().ok())
+            .unwrap_or(0);
+        if buf.len() < end + 4 + content_length {
+            return Ok(None);
+        }
+        buf.advance(end + 4 + content_length);
+
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(uri), Some("RTSP/1.0")) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(Error::Protocol(format!("Invalid RTSP request line: {}", request_line)));
+        };
+        return Ok(Some(Request {
+            method: method.to_string(),
+            uri: uri.to_string(),
+            headers,
+        }));
+    }
+}
+
+/// Stream and track addressed by a request URI
+struct Target {
+    app: String,
+    name: String,
+    track: Option<usize>,
+    /// `?token=` of the URI
+    token: Option<String>,
+}
+
+impl Target {
+    /// `rtsp://host:port/{app}/{stream}[/trackID={n}][?token=...]`
+    fn parse(uri: &str) -> Option<Self> {
+        let path = match uri.strip_prefix("rtsp://") {
+            Some(rest) => &rest[rest.find('/')?..],
+            None => uri,
+        };
+        let (path, token) = match path.split_once('?') {
+            Some((path, query)) => (path, config::token(query).map(str::to_string)),
+            None => (path, None),
+        };
+        let mut segments = path.split('/').filter(|segment| !segment.is_empty());
+        let app = segments.next()?.to_string();
+        let name = segments.next()?.to_string();
+        let track = match segments.next() {
+            Some(control) => Some(control.strip_prefix("trackID=")?.parse().ok()?),
+            None => None,
+        };
+        if segments.next().is_some() {
+            return None;
+        }
+        Some(Self { app, name, track, token })
+    }
+}
+
+/// A described track
+#[derive(Clone)]
+pub(crate) enum Media {
+    H264 {
+        sps: Bytes,
+        pps: Bytes,
+        /// Size of the NAL unit length prefix in the FLV payload
+        length_size: usize,
+    },
+    Aac {
+        config: Bytes,
+        sample_rate: u32,
+        channels: u8,
+    },
+    Opus {
+        channels: u8,
+    },
+}
+
+impl Media {
+    /// Parse an AVCDecoderConfigurationRecord from an FLV sequence header
+    pub(crate) fn h264(tag: &FlvTag) -> Option<Self> {
+        if tag.video_codec() != Some(VideoCodec::Avc) || tag.packet_type() != Some(PacketType::SequenceHeader) {
+            return None;
+        }
+        // Five bytes of FLV video header, then at least seven of the record
+        if tag.data.len() < 5 + 7 {
+            return None;
+        }
+        let mut record = tag.data.slice(5..);
+        let length_size = (record[4] & 0x03) as usize + 1;
+        let sps_count = record[5] & 0x1f;
+        record.advance(6);
+        let mut sps = None;
+        for _ in 0..sps_count {
+            let nal = length_prefixed(&mut record)?;
+            sps.get_or_insert(nal);
+        }
+        let pps_count = *record.first()?;
+        record.advance(1);
+        let mut pps = None;
+        for _ in 0..pps_count {
+            let nal = length_prefixed(&mut record)?;
+            pps.get_or_insert(nal);
+        }
+        Some(Media::H264 {
+            sps: sps?,
+            pps: pps?,
+            length_size,
+        })
+    }
+
+    /// Parse an AudioSpecificConfig from an FLV sequence header
+    fn aac(tag: &FlvTag) -> Option<Self> {
+        if tag.audio_codec() != Some(AudioCodec::Aac) || tag.packet_type() != Some(PacketType::SequenceHeader) {
+            return None;
+        }
+        let config = tag.data.slice(2..);
+        if config.len() < 2 {
+            return None;
+        }
+        let index = ((config[0] & 0x07) << 1 | config[1] >> 7) as usize;
+        let sample_rate = match (index, config.get(2..5)) {
+            (0x0f, Some(rate)) => {
+                // 24-bit explicit frequency after the index
+                let bits = u32::from_be_bytes([config[1], rate[0], rate[1], rate[2]]);
+                (bits >> 7) & 0xff_ffff
+            }
+            _ => *AAC_SAMPLE_RATES.get(index)?,
+        };
+        let channels = match index {
+            0x0f => (config.get(4)? >> 3) & 0x0f,
+            _ => (config[1] >> 3) & 0x0f,
+        };
+        Some(Media::Aac {
+            config,
+            sample_rate,
+            channels: channels.max(1),
+        })
+    }
+
+    /// Parse the `OpusHead` of an Enhanced RTMP sequence start
+    fn opus(tag: &FlvTag) -> Option<Self> {
+        if tag.audio_fourcc() != Some(&OPUS_FOURCC[..]) || tag.packet_type() != Some(PacketType::SequenceHeader) {
+            return None;
+        }
+        Some(Media::Opus {
+            channels: tag.data.get(14).copied().unwrap_or(2).clamp(1, 2),
+        })
+    }
+
+    /// Audio track of a sequence header
+    pub(crate) fn audio(tag: &FlvTag) -> Option<Self> {
+        Self::aac(tag).or_else(|| Self::opus(tag))
+    }
+
+    pub(crate) fn clock_rate(&self) -> u32 {
+        match self {
+            Media::H264 { .. } => VIDEO_CLOCK,
+            Media::Aac { sample_rate, .. } => *sample_rate,
+            Media::Opus { .. } => OPUS_CLOCK,
+        }
+    }
+
+    fn payload_type(&self) -> u8 {
+        match self {
+            Media::H264 { .. } => PT_H264,
+            Media::Aac { .. } => PT_AAC,
+            Media::Opus { .. } => PT_OPUS,
+        }
+    }
+
+    /// `a=rtpmap` encoding and `a=fmtp` parameters
+    pub(crate) fn format(&self) -> (String, String) {
+        match self {
+            Media::H264 { sps, pps, .. } => (
+                format!("H264/{}", VIDEO_CLOCK),
+                format!(
+                    "packetization-mode=1;profile-level-id={};sprop-parameter-sets={},{}",
+                    sps.get(1..4).map(hex).unwrap_or_default(),
+                    base64::engine::general_purpose::STANDARD.encode(sps),
+                    base64::engine::general_purpose::STANDARD.encode(pps)
+                ),
+            ),
+            Media::Aac {
+                config,
+                sample_rate,
+                channels,
+            } => (
+                format!("MPEG4-GENERIC/{}/{}", sample_rate, channels),
+                format!(
+                    "streamtype=5;profile-level-id=1;mode=AAC-hbr;sizelength=13;indexlength=3;indexdeltalength=3;config={}",
+                    hex(config)
+                ),
+            ),
+            Media::Opus { channels } => (
+                format!("opus/{}/2", OPUS_CLOCK),
+                format!("sprop-stereo={}", u8::from(*channels == 2)),
+            ),
+        }
+    }
+
+    /// SDP media description of track `index`
+    fn sdp(&self, index: usize) -> String {
+        let pt = self.payload_type();
+        let media = match self {
+            Media::H264 { .. } => "video",
+            Media::Aac { .. } | Media::Opus { .. } => "audio",
+        };
+        let (rtpmap, fmtp) = self.format();
+        format!(
+            "m={} 0 RTP/AVP {}\r\na=rtpmap:{} {}\r\na=fmtp:{} {}\r\na=control:trackID={}\r\n",
+            media, pt, pt, rtpmap, pt, fmtp, index
+        )
+    }
+}
+
+fn length_prefixed(data: &mut Bytes) -> Option<Bytes> {
+    if data.len() < 2 {
+        return None;
+    }
+    let len = data.get_u16() as usize;
+    (data.len() >= len).then(|| data.split_to(len))
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Tracks of a stream from its cached sequence headers, waiting a moment
+/// for a stream that is just starting. `None` if the stream is not
+/// published or has no H.264, AAC or Opus to offer.
+pub(crate) async fn describe(streams: &StreamManager, app: &str, name: &str) -> Option<Vec<Media>> {
+    let stream = streams.get_or_pull(app, name).await;
+    let deadline = tokio::time::Instant::now() + DESCRIBE_TIMEOUT;
+    loop {
+        if !stream.is_published() {
+            return None;
+        }
+        let data = stream.data().await;
+        let video = data.video_seq.as_ref().and_then(Media::h264);
+        let audio = data.audio_seq.as_ref().and_then(Media::audio);
+        if video.is_some() || tokio::time::Instant::now() >= deadline {
+            let media: Vec<Media> = video.into_iter().chain(audio).collect();
+            return (!media.is_empty()).then_some(media);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Where the RTP of a track goes
+enum Output {
+    Interleaved {
+        channel: u8,
+    },
+    Udp {
+        socket: Arc<UdpSocket>,
+        /// Held so the advertised RTCP port stays ours
+        _rtcp: UdpSocket,
+        peer: SocketAddr,
+    },
+}
+
+/// State between SETUP and TEARDOWN
+struct Session {
+    id: String,
+    app: String,
+    name: String,
+    media: Vec<Media>,
+    outputs: Vec<Option<Arc<Output>>>,
+    playing: Option<AbortOnDrop>,
+}
+
+impl Session {
+    fn udp(&self) -> bool {
+        self.outputs
+            .iter()
+            .flatten()
+            .any(|output| matches!(output.as_ref(), Output::Udp { .. }))
+    }
+}
+
+/// One RTSP control connection
+struct Client {
+    streams: StreamManager,
+    peer: SocketAddr,
+    local: SocketAddr,
+    writer: mpsc::Sender<Bytes>,
+    session: Option<Session>,
+}
+
+/// Response under construction
+struct Reply {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Reply {
+    fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+impl Client {
+    async fn handle(&mut self, request: &Request) -> Bytes {
+        debug!("RTSP {} {} from {}", request.method, request.uri, self.peer);
+        let reply = match request.method.as_str() {
+            "OPTIONS" => Reply::new(200).header(
+                "Public",
+                "OPTIONS, DESCRIBE, SETUP, PLAY, PAUSE, TEARDOWN, GET_PARAMETER, SET_PARAMETER",
+            ),
+            "DESCRIBE" => self.describe(request).await,
+            "SETUP" => self.setup(request).await,
+            "PLAY" => self.play(request).await,
+            "PAUSE" => self.with_session(request, |session| {
+                session.playing = None;
+                Reply::new(200)
+            }),
+            "TEARDOWN" => {
+                let reply = self.with_session(request, |_| Reply::new(200));
+                if reply.status == 200 {
+                    if let Some(session) = self.session.take() {
+                        info!("RTSP client {} stopped playing '{}'", self.peer, session.name);
+                    }
+                }
+                reply
+            }
+            "GET_PARAMETER" | "SET_PARAMETER" => Reply::new(200),
+            _ => Reply::new(501),
+        };
+
+        let mut response = format!(
+            "RTSP/1.0 {} {}\r\nCSeq: {}\r\nServer: {}\r\n",
+            reply.status,
+            reason(reply.status),
+            request.header("CSeq").unwrap_or("0"),
+            SERVER
+        );
+        if let Some(session) = &self.session {
+            response.push_str(&format!("Session: {};timeout={}\r\n", session.id, SESSION_TIMEOUT.as_secs()));
+        }
+        for (name, value) in &reply.headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !reply.body.is_empty() {
+            response.push_str(&format!("Content-Length: {}\r\n", reply.body.len()));
+        }
+        response.push_str("\r\n");
+        response.push_str(&reply.body);
+        Bytes::from(response)
+    }
+
+    /// Run `f` on the session named by the request
+    fn with_session(&mut self, request: &Request, f: impl FnOnce(&mut Session) -> Reply) -> Reply {
+        match &mut self.session {
+            Some(session) if session_id(request) == Some(session.id.as_str()) => f(session),
+            _ => Reply::new(454),
+        }
+    }
+
+    async fn describe(&mut self, request: &Request) -> Reply {
+        let Some(target) = Target::parse(&request.uri).filter(|target| target.track.is_none()) else {
+            return Reply::new(404);
+        };
+        if self.streams.authorize(&target.app, Access::Play, target.token.as_deref()).is_err() {
+            return Reply::new(403);
+        }
+        let Some(media) = describe(&self.streams, &target.app, &target.name).await else {
+            return Reply::new(404);
+        };
+
+        let ip = match self.local.ip() {
+            ip @ std::net::IpAddr::V4(_) => format!("IN IP4 {}", ip),
+            ip => format!("IN IP6 {}", ip),
+        };
+        let mut sdp = format!(
+            "v=0\r\no=- {} 1 {}\r\ns={}\r\nc={}\r\nt=0 0\r\na=tool:{}\r\na=range:npt=now-\r\na=control:*\r\n",
+            rand::random::<u32>(),
+            ip,
+            target.name,
+            ip,
+            SERVER
+        );
+        for (index, media) in media.iter().enumerate() {
+            sdp.push_str(&media.sdp(index));
+        }
+        let mut reply = Reply::new(200)
+            .header("Content-Base", format!("{}/", request.uri.trim_end_matches('/')))
+            .header("Content-Type", "application/sdp");
+        reply.body = sdp;
+        reply
+    }
+
+    async fn setup(&mut self, request: &Request) -> Reply {
+        let Some(target) = Target::parse(&request.uri) else {
+            return Reply::new(404);
+        };
+        let track = target.track.unwrap_or(0);
+
+        // Tracks of the existing session, or of a new one
+        let media = match &self.session {
+            Some(session) => {
+                if session_id(request) != Some(&session.id) {
+                    return Reply::new(454);
+                }
+                if (session.app.as_str(), session.name.as_str()) != (target.app.as_str(), target.name.as_str()) {
+                    return Reply::new(459);
+                }
+                if session.playing.is_some() {
+                    return Reply::new(455);
+                }
+                None
+            }
+            None if self.streams.authorize(&target.app, Access::Play, target.token.as_deref()).is_err() => {
+                return Reply::new(403);
+            }
+            None => match describe(&self.streams, &target.app, &target.name).await {
+                Some(media) => Some(media),
+                None => return Reply::new(404),
+            },
+        };
+        let tracks = match (&media, &self.session) {
+            (Some(media), _) => media.len(),
+            (None, Some(session)) => session.media.len(),
+            (None, None) => 0,
+        };
+        if track >= tracks {
+            return Reply::new(404);
+        }
+
+        let Some((output, transport)) = self.transport(request, track).await else {
+            return Reply::new(461);
+        };
+        if let Some(media) = media {
+            self.session = Some(Session {
+                id: format!("{:016x}", rand::random::<u64>()),
+                outputs: vec![None; media.len()],
+                app: target.app,
+                name: target.name,
+                media,
+                playing: None,
+            });
+        }
+        let session = self.session.as_mut().expect("session exists after SETUP");
+        session.outputs[track] = Some(Arc::new(output));
+        Reply::new(200).header("Transport", transport)
+    }
+
+    /// Output for the first transport of a SETUP request we support, with
+    /// the `Transport` header of the reply
+    async fn transport(&self, request: &Request, track: usize) -> Option<(Output, String)> {
+        let transport = request.header("Transport").unwrap_or_default();
+        // Clients may list alternatives
+        for option in transport.split(',') {
+            let params: Vec<&str> = option.split(';').map(str::trim).collect();
+            let param = |name: &str| params.iter().find_map(|param| param.strip_prefix(name));
+            if params.contains(&"multicast") {
+                continue;
+            }
+            match params.first().copied() {
+                Some("RTP/AVP/TCP") => {
+                    let channel = param("interleaved=")
+                        .and_then(|range| range.split('-').next()?.parse::<u8>().ok())
+                        .unwrap_or((2 * track) as u8);
+                    let reply = format!("RTP/AVP/TCP;unicast;interleaved={}-{}", channel, channel.wrapping_add(1));
+                    return Some((Output::Interleaved { channel }, reply));
+                }
+                Some("RTP/AVP" | "RTP/AVP/UDP") => {
+                    let Some(port) = param("client_port=").and_then(|range| range.split('-').next()?.parse::<u16>().ok())
+                    else {
+                        continue;
+                    };
+                    let (rtp, rtcp) = match bind_udp_pair().await {
+                        Ok(pair) => pair,
+                        Err(e) => {
+                            warn!("RTSP: no UDP ports for {}: {}", self.peer, e);
+                            return None;
+                        }
+                    };
+                    let server_port = rtp.local_addr().map(|addr| addr.port()).unwrap_or_default();
+                    let reply = format!(
+                        "RTP/AVP;unicast;client_port={}-{};server_port={}-{}",
+                        port,
+                        port.wrapping_add(1),
+                        server_port,
+                        server_port.wrapping_add(1)
+                    );
+                    let output = Output::Udp {
+                        socket: Arc::new(rtp),
+                        _rtcp: rtcp,
+                        peer: SocketAddr::new(self.peer.ip(), port),
+                    };
+                    return Some((output, reply));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    async fn play(&mut self, request: &Request) -> Reply {
+        let Some(session) = &mut self.session else {
+            return Reply::new(455);
+        };
+        if session_id(request) != Some(&session.id) {
+            return Reply::new(454);
+        }
+        if session.playing.is_some() {
+            return Reply::new(200).header("Range", "npt=now-");
+        }
+
+        let base = request.uri.trim_end_matches('/').to_string();
+        let mut packetizers = Vec::new();
+        let mut outputs = Vec::new();
+        let mut rtp_info = Vec::new();
+        for (index, (media, output)) in session.media.iter().zip(&session.outputs).enumerate() {
+            let Some(output) = output else {
+                continue;
+            };
+            let packetizer = Packetizer::new(media.clone(), media.payload_type());
+            rtp_info.push(format!("url={}/trackID={};seq={};rtptime={}", base, index, packetizer.seq, packetizer.base));
+            packetizers.push(packetizer);
+            outputs.push(output.clone());
+        }
+        if packetizers.is_empty() {
+            return Reply::new(455);
+        }
+
+        let Some(stream) = self.streams.get_stream(&session.app, &session.name).await else {
+            return Reply::new(404);
+        };
+        if self.streams.admit_subscriber(&session.app, &stream).await.is_err() {
+            return Reply::new(453);
+        }
+        let subscriber_id = format!("rtsp-{}", uuid::Uuid::new_v4().simple());
+        let subscription = stream
+            .subscribe(subscriber_id)
+            .await
+            .with_lag_policy(self.streams.config().app(&session.app).lag_policy());
+        info!(
+            "RTSP client {} playing '{}' (subscribers: {})",
+            self.peer,
+            session.name,
+            stream.subscriber_count()
+        );
+        drop(stream);
+
+        let label = format!("{}/{}", session.name, subscription.id());
+        let source = RtpSource::new(packetizers, label.clone());
+        let writer = self.writer.clone();
+        session.playing = Some(AbortOnDrop(vec![tokio::spawn(async move {
+            if let Err(e) = send_rtp(subscription, source, outputs, writer).await {
+                debug!("RTSP playback {} ended: {}", label, e);
+            }
+        })]));
+        Reply::new(200)
+            .header("Range", "npt=now-")
+            .header("RTP-Info", rtp_info.join(","))
+    }
+}
+
+/// Forward the stream to the session's tracks
+async fn send_rtp(
+    mut subscription: Subscription,
+    mut source: RtpSource,
+    outputs: Vec<Arc<Output>>,
+    writer: mpsc::Sender<Bytes>,
+) -> Result<()> {
+    while let Some(tag) = subscription.recv().await? {
+        for (track, packet) in source.packetize(&tag) {
+            match outputs[track].as_ref() {
+                Output::Interleaved { channel } => {
+                    let mut frame = BytesMut::with_capacity(4 + packet.len());
+                    frame.put_u8(b'$');
+                    frame.put_u8(*channel);
+                    frame.put_u16(packet.len() as u16);
+                    frame.put_slice(&packet);
+                    writer
+                        .send(frame.freeze())
+                        .await
+                        .map_err(|_| Error::Network("RTSP connection closed".to_string()))?;
+                }
+                Output::Udp { socket, peer, .. } => {
+                    socket.send_to(&packet, peer).await?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// RTP packets of a subscriber's tracks
+///
+/// Video starts at a keyframe and audio waits for it too, so both begin
+/// together; timestamps are rebased to start at 0.
+pub(crate) struct RtpSource {
+    packetizers: Vec<Packetizer>,
+    started: bool,
+    rebaser: TimestampRebaser,
+}
+
+impl RtpSource {
+    pub(crate) fn new(packetizers: Vec<Packetizer>, label: String) -> Self {
+        let has_video = packetizers.iter().any(|packetizer| matches!(packetizer.media, Media::H264 { .. }));
+        Self {
+            packetizers,
+            started: !has_video,
+            rebaser: TimestampRebaser::new(label),
+        }
+    }
+
+    /// Packets carrying `tag`, with the index of their track
+    pub(crate) fn packetize(&mut self, tag: &FlvTag) -> Vec<(usize, Bytes)> {
+        if tag.is_sequence_header() {
+            for packetizer in &mut self.packetizers {
+                packetizer.update(tag);
+            }
+            return Vec::new();
+        }
+        if !self.started {
+            if !(tag.is_video() && tag.is_keyframe()) {
+                return Vec::new();
+            }
+            self.started = true;
+        }
+        let timestamp = self.rebaser.rebase(tag.timestamp);
+        let mut packets = Vec::new();
+        for (track, packetizer) in self.packetizers.iter_mut().enumerate() {
+            packets.extend(packetizer.packetize(tag, timestamp).into_iter().map(|packet| (track, packet)));
+        }
+        packets
+    }
+}
+
+/// RTP packetizer of one track
+pub(crate) struct Packetizer {
+    media: Media,
+    payload_type: u8,
+    pub(crate) ssrc: u32,
+    /// Sequence number of the next packet
+    pub(crate) seq: u16,
+    /// RTP timestamp of stream time 0
+    pub(crate) base: u32,
+}
+
+impl Packetizer {
+    pub(crate) fn new(media: Media, payload_type: u8) -> Self {
+        Self {
+            media,
+            payload_type,
+            ssrc: rand::random(),
+            seq: rand::random(),
+            base: rand::random(),
+        }
+    }
+
+    /// Follow a new sequence header of this track's codec
+    fn update(&mut self, tag: &FlvTag) {
+        let media = match self.media {
+            Media::H264 { .. } => Media::h264(tag),
+            Media::Aac { .. } | Media::Opus { .. } => Media::audio(tag),
+        };
+        if let Some(media) = media {
+            self.media = media;
+        }
+    }
+
+    /// RTP packets carrying `tag`, if it belongs to this track
+    fn packetize(&mut self, tag: &FlvTag, timestamp: u32) -> Vec<Bytes> {
+        if tag.packet_type() != Some(PacketType::Data) {
+            return Vec::new();
+        }
+        match &self.media {
+            Media::H264 { sps, pps, length_size } if tag.video_codec() == Some(VideoCodec::Avc) => {
+                if tag.data.len() < 5 {
+                    return Vec::new();
+                }
+                // Signed 24-bit composition time offset
+                let composition = (i32::from_be_bytes([0, tag.data[2], tag.data[3], tag.data[4]]) << 8) >> 8;
+                let pts = (timestamp as i64 + composition as i64).max(0) as u64;
+                let rtp_time = self.rtp_time(pts);
+
+                let mut nals = Vec::new();
+                let mut payload = tag.data.slice(5..);
+                while payload.len() >= *length_size {
+                    let len = payload[..*length_size].iter().fold(0usize, |len, byte| len << 8 | *byte as usize);
+                    payload.advance(*length_size);
+                    if len == 0 || len > payload.len() {
+                        break;
+                    }
+                    nals.push(payload.split_to(len));
+                }
+                let keyframe = nals.iter().any(|nal| nal[0] & 0x1f == NAL_IDR);
+                let mut units = Vec::new();
+                if keyframe {
+                    units.push(sps.clone());
+                    units.push(pps.clone());
+                }
+                // Parameter sets of a keyframe are already in front of it
+                units.extend(nals.into_iter().filter(|nal| match nal[0] & 0x1f {
+                    NAL_AUD => false,
+                    NAL_SPS | NAL_PPS => !keyframe,
+                    _ => true,
+                }));
+
+                let mut packets = Vec::new();
+                let count = units.len();
+                for (i, nal) in units.into_iter().enumerate() {
+                    let last = i + 1 == count;
+                    if nal.len() <= MAX_PAYLOAD {
+                        packets.push(self.packet(rtp_time, last, &nal));
+                        continue;
+                    }
+                    let indicator = (nal[0] & 0xe0) | NAL_FU_A;
+                    let nal_type = nal[0] & 0x1f;
+                    let chunks: Vec<&[u8]> = nal[1..].chunks(MAX_PAYLOAD - 2).collect();
+                    for (j, chunk) in chunks.iter().enumerate() {
+                        let end = j + 1 == chunks.len();
+                        let header = nal_type | if j == 0 { 0x80 } else { 0 } | if end { 0x40 } else { 0 };
+                        let mut fragment = Vec::with_capacity(2 + chunk.len());
+                        fragment.extend_from_slice(&[indicator, header]);
+                        fragment.extend_from_slice(chunk);
+                        packets.push(self.packet(rtp_time, last && end, &fragment));
+                    }
+                }
+                packets
+            }
+            Media::Aac { .. } if tag.audio_codec() == Some(AudioCodec::Aac) => {
+                let frame = tag.data.slice(2..);
+                if frame.is_empty() || frame.len() > MAX_AAC_FRAME {
+                    return Vec::new();
+                }
+                let rtp_time = self.rtp_time(timestamp as u64);
+                // AU-headers-length in bits, then one 13-bit size / 3-bit index header
+                let mut payload = Vec::with_capacity(4 + frame.len());
+                payload.extend_from_slice(&16u16.to_be_bytes());
+                payload.extend_from_slice(&((frame.len() as u16) << 3).to_be_bytes());
+                payload.extend_from_slice(&frame);
+                vec![self.packet(rtp_time, true, &payload)]
+            }
+            Media::Opus { .. } if tag.audio_fourcc() == Some(&OPUS_FOURCC[..]) => {
+                let rtp_time = self.rtp_time(timestamp as u64);
+                vec![self.packet(rtp_time, false, &tag.data[5..])]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn rtp_time(&self, ms: u64) -> u32 {
+        let ticks = ms * self.media.clock_rate() as u64 / 1000;
+        self.base.wrapping_add(ticks as u32)
+    }
+
+    fn packet(&mut self, timestamp: u32, marker: bool, payload: &[u8]) -> Bytes {
+        let mut packet = BytesMut::with_capacity(12 + payload.len());
+        packet.put_u8(0x80);
+        packet.put_u8(self.payload_type | if marker { 0x80 } else { 0 });
+        packet.put_u16(self.seq);
+        packet.put_u32(timestamp);
+        packet.put_u32(self.ssrc);
+        packet.put_slice(payload);
+        self.seq = self.seq.wrapping_add(1);
+        packet.freeze()
+    }
+}
+
+/// Session ID of a request, without its parameters
+fn session_id(request: &Request) -> Option<&str> {
+    request.header("Session").and_then(|value| value.split(';').next()).map(str::trim)
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        403 => "Forbidden",
+        404 => "Not Found",
+        453 => "Not Enough Bandwidth",
+        454 => "Session Not Found",
+        455 => "Method Not Valid in This State",
+        459 => "Aggregate Operation Not Allowed",
+        461 => "Unsupported Transport",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
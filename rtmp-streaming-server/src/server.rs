@@ -1,9 +1,14 @@
 //! RTMP server implementation
 
-use crate::{error::{Error, Result}, session::RtmpSession, stream::StreamManager};
+use crate::session::{RtmpSession, HANDSHAKE_TIMEOUT, PING_INTERVAL, PING_TIMEOUT};
+use crate::{error::Result, stream::StreamManager, tls::TlsAcceptor};
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use tracing::{info, error, warn};
+use tokio::sync::Semaphore;
+use tracing::{error, info, warn};
 
 /// RTMP Server
 pub struct RtmpServer {
@@ -13,6 +18,12 @@ pub struct RtmpServer {
     stream_manager: StreamManager,
     /// Maximum connections
     max_connections: usize,
+    /// Serve RTMPS instead of plain RTMP
+    tls: Option<TlsAcceptor>,
+    /// Ping interval and dead-peer timeout of sessions
+    ping: (Duration, Duration),
+    /// Time a client has for each of the TLS and RTMP handshakes
+    handshake_timeout: Duration,
 }
 
 impl RtmpServer {
@@ -21,35 +32,100 @@ impl RtmpServer {
         Self {
             address,
             stream_manager: StreamManager::new(),
-            max_connections: 1000,
+            max_connections: crate::MAX_CONNECTIONS,
+            tls: None,
+            ping: (PING_INTERVAL, PING_TIMEOUT),
+            handshake_timeout: HANDSHAKE_TIMEOUT,
         }
     }
 
+    /// Create a server from a configuration
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self::new(config.address).with_max_connections(config.max_connections)
+    }
+
     /// Set maximum connections
     pub fn with_max_connections(mut self, max_connections: usize) -> Self {
         self.max_connections = max_connections;
         self
     }
 
+    /// Share a stream manager with other servers (e.g. HTTP-FLV)
+    pub fn with_stream_manager(mut self, stream_manager: StreamManager) -> Self {
+        self.stream_manager = stream_manager;
+        self
+    }
+
+    /// Ping clients every `interval` and drop those silent for `timeout`
+    pub fn with_ping(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.ping = (interval, timeout);
+        self
+    }
+
+    /// Drop clients that take longer than `timeout` for the TLS or the
+    /// RTMP handshake
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// Serve RTMPS: every connection starts with a TLS handshake
+    pub fn with_tls(mut self, tls: TlsAcceptor) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
     /// Run the server
     pub async fn run(self) -> Result<()> {
-        info!("Starting RTMP server on {}", self.address);
+        info!("Starting {} server on {}", self.scheme(), self.address);
 
         let listener = TcpListener::bind(self.address).await?;
-        info!("Server listening on {}", self.address);
+        self.serve(listener).await
+    }
+
+    /// Serve connections from an already bound listener
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        info!("Server listening on {}", listener.local_addr()?);
 
-        // TODO: Implement connection limiting
         // TODO: Implement graceful shutdown
+        let permits = Arc::new(Semaphore::new(self.max_connections));
 
         loop {
             match listener.accept().await {
                 Ok((socket, addr)) => {
-                    info!("New connection from {}", addr);
+                    let Ok(permit) = permits.clone().try_acquire_owned() else {
+                        warn!("Rejected connection from {}: {} connections open", addr, self.max_connections);
+                        continue;
+                    };
+                    let Ok(guard) = self.stream_manager.limits().connect(addr.ip()) else {
+                        continue;
+                    };
+                    info!("New {} connection from {}", self.scheme(), addr);
 
-                    // TODO: Spawn a task to handle the connection
-                    // For now, just accept and close
-                    let _ = socket;
-                    warn!("Connection handling not implemented yet");
+                    let streams = self.stream_manager.clone();
+                    let tls = self.tls.clone();
+                    let (interval, timeout) = self.ping;
+                    let handshake_timeout = self.handshake_timeout;
+                    tokio::spawn(async move {
+                        let _limits = (permit, guard);
+                        match tls {
+                            Some(tls) => match tls.accept(socket, handshake_timeout).await {
+                                Ok(socket) => {
+                                    let session = RtmpSession::new(socket, addr, streams)
+                                        .with_ping(interval, timeout)
+                                        .with_handshake_timeout(handshake_timeout);
+                                    run_session(session).await
+                                }
+                                Err(e) => warn!("Connection from {} dropped: {}", addr, e),
+                            },
+                            None => {
+                                let session = RtmpSession::new(socket, addr, streams)
+                                    .with_ping(interval, timeout)
+                                    .with_handshake_timeout(handshake_timeout);
+                                run_session(session).await
+                            }
+                        }
+                    });
                 }
                 Err(e) => {
                     error!("Error accepting connection: {}", e);
@@ -57,6 +133,23 @@ impl RtmpServer {
             }
         }
     }
+
+    /// Protocol name for log messages
+    fn scheme(&self) -> &'static str {
+        if self.tls.is_some() {
+            "RTMPS"
+        } else {
+            "RTMP"
+        }
+    }
+}
+
+/// Drive a session to completion
+async fn run_session<S: AsyncRead + AsyncWrite + Unpin>(mut session: RtmpSession<S>) {
+    let addr = *session.remote_addr();
+    if let Err(e) = session.handle().await {
+        warn!("Session {} from {} failed: {}", session.session_id(), addr, e);
+    }
 }
 
 /// Server configuration
@@ -1,34 +1,233 @@
 //! RTMP session handling
 
+use crate::chunk::{ChunkDecoder, ChunkEncoder};
+use crate::command::{self, Command};
+use crate::config::{self, Access};
 use crate::error::{Error, Result};
+use crate::flow::{self, FlowControl};
+use crate::flv::{FlvTag, TagType};
+use crate::handshake;
+use crate::protocol::{constants::*, utils, CommandType, Message, MessageType, UserControl};
+use crate::shared_object::{SharedObjectMessage, Updates};
+use crate::stream::{Publisher, StreamManager, Subscription};
+use crate::timestamp::TimestampRebaser;
+use amf::amf0::{self, Value};
+use bytes::{Bytes, BytesMut};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 
+/// Message stream ID handed out by createStream
+const MEDIA_STREAM_ID: u32 = 1;
+
+/// Default time between two pings
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Default silence after which a peer counts as dead
+pub const PING_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Default time a client has for each of the TLS and RTMP handshakes
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest aggregate message sent to players, in payload bytes
+const MAX_AGGREGATE_SIZE: usize = 64 * 1024;
+
+/// State of an RTMP session, as listed at `GET /api/sessions`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct SessionStats {
+    /// Session ID
+    pub id: String,
+    /// Peer address
+    pub remote_addr: String,
+    /// Application name from connect
+    pub app: Option<String>,
+    /// Stream being published
+    pub publishing: Option<String>,
+    /// Stream being played
+    pub playing: Option<String>,
+    /// Round trip time of the last answered ping, in milliseconds
+    pub rtt_ms: Option<u32>,
+    /// Buffer length the player asked for, in milliseconds
+    pub buffer_ms: Option<u32>,
+}
+
+/// Open RTMP sessions, by session ID
+#[derive(Clone, Default)]
+pub struct SessionRegistry {
+    sessions: Arc<Mutex<HashMap<String, SessionStats>>>,
+}
+
+impl SessionRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Every open session, ordered by ID
+    pub fn list(&self) -> Vec<SessionStats> {
+        let mut sessions: Vec<_> = self.sessions.lock().unwrap().values().cloned().collect();
+        sessions.sort_by(|a, b| a.id.cmp(&b.id));
+        sessions
+    }
+
+    /// State of session `id`, if it is open
+    pub fn get(&self, id: &str) -> Option<SessionStats> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    fn update(&self, stats: &SessionStats) {
+        self.sessions.lock().unwrap().insert(stats.id.clone(), stats.clone());
+    }
+
+    fn remove(&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+}
+
+/// Stream being played by this session
+struct PlayState {
+    /// Stream name
+    name: String,
+    /// Tag receiver
+    subscription: Subscription,
+    /// Maps publisher timestamps to this player's timeline
+    rebaser: TimestampRebaser,
+    /// Media time bundled into one aggregate message, if the app
+    /// aggregates
+    aggregate: Option<Duration>,
+    /// Media messages waiting to be sent as one aggregate
+    batch: Vec<Message>,
+    /// When the first message of the batch was queued
+    batch_started: Instant,
+}
+
+impl PlayState {
+    /// When the batched messages have to go out
+    fn batch_due(&self) -> Option<Instant> {
+        let window = self.aggregate.filter(|_| !self.batch.is_empty())?;
+        Some(self.batch_started + window)
+    }
+}
+
 /// RTMP Session
-pub struct RtmpSession {
-    /// TCP stream
-    stream: TcpStream,
+///
+/// Runs over plain TCP by default, or any other byte stream such as a TLS
+/// connection for RTMPS.
+pub struct RtmpSession<S = TcpStream> {
+    /// Connection to the peer
+    stream: S,
     /// Remote address
     remote_addr: std::net::SocketAddr,
     /// Session ID
     session_id: String,
     /// Is connected
     connected: bool,
+    /// Stream registry
+    stream_manager: StreamManager,
+    /// Bytes read but not yet decoded
+    read_buf: BytesMut,
+    /// Encoded chunks waiting to be written
+    write_buf: BytesMut,
+    /// Incoming chunk stream
+    decoder: ChunkDecoder,
+    /// Outgoing chunk stream
+    encoder: ChunkEncoder,
+    /// Acknowledgement windows and byte counters
+    flow: FlowControl,
+    /// Application name from connect
+    app: Option<String>,
+    /// The client asked for AMF3 (`objectEncoding: 3`) in connect; its
+    /// commands are answered with AMF3 command messages
+    amf3: bool,
+    /// Sender for shared object updates to this client, and its receiver
+    shared_object_updates: Updates,
+    shared_object_receiver: mpsc::UnboundedReceiver<SharedObjectMessage>,
+    /// Stream being published
+    publishing: Option<Publisher>,
+    /// Stream being played
+    playing: Option<PlayState>,
+    /// State reported to the session registry
+    stats: SessionStats,
+    /// Time between two pings
+    ping_interval: Duration,
+    /// Silence after which the peer counts as dead
+    ping_timeout: Duration,
+    /// Time the peer has to complete the handshake
+    handshake_timeout: Duration,
+    /// Session start, the clock of ping timestamps
+    started: Instant,
+    /// Last time bytes arrived from the peer
+    last_seen: Instant,
+    /// Last time a tag was sent to the player
+    last_tag: Instant,
+    /// StreamDry was sent and no tag followed yet
+    dry: bool,
 }
 
-impl RtmpSession {
+impl<S: AsyncRead + AsyncWrite + Unpin> RtmpSession<S> {
     /// Create a new RTMP session
-    pub fn new(stream: TcpStream, remote_addr: std::net::SocketAddr) -> Self {
+    pub fn new(
+        stream: S,
+        remote_addr: std::net::SocketAddr,
+        stream_manager: StreamManager,
+    ) -> Self {
         let session_id = format!("session-{}", uuid::Uuid::new_v4().simple());
+        let (shared_object_updates, shared_object_receiver) = mpsc::unbounded_channel();
 
         Self {
             stream,
             remote_addr,
-            session_id,
+            session_id: session_id.clone(),
             connected: true,
+            stream_manager,
+            read_buf: BytesMut::with_capacity(64 * 1024),
+            write_buf: BytesMut::new(),
+            decoder: ChunkDecoder::new(),
+            encoder: ChunkEncoder::new(),
+            flow: FlowControl::new(),
+            app: None,
+            amf3: false,
+            shared_object_updates,
+            shared_object_receiver,
+            publishing: None,
+            playing: None,
+            stats: SessionStats {
+                id: session_id.clone(),
+                remote_addr: remote_addr.to_string(),
+                ..SessionStats::default()
+            },
+            ping_interval: PING_INTERVAL,
+            ping_timeout: PING_TIMEOUT,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            started: Instant::now(),
+            last_seen: Instant::now(),
+            last_tag: Instant::now(),
+            dry: false,
         }
     }
 
+    /// Ping the peer every `interval` and close the session after
+    /// `timeout` without data from it
+    ///
+    /// Writes that block for longer than `timeout` close the session too.
+    pub fn with_ping(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.ping_interval = interval;
+        self.ping_timeout = timeout;
+        self
+    }
+
+    /// Close the session if the peer has not completed the handshake
+    /// within `timeout`
+    pub fn with_handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
     /// Get session ID
     pub fn session_id(&self) -> &str {
         &self.session_id
@@ -48,51 +247,497 @@ impl RtmpSession {
     pub async fn handle(&mut self) -> Result<()> {
         info!("Handling RTMP session {} from {}", self.session_id, self.remote_addr);
 
-        // TODO: Implement RTMP handshake
-        debug!("Starting RTMP handshake...");
-
-        // TODO: Implement RTMP chunk stream processing
-        debug!("Starting RTMP chunk stream processing...");
+        self.perform_handshake().await?;
+        self.report();
+        let result = self.process_chunk_stream().await;
+        self.close().await?;
 
-        // TODO: Implement RTMP command processing
-        debug!("Starting RTMP command processing...");
-
-        // For now, just close the connection
-        self.connected = false;
         info!("Session {} closed", self.session_id);
-
-        Ok(())
+        result
     }
 
     /// Perform RTMP handshake
     async fn perform_handshake(&mut self) -> Result<()> {
-        // RTMP handshake has 3 parts: C0, C1, C2, S0, S1, S2
         debug!("Performing RTMP handshake...");
-
-        // TODO: Implement actual RTMP handshake
-        // For now, just simulate success
+        tokio::time::timeout(self.handshake_timeout, handshake::server_handshake(&mut self.stream))
+            .await
+            .map_err(|_| Error::Handshake("Handshake timed out".to_string()))??;
         debug!("RTMP handshake completed");
 
         Ok(())
     }
 
     /// Process RTMP chunk stream
+    ///
+    /// Reads chunks from the peer and, while playing, interleaves the
+    /// stream's tags into the outgoing chunk stream.
     async fn process_chunk_stream(&mut self) -> Result<()> {
         debug!("Processing RTMP chunk stream...");
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + self.ping_interval, self.ping_interval);
+        self.last_seen = Instant::now();
+
+        loop {
+            while let Some(message) = self.decoder.decode(&mut self.read_buf)? {
+                self.process_message(message).await?;
+            }
+            self.flush().await?;
+
+            let batch_due = self.playing.as_ref().and_then(PlayState::batch_due);
+            tokio::select! {
+                read = self.stream.read_buf(&mut self.read_buf) => {
+                    let read = read?;
+                    if read == 0 {
+                        debug!("Session {}: peer closed the connection", self.session_id);
+                        return Ok(());
+                    }
+                    self.last_seen = Instant::now();
+                    if let Some(ack) = self.flow.received(read) {
+                        self.send_message(CSID_PROTOCOL_CONTROL, ack);
+                    }
+                }
+                _ = ping.tick() => self.ping()?,
+                Some(update) = self.shared_object_receiver.recv() => {
+                    self.send_message(CSID_COMMAND, update.to_message(self.amf3));
+                }
+                _ = sleep_until(batch_due) => self.send_batch(),
+                _ = evicted(&mut self.publishing) => {
+                    info!("Session {}: replaced by a new publisher", self.session_id);
+                    self.publishing = None;
+                    self.send_status("status", "NetStream.Unpublish.Success", "Replaced by a new publisher.");
+                    self.flush().await?;
+                    return Ok(());
+                }
+                // Held back while the player's bandwidth window is full
+                received = next_tag(&mut self.playing), if self.flow.can_send() => match received {
+                    Ok(Some(mut tag)) => {
+                        if let Some(play) = &mut self.playing {
+                            tag.timestamp = play.rebaser.rebase(tag.timestamp);
+                        }
+                        self.last_tag = Instant::now();
+                        if self.dry {
+                            self.dry = false;
+                            self.send_control_event(UserControl::StreamBegin(MEDIA_STREAM_ID));
+                        }
+                        self.queue_tag(&tag);
+                        self.flush().await?;
+                    }
+                    Err(e) => {
+                        warn!("Session {}: {}", self.session_id, e);
+                        self.playing = None;
+                        self.send_status("error", "NetStream.Play.InsufficientBW", "Client is too slow, disconnecting.");
+                        self.flush().await?;
+                        return Ok(());
+                    }
+                    Ok(None) => {
+                        self.send_batch();
+                        let name = self.playing.take().map(|p| p.name).unwrap_or_default();
+                        info!("Session {}: stream {} ended", self.session_id, name);
+                        self.send_control_event(UserControl::StreamEof(MEDIA_STREAM_ID));
+                        self.send_status("status", "NetStream.Play.UnpublishNotify", &format!("{} is now unpublished.", name));
+                        self.flush().await?;
+                        return Ok(());
+                    }
+                },
+            }
+        }
+    }
+
+    /// Dispatch a complete message
+    async fn process_message(&mut self, message: Message) -> Result<()> {
+        match message.message_type {
+            MessageType::SetChunkSize => {
+                let size = utils::read_u32(&message.payload)? & 0x7fff_ffff;
+                debug!("Session {}: peer chunk size {}", self.session_id, size);
+                self.decoder.set_chunk_size(size)?;
+            }
+            MessageType::AbortMessage => {
+                self.decoder.abort(utils::read_u32(&message.payload)?);
+            }
+            MessageType::Acknowledgement | MessageType::WindowAcknowledgementSize | MessageType::SetPeerBandwidth => {
+                if let Some(reply) = self.flow.process(&message)? {
+                    self.send_message(CSID_PROTOCOL_CONTROL, reply);
+                }
+            }
+            MessageType::UserControl => match UserControl::decode(&message.payload) {
+                Ok(event) => self.process_user_control(event),
+                Err(e) => debug!("Session {}: {}", self.session_id, e),
+            },
+            MessageType::CommandAmf0 | MessageType::CommandAmf3 => {
+                let Some(command) = Command::from_message(&message)? else {
+                    return Ok(());
+                };
+                let (kind, transaction_id) = (CommandType::from(command.name.as_str()), command.transaction_id);
+                if let Err(e) = self.process_commands(command).await {
+                    // Tell the client why before closing the connection
+                    self.send_error(&kind, transaction_id, &e);
+                    self.flush().await?;
+                    return Err(e);
+                }
+            }
+            MessageType::DataAmf0 | MessageType::DataAmf3 => self.process_data(message).await?,
+            MessageType::SharedObjectAmf0 | MessageType::SharedObjectAmf3 => {
+                let message = SharedObjectMessage::decode(&message)?;
+                let app = self.app.clone().unwrap_or_default();
+                let shared_objects = self.stream_manager.shared_objects();
+                shared_objects.process(&app, &self.session_id, &self.shared_object_updates, message);
+            }
+            MessageType::Audio | MessageType::Video => {
+                if let (Some(publisher), Some(tag)) = (&self.publishing, message.to_tag()) {
+                    publisher.publish(tag).await?;
+                }
+            }
+            // Published as the media and data messages it bundles
+            MessageType::Aggregate => {
+                for message in message.split_aggregate()? {
+                    match message.message_type {
+                        MessageType::DataAmf0 | MessageType::DataAmf3 => self.process_data(message).await?,
+                        MessageType::Audio | MessageType::Video => {
+                            if let (Some(publisher), Some(tag)) = (&self.publishing, message.to_tag()) {
+                                publisher.publish(tag).await?;
+                            }
+                        }
+                        other => debug!("Session {}: ignoring aggregated {:?} message", self.session_id, other),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
 
-        // TODO: Implement RTMP chunk stream processing
-        // RTMP uses chunk streams for multiplexing multiple logical channels
+    /// Answer pings and record RTT and buffer length
+    fn process_user_control(&mut self, event: UserControl) {
+        match event {
+            UserControl::PingRequest(timestamp) => {
+                self.send_control_event(UserControl::PingResponse(timestamp));
+            }
+            UserControl::PingResponse(timestamp) => {
+                let rtt = self.clock().wrapping_sub(timestamp);
+                debug!("Session {}: RTT {} ms", self.session_id, rtt);
+                self.stats.rtt_ms = Some(rtt);
+                self.report();
+            }
+            UserControl::SetBufferLength(_, length) => {
+                debug!("Session {}: buffer length {} ms", self.session_id, length);
+                self.stats.buffer_ms = Some(length);
+                self.report();
+            }
+            other => debug!("Session {}: ignoring {:?}", self.session_id, other),
+        }
+    }
 
+    /// Ping tick: give up on a silent peer, otherwise ping it and tell a
+    /// player when its stream has gone dry
+    fn ping(&mut self) -> Result<()> {
+        let silence = self.last_seen.elapsed();
+        if silence >= self.ping_timeout {
+            warn!("Session {}: no data from {} for {:?}, closing", self.session_id, self.remote_addr, silence);
+            return Err(Error::Network(format!("Peer silent for {:?}", silence)));
+        }
+        if self.app.is_none() {
+            return Ok(());
+        }
+        self.send_control_event(UserControl::PingRequest(self.clock()));
+        if self.playing.is_some() && !self.dry && self.last_tag.elapsed() >= self.ping_interval {
+            self.dry = true;
+            self.send_control_event(UserControl::StreamDry(MEDIA_STREAM_ID));
+        }
         Ok(())
     }
 
+    /// Milliseconds since the session started, for ping timestamps
+    fn clock(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    /// Publish the current state to the session registry
+    fn report(&self) {
+        self.stream_manager.sessions().update(&self.stats);
+    }
+
     /// Process RTMP commands
-    async fn process_commands(&mut self) -> Result<()> {
-        debug!("Processing RTMP commands...");
+    async fn process_commands(&mut self, command: Command) -> Result<()> {
+        debug!("Session {}: command {} ({})", self.session_id, command.name, command.transaction_id);
+
+        match CommandType::from(command.name.as_str()) {
+            CommandType::Connect => {
+                let app = command::property(&command.command_object, "app")
+                    .and_then(|v| v.try_as_str())
+                    .unwrap_or_default();
+                // The tcUrl host picks the virtual host
+                let host = command::property(&command.command_object, "tcUrl")
+                    .and_then(|v| v.try_as_str())
+                    .and_then(url_host);
+                let app = self.stream_manager.config().app_id(host, app)?;
+                let object_encoding = command::property(&command.command_object, "objectEncoding")
+                    .and_then(|v| v.try_as_f64())
+                    .unwrap_or(0.0);
+                self.amf3 = object_encoding == 3.0;
+                info!("Session {}: connect app '{}'", self.session_id, app);
+                self.stats.app = Some(app.clone());
+                self.report();
+                self.app = Some(app);
+
+                for window in flow::server_windows() {
+                    self.send_message(CSID_PROTOCOL_CONTROL, window);
+                }
+                self.send_control(MessageType::SetChunkSize, &SERVER_CHUNK_SIZE.to_be_bytes());
+                self.encoder.set_chunk_size(SERVER_CHUNK_SIZE);
+
+                let properties = command::object(vec![
+                    ("fmsVer", amf0::string("FMS/3,0,1,123")),
+                    ("capabilities", amf0::number(31)),
+                ]);
+                let mut info = command::status("status", "NetConnection.Connect.Success", "Connection succeeded.");
+                if let Value::Object { entries, .. } = &mut info {
+                    let encoding = if self.amf3 { 3 } else { 0 };
+                    entries.push(amf::Pair { key: "objectEncoding".to_string(), value: amf0::number(encoding) });
+                }
+                self.send_command(0, Command::new("_result", command.transaction_id, properties, vec![info]));
+            }
+            CommandType::CreateStream => {
+                self.send_command(
+                    0,
+                    Command::new("_result", command.transaction_id, Value::Null, vec![amf0::number(MEDIA_STREAM_ID)]),
+                );
+            }
+            CommandType::Publish => {
+                let name = stream_name(command.arg_str(0))?;
+                self.authorize(Access::Publish, command.arg_str(0))?;
+                self.start_publish(name).await?;
+            }
+            CommandType::Play => {
+                let name = stream_name(command.arg_str(0))?;
+                self.authorize(Access::Play, command.arg_str(0))?;
+                self.start_play(name).await?;
+            }
+            CommandType::DeleteStream | CommandType::CloseStream | CommandType::FCUnpublish => {
+                self.stop_publish().await;
+                if let Some(play) = self.playing.take() {
+                    info!("Session {}: stopped playing {}", self.session_id, play.name);
+                    self.stats.playing = None;
+                    self.report();
+                }
+            }
+            CommandType::ReleaseStream | CommandType::FCPublish | CommandType::GetStreamLength => {
+                debug!("Session {}: {} acknowledged", self.session_id, command.name);
+            }
+            kind => {
+                debug!("Session {}: unhandled command {}", self.session_id, command.name);
+                if command.transaction_id != 0.0 {
+                    let error = Error::UnknownCommand(command.name.clone());
+                    self.send_error(&kind, command.transaction_id, &error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle an AMF0 or AMF3 data message (metadata)
+    ///
+    /// The metadata is published as an AMF0 script tag either way.
+    async fn process_data(&mut self, message: Message) -> Result<()> {
+        let Some(publisher) = &self.publishing else {
+            return Ok(());
+        };
+        let mut values = command::data_values(&message)?;
+        if values.first().and_then(|v| v.try_as_str()) == Some("@setDataFrame") {
+            values.remove(0);
+        }
+        if values.first().and_then(|v| v.try_as_str()) != Some("onMetaData") {
+            return Ok(());
+        }
+        let tag = FlvTag::new(TagType::Script, message.timestamp, command::encode_values(&values));
+        publisher.publish(tag).await
+    }
 
-        // TODO: Implement RTMP command processing
-        // Commands like connect, createStream, publish, play, etc.
+    /// Check the `?token=` of a publish or play argument against the app
+    fn authorize(&self, access: Access, arg: Option<&str>) -> Result<()> {
+        let app = self.app.as_deref().unwrap_or_default();
+        self.stream_manager.authorize(app, access, arg.and_then(config::token))
+    }
 
+    /// Start publishing `name`
+    ///
+    /// A stream that is already published is refused with a status; other
+    /// failures, such as a limit, close the connection.
+    async fn start_publish(&mut self, name: String) -> Result<()> {
+        let app = self.app.clone().unwrap_or_default();
+        info!("Session {}: publish '{}' (app '{}')", self.session_id, name, app);
+        match self.stream_manager.start_publish(&app, &name, self.session_id.clone()).await {
+            Ok(publisher) => {
+                self.publishing = Some(publisher);
+                self.stats.publishing = Some(name.clone());
+                self.report();
+                self.send_control_event(UserControl::StreamBegin(MEDIA_STREAM_ID));
+                self.send_status("status", "NetStream.Publish.Start", &format!("{} is now published.", name));
+            }
+            Err(e @ Error::StreamBusy(_)) => {
+                self.send_status("error", e.rtmp_status(&CommandType::Publish), &e.to_string());
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    /// Stop publishing; the stream is dropped after the app's grace period
+    async fn stop_publish(&mut self) {
+        if let Some(publisher) = self.publishing.take() {
+            info!("Session {}: stopped publishing {}", self.session_id, publisher.stream().name().await);
+            self.stats.publishing = None;
+            self.report();
+        }
+    }
+
+    /// Start playing `name`: status, cached headers, then live tags
+    ///
+    /// A full stream closes the connection.
+    async fn start_play(&mut self, name: String) -> Result<()> {
+        info!("Session {}: play '{}' (app '{}')", self.session_id, name, self.app.as_deref().unwrap_or_default());
+        let app = self.app.clone().unwrap_or_default();
+        let stream = self.stream_manager.get_or_pull(&app, &name).await;
+        self.stream_manager.admit_subscriber(&app, &stream).await?;
+        let lag_policy = self.stream_manager.config().app(&app).lag_policy();
+        let subscription = stream.subscribe(self.session_id.clone()).await.with_lag_policy(lag_policy);
+
+        self.send_control_event(UserControl::StreamBegin(MEDIA_STREAM_ID));
+        self.send_status("status", "NetStream.Play.Reset", &format!("Playing and resetting {}.", name));
+        self.send_status("status", "NetStream.Play.Start", &format!("Started playing {}.", name));
+        let sample_access = command::encode_values(&[
+            amf0::string("|RtmpSampleAccess"),
+            Value::Boolean(true),
+            Value::Boolean(true),
+        ]);
+        self.send_message(CSID_DATA, Message::new(MessageType::DataAmf0, MEDIA_STREAM_ID, 0, sample_access));
+        let rebaser = TimestampRebaser::new(format!("{}/{}", name, self.session_id));
+        for mut tag in stream.sequence_headers().await {
+            tag.timestamp = rebaser.position();
+            self.send_tag(&tag);
+        }
+
+        self.stats.playing = Some(name.clone());
+        self.report();
+        self.last_tag = Instant::now();
+        self.dry = false;
+        let aggregate_ms = self.stream_manager.config().app(&app).aggregate_ms;
+        self.playing = Some(PlayState {
+            name,
+            subscription,
+            rebaser,
+            aggregate: (aggregate_ms > 0).then(|| Duration::from_millis(aggregate_ms)),
+            batch: Vec::new(),
+            batch_started: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Queue a protocol control message
+    fn send_control(&mut self, message_type: MessageType, payload: &[u8]) {
+        let message = Message::new(message_type, 0, 0, Bytes::copy_from_slice(payload));
+        self.send_message(CSID_PROTOCOL_CONTROL, message);
+    }
+
+    /// Queue the answer to a failed command: an error status for publish
+    /// and play, an `_error` result for everything else
+    fn send_error(&mut self, command: &CommandType, transaction_id: f64, error: &Error) {
+        let code = error.rtmp_status(command);
+        match command {
+            CommandType::Publish | CommandType::Play => self.send_status("error", code, &error.to_string()),
+            _ => {
+                let info = command::status("error", code, &error.to_string());
+                self.send_command(0, Command::new("_error", transaction_id, Value::Null, vec![info]));
+            }
+        }
+    }
+
+    /// Queue a user control event
+    fn send_control_event(&mut self, event: UserControl) {
+        self.send_message(CSID_PROTOCOL_CONTROL, event.to_message());
+    }
+
+    /// Queue a command message, in the encoding the client negotiated
+    fn send_command(&mut self, message_stream_id: u32, command: Command) {
+        let message = if self.amf3 {
+            Message::new(MessageType::CommandAmf3, message_stream_id, 0, command.encode_amf3())
+        } else {
+            Message::new(MessageType::CommandAmf0, message_stream_id, 0, command.encode())
+        };
+        self.send_message(CSID_COMMAND, message);
+    }
+
+    /// Queue an onStatus command on the media stream
+    fn send_status(&mut self, level: &str, code: &str, description: &str) {
+        let info = command::status(level, code, description);
+        self.send_command(MEDIA_STREAM_ID, Command::new("onStatus", 0.0, Value::Null, vec![info]));
+    }
+
+    /// Queue a media or data tag on the media stream
+    fn send_tag(&mut self, tag: &FlvTag) {
+        let csid = match tag.tag_type {
+            crate::flv::TagType::Audio => CSID_AUDIO,
+            crate::flv::TagType::Video => CSID_VIDEO,
+            crate::flv::TagType::Script => CSID_DATA,
+        };
+        self.send_message(csid, Message::from_tag(tag, MEDIA_STREAM_ID));
+    }
+
+    /// Queue a tag for the player, in an aggregate if its app wants that
+    ///
+    /// Metadata and sequence headers are never aggregated. A batch goes
+    /// out once it covers the app's aggregation time or grows too large;
+    /// the play loop also sends it when that time has passed on the clock.
+    fn queue_tag(&mut self, tag: &FlvTag) {
+        let Some(play) = self.playing.as_mut().filter(|play| play.aggregate.is_some()) else {
+            self.send_tag(tag);
+            return;
+        };
+        if tag.is_script() || tag.is_sequence_header() {
+            self.send_batch();
+            self.send_tag(tag);
+            return;
+        }
+        if play.batch.is_empty() {
+            play.batch_started = Instant::now();
+        }
+        play.batch.push(Message::from_tag(tag, MEDIA_STREAM_ID));
+        let span = Duration::from_millis(tag.timestamp.wrapping_sub(play.batch[0].timestamp) as u64);
+        let size: usize = play.batch.iter().map(|m| m.payload.len()).sum();
+        if Some(span) >= play.aggregate || size >= MAX_AGGREGATE_SIZE {
+            self.send_batch();
+        }
+    }
+
+    /// Queue the batched media messages, as one aggregate if there are
+    /// several
+    fn send_batch(&mut self) {
+        let Some(play) = self.playing.as_mut() else {
+            return;
+        };
+        let batch = std::mem::take(&mut play.batch);
+        match batch.as_slice() {
+            [] => {}
+            [message] => {
+                let csid = if message.message_type == MessageType::Audio { CSID_AUDIO } else { CSID_VIDEO };
+                self.send_message(csid, message.clone());
+            }
+            messages => self.send_message(CSID_VIDEO, Message::aggregate(messages, MEDIA_STREAM_ID)),
+        }
+    }
+
+    /// Queue a message on a chunk stream
+    fn send_message(&mut self, chunk_stream_id: u32, message: Message) {
+        self.encoder.encode(chunk_stream_id, &message, &mut self.write_buf);
+    }
+
+    /// Write queued chunks to the socket
+    async fn flush(&mut self) -> Result<()> {
+        if !self.write_buf.is_empty() {
+            let out = self.write_buf.split();
+            // A peer that stopped reading counts as dead, too
+            tokio::time::timeout(self.ping_timeout, self.stream.write_all(&out))
+                .await
+                .map_err(|_| Error::Network(format!("Write blocked for {:?}", self.ping_timeout)))??;
+            self.flow.sent(out.len());
+        }
         Ok(())
     }
 
@@ -101,15 +746,60 @@ impl RtmpSession {
         if self.connected {
             self.connected = false;
             debug!("Closing session {}", self.session_id);
+            self.stop_publish().await;
+            self.playing = None;
+            self.stream_manager.shared_objects().release_all(&self.session_id);
+            self.stream_manager.sessions().remove(&self.session_id);
         }
         Ok(())
     }
 }
 
-impl Drop for RtmpSession {
+impl<S> Drop for RtmpSession<S> {
     fn drop(&mut self) {
         if self.connected {
             warn!("Session {} dropped without proper close", self.session_id);
         }
     }
-}
\ No newline at end of file
+}
+
+/// Wait until the published stream is taken over, pending forever when idle
+async fn evicted(publishing: &mut Option<Publisher>) {
+    match publishing {
+        Some(publisher) => publisher.evicted().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Wait until `deadline`, pending forever without one
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Receive the next tag of the played stream, pending forever when idle
+async fn next_tag(playing: &mut Option<PlayState>) -> Result<Option<FlvTag>> {
+    match playing {
+        Some(play) => play.subscription.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Stream name from a publish/play argument, without query string
+fn stream_name(arg: Option<&str>) -> Result<String> {
+    let name = arg
+        .map(|s| s.split('?').next().unwrap_or_default())
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| Error::InvalidInput("Missing stream name".to_string()))?;
+    Ok(name.to_string())
+}
+
+/// Host of a URL such as `rtmp://user@host:1935/app`, port included
+fn url_host(url: &str) -> Option<&str> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?']).next()?;
+    let host = authority.rsplit_once('@').map_or(authority, |(_, host)| host);
+    (!host.is_empty()).then_some(host)
+}
@@ -0,0 +1,442 @@
+//! Remote shared objects
+//!
+//! RTMP clients connect to named shared objects of their app to keep
+//! properties (chat lines, cue points...) in sync and to broadcast
+//! messages. A client uses an object, then requests changes and removals;
+//! the server applies them, bumps the object's version and tells every
+//! other client. Objects a client marks persistent are saved to
+//! `shared_objects_dir` when it is configured and loaded again on first
+//! use; the others go away with their last client.
+
+use crate::command::{self, AMF3_FORMAT};
+use crate::error::{Error, Result};
+use crate::protocol::{Message, MessageType};
+use amf::amf0::Value;
+use amf::Pair;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+use tracing::{debug, warn};
+
+/// Persistence flag in the message header
+const PERSISTENT_FLAG: u32 = 2;
+
+/// Extension of saved shared objects
+const FILE_EXTENSION: &str = "so";
+
+/// Event of a shared object message
+#[derive(Debug, Clone, PartialEq)]
+pub enum SharedObjectEvent {
+    /// Client connects to the object
+    Use,
+    /// Client disconnects from the object
+    Release,
+    /// Client asks to set a property
+    RequestChange {
+        /// Property name
+        name: String,
+        /// New value
+        value: Value,
+    },
+    /// A property was set
+    Change {
+        /// Property name
+        name: String,
+        /// New value
+        value: Value,
+    },
+    /// The client's own change or removal was applied
+    Success {
+        /// Property name
+        name: String,
+    },
+    /// Message for every client of the object
+    SendMessage {
+        /// Handler name, then its arguments
+        values: Vec<Value>,
+    },
+    /// Status or error
+    Status {
+        /// Status code
+        code: String,
+        /// `status`, `warning` or `error`
+        level: String,
+    },
+    /// Client should drop its local copy
+    Clear,
+    /// A property was deleted
+    Remove {
+        /// Property name
+        name: String,
+    },
+    /// Client asks to delete a property
+    RequestRemove {
+        /// Property name
+        name: String,
+    },
+    /// Client is connected to the object
+    UseSuccess,
+}
+
+impl SharedObjectEvent {
+    /// Decode an event of type `kind`
+    fn decode(kind: u8, mut data: &[u8]) -> Result<Self> {
+        Ok(match kind {
+            1 => SharedObjectEvent::Use,
+            2 => SharedObjectEvent::Release,
+            3 | 4 => {
+                let name = read_string(&mut data)?;
+                let value = command::decode_values(data)?.into_iter().next().unwrap_or(Value::Undefined);
+                if kind == 3 {
+                    SharedObjectEvent::RequestChange { name, value }
+                } else {
+                    SharedObjectEvent::Change { name, value }
+                }
+            }
+            5 => SharedObjectEvent::Success { name: read_string(&mut data)? },
+            6 => SharedObjectEvent::SendMessage {
+                values: command::decode_values(data)?,
+            },
+            7 => SharedObjectEvent::Status {
+                code: read_string(&mut data)?,
+                level: read_string(&mut data)?,
+            },
+            8 => SharedObjectEvent::Clear,
+            9 => SharedObjectEvent::Remove { name: read_string(&mut data)? },
+            10 => SharedObjectEvent::RequestRemove { name: read_string(&mut data)? },
+            11 => SharedObjectEvent::UseSuccess,
+            _ => return Err(Error::Protocol(format!("Unknown shared object event {}", kind))),
+        })
+    }
+
+    /// Append the event with its type and length
+    fn encode(&self, out: &mut Vec<u8>) {
+        let mut data = Vec::new();
+        let kind = match self {
+            SharedObjectEvent::Use => 1,
+            SharedObjectEvent::Release => 2,
+            SharedObjectEvent::RequestChange { name, value } | SharedObjectEvent::Change { name, value } => {
+                write_string(&mut data, name);
+                data.extend_from_slice(&command::encode_values(std::slice::from_ref(value)));
+                if matches!(self, SharedObjectEvent::RequestChange { .. }) {
+                    3
+                } else {
+                    4
+                }
+            }
+            SharedObjectEvent::Success { name } => {
+                write_string(&mut data, name);
+                5
+            }
+            SharedObjectEvent::SendMessage { values } => {
+                data.extend_from_slice(&command::encode_values(values));
+                6
+            }
+            SharedObjectEvent::Status { code, level } => {
+                write_string(&mut data, code);
+                write_string(&mut data, level);
+                7
+            }
+            SharedObjectEvent::Clear => 8,
+            SharedObjectEvent::Remove { name } => {
+                write_string(&mut data, name);
+                9
+            }
+            SharedObjectEvent::RequestRemove { name } => {
+                write_string(&mut data, name);
+                10
+            }
+            SharedObjectEvent::UseSuccess => 11,
+        };
+        out.push(kind);
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&data);
+    }
+}
+
+/// Shared object message (types 16 and 19)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SharedObjectMessage {
+    /// Object name
+    pub name: String,
+    /// Object version
+    pub version: u32,
+    /// Whether the object outlives its clients
+    pub persistent: bool,
+    /// Events, in order
+    pub events: Vec<SharedObjectEvent>,
+}
+
+impl SharedObjectMessage {
+    /// Create a message about object `name`
+    pub fn new(name: &str, version: u32, persistent: bool, events: Vec<SharedObjectEvent>) -> Self {
+        Self {
+            name: name.to_string(),
+            version,
+            persistent,
+            events,
+        }
+    }
+
+    /// Decode an AMF0 or AMF3 shared object message
+    pub fn decode(message: &Message) -> Result<Self> {
+        let mut data = match message.message_type {
+            MessageType::SharedObjectAmf0 => &message.payload[..],
+            MessageType::SharedObjectAmf3 => command::amf3_body(&message.payload),
+            other => return Err(Error::Protocol(format!("{:?} is not a shared object message", other))),
+        };
+        let name = read_string(&mut data)?;
+        let header = take(&mut data, 12)?;
+        let version = u32::from_be_bytes(header[..4].try_into().unwrap());
+        let flags = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let mut events = Vec::new();
+        while !data.is_empty() {
+            let kind = take(&mut data, 1)?[0];
+            let length = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap()) as usize;
+            events.push(SharedObjectEvent::decode(kind, take(&mut data, length)?)?);
+        }
+        Ok(Self {
+            name,
+            version,
+            persistent: flags & PERSISTENT_FLAG != 0,
+            events,
+        })
+    }
+
+    /// Encode as a message on stream 0, AMF3 for clients that negotiated it
+    pub fn to_message(&self, amf3: bool) -> Message {
+        let mut payload = Vec::new();
+        if amf3 {
+            payload.push(AMF3_FORMAT);
+        }
+        write_string(&mut payload, &self.name);
+        payload.extend_from_slice(&self.version.to_be_bytes());
+        let flags = if self.persistent { PERSISTENT_FLAG } else { 0 };
+        payload.extend_from_slice(&flags.to_be_bytes());
+        payload.extend_from_slice(&[0; 4]);
+        for event in &self.events {
+            event.encode(&mut payload);
+        }
+        let message_type = if amf3 { MessageType::SharedObjectAmf3 } else { MessageType::SharedObjectAmf0 };
+        Message::new(message_type, 0, 0, Bytes::from(payload))
+    }
+}
+
+/// Sends shared object messages to one client
+pub(crate) type Updates = mpsc::UnboundedSender<SharedObjectMessage>;
+
+/// One shared object and its clients
+#[derive(Default)]
+struct SharedObject {
+    version: u32,
+    persistent: bool,
+    /// Properties in the order they were first set
+    properties: Vec<Pair<String, Value>>,
+    /// Connected clients by ID
+    clients: HashMap<String, Updates>,
+}
+
+impl SharedObject {
+    /// Send `events` to every client but `except`
+    fn broadcast(&self, name: &str, except: Option<&str>, events: Vec<SharedObjectEvent>) {
+        let message = SharedObjectMessage::new(name, self.version, self.persistent, events);
+        for (id, client) in &self.clients {
+            if Some(id.as_str()) != except {
+                // A client that went away is released with its session
+                let _ = client.send(message.clone());
+            }
+        }
+    }
+
+    /// Send `events` to client `id`
+    fn reply(&self, name: &str, id: &str, events: Vec<SharedObjectEvent>) {
+        if let Some(client) = self.clients.get(id) {
+            let _ = client.send(SharedObjectMessage::new(name, self.version, self.persistent, events));
+        }
+    }
+}
+
+/// Shared objects of every app, by app and object name
+#[derive(Clone, Default)]
+pub struct SharedObjects {
+    objects: Arc<Mutex<HashMap<(String, String), SharedObject>>>,
+    /// Where persistent objects are saved
+    dir: Option<PathBuf>,
+}
+
+impl SharedObjects {
+    /// Create an empty registry; persistent objects are saved in `dir`
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self {
+            objects: Arc::default(),
+            dir,
+        }
+    }
+
+    /// Properties of object `name` in `app`, if it is in use
+    pub fn properties(&self, app: &str, name: &str) -> Option<Vec<Pair<String, Value>>> {
+        let objects = self.objects.lock().unwrap();
+        objects.get(&(app.to_string(), name.to_string())).map(|o| o.properties.clone())
+    }
+
+    /// Apply a message from client `id` of `app`; `updates` is where the
+    /// client receives its replies and the other clients' changes
+    ///
+    /// Requests for objects the client does not use are ignored.
+    pub(crate) fn process(&self, app: &str, id: &str, updates: &Updates, message: SharedObjectMessage) {
+        let name = message.name;
+        let key = (app.to_string(), name.clone());
+        let mut objects = self.objects.lock().unwrap();
+        let mut changed = false;
+        for event in message.events {
+            if let SharedObjectEvent::Use = event {
+                let object = objects
+                    .entry(key.clone())
+                    .or_insert_with(|| self.load(app, &name, message.persistent));
+                object.clients.insert(id.to_string(), updates.clone());
+                let mut events = vec![SharedObjectEvent::UseSuccess, SharedObjectEvent::Clear];
+                events.extend(object.properties.iter().map(|p| SharedObjectEvent::Change {
+                    name: p.key.clone(),
+                    value: p.value.clone(),
+                }));
+                object.reply(&name, id, events);
+                continue;
+            }
+            let Some(object) = objects.get_mut(&key).filter(|o| o.clients.contains_key(id)) else {
+                debug!("Shared object {}/{}: {:?} from {} without use", app, name, event, id);
+                continue;
+            };
+            match event {
+                SharedObjectEvent::Release => {
+                    object.clients.remove(id);
+                    if object.clients.is_empty() && !object.persistent {
+                        objects.remove(&key);
+                    }
+                }
+                SharedObjectEvent::RequestChange { name: property, value } => {
+                    match object.properties.iter_mut().find(|p| p.key == property) {
+                        Some(pair) => pair.value = value.clone(),
+                        None => object.properties.push(Pair {
+                            key: property.clone(),
+                            value: value.clone(),
+                        }),
+                    }
+                    object.version = object.version.wrapping_add(1);
+                    changed |= object.persistent;
+                    object.reply(&name, id, vec![SharedObjectEvent::Success { name: property.clone() }]);
+                    object.broadcast(&name, Some(id), vec![SharedObjectEvent::Change { name: property, value }]);
+                }
+                SharedObjectEvent::RequestRemove { name: property } => {
+                    object.properties.retain(|p| p.key != property);
+                    object.version = object.version.wrapping_add(1);
+                    changed |= object.persistent;
+                    object.reply(&name, id, vec![SharedObjectEvent::Success { name: property.clone() }]);
+                    object.broadcast(&name, Some(id), vec![SharedObjectEvent::Remove { name: property }]);
+                }
+                SharedObjectEvent::SendMessage { values } => {
+                    object.broadcast(&name, None, vec![SharedObjectEvent::SendMessage { values }]);
+                }
+                other => debug!("Shared object {}/{}: ignoring {:?} from {}", app, name, other, id),
+            }
+        }
+
+        if changed {
+            if let Some(object) = objects.get(&key) {
+                let data = command::encode_values(&[
+                    Value::Number(object.version.into()),
+                    Value::EcmaArray {
+                        entries: object.properties.clone(),
+                    },
+                ]);
+                drop(objects);
+                self.save(app, &name, &data);
+            }
+        }
+    }
+
+    /// Release every object client `id` uses, e.g. when it disconnects
+    pub(crate) fn release_all(&self, id: &str) {
+        self.objects.lock().unwrap().retain(|_, object| {
+            object.clients.remove(id);
+            object.persistent || !object.clients.is_empty()
+        });
+    }
+
+    /// Object `name` of `app`, as saved earlier if it is persistent
+    fn load(&self, app: &str, name: &str, persistent: bool) -> SharedObject {
+        let mut object = SharedObject {
+            persistent,
+            ..SharedObject::default()
+        };
+        let Some(path) = self.path(app, name).filter(|_| persistent) else {
+            return object;
+        };
+        let Ok(data) = std::fs::read(&path) else {
+            return object;
+        };
+        match command::decode_values(&data).as_deref() {
+            Ok([Value::Number(version), Value::EcmaArray { entries }]) => {
+                object.version = *version as u32;
+                object.properties = entries.clone();
+            }
+            _ => warn!("Ignoring invalid shared object file {}", path.display()),
+        }
+        object
+    }
+
+    /// Save a persistent object's version and properties
+    fn save(&self, app: &str, name: &str, data: &[u8]) {
+        let Some(path) = self.path(app, name) else {
+            return;
+        };
+        let written = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, data));
+        if let Err(e) = written {
+            warn!("Saving shared object {} failed: {}", path.display(), e);
+        }
+    }
+
+    /// File of object `name` in `app`; `None` when objects are not saved
+    /// or the name would leave the directory
+    fn path(&self, app: &str, name: &str) -> Option<PathBuf> {
+        let mut path = self.dir.clone()?;
+        for part in app.split('/').chain(name.split('/')) {
+            if part.is_empty() || part == "." || part == ".." || part.contains('\\') {
+                warn!("Not saving shared object '{}' of '{}': invalid name", name, app);
+                return None;
+            }
+            path.push(part);
+        }
+        let mut path = path.into_os_string();
+        path.push(".");
+        path.push(FILE_EXTENSION);
+        Some(path.into())
+    }
+}
+
+/// Read a string with a 16-bit length
+fn read_string(data: &mut &[u8]) -> Result<String> {
+    let length = u16::from_be_bytes(take(data, 2)?.try_into().unwrap()) as usize;
+    String::from_utf8(take(data, length)?.to_vec())
+        .map_err(|_| Error::Protocol("Shared object string is not UTF-8".to_string()))
+}
+
+/// Write a string with a 16-bit length
+fn write_string(out: &mut Vec<u8>, value: &str) {
+    out.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    out.extend_from_slice(value.as_bytes());
+}
+
+/// Split off the next `length` bytes
+fn take<'a>(data: &mut &'a [u8], length: usize) -> Result<&'a [u8]> {
+    if data.len() < length {
+        return Err(Error::Protocol("Truncated shared object message".to_string()));
+    }
+    let (head, rest) = data.split_at(length);
+    *data = rest;
+    Ok(head)
+}
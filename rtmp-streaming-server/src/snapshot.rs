@@ -0,0 +1,113 @@
+//! Live stream snapshots
+//!
+//! The latest cached keyframe of a stream is decoded together with its
+//! sequence header by a local ffmpeg and returned as a JPEG. Snapshots are
+//! cached per stream for the configured interval, so dashboards polling
+//! many thumbnails start at most one ffmpeg per stream and interval.
+
+use crate::error::{Error, Result};
+use crate::flv::FlvMuxer;
+use crate::stream::StreamManager;
+use bytes::Bytes;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tracing::debug;
+
+/// Upper bound for one ffmpeg decode
+const DECODE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Last snapshot of one stream; the lock is held while ffmpeg runs so
+/// concurrent requests share a single decode
+type Slot = Arc<tokio::sync::Mutex<Option<(Instant, Bytes)>>>;
+
+/// Recent snapshots, keyed by app and stream name
+#[derive(Clone, Default)]
+pub struct SnapshotCache {
+    slots: Arc<Mutex<HashMap<(String, String), Slot>>>,
+}
+
+impl SnapshotCache {
+    /// Create an empty snapshot cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// JPEG of the latest keyframe of `name` in `app`
+    ///
+    /// Fails with [`Error::NotFound`] when the stream does not exist or has
+    /// no keyframe yet.
+    pub async fn get(&self, streams: &StreamManager, app: &str, name: &str) -> Result<Bytes> {
+        let key = (app.to_string(), name.to_string());
+        let Some(stream) = streams.get_stream(app, name).await else {
+            self.slots.lock().unwrap().remove(&key);
+            return Err(Error::NotFound(format!("Stream '{}/{}' not found", app, name)));
+        };
+
+        let slot = self.slots.lock().unwrap().entry(key).or_default().clone();
+        let mut cached = slot.lock().await;
+        if let Some((taken, jpeg)) = cached.as_ref() {
+            if taken.elapsed() < streams.config().snapshot_interval() {
+                return Ok(jpeg.clone());
+            }
+        }
+
+        let Some((sequence_header, keyframe)) = stream.keyframe().await else {
+            return Err(Error::NotFound(format!("Stream '{}' has no keyframe yet", name)));
+        };
+        let mut muxer = FlvMuxer::new();
+        let mut input = muxer.header(&stream.flv_header()).to_vec();
+        for mut tag in [sequence_header, keyframe] {
+            tag.timestamp = 0;
+            input.extend_from_slice(&muxer.tag(&tag));
+        }
+
+        let jpeg = tokio::time::timeout(DECODE_TIMEOUT, decode(streams.config().ffmpeg(), &input))
+            .await
+            .map_err(|_| Error::Internal("ffmpeg timed out decoding the snapshot".to_string()))??;
+        debug!("Snapshot of {}: {} bytes", name, jpeg.len());
+        *cached = Some((Instant::now(), jpeg.clone()));
+        Ok(jpeg)
+    }
+}
+
+/// Decode the first video frame of an FLV buffer to JPEG with ffmpeg
+async fn decode(ffmpeg: &str, input: &[u8]) -> Result<Bytes> {
+    let mut child = Command::new(ffmpeg)
+        .args(["-hide_banner", "-loglevel", "error", "-f", "flv", "-i", "pipe:0"])
+        .args(["-frames:v", "1", "-f", "image2", "-c:v", "mjpeg", "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| Error::Internal(format!("Failed to start {}: {}", ffmpeg, e)))?;
+    let (Some(mut stdin), Some(mut stdout), Some(mut stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        return Err(Error::Internal("ffmpeg pipes unavailable".to_string()));
+    };
+
+    // ffmpeg may stop reading once it has its frame, so a failed write is
+    // judged by the exit status instead
+    let write = async move {
+        let _ = stdin.write_all(input).await;
+    };
+    let mut jpeg = Vec::new();
+    let mut errors = Vec::new();
+    let (_, read, _) = tokio::join!(write, stdout.read_to_end(&mut jpeg), stderr.read_to_end(&mut errors));
+    read?;
+
+    let status = child.wait().await?;
+    if !status.success() || jpeg.is_empty() {
+        return Err(Error::Internal(format!(
+            "ffmpeg snapshot failed ({}): {}",
+            status,
+            String::from_utf8_lossy(&errors).trim()
+        )));
+    }
+    Ok(Bytes::from(jpeg))
+}
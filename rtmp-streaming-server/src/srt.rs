@@ -0,0 +1,777 @@
+//! SRT ingest listener
+//!
+//! Encoders connect in caller mode and publish an MPEG-TS payload, e.g.
+//! `srt://host:9000?streamid=#!::r=live/cam,m=publish`. The `r` resource
+//! names the app and stream (`live` when it has no app part); only the
+//! `publish` mode is accepted. The transport stream is demuxed with
+//! [`TsDemuxer`] and fed into the shared [`StreamManager`], so RTMP and
+//! HTTP-FLV subscribers can watch it like any other stream.
+//!
+//! The listener speaks the HSv5 handshake without encryption. Received
+//! packets are reordered and lost ones requested again with NAKs; a gap
+//! that is still open after the negotiated latency is skipped.
+
+use crate::config::Access;
+use crate::error::Result;
+use crate::mpegts::TsDemuxer;
+use crate::stream::{Publisher, StreamManager};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Largest UDP payload we expect from a peer
+const MAX_PACKET_SIZE: usize = 1500;
+
+/// Packets buffered per connection before the listener drops them
+const CONNECTION_QUEUE: usize = 1024;
+
+/// Interval of full ACKs
+const ACK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Interval of periodic NAK reports for packets still missing
+const NAK_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A keepalive is sent when nothing else was sent for this long
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A connection without any packet from the peer for this long is closed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Receiver latency unless the caller asks for more
+const DEFAULT_LATENCY: Duration = Duration::from_millis(120);
+
+/// Packets further ahead of the next expected one are ignored
+const MAX_REORDER: i32 = 8192;
+
+/// Sequence numbers are 31 bits
+const SEQ_MASK: u32 = 0x7fff_ffff;
+
+/// Control packet types
+const CONTROL_HANDSHAKE: u16 = 0x0000;
+const CONTROL_KEEPALIVE: u16 = 0x0001;
+const CONTROL_ACK: u16 = 0x0002;
+const CONTROL_NAK: u16 = 0x0003;
+const CONTROL_SHUTDOWN: u16 = 0x0005;
+const CONTROL_ACKACK: u16 = 0x0006;
+
+/// Handshake types
+const HANDSHAKE_INDUCTION: u32 = 0x0000_0001;
+const HANDSHAKE_CONCLUSION: u32 = 0xffff_ffff;
+
+/// Extension field of an HSv5 induction response
+const SRT_MAGIC: u16 = 0x4a17;
+
+/// Handshake extension types
+const EXT_HSREQ: u16 = 1;
+const EXT_HSRSP: u16 = 2;
+const EXT_KMREQ: u16 = 3;
+const EXT_SID: u16 = 5;
+
+/// Extension flag announcing HSREQ/HSRSP in the conclusion
+const EXT_FLAG_HSREQ: u16 = 0x0001;
+
+/// SRT library version announced in HSRSP (1.5.0)
+const SRT_VERSION: u32 = 0x0001_0500;
+
+/// Capabilities announced in HSRSP: TSBPDSND, TSBPDRCV, TLPKTDROP,
+/// PERIODICNAK and REXMITFLG
+const SRT_FLAGS: u32 = 0x01 | 0x02 | 0x08 | 0x10 | 0x20;
+
+/// Handshake types at and above this value reject the connection
+const REJECTION_BASE: u32 = 1000;
+
+/// Rejection reasons: handshake protocol
+const REJ_ROGUE: u32 = 4;
+const REJ_VERSION: u32 = 8;
+const REJ_UNSECURE: u32 = 11;
+
+/// Rejection reasons: stream ID access control
+const REJX_BAD_REQUEST: u32 = 1400;
+const REJX_UNAUTHORIZED: u32 = 1401;
+const REJX_BAD_MODE: u32 = 1405;
+const REJX_CONFLICT: u32 = 1409;
+
+/// SRT ingest server
+pub struct SrtServer {
+    /// Address to bind to
+    address: SocketAddr,
+    /// Stream manager
+    stream_manager: StreamManager,
+}
+
+impl SrtServer {
+    /// Create a new SRT server
+    pub fn new(address: SocketAddr) -> Self {
+        Self {
+            address,
+            stream_manager: StreamManager::new(),
+        }
+    }
+
+    /// Share a stream manager with other servers (e.g. RTMP)
+    pub fn with_stream_manager(mut self, stream_manager: StreamManager) -> Self {
+        self.stream_manager = stream_manager;
+        self
+    }
+
+    /// Run the server
+    pub async fn run(self) -> Result<()> {
+        info!("Starting SRT server on {}", self.address);
+
+        let socket = UdpSocket::bind(self.address).await?;
+        self.serve(socket).await
+    }
+
+    /// Serve callers on an already bound socket
+    pub async fn serve(self, socket: UdpSocket) -> Result<()> {
+        info!("SRT listening on {}", socket.local_addr()?);
+
+        let socket = Arc::new(socket);
+        let secret: u64 = rand::random();
+        let mut connections: HashMap<SocketAddr, mpsc::Sender<Packet>> = HashMap::new();
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // e.g. ICMP port unreachable from a caller that went away
+                    debug!("SRT receive error: {}", e);
+                    continue;
+                }
+            };
+            let Some(packet) = Packet::parse(Bytes::copy_from_slice(&buf[..len])) else {
+                continue;
+            };
+
+            // Packets of established connections go to their task
+            let packet = match connections.get(&peer) {
+                Some(connection) => match connection.try_send(packet) {
+                    Ok(()) | Err(mpsc::error::TrySendError::Full(_)) => continue,
+                    Err(mpsc::error::TrySendError::Closed(packet)) => {
+                        connections.remove(&peer);
+                        packet
+                    }
+                },
+                None => packet,
+            };
+
+            let Packet::Control { kind: CONTROL_HANDSHAKE, cif, .. } = packet else {
+                continue;
+            };
+            let Some(handshake) = Handshake::parse(&cif) else {
+                debug!("Malformed SRT handshake from {}", peer);
+                continue;
+            };
+            let listener = Listener {
+                socket: &socket,
+                peer,
+                secret,
+            };
+            match handshake.kind {
+                HANDSHAKE_INDUCTION => listener.induction(&handshake).await,
+                HANDSHAKE_CONCLUSION => {
+                    connections.retain(|_, connection| !connection.is_closed());
+                    if let Some(connection) = listener.conclusion(&handshake, &self.stream_manager).await {
+                        connections.insert(peer, connection);
+                    }
+                }
+                kind => debug!("Unexpected SRT handshake type {:#x} from {}", kind, peer),
+            }
+        }
+    }
+}
+
+/// Handshake handling for a peer without a connection
+struct Listener<'a> {
+    socket: &'a Arc<UdpSocket>,
+    peer: SocketAddr,
+    secret: u64,
+}
+
+impl Listener<'_> {
+    /// Answer an induction with our cookie; no state is kept until the
+    /// caller comes back with it
+    async fn induction(&self, request: &Handshake) {
+        let response = Handshake {
+            version: 5,
+            encryption: 0,
+            extension: SRT_MAGIC,
+            isn: request.isn,
+            mtu: request.mtu,
+            flow_window: request.flow_window,
+            kind: HANDSHAKE_INDUCTION,
+            socket_id: self.socket_id(),
+            cookie: self.cookie(0),
+            peer_ip: self.peer.ip(),
+            extensions: Vec::new(),
+        };
+        self.send(request.socket_id, response.encode()).await;
+    }
+
+    /// Validate a conclusion and start publishing; returns the channel
+    /// feeding the new connection
+    async fn conclusion(&self, request: &Handshake, streams: &StreamManager) -> Option<mpsc::Sender<Packet>> {
+        if request.cookie != self.cookie(0) && request.cookie != self.cookie(1) {
+            return self.reject(request, REJ_ROGUE, "invalid cookie").await;
+        }
+        if request.version != 5 {
+            return self.reject(request, REJ_VERSION, "only HSv5 is supported").await;
+        }
+        if request.encryption != 0 || request.extension(EXT_KMREQ).is_some() {
+            return self.reject(request, REJ_UNSECURE, "encryption is not supported").await;
+        }
+
+        let stream_id = request.extension(EXT_SID).map(decode_stream_id).unwrap_or_default();
+        let (app, name, token) = match parse_stream_id(&stream_id) {
+            Ok(resource) => resource,
+            Err(code) => {
+                let reason = format!("unsupported stream ID '{}'", stream_id);
+                return self.reject(request, code, &reason).await;
+            }
+        };
+        if let Err(e) = streams.authorize(&app, Access::Publish, token.as_deref()) {
+            return self.reject(request, REJX_UNAUTHORIZED, &e.to_string()).await;
+        }
+
+        // HSREQ: SRT version, flags, receiver and sender TSBPD delay (ms)
+        let peer_latency = request
+            .extension(EXT_HSREQ)
+            .filter(|hsreq| hsreq.len() >= 12)
+            .map(|hsreq| Duration::from_millis(u16::from_be_bytes([hsreq[10], hsreq[11]]) as u64))
+            .unwrap_or_default();
+        let latency = peer_latency.max(DEFAULT_LATENCY);
+
+        let publisher_id = format!("srt-{}", uuid::Uuid::new_v4().simple());
+        let publisher = match streams.start_publish(&app, &name, publisher_id).await {
+            Ok(publisher) => publisher,
+            Err(e) => return self.reject(request, REJX_CONFLICT, &e.to_string()).await,
+        };
+        info!("SRT caller {} publishing {} (app '{}', latency {:?})", self.peer, name, app, latency);
+
+        let mut hsrsp = BytesMut::with_capacity(12);
+        hsrsp.put_u32(SRT_VERSION);
+        hsrsp.put_u32(SRT_FLAGS);
+        hsrsp.put_u16(latency.as_millis() as u16);
+        hsrsp.put_u16(latency.as_millis() as u16);
+        let response = Handshake {
+            version: 5,
+            encryption: 0,
+            extension: EXT_FLAG_HSREQ,
+            isn: request.isn,
+            mtu: request.mtu.min(MAX_PACKET_SIZE as u32),
+            flow_window: request.flow_window,
+            kind: HANDSHAKE_CONCLUSION,
+            socket_id: self.socket_id(),
+            cookie: request.cookie,
+            peer_ip: self.peer.ip(),
+            extensions: vec![(EXT_HSRSP, hsrsp.freeze())],
+        }
+        .encode();
+
+        let (sender, receiver) = mpsc::channel(CONNECTION_QUEUE);
+        let mut connection = Connection::new(self.socket.clone(), self.peer, request, latency, response);
+        connection.send_control(CONTROL_HANDSHAKE, 0, connection.response.clone()).await;
+        tokio::spawn(connection.run(receiver, publisher, name));
+        Some(sender)
+    }
+
+    /// Refuse the caller with `code`
+    async fn reject(&self, request: &Handshake, code: u32, reason: &str) -> Option<mpsc::Sender<Packet>> {
+        warn!("Rejected SRT caller {}: {}", self.peer, reason);
+        let response = Handshake {
+            version: 5,
+            encryption: 0,
+            extension: 0,
+            isn: request.isn,
+            mtu: request.mtu,
+            flow_window: request.flow_window,
+            kind: REJECTION_BASE + code,
+            socket_id: self.socket_id(),
+            cookie: request.cookie,
+            peer_ip: self.peer.ip(),
+            extensions: Vec::new(),
+        };
+        self.send(request.socket_id, response.encode()).await;
+        None
+    }
+
+    /// Stateless SYN cookie for the peer, `age` minutes back
+    fn cookie(&self, age: u64) -> u32 {
+        let minute = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs() / 60;
+        self.hash(minute - age) as u32
+    }
+
+    /// Our socket ID towards the peer, stable across handshake packets
+    fn socket_id(&self) -> u32 {
+        self.hash(u64::MAX) as u32 & 0x3fff_ffff
+    }
+
+    fn hash(&self, salt: u64) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (self.secret, self.peer, salt).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    async fn send(&self, dest: u32, cif: Bytes) {
+        let packet = control_packet(CONTROL_HANDSHAKE, 0, 0, dest, &cif);
+        if let Err(e) = self.socket.send_to(&packet, self.peer).await {
+            debug!("SRT send to {} failed: {}", self.peer, e);
+        }
+    }
+}
+
+/// One publishing caller
+struct Connection {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    peer_socket_id: u32,
+    /// Origin of packet timestamps
+    start: Instant,
+    /// How long a gap is waited for before it is skipped
+    latency: Duration,
+    /// Conclusion response, repeated if the caller did not receive it
+    response: Bytes,
+    /// Next sequence number to hand to the demuxer
+    next_seq: u32,
+    /// Highest sequence number received
+    last_seq: u32,
+    /// Packets received ahead of a gap, with their arrival time
+    pending: HashMap<u32, (Instant, Bytes)>,
+    /// Last full ACK number and the sequence number it acknowledged
+    ack_number: u32,
+    acked_seq: u32,
+    /// Send times of recent ACKs, for RTT from ACKACKs
+    acks: VecDeque<(u32, Instant)>,
+    rtt: Duration,
+    rtt_var: Duration,
+    last_sent: Instant,
+}
+
+impl Connection {
+    fn new(socket: Arc<UdpSocket>, peer: SocketAddr, request: &Handshake, latency: Duration, response: Bytes) -> Self {
+        let isn = request.isn & SEQ_MASK;
+        Self {
+            socket,
+            peer,
+            peer_socket_id: request.socket_id,
+            start: Instant::now(),
+            latency,
+            response,
+            next_seq: isn,
+            last_seq: isn.wrapping_sub(1) & SEQ_MASK,
+            pending: HashMap::new(),
+            ack_number: 0,
+            acked_seq: isn,
+            acks: VecDeque::new(),
+            rtt: Duration::from_millis(100),
+            rtt_var: Duration::from_millis(50),
+            last_sent: Instant::now(),
+        }
+    }
+
+    /// Receive until the caller leaves, goes quiet or is evicted
+    async fn run(mut self, mut packets: mpsc::Receiver<Packet>, mut publisher: Publisher, name: String) {
+        let mut demuxer = TsDemuxer::new();
+        let mut ack = tokio::time::interval(ACK_INTERVAL);
+        let mut nak = tokio::time::interval(NAK_INTERVAL);
+        let mut last_received = Instant::now();
+
+        loop {
+            tokio::select! {
+                packet = packets.recv() => {
+                    let Some(packet) = packet else { break };
+                    last_received = Instant::now();
+                    match packet {
+                        Packet::Data { seq, payload } => {
+                            let payloads = self.receive(seq, payload).await;
+                            if let Err(e) = deliver(&mut demuxer, &publisher, payloads).await {
+                                warn!("SRT publisher [{}]: {}", name, e);
+                                self.send_control(CONTROL_SHUTDOWN, 0, Bytes::new()).await;
+                                break;
+                            }
+                        }
+                        Packet::Control { kind: CONTROL_HANDSHAKE, .. } => {
+                            self.send_control(CONTROL_HANDSHAKE, 0, self.response.clone()).await;
+                        }
+                        Packet::Control { kind: CONTROL_ACKACK, info, .. } => self.ackack(info),
+                        Packet::Control { kind: CONTROL_SHUTDOWN, .. } => {
+                            info!("SRT caller {} closed {}", self.peer, name);
+                            if let Err(e) = finish(&mut demuxer, &publisher).await {
+                                debug!("SRT publisher [{}]: {}", name, e);
+                            }
+                            break;
+                        }
+                        Packet::Control { kind, .. } => debug!("SRT control packet {:#x} from {}", kind, self.peer),
+                    }
+                }
+                _ = ack.tick() => {
+                    if last_received.elapsed() > IDLE_TIMEOUT {
+                        warn!("SRT caller {} timed out on {}", self.peer, name);
+                        break;
+                    }
+                    let payloads = self.skip_late();
+                    if let Err(e) = deliver(&mut demuxer, &publisher, payloads).await {
+                        warn!("SRT publisher [{}]: {}", name, e);
+                        self.send_control(CONTROL_SHUTDOWN, 0, Bytes::new()).await;
+                        break;
+                    }
+                    self.ack().await;
+                }
+                _ = nak.tick() => {
+                    let lost = self.missing();
+                    if !lost.is_empty() {
+                        self.send_control(CONTROL_NAK, 0, encode_loss_list(&lost)).await;
+                    }
+                }
+                _ = publisher.evicted() => {
+                    info!("SRT publisher [{}]: replaced by a new publisher", name);
+                    self.send_control(CONTROL_SHUTDOWN, 0, Bytes::new()).await;
+                    break;
+                }
+            }
+        }
+        // Publisher gone: the stream is dropped after the app's grace period
+    }
+
+    /// Store a data packet; returns the payloads now in order. A gap
+    /// before it is reported at once with a NAK.
+    async fn receive(&mut self, seq: u32, payload: Bytes) -> Vec<Bytes> {
+        let offset = seq_offset(self.next_seq, seq);
+        if !(0..MAX_REORDER).contains(&offset) {
+            return Vec::new();
+        }
+        let ahead = seq_offset(self.last_seq, seq);
+        if ahead > 1 {
+            let first = (self.last_seq + 1) & SEQ_MASK;
+            let last = seq.wrapping_sub(1) & SEQ_MASK;
+            self.send_control(CONTROL_NAK, 0, encode_loss_list(&[(first, last)])).await;
+        }
+        if ahead > 0 {
+            self.last_seq = seq;
+        }
+        self.pending.insert(seq, (Instant::now(), payload));
+        self.drain()
+    }
+
+    /// Take the packets that follow `next_seq` without a gap
+    fn drain(&mut self) -> Vec<Bytes> {
+        let mut payloads = Vec::new();
+        while let Some((_, payload)) = self.pending.remove(&self.next_seq) {
+            payloads.push(payload);
+            self.next_seq = (self.next_seq + 1) & SEQ_MASK;
+        }
+        payloads
+    }
+
+    /// Give up on gaps older than the latency
+    fn skip_late(&mut self) -> Vec<Bytes> {
+        let mut payloads = Vec::new();
+        loop {
+            let oldest = self.pending.iter().min_by_key(|(&seq, _)| seq_offset(self.next_seq, seq));
+            let Some((&seq, &(arrived, _))) = oldest else { break };
+            if arrived.elapsed() < self.latency {
+                break;
+            }
+            warn!(
+                "SRT caller {}: dropped {} lost packet(s)",
+                self.peer,
+                seq_offset(self.next_seq, seq)
+            );
+            self.next_seq = seq;
+            payloads.extend(self.drain());
+        }
+        payloads
+    }
+
+    /// Ranges of sequence numbers still missing before `last_seq`
+    fn missing(&self) -> Vec<(u32, u32)> {
+        let mut ranges: Vec<(u32, u32)> = Vec::new();
+        let mut seq = self.next_seq;
+        while seq_offset(seq, self.last_seq) >= 0 {
+            if !self.pending.contains_key(&seq) {
+                match ranges.last_mut() {
+                    Some((_, last)) if (*last + 1) & SEQ_MASK == seq => *last = seq,
+                    _ => ranges.push((seq, seq)),
+                }
+            }
+            seq = (seq + 1) & SEQ_MASK;
+        }
+        ranges
+    }
+
+    /// Full ACK of everything before `next_seq`, if it moved
+    async fn ack(&mut self) {
+        if self.next_seq == self.acked_seq {
+            if self.last_sent.elapsed() >= KEEPALIVE_INTERVAL {
+                self.send_control(CONTROL_KEEPALIVE, 0, Bytes::new()).await;
+            }
+            return;
+        }
+        self.acked_seq = self.next_seq;
+        self.ack_number = self.ack_number.wrapping_add(1);
+        self.acks.push_back((self.ack_number, Instant::now()));
+        if self.acks.len() > 64 {
+            self.acks.pop_front();
+        }
+
+        let mut cif = BytesMut::with_capacity(28);
+        cif.put_u32(self.next_seq);
+        cif.put_u32(self.rtt.as_micros() as u32);
+        cif.put_u32(self.rtt_var.as_micros() as u32);
+        cif.put_u32((MAX_REORDER as usize - self.pending.len()) as u32);
+        // Receive rate and link capacity estimates are not measured
+        cif.put_u32(0);
+        cif.put_u32(0);
+        cif.put_u32(0);
+        self.send_control(CONTROL_ACK, self.ack_number, cif.freeze()).await;
+    }
+
+    /// Update the RTT estimate from the ACKACK of `ack_number`
+    fn ackack(&mut self, ack_number: u32) {
+        let Some(&(_, sent)) = self.acks.iter().find(|(number, _)| *number == ack_number) else {
+            return;
+        };
+        let sample = sent.elapsed();
+        let deviation = sample.abs_diff(self.rtt);
+        self.rtt = (self.rtt * 7 + sample) / 8;
+        self.rtt_var = (self.rtt_var * 3 + deviation) / 4;
+    }
+
+    async fn send_control(&mut self, kind: u16, info: u32, cif: Bytes) {
+        let timestamp = self.start.elapsed().as_micros() as u32;
+        let packet = control_packet(kind, info, timestamp, self.peer_socket_id, &cif);
+        if let Err(e) = self.socket.send_to(&packet, self.peer).await {
+            debug!("SRT send to {} failed: {}", self.peer, e);
+        }
+        self.last_sent = Instant::now();
+    }
+}
+
+/// Demux transport stream payloads and publish the resulting tags
+async fn deliver(demuxer: &mut TsDemuxer, publisher: &Publisher, payloads: Vec<Bytes>) -> Result<()> {
+    for payload in payloads {
+        demuxer.push(&payload);
+        while let Some(tag) = demuxer.next_tag()? {
+            publisher.publish(tag).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Publish what the demuxer still holds once the caller has closed
+async fn finish(demuxer: &mut TsDemuxer, publisher: &Publisher) -> Result<()> {
+    demuxer.flush()?;
+    while let Some(tag) = demuxer.next_tag()? {
+        publisher.publish(tag).await?;
+    }
+    Ok(())
+}
+
+/// Signed distance from `from` to `to` on the 31-bit sequence circle
+fn seq_offset(from: u32, to: u32) -> i32 {
+    // Sign-extend the 31-bit difference
+    ((to.wrapping_sub(from) << 1) as i32) >> 1
+}
+
+/// NAK loss list: single numbers, or ranges with the first one flagged
+fn encode_loss_list(ranges: &[(u32, u32)]) -> Bytes {
+    let mut cif = BytesMut::with_capacity(ranges.len() * 8);
+    for &(first, last) in ranges {
+        if first == last {
+            cif.put_u32(first);
+        } else {
+            cif.put_u32(first | 0x8000_0000);
+            cif.put_u32(last);
+        }
+    }
+    cif.freeze()
+}
+
+/// Stream ID from an SID extension: 32-bit words with their bytes reversed
+fn decode_stream_id(content: &Bytes) -> String {
+    let bytes: Vec<u8> = content
+        .chunks(4)
+        .flat_map(|word| word.iter().rev().copied())
+        .filter(|&b| b != 0)
+        .collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// App, stream name and token of a publish request, or a rejection reason
+///
+/// Accepts the access control syntax (`#!::r=app/stream,m=publish`, with
+/// an optional `token` key) and a plain `app/stream` resource.
+fn parse_stream_id(stream_id: &str) -> std::result::Result<(String, String, Option<String>), u32> {
+    let (resource, mode, token) = match stream_id.strip_prefix("#!::") {
+        Some(pairs) => {
+            let mut resource = None;
+            let mut mode = None;
+            let mut token = None;
+            for pair in pairs.split(',') {
+                match pair.split_once('=') {
+                    Some(("r", value)) => resource = Some(value),
+                    Some(("m", value)) => mode = Some(value),
+                    Some(("token", value)) => token = Some(value.to_string()),
+                    Some(_) => {}
+                    None => return Err(REJX_BAD_REQUEST),
+                }
+            }
+            (resource.ok_or(REJX_BAD_REQUEST)?, mode.unwrap_or("publish"), token)
+        }
+        None => (stream_id, "publish", None),
+    };
+    if mode != "publish" {
+        return Err(REJX_BAD_MODE);
+    }
+    let (app, name) = resource.trim_matches('/').rsplit_once('/').unwrap_or(("live", resource));
+    // Only the default host's apps are reachable over SRT
+    if name.is_empty() || app.is_empty() || app.contains('/') {
+        return Err(REJX_BAD_REQUEST);
+    }
+    Ok((app.to_string(), name.to_string(), token))
+}
+
+/// A received SRT packet
+#[derive(Debug)]
+enum Packet {
+    Data { seq: u32, payload: Bytes },
+    Control { kind: u16, info: u32, cif: Bytes },
+}
+
+impl Packet {
+    fn parse(mut packet: Bytes) -> Option<Self> {
+        if packet.len() < 16 {
+            return None;
+        }
+        let first = packet.get_u32();
+        let info = packet.get_u32();
+        let _timestamp = packet.get_u32();
+        let _dest_socket_id = packet.get_u32();
+        Some(if first & 0x8000_0000 == 0 {
+            Packet::Data {
+                seq: first,
+                payload: packet,
+            }
+        } else {
+            Packet::Control {
+                kind: ((first >> 16) & 0x7fff) as u16,
+                info,
+                cif: packet,
+            }
+        })
+    }
+}
+
+/// Control packet with header
+fn control_packet(kind: u16, info: u32, timestamp: u32, dest: u32, cif: &[u8]) -> Bytes {
+    let mut packet = BytesMut::with_capacity(16 + cif.len());
+    packet.put_u32(0x8000_0000 | (kind as u32) << 16);
+    packet.put_u32(info);
+    packet.put_u32(timestamp);
+    packet.put_u32(dest);
+    packet.put_slice(cif);
+    packet.freeze()
+}
+
+/// Handshake control information
+#[derive(Debug)]
+struct Handshake {
+    version: u32,
+    encryption: u16,
+    extension: u16,
+    isn: u32,
+    mtu: u32,
+    flow_window: u32,
+    kind: u32,
+    socket_id: u32,
+    cookie: u32,
+    peer_ip: IpAddr,
+    extensions: Vec<(u16, Bytes)>,
+}
+
+impl Handshake {
+    fn parse(cif: &Bytes) -> Option<Self> {
+        if cif.len() < 48 {
+            return None;
+        }
+        let mut cif = cif.clone();
+        let version = cif.get_u32();
+        let encryption = cif.get_u16();
+        let extension = cif.get_u16();
+        let isn = cif.get_u32();
+        let mtu = cif.get_u32();
+        let flow_window = cif.get_u32();
+        let kind = cif.get_u32();
+        let socket_id = cif.get_u32();
+        let cookie = cif.get_u32();
+        let mut ip = [0u8; 16];
+        cif.copy_to_slice(&mut ip);
+
+        let mut extensions = Vec::new();
+        while cif.len() >= 4 {
+            let ext_type = cif.get_u16();
+            let len = cif.get_u16() as usize * 4;
+            if cif.len() < len {
+                return None;
+            }
+            extensions.push((ext_type, cif.split_to(len)));
+        }
+
+        Some(Self {
+            version,
+            encryption,
+            extension,
+            isn,
+            mtu,
+            flow_window,
+            kind,
+            socket_id,
+            cookie,
+            peer_ip: IpAddr::from(ip),
+            extensions,
+        })
+    }
+
+    fn extension(&self, ext_type: u16) -> Option<&Bytes> {
+        self.extensions.iter().find(|(t, _)| *t == ext_type).map(|(_, content)| content)
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut cif = BytesMut::with_capacity(48);
+        cif.put_u32(self.version);
+        cif.put_u16(self.encryption);
+        cif.put_u16(self.extension);
+        cif.put_u32(self.isn);
+        cif.put_u32(self.mtu);
+        cif.put_u32(self.flow_window);
+        cif.put_u32(self.kind);
+        cif.put_u32(self.socket_id);
+        cif.put_u32(self.cookie);
+        // Peer address as 32-bit words in host order, as libsrt sends it
+        let ip = match self.peer_ip {
+            IpAddr::V4(ip) => {
+                let mut words = [0u8; 16];
+                words[..4].copy_from_slice(&ip.octets());
+                words
+            }
+            IpAddr::V6(ip) => ip.octets(),
+        };
+        for word in ip.chunks(4) {
+            cif.put_slice(&[word[3], word[2], word[1], word[0]]);
+        }
+        for (ext_type, content) in &self.extensions {
+            cif.put_u16(*ext_type);
+            cif.put_u16((content.len() / 4) as u16);
+            cif.put_slice(content);
+        }
+        cif.freeze()
+    }
+}
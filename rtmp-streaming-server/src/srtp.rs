@@ -0,0 +1,318 @@
+//! SRTP and SRTCP with `AES_CM_128_HMAC_SHA1_80` (RFC 3711)
+//!
+//! WebRTC sessions get their master keys from the DTLS-SRTP handshake;
+//! each direction of a session then has its own [`SrtpContext`]. The key
+//! derivation rate is 0, so session keys never change.
+
+use crate::error::{Error, Result};
+use bytes::{BufMut, Bytes, BytesMut};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::symm::{encrypt, Cipher};
+use std::collections::HashMap;
+
+/// Master key length of the profile
+pub const MASTER_KEY_LEN: usize = 16;
+
+/// Master salt length of the profile
+pub const MASTER_SALT_LEN: usize = 14;
+
+/// Truncated HMAC-SHA1 tag appended to every packet
+const AUTH_TAG_LEN: usize = 10;
+
+/// Session authentication key length
+const AUTH_KEY_LEN: usize = 20;
+
+/// Key derivation labels (RFC 3711 section 4.3.2)
+const LABEL_RTP_CIPHER: u8 = 0;
+const LABEL_RTP_AUTH: u8 = 1;
+const LABEL_RTP_SALT: u8 = 2;
+const LABEL_RTCP_CIPHER: u8 = 3;
+const LABEL_RTCP_AUTH: u8 = 4;
+const LABEL_RTCP_SALT: u8 = 5;
+
+/// SRTCP packets carry the encryption flag in the top bit of their index
+const SRTCP_E_FLAG: u32 = 0x8000_0000;
+
+/// Session keys derived from a master key and salt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SessionKeys {
+    /// AES-128 key of the keystream
+    pub cipher_key: [u8; 16],
+    /// Salt mixed into every packet IV
+    pub cipher_salt: [u8; 14],
+    /// HMAC-SHA1 key of the authentication tag
+    pub auth_key: [u8; AUTH_KEY_LEN],
+}
+
+impl SessionKeys {
+    /// Derive the SRTP keys, or the SRTCP keys if `rtcp`
+    pub fn derive(master_key: &[u8], master_salt: &[u8], rtcp: bool) -> Result<Self> {
+        if master_key.len() != MASTER_KEY_LEN || master_salt.len() != MASTER_SALT_LEN {
+            return Err(Error::InvalidInput("SRTP master key or salt of the wrong size".to_string()));
+        }
+        let labels = match rtcp {
+            false => [LABEL_RTP_CIPHER, LABEL_RTP_AUTH, LABEL_RTP_SALT],
+            true => [LABEL_RTCP_CIPHER, LABEL_RTCP_AUTH, LABEL_RTCP_SALT],
+        };
+        let mut keys = Self {
+            cipher_key: [0; 16],
+            cipher_salt: [0; 14],
+            auth_key: [0; AUTH_KEY_LEN],
+        };
+        keys.cipher_key.copy_from_slice(&prf(master_key, master_salt, labels[0], 16)?);
+        keys.auth_key.copy_from_slice(&prf(master_key, master_salt, labels[1], AUTH_KEY_LEN)?);
+        keys.cipher_salt.copy_from_slice(&prf(master_key, master_salt, labels[2], 14)?);
+        Ok(keys)
+    }
+}
+
+/// AES-CM key derivation with a zero packet index
+fn prf(master_key: &[u8], master_salt: &[u8], label: u8, len: usize) -> Result<Vec<u8>> {
+    let mut iv = [0u8; 16];
+    iv[..MASTER_SALT_LEN].copy_from_slice(master_salt);
+    iv[7] ^= label;
+    keystream(master_key, &iv, &vec![0; len])
+}
+
+/// AES-128 in counter mode, which encrypts and decrypts alike
+fn keystream(key: &[u8], iv: &[u8; 16], data: &[u8]) -> Result<Vec<u8>> {
+    encrypt(Cipher::aes_128_ctr(), key, Some(iv), data).map_err(|e| Error::Internal(format!("AES-CM: {}", e)))
+}
+
+/// One direction of an SRTP session
+pub struct SrtpContext {
+    rtp: SessionKeys,
+    rtcp: SessionKeys,
+    rtp_auth: PKey<Private>,
+    rtcp_auth: PKey<Private>,
+    /// Rollover counter and highest sequence number per SSRC
+    rollover: HashMap<u32, (u32, u16)>,
+    /// Index of the next SRTCP packet sent
+    rtcp_index: u32,
+}
+
+impl SrtpContext {
+    /// Context for a master key and salt from the key exchange
+    pub fn new(master_key: &[u8], master_salt: &[u8]) -> Result<Self> {
+        let rtp = SessionKeys::derive(master_key, master_salt, false)?;
+        let rtcp = SessionKeys::derive(master_key, master_salt, true)?;
+        let hmac_key = |key: &[u8]| PKey::hmac(key).map_err(|e| Error::Internal(format!("HMAC key: {}", e)));
+        Ok(Self {
+            rtp_auth: hmac_key(&rtp.auth_key)?,
+            rtcp_auth: hmac_key(&rtcp.auth_key)?,
+            rtp,
+            rtcp,
+            rollover: HashMap::new(),
+            rtcp_index: 0,
+        })
+    }
+
+    /// Encrypt and authenticate an RTP packet
+    pub fn protect_rtp(&mut self, packet: &[u8]) -> Result<Bytes> {
+        let header_len = rtp_header_len(packet)?;
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let (roc, highest) = self.rollover.entry(ssrc).or_insert((0, seq));
+        if seq < *highest && *highest - seq > 0x8000 {
+            *roc = roc.wrapping_add(1);
+        }
+        if seq.wrapping_sub(*highest) < 0x8000 {
+            *highest = seq;
+        }
+        let index = (*roc as u64) << 16 | seq as u64;
+
+        let payload = keystream(&self.rtp.cipher_key, &rtp_iv(&self.rtp.cipher_salt, ssrc, index), &packet[header_len..])?;
+        let mut out = BytesMut::with_capacity(packet.len() + AUTH_TAG_LEN);
+        out.put_slice(&packet[..header_len]);
+        out.put_slice(&payload);
+        let tag = auth_tag(&self.rtp_auth, &[&out, &((index >> 16) as u32).to_be_bytes()])?;
+        out.put_slice(&tag);
+        Ok(out.freeze())
+    }
+
+    /// Authenticate and decrypt an SRTP packet
+    pub fn unprotect_rtp(&mut self, packet: &[u8]) -> Result<Bytes> {
+        if packet.len() < 12 + AUTH_TAG_LEN {
+            return Err(Error::Protocol("SRTP packet too short".to_string()));
+        }
+        let (authenticated, tag) = packet.split_at(packet.len() - AUTH_TAG_LEN);
+        let header_len = rtp_header_len(authenticated)?;
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+
+        // Guess the rollover counter from the highest sequence number seen
+        let (roc, highest) = self.rollover.get(&ssrc).copied().unwrap_or((0, seq));
+        let guess = if highest < 0x8000 {
+            if seq > highest && seq - highest > 0x8000 {
+                roc.wrapping_sub(1)
+            } else {
+                roc
+            }
+        } else if highest - 0x8000 > seq {
+            roc.wrapping_add(1)
+        } else {
+            roc
+        };
+        let expected = auth_tag(&self.rtp_auth, &[authenticated, &guess.to_be_bytes()])?;
+        if !openssl::memcmp::eq(&expected, tag) {
+            return Err(Error::Protocol("SRTP authentication failed".to_string()));
+        }
+        if guess != roc || seq.wrapping_sub(highest) < 0x8000 {
+            self.rollover.insert(ssrc, (guess, seq));
+        }
+
+        let index = (guess as u64) << 16 | seq as u64;
+        let iv = rtp_iv(&self.rtp.cipher_salt, ssrc, index);
+        let payload = keystream(&self.rtp.cipher_key, &iv, &authenticated[header_len..])?;
+        let mut out = BytesMut::with_capacity(authenticated.len());
+        out.put_slice(&authenticated[..header_len]);
+        out.put_slice(&payload);
+        Ok(out.freeze())
+    }
+
+    /// Encrypt and authenticate an RTCP (compound) packet
+    pub fn protect_rtcp(&mut self, packet: &[u8]) -> Result<Bytes> {
+        if packet.len() < 8 {
+            return Err(Error::InvalidInput("RTCP packet too short".to_string()));
+        }
+        let ssrc = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let index = self.rtcp_index;
+        self.rtcp_index = (self.rtcp_index + 1) & !SRTCP_E_FLAG;
+
+        let iv = rtp_iv(&self.rtcp.cipher_salt, ssrc, index as u64);
+        let payload = keystream(&self.rtcp.cipher_key, &iv, &packet[8..])?;
+        let mut out = BytesMut::with_capacity(packet.len() + 4 + AUTH_TAG_LEN);
+        out.put_slice(&packet[..8]);
+        out.put_slice(&payload);
+        out.put_u32(SRTCP_E_FLAG | index);
+        let tag = auth_tag(&self.rtcp_auth, &[&out])?;
+        out.put_slice(&tag);
+        Ok(out.freeze())
+    }
+
+    /// Authenticate and decrypt an SRTCP packet
+    pub fn unprotect_rtcp(&mut self, packet: &[u8]) -> Result<Bytes> {
+        if packet.len() < 8 + 4 + AUTH_TAG_LEN {
+            return Err(Error::Protocol("SRTCP packet too short".to_string()));
+        }
+        let (authenticated, tag) = packet.split_at(packet.len() - AUTH_TAG_LEN);
+        let expected = auth_tag(&self.rtcp_auth, &[authenticated])?;
+        if !openssl::memcmp::eq(&expected, tag) {
+            return Err(Error::Protocol("SRTCP authentication failed".to_string()));
+        }
+        let (body, trailer) = authenticated.split_at(authenticated.len() - 4);
+        let e_index = u32::from_be_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        if e_index & SRTCP_E_FLAG == 0 {
+            return Ok(Bytes::copy_from_slice(body));
+        }
+        let ssrc = u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]);
+        let iv = rtp_iv(&self.rtcp.cipher_salt, ssrc, (e_index & !SRTCP_E_FLAG) as u64);
+        let payload = keystream(&self.rtcp.cipher_key, &iv, &body[8..])?;
+        let mut out = BytesMut::with_capacity(body.len());
+        out.put_slice(&body[..8]);
+        out.put_slice(&payload);
+        Ok(out.freeze())
+    }
+}
+
+/// Length of the RTP header, with CSRCs and extension
+fn rtp_header_len(packet: &[u8]) -> Result<usize> {
+    let invalid = || Error::Protocol("Invalid RTP header".to_string());
+    if packet.len() < 12 || packet[0] >> 6 != 2 {
+        return Err(invalid());
+    }
+    let mut len = 12 + 4 * (packet[0] & 0x0f) as usize;
+    if packet[0] & 0x10 != 0 {
+        let words = packet.get(len + 2..len + 4).ok_or_else(invalid)?;
+        len += 4 + 4 * u16::from_be_bytes([words[0], words[1]]) as usize;
+    }
+    if len > packet.len() {
+        return Err(invalid());
+    }
+    Ok(len)
+}
+
+/// Packet IV: salt, SSRC and 48-bit index combined (RFC 3711 section 4.1.1)
+fn rtp_iv(salt: &[u8; 14], ssrc: u32, index: u64) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    iv[..14].copy_from_slice(salt);
+    for (byte, value) in iv[4..8].iter_mut().zip(ssrc.to_be_bytes()) {
+        *byte ^= value;
+    }
+    for (byte, value) in iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]) {
+        *byte ^= value;
+    }
+    iv
+}
+
+/// HMAC-SHA1 over `parts`, truncated to 80 bits
+fn auth_tag(key: &PKey<Private>, parts: &[&[u8]]) -> Result<Vec<u8>> {
+    let hmac = || -> std::result::Result<Vec<u8>, openssl::error::ErrorStack> {
+        let mut signer = Signer::new(MessageDigest::sha1(), key)?;
+        for part in parts {
+            signer.update(part)?;
+        }
+        signer.sign_to_vec()
+    };
+    let mut tag = hmac().map_err(|e| Error::Internal(format!("HMAC-SHA1: {}", e)))?;
+    tag.truncate(AUTH_TAG_LEN);
+    Ok(tag)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn rtp(seq: u16, payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0x80, 96];
+        packet.extend_from_slice(&seq.to_be_bytes());
+        packet.extend_from_slice(&1000u32.to_be_bytes());
+        packet.extend_from_slice(&42u32.to_be_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    #[test]
+    fn test_key_derivation() {
+        // RFC 3711 appendix B.3
+        let master_key = hex("E1F97A0D3E018BE0D64FA32C06DE4139");
+        let master_salt = hex("0EC675AD498AFEEBB6960B3AABE6");
+        let keys = SessionKeys::derive(&master_key, &master_salt, false).unwrap();
+        assert_eq!(keys.cipher_key.to_vec(), hex("C61E7A93744F39EE10734AFE3FF7A087"));
+        assert_eq!(keys.cipher_salt.to_vec(), hex("30CBBC08863D8C85D49DB34A9AE1"));
+        assert_eq!(keys.auth_key.to_vec(), hex("CEBE321F6FF7716B6FD4AB49AF256A156D38BAA4"));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let master_key = hex("E1F97A0D3E018BE0D64FA32C06DE4139");
+        let master_salt = hex("0EC675AD498AFEEBB6960B3AABE6");
+
+        // Packets survive a round trip across a rollover, and a flipped
+        // bit is caught
+        let mut sender = SrtpContext::new(&master_key, &master_salt).unwrap();
+        let mut receiver = SrtpContext::new(&master_key, &master_salt).unwrap();
+        for seq in [65534u16, 65535, 0, 1] {
+            let packet = rtp(seq, b"payload");
+            let protected = sender.protect_rtp(&packet).unwrap();
+            assert_ne!(&protected[12..19], b"payload");
+            assert_eq!(receiver.unprotect_rtp(&protected).unwrap().to_vec(), packet);
+        }
+        let mut protected = sender.protect_rtp(&rtp(2, b"payload")).unwrap().to_vec();
+        protected[13] ^= 1;
+        assert!(receiver.unprotect_rtp(&protected).is_err());
+
+        let report = [0x81, 201, 0, 1, 0, 0, 0, 42];
+        let protected = sender.protect_rtcp(&report).unwrap();
+        assert_eq!(receiver.unprotect_rtcp(&protected).unwrap().to_vec(), report);
+    }
+}
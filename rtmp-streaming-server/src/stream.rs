@@ -1,11 +1,32 @@
 //! Stream management
 
+use crate::config::{Access, Config, PublisherPolicy};
+use crate::edge::EdgeManager;
+use crate::events::{Event, EventBus};
 use crate::error::{Error, Result};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use crate::flv::{FlvHeader, FlvTag, TagType};
+use crate::hls::HlsManager;
+use crate::limits::{Limiter, PublishGuard};
+use crate::recording::RecordingManager;
+use crate::relay::RelayManager;
+use crate::rtsp::RtspManager;
+use crate::session::SessionRegistry;
+use crate::shared_object::SharedObjects;
+use crate::snapshot::SnapshotCache;
+use crate::timeshift::{Cursor, Next, TimeshiftBuffer};
+use crate::transcode::TranscodeManager;
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, watch, RwLock};
 use tracing::{debug, info, warn};
 
+/// Capacity of the per-stream broadcast channel, in tags
+const CHANNEL_CAPACITY: usize = 1024;
+
 /// Stream data
 #[derive(Debug, Clone)]
 pub struct StreamData {
@@ -19,6 +40,16 @@ pub struct StreamData {
     pub created_at: std::time::SystemTime,
     /// Last activity timestamp
     pub last_activity: std::time::SystemTime,
+    /// Current publisher ID
+    pub publisher: Option<String>,
+    /// Last onMetaData script tag
+    pub metadata_tag: Option<FlvTag>,
+    /// Last video sequence header (AVC/HEVC decoder configuration)
+    pub video_seq: Option<FlvTag>,
+    /// Last audio sequence header (AAC AudioSpecificConfig)
+    pub audio_seq: Option<FlvTag>,
+    /// Last video keyframe
+    pub keyframe: Option<FlvTag>,
 }
 
 impl StreamData {
@@ -31,6 +62,11 @@ impl StreamData {
             metadata: HashMap::new(),
             created_at: now,
             last_activity: now,
+            publisher: None,
+            metadata_tag: None,
+            video_seq: None,
+            audio_seq: None,
+            keyframe: None,
         }
     }
 
@@ -46,6 +82,82 @@ impl StreamData {
             Err(_) => false,
         }
     }
+
+    /// Cached metadata and sequence headers
+    pub fn sequence_headers(&self) -> Vec<FlvTag> {
+        [&self.metadata_tag, &self.video_seq, &self.audio_seq]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+/// How a subscriber recovers when it falls behind the publisher
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LagPolicy {
+    /// Disconnect after lagging more often than this (never if `None`)
+    pub max_lag_events: Option<u32>,
+    /// Re-send the cached sequence headers before the resync keyframe
+    pub resend_sequence_headers: bool,
+}
+
+impl Default for LagPolicy {
+    fn default() -> Self {
+        Self {
+            max_lag_events: None,
+            resend_sequence_headers: true,
+        }
+    }
+}
+
+/// Snapshot of a subscriber's lag counters
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubscriberStats {
+    /// Subscriber ID
+    pub id: String,
+    /// Times the subscriber fell behind the channel
+    pub lag_events: u64,
+    /// Tags overwritten in the channel before the subscriber read them
+    pub dropped_tags: u64,
+    /// Tags discarded while waiting for a keyframe
+    pub skipped_tags: u64,
+}
+
+/// Live lag counters shared between a subscription and its stream
+#[derive(Debug, Default)]
+struct LagCounters {
+    lag_events: AtomicU64,
+    dropped_tags: AtomicU64,
+    skipped_tags: AtomicU64,
+}
+
+/// A registered subscriber
+#[derive(Debug)]
+struct SubscriberEntry {
+    id: String,
+    counters: Arc<LagCounters>,
+}
+
+impl LagCounters {
+    fn stats(&self, id: &str) -> SubscriberStats {
+        SubscriberStats {
+            id: id.to_string(),
+            lag_events: self.lag_events.load(Ordering::Relaxed),
+            dropped_tags: self.dropped_tags.load(Ordering::Relaxed),
+            skipped_tags: self.skipped_tags.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Publishers currently feeding a stream
+#[derive(Debug, Default)]
+struct PublisherSlots {
+    /// Publisher IDs with their eviction signal
+    active: Vec<(String, watch::Sender<bool>)>,
+    /// Bumped whenever the last publisher leaves, so stale grace timers
+    /// can tell the stream has been published again since
+    generation: u64,
 }
 
 /// Stream
@@ -53,50 +165,292 @@ pub struct Stream {
     /// Stream data
     data: Arc<RwLock<StreamData>>,
     /// Subscribers
-    subscribers: Vec<String>, // TODO: Replace with actual subscriber connections
+    subscribers: Arc<Mutex<Vec<SubscriberEntry>>>,
+    /// Publishers
+    publishers: Arc<Mutex<PublisherSlots>>,
+    /// Tag fan-out to subscribers
+    sender: broadcast::Sender<FlvTag>,
+    /// Forward audio tags (dropped otherwise)
+    audio: bool,
+    /// Where subscriber and codec events go
+    events: Option<EventBus>,
+    /// App named in those events
+    app: String,
+    /// Recent tags for time-shifted viewers
+    timeshift: Option<Arc<Mutex<TimeshiftBuffer>>>,
 }
 
 impl Stream {
     /// Create a new stream
     pub fn new(name: String) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
         Self {
             data: Arc::new(RwLock::new(StreamData::new(name))),
-            subscribers: Vec::new(),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            publishers: Arc::new(Mutex::new(PublisherSlots::default())),
+            sender,
+            audio: true,
+            events: None,
+            app: String::new(),
+            timeshift: None,
         }
     }
 
+    /// Enable or disable audio forwarding
+    pub fn with_audio(mut self, audio: bool) -> Self {
+        self.audio = audio;
+        self
+    }
+
+    /// Report subscriber and codec changes on `events`, as a stream of
+    /// `app`
+    pub fn with_events(mut self, app: &str, events: EventBus) -> Self {
+        self.app = app.to_string();
+        self.events = Some(events);
+        self
+    }
+
+    /// Keep up to `window` of tags, in at most `max_bytes`, for viewers
+    /// that start in the past (see [`Stream::subscribe_at`])
+    pub fn with_timeshift(mut self, window: Duration, max_bytes: usize) -> Self {
+        self.timeshift = Some(Arc::new(Mutex::new(TimeshiftBuffer::new(window, max_bytes))));
+        self
+    }
+
     /// Get stream name
     pub async fn name(&self) -> String {
         self.data.read().await.name.clone()
     }
 
+    /// Get a snapshot of the stream data
+    pub async fn data(&self) -> StreamData {
+        self.data.read().await.clone()
+    }
+
+    /// Check if audio tags are forwarded
+    pub fn has_audio(&self) -> bool {
+        self.audio
+    }
+
+    /// FLV header describing what subscribers of this stream receive
+    pub fn flv_header(&self) -> FlvHeader {
+        FlvHeader::new(self.audio, true)
+    }
+
     /// Add a subscriber
-    pub async fn add_subscriber(&mut self, subscriber_id: String) {
-        self.subscribers.push(subscriber_id);
+    pub async fn add_subscriber(&self, subscriber_id: String) {
+        self.register(subscriber_id).await;
+    }
+
+    /// Add a subscriber and return its lag counters
+    async fn register(&self, subscriber_id: String) -> Arc<LagCounters> {
+        let counters = Arc::new(LagCounters::default());
+        self.subscribers.lock().unwrap().push(SubscriberEntry {
+            id: subscriber_id.clone(),
+            counters: Arc::clone(&counters),
+        });
         self.data.write().await.update_activity();
-        debug!("Added subscriber to stream {}", self.data.read().await.name);
+        let name = self.name().await;
+        debug!("Added subscriber to stream {}", name);
+        if let Some(events) = &self.events {
+            events.emit(Event::SubscriberJoined {
+                app: self.app.clone(),
+                stream: name,
+                subscriber: subscriber_id,
+            });
+        }
+        counters
     }
 
     /// Remove a subscriber
-    pub async fn remove_subscriber(&mut self, subscriber_id: &str) {
-        self.subscribers.retain(|id| id != subscriber_id);
+    pub async fn remove_subscriber(&self, subscriber_id: &str) {
+        let removed = {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            let before = subscribers.len();
+            subscribers.retain(|entry| entry.id != subscriber_id);
+            subscribers.len() < before
+        };
         self.data.write().await.update_activity();
-        debug!("Removed subscriber from stream {}", self.data.read().await.name);
+        let name = self.name().await;
+        debug!("Removed subscriber from stream {}", name);
+        if let (true, Some(events)) = (removed, &self.events) {
+            events.emit(Event::SubscriberLeft {
+                app: self.app.clone(),
+                stream: name,
+                subscriber: subscriber_id.to_string(),
+            });
+        }
     }
 
     /// Get subscriber count
     pub fn subscriber_count(&self) -> usize {
-        self.subscribers.len()
+        self.subscribers.lock().unwrap().len()
     }
 
-    /// Publish data to stream
-    pub async fn publish(&mut self, data: &[u8]) -> Result<()> {
-        // TODO: Implement actual data publishing
-        let stream_name = self.data.read().await.name.clone();
-        debug!("Publishing {} bytes to stream {}", data.len(), stream_name);
-        self.data.write().await.update_activity();
+    /// Lag counters of every current subscriber
+    pub fn subscriber_stats(&self) -> Vec<SubscriberStats> {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|entry| entry.counters.stats(&entry.id))
+            .collect()
+    }
+
+    /// Register a subscriber and return its tag receiver
+    ///
+    /// The returned [`Subscription`] does not keep the stream alive: once
+    /// every publisher handle is gone the receiver reports `Closed`.
+    pub async fn subscribe(&self, subscriber_id: String) -> Subscription {
+        let receiver = self.sender.subscribe();
+        let counters = self.register(subscriber_id.clone()).await;
+        let mut subscription = self.subscription(subscriber_id, receiver).await;
+        subscription.counters = counters;
+        subscription.subscribers = Some(Arc::clone(&self.subscribers));
+        subscription
+    }
+
+    /// Tag receiver for a consumer inside the server, such as a push relay
+    ///
+    /// Unlike [`Stream::subscribe`], the consumer is not a viewer: it is
+    /// left out of [`Stream::subscriber_count`] and viewer limits, and
+    /// joins and leaves without subscriber events.
+    pub(crate) async fn subscribe_internal(&self, consumer_id: String) -> Subscription {
+        let receiver = self.sender.subscribe();
+        self.subscription(consumer_id, receiver).await
+    }
+
+    /// Unregistered subscription of `id` reading from `receiver`
+    async fn subscription(&self, id: String, receiver: broadcast::Receiver<FlvTag>) -> Subscription {
+        Subscription {
+            id,
+            app: self.app.clone(),
+            stream: self.name().await,
+            events: self.events.clone(),
+            receiver,
+            subscribers: None,
+            data: Arc::clone(&self.data),
+            counters: Arc::new(LagCounters::default()),
+            policy: LagPolicy::default(),
+            resyncing: false,
+            resync_headers: Vec::new(),
+            pending: VecDeque::new(),
+            timeshift: None,
+        }
+    }
+
+    /// Register a subscriber that starts `offset` in the past
+    ///
+    /// Playback starts at the buffered video keyframe closest to that time,
+    /// with the metadata and sequence headers in effect there, and goes on
+    /// at the speed the tags were published, so the viewer stays `offset`
+    /// behind live. Without a keyframe in the buffer yet, the viewer starts
+    /// live, after the cached headers. Fails with [`Error::InvalidInput`] if the stream keeps no
+    /// time-shift buffer.
+    pub async fn subscribe_at(&self, subscriber_id: String, offset: Duration) -> Result<Subscription> {
+        let Some(buffer) = &self.timeshift else {
+            return Err(Error::InvalidInput("Time-shift is not enabled for this stream".to_string()));
+        };
+        let mut subscription = self.subscribe(subscriber_id).await;
+        // Subscribed first, so no tag published after the seek is missed
+        let start = buffer.lock().unwrap().seek(offset);
+        match start {
+            Some((position, headers)) => {
+                subscription.pending.extend(headers);
+                subscription.timeshift = Some(Cursor::new(Arc::clone(buffer), position));
+            }
+            None => subscription.pending.extend(self.sequence_headers().await),
+        }
+        Ok(subscription)
+    }
 
-        // TODO: Broadcast to subscribers
+    /// Check if the stream currently has a publisher
+    pub fn is_published(&self) -> bool {
+        self.publisher_count() > 0
+    }
+
+    /// Number of active publishers
+    pub fn publisher_count(&self) -> usize {
+        self.publishers.lock().unwrap().active.len()
+    }
+
+    /// Check if two handles refer to the same stream
+    pub fn same_as(&self, other: &Stream) -> bool {
+        Arc::ptr_eq(&self.data, &other.data)
+    }
+
+    /// Cached tags a new subscriber needs before live data: metadata and
+    /// sequence headers
+    pub async fn sequence_headers(&self) -> Vec<FlvTag> {
+        self.data.read().await.sequence_headers()
+    }
+
+    /// Latest video keyframe with the sequence header it decodes against
+    pub async fn keyframe(&self) -> Option<(FlvTag, FlvTag)> {
+        let data = self.data.read().await;
+        Some((data.video_seq.clone()?, data.keyframe.clone()?))
+    }
+
+    /// Publish a tag to the stream
+    ///
+    /// Metadata and sequence headers are cached for late joiners, and the
+    /// latest keyframe for snapshots, before the tag is broadcast to
+    /// current subscribers.
+    pub async fn publish(&self, tag: FlvTag) -> Result<()> {
+        if tag.is_audio() && !self.audio {
+            return Ok(());
+        }
+        // Buffered before the broadcast, which wakes time-shifted viewers
+        if let Some(buffer) = &self.timeshift {
+            buffer.lock().unwrap().push(tag.clone());
+        }
+
+        let codec_change = {
+            let mut data = self.data.write().await;
+            data.update_activity();
+            let cached = match tag.tag_type {
+                TagType::Video => &data.video_seq,
+                _ => &data.audio_seq,
+            };
+            let codec_change = (tag.is_sequence_header()
+                && cached.as_ref().map(|seq| &seq.data) != Some(&tag.data))
+                .then(|| (data.name.clone(), codec_name(&tag)));
+            match tag.tag_type {
+                TagType::Video if tag.is_sequence_header() => {
+                    info!(
+                        "Stream {}: video sequence header ({} bytes)",
+                        data.name,
+                        tag.data.len()
+                    );
+                    data.video_seq = Some(tag.clone());
+                }
+                TagType::Video if tag.is_keyframe() => {
+                    data.keyframe = Some(tag.clone());
+                }
+                TagType::Audio if tag.is_sequence_header() => {
+                    debug!("Stream {}: audio sequence header", data.name);
+                    data.audio_seq = Some(tag.clone());
+                }
+                TagType::Script => {
+                    debug!("Stream {}: metadata", data.name);
+                    data.metadata_tag = Some(tag.clone());
+                }
+                _ => {}
+            }
+            codec_change
+        };
+        if let (Some((stream, codec)), Some(events)) = (codec_change, &self.events) {
+            let media = if tag.is_video() { "video" } else { "audio" };
+            events.emit(Event::CodecChanged {
+                app: self.app.clone(),
+                stream,
+                media: media.to_string(),
+                codec,
+            });
+        }
+
+        // No receivers is not an error: the stream simply has no viewers yet
+        let _ = self.sender.send(tag);
         Ok(())
     }
 
@@ -106,12 +460,373 @@ impl Stream {
     }
 }
 
+impl Clone for Stream {
+    fn clone(&self) -> Self {
+        Self {
+            data: Arc::clone(&self.data),
+            subscribers: Arc::clone(&self.subscribers),
+            publishers: Arc::clone(&self.publishers),
+            sender: self.sender.clone(),
+            audio: self.audio,
+            events: self.events.clone(),
+            app: self.app.clone(),
+            timeshift: self.timeshift.clone(),
+        }
+    }
+}
+
+/// A subscriber's handle on a stream
+///
+/// Dropping it unregisters the subscriber.
+pub struct Subscription {
+    /// Subscriber ID
+    id: String,
+    /// App of the stream, for the leave event
+    app: String,
+    /// Stream name, for the leave event
+    stream: String,
+    /// Where the leave event goes
+    events: Option<EventBus>,
+    /// Tag receiver
+    pub receiver: broadcast::Receiver<FlvTag>,
+    /// Subscriber list of the stream, unless the subscription is internal
+    subscribers: Option<Arc<Mutex<Vec<SubscriberEntry>>>>,
+    /// Stream data, for the sequence headers re-sent on resync
+    data: Arc<RwLock<StreamData>>,
+    /// Lag counters, also visible through [`Stream::subscriber_stats`]
+    counters: Arc<LagCounters>,
+    /// Lag handling
+    policy: LagPolicy,
+    /// Discarding tags until the next video keyframe
+    resyncing: bool,
+    /// Sequence headers to send in front of the resync keyframe
+    resync_headers: Vec<FlvTag>,
+    /// Tags ready to be returned before reading the channel again
+    pending: VecDeque<FlvTag>,
+    /// Position in the time-shift buffer, for viewers behind live
+    timeshift: Option<Cursor>,
+}
+
+impl Subscription {
+    /// Get subscriber ID
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Set how this subscriber recovers from lag
+    pub fn with_lag_policy(mut self, policy: LagPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Current lag counters
+    pub fn stats(&self) -> SubscriberStats {
+        self.counters.stats(&self.id)
+    }
+
+    /// Receive the next tag, or `None` once the stream has ended
+    ///
+    /// When the subscriber falls behind, inter frames are skipped until the
+    /// next video keyframe so the decoder never sees frames whose
+    /// references were dropped. Fails when the subscriber lagged more often
+    /// than its [`LagPolicy`] allows.
+    pub async fn recv(&mut self) -> Result<Option<FlvTag>> {
+        if self.timeshift.is_some() {
+            return self.recv_shifted().await;
+        }
+        if let Some(tag) = self.pending.pop_front() {
+            return Ok(Some(tag));
+        }
+        loop {
+            let tag = match self.receiver.recv().await {
+                Ok(tag) => tag,
+                Err(RecvError::Lagged(skipped)) => {
+                    self.lagged(skipped).await?;
+                    continue;
+                }
+                Err(RecvError::Closed) => return Ok(None),
+            };
+            if !self.resyncing || tag.is_script() || tag.is_sequence_header() {
+                return Ok(Some(tag));
+            }
+            if tag.is_video() && tag.is_keyframe() {
+                self.resyncing = false;
+                debug!("Subscriber {} resynced at {} ms", self.id, tag.timestamp);
+                for mut header in self.resync_headers.drain(..) {
+                    header.timestamp = tag.timestamp;
+                    self.pending.push_back(header);
+                }
+                self.pending.push_back(tag);
+                return Ok(self.pending.pop_front());
+            }
+            self.counters.skipped_tags.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Receive the next tag from the time-shift buffer, in real time
+    ///
+    /// The channel only signals new tags and the end of the stream. A
+    /// viewer whose position was dropped from the buffer restarts at the
+    /// oldest keyframe, which counts as lagging.
+    async fn recv_shifted(&mut self) -> Result<Option<FlvTag>> {
+        loop {
+            if let Some(tag) = self.pending.pop_front() {
+                return Ok(Some(tag));
+            }
+            let Some(cursor) = self.timeshift.as_mut() else {
+                return Ok(None);
+            };
+            match cursor.next() {
+                Next::Tag(tag) => {
+                    cursor.pace(tag.timestamp).await;
+                    return Ok(Some(tag));
+                }
+                Next::Evicted => {
+                    self.counters.lag_events.fetch_add(1, Ordering::Relaxed);
+                    info!("Time-shifted subscriber {} fell out of the buffer", self.id);
+                    let headers = cursor.rewind();
+                    self.pending.extend(headers);
+                }
+                Next::Wait => match self.receiver.recv().await {
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return Ok(None),
+                },
+            }
+        }
+    }
+
+    /// Skip ahead to the next video keyframe
+    ///
+    /// Inter frames are discarded until a keyframe arrives; the cached
+    /// metadata and sequence headers are then returned in front of it
+    /// unless the [`LagPolicy`] says otherwise. Streams without video
+    /// resume immediately.
+    pub async fn resync(&mut self) {
+        // Set before awaiting so a cancelled call still resyncs
+        self.resyncing = true;
+        self.pending.clear();
+        let data = self.data.read().await;
+        self.resyncing = data.video_seq.is_some();
+        self.resync_headers = if self.policy.resend_sequence_headers {
+            data.sequence_headers()
+        } else {
+            Vec::new()
+        };
+    }
+
+    /// Discard tags for `duration`, e.g. while a consumer reconnects
+    ///
+    /// Returns `false` if the stream ended in the meantime.
+    pub async fn skip_for(&mut self, duration: Duration) -> bool {
+        let deadline = tokio::time::sleep(duration);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => return true,
+                received = self.recv() => {
+                    if !matches!(received, Ok(Some(_))) {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Account for `skipped` overwritten tags and start a resync
+    async fn lagged(&mut self, skipped: u64) -> Result<()> {
+        let lag_events = self.counters.lag_events.fetch_add(1, Ordering::Relaxed) + 1;
+        self.counters.dropped_tags.fetch_add(skipped, Ordering::Relaxed);
+        if let Some(max) = self.policy.max_lag_events {
+            if lag_events > max as u64 {
+                warn!("Subscriber {} lagged {} times, disconnecting", self.id, lag_events);
+                return Err(Error::Stream(format!(
+                    "Subscriber {} lagged {} times",
+                    self.id, lag_events
+                )));
+            }
+        }
+
+        self.resync().await;
+        info!(
+            "Subscriber {} lagged, dropped {} tags{}",
+            self.id,
+            skipped,
+            if self.resyncing { "; waiting for keyframe" } else { "" }
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let Some(subscribers) = &self.subscribers else {
+            return;
+        };
+        let removed = match subscribers.lock() {
+            Ok(mut subscribers) => {
+                let before = subscribers.len();
+                subscribers.retain(|entry| entry.id != self.id);
+                subscribers.len() < before
+            }
+            Err(_) => false,
+        };
+        if let (true, Some(events)) = (removed, &self.events) {
+            events.emit(Event::SubscriberLeft {
+                app: std::mem::take(&mut self.app),
+                stream: std::mem::take(&mut self.stream),
+                subscriber: self.id.clone(),
+            });
+        }
+    }
+}
+
+/// A publisher's handle on a stream
+///
+/// Obtained from [`StreamManager::start_publish`]. Dropping it unregisters
+/// the publisher; when the last one leaves, the stream is kept for the
+/// app's grace period so subscribers can wait for a reconnect.
+pub struct Publisher {
+    /// Publisher ID
+    id: String,
+    /// App the stream belongs to
+    app: String,
+    /// Stream name
+    name: String,
+    /// Published stream
+    stream: Stream,
+    /// Registry the stream belongs to
+    manager: StreamManager,
+    /// Set when another publisher replaced this one
+    evicted: watch::Receiver<bool>,
+    /// How long the stream outlives its last publisher
+    grace_period: Duration,
+    /// Slot in the app's publisher limit
+    _limit: PublishGuard,
+}
+
+impl Publisher {
+    /// Get publisher ID
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Get the published stream
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+
+    /// Check if another publisher replaced this one
+    pub fn is_evicted(&self) -> bool {
+        *self.evicted.borrow()
+    }
+
+    /// Wait until another publisher replaces this one
+    pub async fn evicted(&mut self) {
+        while !*self.evicted.borrow_and_update() {
+            if self.evicted.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Publish a tag, unless this publisher has been replaced
+    pub async fn publish(&self, tag: FlvTag) -> Result<()> {
+        if self.is_evicted() {
+            return Err(Error::Stream(format!(
+                "Publisher {} of '{}' was replaced",
+                self.id, self.name
+            )));
+        }
+        self.stream.publish(tag).await
+    }
+}
+
+impl Drop for Publisher {
+    fn drop(&mut self) {
+        self.manager.events.emit(Event::PublishStopped {
+            app: self.app.clone(),
+            stream: self.name.clone(),
+            publisher: self.id.clone(),
+        });
+        let generation = {
+            let mut slots = self.stream.publishers.lock().unwrap();
+            let before = slots.active.len();
+            slots.active.retain(|(id, _)| id != &self.id);
+            if slots.active.len() == before || !slots.active.is_empty() {
+                // Replaced, or other publishers remain
+                return;
+            }
+            slots.generation += 1;
+            slots.generation
+        };
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            // Outside a runtime no task can be holding the lock across an await
+            clear_publisher(&mut self.stream.data.blocking_write(), &self.id);
+            return;
+        };
+        if !self.grace_period.is_zero() {
+            info!(
+                "Publisher for {} disconnected; keeping stream for {:?}",
+                self.name, self.grace_period
+            );
+        }
+        let manager = self.manager.clone();
+        let stream = self.stream.clone();
+        let app = self.app.clone();
+        let name = self.name.clone();
+        let grace_period = self.grace_period;
+        let id = self.id.clone();
+        runtime.spawn(async move {
+            clear_publisher(&mut *stream.data.write().await, &id);
+            tokio::time::sleep(grace_period).await;
+            manager.remove_unpublished(&app, &name, stream, generation).await;
+        });
+    }
+}
+
+/// Forget publisher `id`, unless another one has taken the stream since
+fn clear_publisher(data: &mut StreamData, id: &str) {
+    if data.publisher.as_deref() == Some(id) {
+        data.publisher = None;
+    }
+}
+
 /// Stream manager
+///
+/// Streams are kept per app: the same name in two apps (or in the same app
+/// of two virtual hosts) is two separate streams.
+#[derive(Clone)]
 pub struct StreamManager {
-    /// Active streams
-    streams: Arc<RwLock<HashMap<String, Stream>>>,
+    /// Active streams by app and name
+    streams: Arc<RwLock<HashMap<(String, String), Stream>>>,
     /// Stream timeout
     stream_timeout: std::time::Duration,
+    /// Forward audio tags on new streams
+    audio: bool,
+    /// Per-app settings
+    config: Arc<Config>,
+    /// Push relays to upstream servers
+    relays: RelayManager,
+    /// Streams pulled from an origin in edge mode
+    edge: EdgeManager,
+    /// Streams pulled from RTSP cameras
+    rtsp: RtspManager,
+    /// ffmpeg renditions of published streams
+    transcodes: TranscodeManager,
+    /// JPEG snapshots of live streams
+    snapshots: SnapshotCache,
+    /// FLV recordings of published streams
+    recordings: RecordingManager,
+    /// HLS segments of published streams
+    hls: HlsManager,
+    /// Connection, publisher and viewer limits
+    limits: Limiter,
+    /// Open RTMP sessions
+    sessions: SessionRegistry,
+    /// Stream lifecycle events
+    events: EventBus,
+    /// Remote shared objects of RTMP clients
+    shared_objects: SharedObjects,
 }
 
 impl StreamManager {
@@ -120,58 +835,226 @@ impl StreamManager {
         Self {
             streams: Arc::new(RwLock::new(HashMap::new())),
             stream_timeout: std::time::Duration::from_secs(300), // 5 minutes
+            audio: false,
+            config: Arc::new(Config::default()),
+            relays: RelayManager::new(),
+            edge: EdgeManager::new(),
+            rtsp: RtspManager::new(),
+            transcodes: TranscodeManager::new(),
+            snapshots: SnapshotCache::new(),
+            recordings: RecordingManager::new(),
+            hls: HlsManager::new(),
+            limits: Limiter::default(),
+            sessions: SessionRegistry::new(),
+            events: EventBus::new(),
+            shared_objects: SharedObjects::default(),
         }
     }
 
-    /// Create a new stream
-    pub async fn create_stream(&self, name: String) -> Result<()> {
+    /// Use per-app settings from a configuration
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.limits = Limiter::new(config.limits.clone());
+        self.shared_objects = SharedObjects::new(config.shared_objects_dir.as_ref().map(PathBuf::from));
+        self.config = Arc::new(config);
+        self
+    }
+
+    /// Get the configuration
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Get the push relays
+    pub fn relays(&self) -> &RelayManager {
+        &self.relays
+    }
+
+    /// Get the edge pulls
+    pub fn edge(&self) -> &EdgeManager {
+        &self.edge
+    }
+
+    /// Get the RTSP camera pulls
+    pub fn rtsp(&self) -> &RtspManager {
+        &self.rtsp
+    }
+
+    /// Get the transcode workers
+    pub fn transcodes(&self) -> &TranscodeManager {
+        &self.transcodes
+    }
+
+    /// Get the snapshot cache
+    pub fn snapshots(&self) -> &SnapshotCache {
+        &self.snapshots
+    }
+
+    /// Get the running recordings
+    pub fn recordings(&self) -> &RecordingManager {
+        &self.recordings
+    }
+
+    /// Get the HLS playlists
+    pub fn hls(&self) -> &HlsManager {
+        &self.hls
+    }
+
+    /// Get the connection limits
+    pub fn limits(&self) -> &Limiter {
+        &self.limits
+    }
+
+    /// Get the stream lifecycle events
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /// Get the open RTMP sessions
+    pub fn sessions(&self) -> &SessionRegistry {
+        &self.sessions
+    }
+
+    /// Get the remote shared objects
+    pub fn shared_objects(&self) -> &SharedObjects {
+        &self.shared_objects
+    }
+
+    /// Check the token a client presented to publish or play in `app`
+    ///
+    /// Fails with [`Error::AuthDenied`] when the app requires another one.
+    pub fn authorize(&self, app: &str, access: Access, token: Option<&str>) -> Result<()> {
+        self.config.app(app).authorize(access, token)
+    }
+
+    /// Check `app`'s viewer limit before a client subscribes to `stream`
+    ///
+    /// Fails with [`Error::ResourceLimit`] when the stream is full.
+    pub async fn admit_subscriber(&self, app: &str, stream: &Stream) -> Result<()> {
+        self.limits.subscribe(stream, self.config.app(app).max_subscribers).await
+    }
+
+    /// Enable or disable audio forwarding for new streams
+    ///
+    /// Audio is dropped by default, as the HTTP-FLV relay always did. Apps
+    /// with an `audio` setting of their own ignore this.
+    pub fn with_audio(mut self, audio: bool) -> Self {
+        self.audio = audio;
+        self
+    }
+
+    /// Set the inactivity timeout used by [`StreamManager::cleanup_inactive`]
+    pub fn with_stream_timeout(mut self, timeout: std::time::Duration) -> Self {
+        self.stream_timeout = timeout;
+        self
+    }
+
+    /// Create a new stream in `app`
+    pub async fn create_stream(&self, app: &str, name: String) -> Result<()> {
         let mut streams = self.streams.write().await;
 
-        if streams.contains_key(&name) {
-            return Err(Error::Stream(format!("Stream '{}' already exists", name)));
+        let key = (app.to_string(), name.clone());
+        if streams.contains_key(&key) {
+            return Err(Error::Stream(format!("Stream '{}/{}' already exists", app, name)));
         }
 
-        let stream = Stream::new(name.clone());
-        streams.insert(name.clone(), stream);
+        streams.insert(key, self.new_stream(app, &name));
 
-        info!("Created new stream: {}", name);
+        info!("Created new stream: {}/{}", app, name);
         Ok(())
     }
 
-    /// Get a stream
-    pub async fn get_stream(&self, name: &str) -> Option<Stream> {
+    /// Get a stream of `app`
+    pub async fn get_stream(&self, app: &str, name: &str) -> Option<Stream> {
         let streams = self.streams.read().await;
-        streams.get(name).cloned()
+        streams.get(&(app.to_string(), name.to_string())).cloned()
     }
 
-    /// Remove a stream
-    pub async fn remove_stream(&self, name: &str) -> Result<()> {
+    /// Get a stream of `app`, creating it if it does not exist yet
+    pub async fn get_or_create(&self, app: &str, name: &str) -> Stream {
         let mut streams = self.streams.write().await;
+        self.entry(&mut streams, app, name)
+    }
+
+    /// Stream `name` of `app` in `streams`, created if needed
+    fn entry(&self, streams: &mut HashMap<(String, String), Stream>, app: &str, name: &str) -> Stream {
+        streams
+            .entry((app.to_string(), name.to_string()))
+            .or_insert_with(|| {
+                info!("Created new stream: {}/{}", app, name);
+                self.new_stream(app, name)
+            })
+            .clone()
+    }
 
-        if streams.remove(name).is_some() {
-            info!("Removed stream: {}", name);
+    /// Empty stream with the settings of `app`
+    fn new_stream(&self, app: &str, name: &str) -> Stream {
+        let app_config = self.config.app(app);
+        let stream = Stream::new(name.to_string())
+            .with_audio(app_config.audio.unwrap_or(self.audio))
+            .with_events(app, self.events.clone());
+        match app_config.timeshift() {
+            Some(window) => stream.with_timeshift(window, app_config.timeshift_buffer_size()),
+            None => stream,
+        }
+    }
+
+    /// Get a stream for a viewer of `app`
+    ///
+    /// A stream that is not published locally is pulled from the app's
+    /// RTSP camera of that name, or from the app's origin in edge mode;
+    /// otherwise the stream is created if needed so a later publisher
+    /// feeds the viewer.
+    pub async fn get_or_pull(&self, app: &str, name: &str) -> Stream {
+        let stream = self.get_or_create(app, name).await;
+        if !stream.is_published() {
+            let app_config = self.config.app(app);
+            if let Some(camera) = app_config.cameras.get(name) {
+                self.rtsp.start(self, app, name, camera).await;
+            } else if let Some(edge) = &app_config.edge {
+                self.edge.start(self, app, name, edge).await;
+            }
+        }
+        stream
+    }
+
+    /// Remove a stream of `app`
+    pub async fn remove_stream(&self, app: &str, name: &str) -> Result<()> {
+        let mut streams = self.streams.write().await;
+
+        if streams.remove(&(app.to_string(), name.to_string())).is_some() {
+            info!("Removed stream: {}/{}", app, name);
             Ok(())
         } else {
-            Err(Error::Stream(format!("Stream '{}' not found", name)))
+            Err(Error::NotFound(format!("Stream '{}/{}' not found", app, name)))
         }
     }
 
-    /// List all streams
+    /// List all streams as `{app}/{name}`
     pub async fn list_streams(&self) -> Vec<String> {
         let streams = self.streams.read().await;
-        streams.keys().cloned().collect()
+        streams.keys().map(|(app, name)| format!("{}/{}", app, name)).collect()
     }
 
     /// Clean up inactive streams
+    ///
+    /// A stream is removed when it has neither a publisher nor subscribers
+    /// and has seen no activity within the stream timeout.
     pub async fn cleanup_inactive(&self) -> usize {
         let mut streams = self.streams.write().await;
         let initial_count = streams.len();
 
-        streams.retain(|_, stream| {
-            // TODO: Check if stream is active
-            // For now, keep all streams
-            true
-        });
+        let mut idle = Vec::new();
+        for (key, stream) in streams.iter() {
+            if stream.subscriber_count() == 0
+                && !stream.is_published()
+                && !stream.is_active(self.stream_timeout).await
+            {
+                idle.push(key.clone());
+            }
+        }
+        for key in idle {
+            streams.remove(&key);
+        }
 
         let removed = initial_count - streams.len();
         if removed > 0 {
@@ -181,38 +1064,138 @@ impl StreamManager {
         removed
     }
 
-    /// Subscribe to a stream
-    pub async fn subscribe(&self, stream_name: &str, subscriber_id: String) -> Result<()> {
+    /// Start publishing `name` through `app`
+    ///
+    /// If the stream already has a publisher, the app's publisher policy
+    /// decides whether the new one is rejected, replaces the old one or
+    /// publishes alongside it. A publisher that takes over an idle stream
+    /// (first publish, reconnect within the grace period, or replace)
+    /// clears the cached headers; it is expected to send fresh ones. The
+    /// app's push relays, transcode workers, recording and HLS segmenter
+    /// start with the first publisher.
+    pub async fn start_publish(&self, app: &str, name: &str, publisher_id: String) -> Result<Publisher> {
+        let app_config = self.config.app(app).clone();
+        // The slot is claimed under the map lock, so a pending removal of
+        // the stream's previous publisher cannot orphan it in between
         let mut streams = self.streams.write().await;
+        let stream = self.entry(&mut streams, app, name);
+        let (evicted, sole, limit) = {
+            let mut slots = stream.publishers.lock().unwrap();
+            let busy = !slots.active.is_empty();
+            if busy && app_config.publisher_policy == PublisherPolicy::Reject {
+                warn!("Rejected publisher {} for '{}': already published", publisher_id, name);
+                return Err(Error::StreamBusy(format!(
+                    "Stream '{}' is already being published",
+                    name
+                )));
+            }
+            // A replacement takes over the slot of the publisher it evicts
+            let replacing = busy && app_config.publisher_policy == PublisherPolicy::Replace;
+            let limit = self
+                .limits
+                .publish(app, &publisher_id, app_config.max_publishers.filter(|_| !replacing))?;
+            if busy {
+                match app_config.publisher_policy {
+                    PublisherPolicy::Reject => {}
+                    PublisherPolicy::Replace => {
+                        for (old_id, evict) in slots.active.drain(..) {
+                            info!("Publisher {} replaces {} on '{}'", publisher_id, old_id, name);
+                            let _ = evict.send(true);
+                        }
+                    }
+                    PublisherPolicy::Allow => {
+                        info!("Publisher {} joins {} other(s) on '{}'", publisher_id, slots.active.len(), name);
+                    }
+                }
+            }
+            let (evict, evicted) = watch::channel(false);
+            slots.active.push((publisher_id.clone(), evict));
+            (evicted, slots.active.len() == 1, limit)
+        };
+        drop(streams);
 
-        if let Some(stream) = streams.get_mut(stream_name) {
-            stream.add_subscriber(subscriber_id).await;
-            Ok(())
-        } else {
-            Err(Error::Stream(format!("Stream '{}' not found", stream_name)))
+        {
+            let mut data = stream.data.write().await;
+            if let (true, Some(buffer)) = (sole, &stream.timeshift) {
+                buffer.lock().unwrap().clear();
+            }
+            if sole {
+                data.metadata_tag = None;
+                data.video_seq = None;
+                data.audio_seq = None;
+                data.keyframe = None;
+            }
+            data.publisher = Some(publisher_id.clone());
+            data.update_activity();
+        }
+        if sole {
+            self.relays.start(app, self.config.app_name(app), name, &stream, &app_config.push).await;
+            self.transcodes.start(self, app, name, &stream, &app_config.transcode);
+            if let Some(dir) = &app_config.record_dir {
+                self.recordings.start(self, app, name, &stream, dir).await;
+            }
+            if app_config.hls {
+                self.hls.start(app, name, &stream, app_config.hls_segment()).await;
+            }
         }
+
+        self.events.emit(Event::PublishStarted {
+            app: app.to_string(),
+            stream: name.to_string(),
+            publisher: publisher_id.clone(),
+        });
+        Ok(Publisher {
+            id: publisher_id,
+            app: app.to_string(),
+            name: name.to_string(),
+            stream,
+            manager: self.clone(),
+            evicted,
+            grace_period: app_config.grace_period(),
+            _limit: limit,
+        })
     }
 
-    /// Unsubscribe from a stream
-    pub async fn unsubscribe(&self, stream_name: &str, subscriber_id: &str) -> Result<()> {
+    /// Remove `name` if it is still the same unpublished stream as when
+    /// its last publisher left
+    async fn remove_unpublished(&self, app: &str, name: &str, stream: Stream, generation: u64) {
         let mut streams = self.streams.write().await;
+        let key = (app.to_string(), name.to_string());
+        let unchanged = streams.get(&key).is_some_and(|current| current.same_as(&stream)) && {
+            let slots = stream.publishers.lock().unwrap();
+            slots.active.is_empty() && slots.generation == generation
+        };
+        if unchanged {
+            streams.remove(&key);
+            stream.data.write().await.publisher = None;
+            info!("Publisher for {} did not return; removed stream state", name);
+        }
+    }
 
-        if let Some(stream) = streams.get_mut(stream_name) {
-            stream.remove_subscriber(subscriber_id).await;
-            Ok(())
-        } else {
-            Err(Error::Stream(format!("Stream '{}' not found", stream_name)))
+    /// Subscribe to a stream of `app`
+    pub async fn subscribe(&self, app: &str, stream_name: &str, subscriber_id: String) -> Result<Subscription> {
+        match self.get_stream(app, stream_name).await {
+            Some(stream) => Ok(stream.subscribe(subscriber_id).await),
+            None => Err(Error::NotFound(format!("Stream '{}/{}' not found", app, stream_name))),
         }
     }
 
-    /// Publish data to a stream
-    pub async fn publish(&self, stream_name: &str, data: &[u8]) -> Result<()> {
-        let mut streams = self.streams.write().await;
+    /// Unsubscribe from a stream of `app`
+    pub async fn unsubscribe(&self, app: &str, stream_name: &str, subscriber_id: &str) -> Result<()> {
+        match self.get_stream(app, stream_name).await {
+            Some(stream) => {
+                stream.remove_subscriber(subscriber_id).await;
+                Ok(())
+            }
+            None => Err(Error::NotFound(format!("Stream '{}/{}' not found", app, stream_name))),
+        }
+    }
 
-        if let Some(stream) = streams.get_mut(stream_name) {
-            stream.publish(data).await
-        } else {
-            Err(Error::Stream(format!("Stream '{}' not found", stream_name)))
+    /// Publish a tag to a stream of `app`
+    pub async fn publish(&self, app: &str, stream_name: &str, tag: FlvTag) -> Result<()> {
+        match self.get_stream(app, stream_name).await {
+            Some(stream) => stream.publish(tag).await,
+            None => Err(Error::NotFound(format!("Stream '{}/{}' not found", app, stream_name))),
         }
     }
 }
@@ -223,11 +1206,47 @@ impl Default for StreamManager {
     }
 }
 
-impl Clone for Stream {
-    fn clone(&self) -> Self {
-        Self {
-            data: Arc::clone(&self.data),
-            subscribers: self.subscribers.clone(),
-        }
+/// Codec of a sequence header, for [`Event::CodecChanged`]
+fn codec_name(tag: &FlvTag) -> String {
+    if let Some(fourcc) = tag.audio_fourcc() {
+        return String::from_utf8_lossy(fourcc).into_owned();
+    }
+    match (tag.video_codec(), tag.audio_codec()) {
+        (Some(codec), _) => format!("{:?}", codec),
+        (_, Some(codec)) => format!("{:?}", codec),
+        _ => "unknown".to_string(),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AppConfig;
+
+    #[tokio::test]
+    async fn test_publisher_cleared_while_data_is_read() {
+        let mut config = Config::default();
+        config.apps.insert(
+            "live".to_string(),
+            AppConfig {
+                grace_period_ms: 5000,
+                ..AppConfig::default()
+            },
+        );
+        let streams = StreamManager::new().with_config(config);
+        let publisher = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+        let stream = publisher.stream().clone();
+        // Held while the publisher leaves, then the stream stays for the grace period
+        let reader = stream.data.read().await;
+        drop(publisher);
+        drop(reader);
+
+        tokio::time::timeout(Duration::from_secs(1), async {
+            while stream.data().await.publisher.is_some() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("publisher left in the stream data");
+    }
+}
@@ -0,0 +1,237 @@
+//! Time-shift buffer
+//!
+//! Streams of apps with `timeshift_secs` keep their recent tags in memory,
+//! so a viewer can start some seconds in the past (`GET
+//! /{app}/{stream}?offset=-120`) and watch from there at normal speed. The
+//! buffer is bounded both by age and by size; the oldest tags go first.
+//! Tags are numbered as they arrive, so readers can tell when their
+//! position has been dropped.
+
+use crate::flv::{FlvTag, TagType};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Bytes of FLV framing counted for every buffered tag
+const TAG_OVERHEAD: usize = 15;
+
+/// Timestamp jumps beyond this restart the playback clock instead of
+/// stalling or rushing the viewer (ms)
+const MAX_PACE_JUMP_MS: i32 = 10_000;
+
+/// What a reader finds at its position
+pub(crate) enum Next {
+    /// The tag at the position
+    Tag(FlvTag),
+    /// The position has been dropped from the buffer
+    Evicted,
+    /// The position has not been published yet
+    Wait,
+}
+
+/// Recent tags of one stream
+pub(crate) struct TimeshiftBuffer {
+    /// Buffered tags, oldest first
+    tags: VecDeque<FlvTag>,
+    /// Number of the oldest buffered tag
+    first: u64,
+    /// Size of the buffered tags
+    bytes: usize,
+    /// Size limit
+    max_bytes: usize,
+    /// Age limit (ms)
+    window: u32,
+    /// Metadata and sequence headers dropped from the buffer, still in
+    /// effect for the oldest tags
+    metadata: Option<FlvTag>,
+    video_seq: Option<FlvTag>,
+    audio_seq: Option<FlvTag>,
+}
+
+impl TimeshiftBuffer {
+    /// Keep up to `window` of tags, in at most `max_bytes`
+    pub(crate) fn new(window: Duration, max_bytes: usize) -> Self {
+        Self {
+            tags: VecDeque::new(),
+            first: 0,
+            bytes: 0,
+            max_bytes,
+            window: window.as_millis().min(u32::MAX as u128) as u32,
+            metadata: None,
+            video_seq: None,
+            audio_seq: None,
+        }
+    }
+
+    /// Append a tag, dropping the oldest ones beyond the limits
+    pub(crate) fn push(&mut self, tag: FlvTag) {
+        let latest = tag.timestamp;
+        self.bytes += tag.data.len() + TAG_OVERHEAD;
+        self.tags.push_back(tag);
+        while let Some(oldest) = self.tags.front() {
+            // Older than the latest tag by more than the window; tags a
+            // little ahead of it (interleaving) are not old
+            let age = latest.wrapping_sub(oldest.timestamp);
+            let expired = age > self.window && age <= i32::MAX as u32;
+            if self.bytes <= self.max_bytes && !expired {
+                break;
+            }
+            let oldest = self.tags.pop_front().unwrap();
+            self.bytes -= oldest.data.len() + TAG_OVERHEAD;
+            self.first += 1;
+            self.keep_header(oldest);
+        }
+    }
+
+    /// Drop every tag, e.g. when a new publisher starts over; numbering
+    /// continues so readers notice
+    pub(crate) fn clear(&mut self) {
+        self.first += self.tags.len() as u64;
+        self.tags.clear();
+        self.bytes = 0;
+        self.metadata = None;
+        self.video_seq = None;
+        self.audio_seq = None;
+    }
+
+    /// Tag number `position`
+    pub(crate) fn get(&self, position: u64) -> Next {
+        if position < self.first {
+            return Next::Evicted;
+        }
+        match self.tags.get((position - self.first) as usize) {
+            Some(tag) => Next::Tag(tag.clone()),
+            None => Next::Wait,
+        }
+    }
+
+    /// Where to start playing `offset` behind the latest tag
+    ///
+    /// Returns the position of the video keyframe closest to that time
+    /// (the oldest one if the buffer does not reach back that far), with
+    /// the metadata and sequence headers in effect there, timed like the
+    /// keyframe. `None` while the buffer holds no keyframe.
+    pub(crate) fn seek(&self, offset: Duration) -> Option<(u64, Vec<FlvTag>)> {
+        let latest = self.tags.back()?.timestamp;
+        let offset = offset.as_millis().min(i32::MAX as u128) as i64;
+        let (index, keyframe) = self
+            .tags
+            .iter()
+            .enumerate()
+            .filter(|(_, tag)| tag.is_video() && tag.is_keyframe() && !tag.is_sequence_header())
+            .min_by_key(|(_, tag)| (latest.wrapping_sub(tag.timestamp) as i32 as i64 - offset).abs())?;
+
+        let mut metadata = self.metadata.clone();
+        let mut video_seq = self.video_seq.clone();
+        let mut audio_seq = self.audio_seq.clone();
+        for tag in self.tags.range(..index) {
+            match header_slot(tag) {
+                Some(Slot::Metadata) => metadata = Some(tag.clone()),
+                Some(Slot::Video) => video_seq = Some(tag.clone()),
+                Some(Slot::Audio) => audio_seq = Some(tag.clone()),
+                None => {}
+            }
+        }
+        let headers = [metadata, video_seq, audio_seq]
+            .into_iter()
+            .flatten()
+            .map(|mut tag| {
+                tag.timestamp = keyframe.timestamp;
+                tag
+            })
+            .collect();
+        Some((self.first + index as u64, headers))
+    }
+
+    /// Position after the latest tag
+    pub(crate) fn end(&self) -> u64 {
+        self.first + self.tags.len() as u64
+    }
+
+    /// Remember a dropped header that still applies to the buffered tags
+    fn keep_header(&mut self, tag: FlvTag) {
+        match header_slot(&tag) {
+            Some(Slot::Metadata) => self.metadata = Some(tag),
+            Some(Slot::Video) => self.video_seq = Some(tag),
+            Some(Slot::Audio) => self.audio_seq = Some(tag),
+            None => {}
+        }
+    }
+}
+
+/// Kinds of header a decoder needs before the first frame
+enum Slot {
+    Metadata,
+    Video,
+    Audio,
+}
+
+fn header_slot(tag: &FlvTag) -> Option<Slot> {
+    match tag.tag_type {
+        TagType::Script => Some(Slot::Metadata),
+        TagType::Video if tag.is_sequence_header() => Some(Slot::Video),
+        TagType::Audio if tag.is_sequence_header() => Some(Slot::Audio),
+        _ => None,
+    }
+}
+
+/// A viewer's position in a time-shift buffer
+pub(crate) struct Cursor {
+    buffer: Arc<Mutex<TimeshiftBuffer>>,
+    /// Number of the next tag to play
+    position: u64,
+    /// Wall clock time and timestamp of a tag played earlier, so later
+    /// tags are played at the speed they were published
+    clock: Option<(Instant, u32)>,
+}
+
+impl Cursor {
+    /// Start at `position` of `buffer`
+    pub(crate) fn new(buffer: Arc<Mutex<TimeshiftBuffer>>, position: u64) -> Self {
+        Self {
+            buffer,
+            position,
+            clock: None,
+        }
+    }
+
+    /// Tag at the position, moving past it
+    pub(crate) fn next(&mut self) -> Next {
+        let next = self.buffer.lock().unwrap().get(self.position);
+        if matches!(next, Next::Tag(_)) {
+            self.position += 1;
+        }
+        next
+    }
+
+    /// Move to the oldest keyframe after the position was dropped; returns
+    /// the headers to play in front of it
+    pub(crate) fn rewind(&mut self) -> Vec<FlvTag> {
+        let buffer = self.buffer.lock().unwrap();
+        self.clock = None;
+        match buffer.seek(Duration::MAX) {
+            Some((position, headers)) => {
+                self.position = position;
+                headers
+            }
+            None => {
+                self.position = buffer.end();
+                Vec::new()
+            }
+        }
+    }
+
+    /// Wait until a tag with `timestamp` is due
+    pub(crate) async fn pace(&mut self, timestamp: u32) {
+        let now = Instant::now();
+        let (start, base) = *self.clock.get_or_insert((now, timestamp));
+        let elapsed = timestamp.wrapping_sub(base) as i32;
+        let due = start + Duration::from_millis(elapsed.max(0) as u64);
+        if elapsed < -MAX_PACE_JUMP_MS || due > now + Duration::from_millis(MAX_PACE_JUMP_MS as u64) {
+            self.clock = Some((now, timestamp));
+            return;
+        }
+        tokio::time::sleep_until(due).await;
+    }
+}
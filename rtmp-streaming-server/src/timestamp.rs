@@ -0,0 +1,117 @@
+//! Per-subscriber timestamp rebasing
+//!
+//! Publishers send absolute timestamps, which may start anywhere, wrap at
+//! 24 or 32 bits, or jump backwards when a publisher reconnects. Each
+//! subscriber runs its own [`TimestampRebaser`] so its output starts near 0
+//! and keeps moving forward across all of these.
+
+use tracing::warn;
+
+/// Largest backward step accepted as normal audio/video interleaving (ms)
+pub const DEFAULT_MAX_BACKWARD_MS: u32 = 1_000;
+
+/// Largest forward step accepted as a normal gap between tags (ms)
+pub const DEFAULT_MAX_GAP_MS: u32 = 10_000;
+
+/// Step used after a discontinuity when no frame interval is known yet (ms)
+const DEFAULT_STEP_MS: i64 = 1;
+
+/// Period of 24-bit timestamps (publishers that ignore the extended byte)
+const WRAP_24: i64 = 1 << 24;
+
+/// Period of 32-bit timestamps
+const WRAP_32: i64 = 1 << 32;
+
+/// Rebases one subscriber's timestamps to start at 0 and stay monotonic
+#[derive(Debug)]
+pub struct TimestampRebaser {
+    /// Context for discontinuity logs (stream / subscriber)
+    label: String,
+    /// Largest backward step passed through unchanged
+    max_backward: i64,
+    /// Largest forward step passed through unchanged
+    max_gap: i64,
+    /// Last input timestamp
+    last_in: Option<u32>,
+    /// Last output timestamp
+    last_out: i64,
+    /// Highest output timestamp so far
+    high_out: i64,
+    /// Last normal forward step, reused to bridge discontinuities
+    last_step: i64,
+    /// Number of discontinuities repaired
+    discontinuities: u64,
+}
+
+impl TimestampRebaser {
+    /// Create a rebaser with the default limits
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            max_backward: DEFAULT_MAX_BACKWARD_MS as i64,
+            max_gap: DEFAULT_MAX_GAP_MS as i64,
+            last_in: None,
+            last_out: 0,
+            high_out: 0,
+            last_step: DEFAULT_STEP_MS,
+            discontinuities: 0,
+        }
+    }
+
+    /// Set the backward and forward steps tolerated before a discontinuity
+    /// is declared
+    pub fn with_limits(mut self, max_backward_ms: u32, max_gap_ms: u32) -> Self {
+        self.max_backward = max_backward_ms as i64;
+        self.max_gap = max_gap_ms as i64;
+        self
+    }
+
+    /// Current output position, used for cached headers sent on join
+    pub fn position(&self) -> u32 {
+        self.high_out as u32
+    }
+
+    /// Number of discontinuities repaired so far
+    pub fn discontinuities(&self) -> u64 {
+        self.discontinuities
+    }
+
+    /// Map a publisher timestamp to this subscriber's timeline
+    pub fn rebase(&mut self, timestamp: u32) -> u32 {
+        let Some(last_in) = self.last_in.replace(timestamp) else {
+            // First tag anchors the timeline at the current position
+            self.last_out = self.high_out;
+            return self.high_out as u32;
+        };
+
+        let mut delta = timestamp as i64 - last_in as i64;
+        if delta < -(WRAP_24 / 2) && last_in < WRAP_24 as u32 && timestamp < WRAP_24 as u32 {
+            // Publisher only fills the lower 24 bits and wrapped
+            delta += WRAP_24;
+        }
+        if delta < -(WRAP_32 / 2) {
+            delta += WRAP_32;
+        }
+
+        if delta < -self.max_backward || delta > self.max_gap {
+            self.discontinuities += 1;
+            warn!(
+                "[{}] timestamp discontinuity: {} -> {} ({:+} ms), continuing at {}",
+                self.label,
+                last_in,
+                timestamp,
+                delta,
+                self.high_out + self.last_step
+            );
+            self.last_out = self.high_out + self.last_step;
+        } else {
+            if delta > 0 {
+                self.last_step = delta;
+            }
+            self.last_out = (self.last_out + delta).max(0);
+        }
+
+        self.high_out = self.high_out.max(self.last_out);
+        (self.last_out & 0xFFFF_FFFF) as u32
+    }
+}
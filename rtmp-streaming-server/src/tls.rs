@@ -0,0 +1,146 @@
+//! TLS for the RTMPS and HTTPS listeners
+//!
+//! Certificates and keys are read from PEM files. [`TlsAcceptor::watch`]
+//! reloads them when either file changes or the process receives SIGHUP;
+//! connections already established keep the certificate they started with.
+//! A reload that fails (e.g. a half-written file) keeps the previous
+//! certificate.
+
+use crate::config::TlsConfig;
+use crate::error::{Error, Result};
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tracing::{info, warn};
+
+/// How often the PEM files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Accepts TLS connections with a certificate that can be reloaded
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    config: TlsConfig,
+    current: Arc<RwLock<tokio_rustls::TlsAcceptor>>,
+}
+
+impl TlsAcceptor {
+    /// Load the certificate and key named in `config`
+    pub fn load(config: &TlsConfig) -> Result<Self> {
+        let acceptor = build(config)?;
+        info!("Loaded TLS certificate from {}", config.cert_path);
+        Ok(Self {
+            config: config.clone(),
+            current: Arc::new(RwLock::new(acceptor)),
+        })
+    }
+
+    /// Re-read the PEM files; new connections use the new certificate
+    pub fn reload(&self) -> Result<()> {
+        let acceptor = build(&self.config)?;
+        *self.current.write().unwrap() = acceptor;
+        info!("Reloaded TLS certificate from {}", self.config.cert_path);
+        Ok(())
+    }
+
+    /// Reload in the background whenever the PEM files change or the
+    /// process receives SIGHUP
+    pub fn watch(&self) {
+        let acceptor = self.clone();
+        let mut modified = self.modified();
+        tokio::spawn(async move {
+            let mut check = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+            let mut hangup = hangup_signal();
+            loop {
+                tokio::select! {
+                    _ = check.tick() => {
+                        let now = acceptor.modified();
+                        if now == modified {
+                            continue;
+                        }
+                        modified = now;
+                        info!("TLS certificate files changed");
+                    }
+                    Some(()) = recv_hangup(&mut hangup) => info!("SIGHUP received"),
+                }
+                if let Err(e) = acceptor.reload() {
+                    warn!("Keeping the previous TLS certificate: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Complete the TLS handshake on an accepted connection, giving up
+    /// after `timeout`
+    pub(crate) async fn accept(&self, socket: TcpStream, timeout: Duration) -> Result<TlsStream<TcpStream>> {
+        let acceptor = self.current.read().unwrap().clone();
+        tokio::time::timeout(timeout, acceptor.accept(socket))
+            .await
+            .map_err(|_| Error::Network("TLS handshake timed out".to_string()))?
+            .map_err(|e| Error::Network(format!("TLS handshake failed: {}", e)))
+    }
+
+    /// Modification times of the certificate and key files
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.config.cert_path, &self.config.key_path]
+            .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+    }
+}
+
+/// Build an acceptor from the PEM files of `config`
+fn build(config: &TlsConfig) -> Result<tokio_rustls::TlsAcceptor> {
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|e| Error::Config(format!("{}: {}", path, e)))
+    };
+
+    let certs = rustls_pemfile::certs(&mut open(&config.cert_path)?)
+        .collect::<std::io::Result<Vec<_>>>()
+        .map_err(|e| Error::Config(format!("{}: {}", config.cert_path, e)))?;
+    if certs.is_empty() {
+        return Err(Error::Config(format!("{}: no certificate found", config.cert_path)));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&config.key_path)?)
+        .map_err(|e| Error::Config(format!("{}: {}", config.key_path, e)))?
+        .ok_or_else(|| Error::Config(format!("{}: no private key found", config.key_path)))?;
+
+    let server_config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::Config(format!("Invalid TLS certificate or key: {}", e)))?;
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(server_config)))
+}
+
+#[cfg(unix)]
+type Hangup = Option<tokio::signal::unix::Signal>;
+
+#[cfg(not(unix))]
+type Hangup = ();
+
+#[cfg(unix)]
+fn hangup_signal() -> Hangup {
+    use tokio::signal::unix::{signal, SignalKind};
+    signal(SignalKind::hangup())
+        .map_err(|e| warn!("Cannot listen for SIGHUP: {}", e))
+        .ok()
+}
+
+#[cfg(not(unix))]
+fn hangup_signal() -> Hangup {}
+
+/// Wait for the next SIGHUP; never completes where it is unavailable
+#[cfg(unix)]
+async fn recv_hangup(hangup: &mut Hangup) -> Option<()> {
+    match hangup {
+        Some(signal) => signal.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(not(unix))]
+async fn recv_hangup(_: &mut Hangup) -> Option<()> {
+    std::future::pending().await
+}
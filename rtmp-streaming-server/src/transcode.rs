@@ -0,0 +1,287 @@
+//! Transcode workers
+//!
+//! Every published stream of an app with [`TranscodeProfile`]s gets one
+//! supervised ffmpeg child per profile. The source stream is written to the
+//! child's stdin as FLV and the FLV it writes to stdout is published as
+//! `{stream}_{profile}`. Crashed workers are restarted with backoff; workers
+//! stop once the source stream is gone.
+
+use crate::config::TranscodeProfile;
+use crate::error::{Error, Result};
+use crate::flv::{FlvDemuxer, FlvMuxer};
+use crate::stream::{Publisher, Stream, StreamManager, Subscription};
+use bytes::{Buf, BytesMut};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, Command};
+use tracing::{info, warn};
+
+/// Delay before the first restart
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the restart delay
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Source bytes buffered for a worker before tags are left to lag
+const MAX_PENDING_INPUT: usize = 4 * 1024 * 1024;
+
+/// Lifecycle state of a transcode worker
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WorkerState {
+    /// ffmpeg is being started
+    Starting,
+    /// ffmpeg is running
+    Running,
+    /// Waiting before restarting a crashed ffmpeg
+    Backoff,
+}
+
+/// Status of one transcode worker
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TranscodeStatus {
+    /// Local application name
+    pub app: String,
+    /// Source stream name
+    pub stream: String,
+    /// Profile name
+    pub profile: String,
+    /// Rendition stream name
+    pub output: String,
+    /// Worker state
+    pub state: WorkerState,
+    /// Process ID of the running ffmpeg
+    pub pid: Option<u32>,
+    /// Times ffmpeg was restarted
+    pub restarts: u64,
+    /// How the last ffmpeg process ended
+    pub last_exit: Option<String>,
+}
+
+/// Running transcode workers, keyed by rendition stream name
+#[derive(Clone, Default)]
+pub struct TranscodeManager {
+    workers: Arc<Mutex<HashMap<(String, String), TranscodeStatus>>>,
+}
+
+impl TranscodeManager {
+    /// Create an empty transcode manager
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Status of every running worker, ordered by rendition name
+    pub fn statuses(&self) -> Vec<TranscodeStatus> {
+        let mut statuses: Vec<_> = self.workers.lock().unwrap().values().cloned().collect();
+        statuses.sort_by(|a, b| a.output.cmp(&b.output));
+        statuses
+    }
+
+    /// Start the workers `profiles` define for `stream`, skipping those
+    /// already running and streams that are renditions themselves
+    pub(crate) fn start(
+        &self,
+        streams: &StreamManager,
+        app: &str,
+        name: &str,
+        stream: &Stream,
+        profiles: &[TranscodeProfile],
+    ) {
+        for profile in profiles {
+            let output = format!("{}_{}", name, profile.name);
+            {
+                let mut workers = self.workers.lock().unwrap();
+                let key = (app.to_string(), output.clone());
+                if workers.contains_key(&key) || workers.values().any(|w| w.app == app && w.output == name) {
+                    continue;
+                }
+                workers.insert(
+                    key,
+                    TranscodeStatus {
+                        app: app.to_string(),
+                        stream: name.to_string(),
+                        profile: profile.name.clone(),
+                        output: output.clone(),
+                        state: WorkerState::Starting,
+                        pid: None,
+                        restarts: 0,
+                        last_exit: None,
+                    },
+                );
+            }
+
+            info!("Starting transcode worker {} -> {}", name, output);
+            let worker = Worker {
+                manager: self.clone(),
+                streams: streams.clone(),
+                app: app.to_string(),
+                output,
+                ffmpeg: streams.config().ffmpeg().to_string(),
+                args: profile.args.clone(),
+            };
+            tokio::spawn(worker.run(stream.clone()));
+        }
+    }
+
+    /// Apply `f` to the status of a worker
+    fn update(&self, key: &(String, String), f: impl FnOnce(&mut TranscodeStatus)) {
+        if let Some(status) = self.workers.lock().unwrap().get_mut(key) {
+            f(status);
+        }
+    }
+}
+
+/// Supervisor of one ffmpeg child
+struct Worker {
+    manager: TranscodeManager,
+    streams: StreamManager,
+    app: String,
+    output: String,
+    ffmpeg: String,
+    args: Vec<String>,
+}
+
+impl Worker {
+    /// Key of this worker's status
+    fn key(&self) -> (String, String) {
+        (self.app.clone(), self.output.clone())
+    }
+
+    /// Run ffmpeg until the source stream ends, restarting it on exit
+    async fn run(self, source: Stream) {
+        // Only the subscription is kept, so the source can still end
+        let mut subscription = source.subscribe_internal(format!("transcode-{}", self.output)).await;
+        drop(source);
+
+        let publisher_id = format!("transcode-{}", uuid::Uuid::new_v4().simple());
+        let publisher = match self.streams.start_publish(&self.app, &self.output, publisher_id).await {
+            Ok(publisher) => publisher,
+            Err(e) => {
+                warn!("Transcode worker for {} not started: {}", self.output, e);
+                self.manager.workers.lock().unwrap().remove(&self.key());
+                return;
+            }
+        };
+
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            self.manager.update(&self.key(), |status| status.state = WorkerState::Starting);
+            let exit = match self.transcode(&mut subscription, &publisher, &mut backoff).await {
+                Ok(None) => break,
+                Ok(Some(exit)) => exit,
+                Err(e) => e.to_string(),
+            };
+            warn!("Transcode worker {} exited ({}); restarting in {:?}", self.output, exit, backoff);
+            self.manager.update(&self.key(), |status| {
+                status.state = WorkerState::Backoff;
+                status.pid = None;
+                status.restarts += 1;
+                status.last_exit = Some(exit);
+            });
+
+            if !subscription.skip_for(backoff).await {
+                break;
+            }
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+
+        info!("Transcode worker {} stopped", self.output);
+        self.manager.workers.lock().unwrap().remove(&self.key());
+    }
+
+    /// One ffmpeg process; `None` means the source stream ended, otherwise
+    /// how ffmpeg exited
+    async fn transcode(
+        &self,
+        subscription: &mut Subscription,
+        publisher: &Publisher,
+        backoff: &mut Duration,
+    ) -> Result<Option<String>> {
+        let mut child = Command::new(&self.ffmpeg)
+            .args(["-hide_banner", "-f", "flv", "-i", "pipe:0"])
+            .args(&self.args)
+            .args(["-f", "flv", "pipe:1"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| Error::Internal(format!("Failed to start {}: {}", self.ffmpeg, e)))?;
+        let pid = child.id();
+        info!("Transcode worker {} running ffmpeg (pid {:?})", self.output, pid);
+        self.manager.update(&self.key(), |status| {
+            status.state = WorkerState::Running;
+            status.pid = pid;
+        });
+        if let Some(stderr) = child.stderr.take() {
+            tokio::spawn(log_stderr(self.output.clone(), stderr));
+        }
+
+        let (Some(mut stdin), Some(mut stdout)) = (child.stdin.take(), child.stdout.take()) else {
+            return Err(Error::Internal("ffmpeg pipes unavailable".to_string()));
+        };
+
+        // Every run starts from a keyframe behind a fresh FLV header
+        subscription.resync().await;
+        let mut muxer = FlvMuxer::new();
+        let mut input = BytesMut::from(&muxer.header(&publisher.stream().flv_header())[..]);
+        let mut output = BytesMut::with_capacity(64 * 1024);
+        let mut demuxer = FlvDemuxer::new();
+        let mut produced = false;
+
+        loop {
+            tokio::select! {
+                received = subscription.recv(), if input.len() < MAX_PENDING_INPUT => match received? {
+                    Some(tag) => input.extend_from_slice(&muxer.tag(&tag)),
+                    None => {
+                        let _ = child.kill().await;
+                        return Ok(None);
+                    }
+                },
+                written = stdin.write(&input), if !input.is_empty() => {
+                    // ffmpeg closing its input shows up as an exit on stdout
+                    match written {
+                        Ok(n) => input.advance(n),
+                        Err(_) => input.clear(),
+                    }
+                }
+                read = stdout.read_buf(&mut output) => {
+                    if read? == 0 {
+                        break;
+                    }
+                    demuxer.push(&output.split());
+                    while let Some(tag) = demuxer.next_tag()? {
+                        if !produced {
+                            produced = true;
+                            *backoff = INITIAL_BACKOFF;
+                        }
+                        publisher.publish(tag).await?;
+                    }
+                }
+            }
+        }
+
+        drop(stdin);
+        Ok(Some(exit_description(&mut child).await))
+    }
+}
+
+/// Wait for `child` and describe how it ended
+async fn exit_description(child: &mut Child) -> String {
+    match child.wait().await {
+        Ok(status) => status.to_string(),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// Forward ffmpeg's stderr to the log, one line at a time
+async fn log_stderr(output: String, stderr: ChildStderr) {
+    let mut lines = BufReader::new(stderr).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        info!("ffmpeg [{}]: {}", output, line);
+    }
+}
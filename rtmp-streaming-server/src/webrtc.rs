@@ -0,0 +1,1135 @@
+//! WebRTC ingest (WHIP) and playback (WHEP)
+//!
+//! Offers arrive on the HTTP server (`POST /whip/{app}/{stream}` and
+//! `POST /whep/{app}/{stream}`, see [`crate::http`]) and are answered with a
+//! host candidate on the server's UDP port. That one port carries every
+//! session: the server is an ICE-lite agent that only answers binding
+//! requests, so no STUN or TURN server is involved. Packets are routed by
+//! the ICE username until the peer's address is known, then by address.
+//!
+//! Media is keyed with DTLS-SRTP (the server takes the passive role) and
+//! protected with [`crate::srtp`]. Published H.264 and Opus are turned
+//! into FLV tags by the RTSP depacketizers; played streams are packetized
+//! from the cached tags like RTSP output, with H.264 plus Opus or AAC.
+//! Browsers only send keyframes when asked, so a publisher gets a PLI
+//! until its first keyframe and whenever a new viewer joins.
+
+use crate::config::Access;
+use crate::error::{Error, Result};
+use crate::flv::FlvTag;
+use crate::rtsp::{AbortOnDrop, Codec, RtpReceiver};
+use crate::rtsp_server::{describe, Media, Packetizer, RtpSource};
+use crate::srtp::SrtpContext;
+use crate::stream::{Publisher, StreamManager, Subscription};
+use bytes::{BufMut, Bytes, BytesMut};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use openssl::ssl::{ErrorCode, Ssl, SslContext, SslMethod, SslOptions, SslStream, SslVerifyMode};
+use openssl::x509::{X509NameBuilder, X509Ref, X509};
+use rand::distributions::{Alphanumeric, DistString};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Largest UDP payload we expect from a peer
+const MAX_PACKET_SIZE: usize = 1500;
+
+/// Packets queued per session before the listener drops them
+const SESSION_QUEUE: usize = 1024;
+
+/// Sessions without a packet from the peer for this long are closed
+const SESSION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Interval of timeout checks and keyframe requests
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// DTLS records are kept below this size
+const DTLS_MTU: u32 = 1200;
+
+/// The only SRTP protection profile offered
+const SRTP_PROFILE: &str = "SRTP_AES128_CM_SHA1_80";
+
+/// Label of the DTLS-SRTP keying material exporter (RFC 5764)
+const SRTP_EXPORTER_LABEL: &str = "EXTRACTOR-dtls_srtp";
+
+/// STUN message types and attributes (RFC 8489, RFC 8445)
+const STUN_BINDING_REQUEST: u16 = 0x0001;
+const STUN_BINDING_SUCCESS: u16 = 0x0101;
+const STUN_MAGIC_COOKIE: u32 = 0x2112_a442;
+const STUN_USERNAME: u16 = 0x0006;
+const STUN_MESSAGE_INTEGRITY: u16 = 0x0008;
+const STUN_XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const STUN_USE_CANDIDATE: u16 = 0x0025;
+const STUN_FINGERPRINT: u16 = 0x8028;
+const STUN_FINGERPRINT_XOR: u32 = 0x5354_554e;
+
+/// RTCP payload-specific feedback, and its picture loss indication format
+const RTCP_PSFB: u8 = 206;
+const RTCP_FMT_PLI: u8 = 1;
+
+/// WebRTC server: the UDP port shared by all sessions
+pub struct WebRtcServer {
+    /// Address to bind to
+    address: SocketAddr,
+    /// Address announced in answers instead of the bound one
+    candidate_ip: Option<IpAddr>,
+    /// Sessions created by the HTTP server
+    sessions: WebRtcSessions,
+}
+
+impl WebRtcServer {
+    /// Create a new WebRTC server with a fresh DTLS certificate
+    pub fn new(address: SocketAddr) -> Result<Self> {
+        Ok(Self {
+            address,
+            candidate_ip: None,
+            sessions: WebRtcSessions::new()?,
+        })
+    }
+
+    /// Announce this IP in the host candidate, e.g. the public address of
+    /// a server bound to `0.0.0.0`
+    pub fn with_candidate_ip(mut self, ip: IpAddr) -> Self {
+        self.candidate_ip = Some(ip);
+        self
+    }
+
+    /// Session registry, to be handed to the HTTP server
+    pub fn sessions(&self) -> WebRtcSessions {
+        self.sessions.clone()
+    }
+
+    /// Run the server
+    pub async fn run(self) -> Result<()> {
+        info!("Starting WebRTC server on {}", self.address);
+
+        let socket = UdpSocket::bind(self.address).await?;
+        self.serve(socket).await
+    }
+
+    /// Serve sessions on an already bound socket
+    pub async fn serve(self, socket: UdpSocket) -> Result<()> {
+        let local = socket.local_addr()?;
+        let ip = match self.candidate_ip {
+            Some(ip) => ip,
+            None if local.ip().is_unspecified() => {
+                let ip = default_ip().await;
+                warn!("WebRTC bound to {}, announcing {} (set a candidate IP to override)", local, ip);
+                ip
+            }
+            None => local.ip(),
+        };
+        let candidate = SocketAddr::new(ip, local.port());
+        info!("WebRTC listening on {} (candidate {})", local, candidate);
+
+        let socket = Arc::new(socket);
+        self.sessions.registry().socket = Some((socket.clone(), candidate));
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    // ICMP errors of earlier sends show up here on some platforms
+                    debug!("WebRTC receive error: {}", e);
+                    continue;
+                }
+            };
+            let data = Bytes::copy_from_slice(&buf[..len]);
+            let Some(packets) = self.sessions.route(peer, &data) else {
+                continue;
+            };
+            if packets.try_send((peer, data)).is_err() {
+                debug!("WebRTC session of {} is not keeping up, packet dropped", peer);
+            }
+        }
+    }
+}
+
+/// Local address of the default route, or loopback without one
+async fn default_ip() -> IpAddr {
+    // Connecting a UDP socket sends nothing, it only picks the interface
+    let probe = async {
+        let socket = UdpSocket::bind("0.0.0.0:0").await.ok()?;
+        socket.connect("192.0.2.1:9").await.ok()?;
+        socket.local_addr().ok().map(|addr| addr.ip())
+    };
+    probe.await.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// WebRTC sessions, shared between the UDP listener and the HTTP server
+#[derive(Clone)]
+pub struct WebRtcSessions {
+    registry: Arc<Mutex<Registry>>,
+    /// DTLS context with our certificate
+    dtls: SslContext,
+    /// SHA-256 fingerprint of the certificate, as announced in answers
+    fingerprint: Arc<str>,
+}
+
+#[derive(Default)]
+struct Registry {
+    /// Listener socket and the candidate announced for it, once bound
+    socket: Option<(Arc<UdpSocket>, SocketAddr)>,
+    sessions: HashMap<String, SessionEntry>,
+    /// Session IDs by our ICE username fragment
+    ufrags: HashMap<String, String>,
+    /// Session IDs by the nominated address of the peer
+    peers: HashMap<SocketAddr, String>,
+}
+
+struct SessionEntry {
+    ufrag: String,
+    packets: mpsc::Sender<(SocketAddr, Bytes)>,
+    /// Aborts the session task when the entry is removed
+    _task: AbortOnDrop,
+}
+
+impl WebRtcSessions {
+    fn new() -> Result<Self> {
+        let (dtls, fingerprint) = dtls_context().map_err(|e| Error::Internal(format!("DTLS setup: {}", e)))?;
+        Ok(Self {
+            registry: Arc::new(Mutex::new(Registry::default())),
+            dtls,
+            fingerprint: fingerprint.into(),
+        })
+    }
+
+    fn registry(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap()
+    }
+
+    /// Start a WHIP session publishing into `name` of `app` with the
+    /// client's `token`; returns the session ID and the SDP answer
+    pub(crate) async fn whip(
+        &self,
+        streams: &StreamManager,
+        app: &str,
+        name: &str,
+        token: Option<&str>,
+        offer: &str,
+    ) -> Result<(String, String)> {
+        streams.authorize(app, Access::Publish, token)?;
+        let offer = Offer::parse(offer)?;
+        let (socket, candidate) = self.socket()?;
+
+        // First H.264 video and Opus audio section; everything else is declined
+        let mut accepted = vec![None; offer.media.len()];
+        let mut tracks = Vec::new();
+        if let Some(index) = offer.media.iter().position(|media| media.kind == "video") {
+            let formats = &offer.media[index].formats;
+            let h264 = formats
+                .iter()
+                .filter(|format| format.encoding == "H264")
+                .max_by_key(|format| format.fmtp.contains("packetization-mode=1"));
+            if let Some(format) = h264 {
+                let codec = Codec::H264 {
+                    parameter_sets: Vec::new(),
+                };
+                tracks.push((format.payload_type, format.clock_rate, codec));
+                accepted[index] = Some(Answer::echo(format).feedback("nack pli".to_string()));
+            }
+        }
+        if let Some(index) = offer.media.iter().position(|media| media.kind == "audio") {
+            if let Some(format) = offer.media[index].formats.iter().find(|format| format.encoding == "OPUS") {
+                let codec = Codec::Opus {
+                    channels: format.channels.clamp(1, 2),
+                };
+                tracks.push((format.payload_type, format.clock_rate, codec));
+                accepted[index] = Some(Answer::echo(format));
+            }
+        }
+        if tracks.is_empty() {
+            return Err(Error::InvalidInput("Offer has no H.264 or Opus track".to_string()));
+        }
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let publisher = streams.start_publish(app, name, format!("webrtc-{}", id)).await?;
+        let transport = self.transport(&id, &offer, socket)?;
+        let answer = answer(&offer, &transport, &self.fingerprint, candidate, &accepted, "recvonly");
+        info!("WHIP session {} publishing '{}/{}' ({} track(s))", id, app, name, tracks.len());
+        self.spawn(transport, |transport, packets| publish(transport, packets, publisher, tracks));
+        Ok((id, answer))
+    }
+
+    /// Start a WHEP session playing `name` of `app` with the client's
+    /// `token`; returns the session ID and the SDP answer
+    pub(crate) async fn whep(
+        &self,
+        streams: &StreamManager,
+        app: &str,
+        name: &str,
+        token: Option<&str>,
+        offer: &str,
+    ) -> Result<(String, String)> {
+        streams.authorize(app, Access::Play, token)?;
+        let offer = Offer::parse(offer)?;
+        let (socket, candidate) = self.socket()?;
+        let tracks = describe(streams, app, name)
+            .await
+            .ok_or_else(|| Error::NotFound(format!("Stream '{}' is not published", name)))?;
+        if let Some(stream) = streams.get_stream(app, name).await {
+            streams.admit_subscriber(app, &stream).await?;
+        }
+
+        let mut accepted = vec![None; offer.media.len()];
+        let mut packetizers = Vec::new();
+        for media in tracks {
+            let (kind, encoding) = match &media {
+                Media::H264 { .. } => ("video", "H264"),
+                Media::Aac { .. } => ("audio", "MPEG4-GENERIC"),
+                Media::Opus { .. } => ("audio", "OPUS"),
+            };
+            let Some(index) = offer.media.iter().position(|offered| offered.kind == kind) else {
+                continue;
+            };
+            let formats = &offer.media[index].formats;
+            let Some(format) = formats
+                .iter()
+                .filter(|format| format.encoding == encoding)
+                .max_by_key(|format| format.fmtp.contains("packetization-mode=1"))
+            else {
+                continue;
+            };
+            let mut answer = match media {
+                // The offer's fmtp, as the AudioSpecificConfig is ours
+                Media::Aac { .. } => {
+                    let (rtpmap, fmtp) = media.format();
+                    Answer::new(format.payload_type, rtpmap, fmtp)
+                }
+                _ => Answer::echo(format),
+            };
+            let packetizer = Packetizer::new(media, format.payload_type);
+            answer.ssrc = Some(packetizer.ssrc);
+            accepted[index] = Some(answer);
+            packetizers.push(packetizer);
+        }
+        if packetizers.is_empty() {
+            return Err(Error::InvalidInput(format!("Offer has no codec of stream '{}'", name)));
+        }
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let transport = self.transport(&id, &offer, socket)?;
+        let answer = answer(&offer, &transport, &self.fingerprint, candidate, &accepted, "sendonly");
+        info!("WHEP session {} playing '{}/{}' ({} track(s))", id, app, name, packetizers.len());
+        let (streams, app, name) = (streams.clone(), app.to_string(), name.to_string());
+        self.spawn(transport, move |transport, packets| {
+            play(transport, packets, streams, app, name, packetizers)
+        });
+        Ok((id, answer))
+    }
+
+    /// End a session; `false` if there is none with this ID
+    pub(crate) fn delete(&self, id: &str) -> bool {
+        let entry = self.registry().remove(id);
+        // Dropped outside the lock, aborting the session task
+        let found = entry.is_some();
+        drop(entry);
+        found
+    }
+
+    fn socket(&self) -> Result<(Arc<UdpSocket>, SocketAddr)> {
+        self.registry()
+            .socket
+            .clone()
+            .ok_or_else(|| Error::Network("WebRTC listener is not running".to_string()))
+    }
+
+    /// ICE and DTLS state of a new session
+    fn transport(&self, id: &str, offer: &Offer, socket: Arc<UdpSocket>) -> Result<Transport> {
+        let mut ssl = Ssl::new(&self.dtls).map_err(|e| Error::Internal(format!("DTLS session: {}", e)))?;
+        ssl.set_mtu(DTLS_MTU)
+            .map_err(|e| Error::Internal(format!("DTLS session: {}", e)))?;
+        ssl.set_accept_state();
+        let ufrag = Alphanumeric.sample_string(&mut rand::thread_rng(), 8);
+        let dtls = SslStream::new(ssl, Datagrams::default())
+            .map_err(|e| Error::Internal(format!("DTLS session: {}", e)))?;
+        Ok(Transport {
+            id: id.to_string(),
+            username: format!("{}:{}", ufrag, offer.ufrag),
+            ufrag,
+            pwd: Alphanumeric.sample_string(&mut rand::thread_rng(), 24),
+            remote_fingerprint: offer.fingerprint.clone(),
+            socket,
+            sessions: self.clone(),
+            peer: None,
+            dtls,
+            srtp: None,
+            last_seen: Instant::now(),
+        })
+    }
+
+    /// Register the session and run it until it ends or is deleted
+    fn spawn<F, Fut>(&self, transport: Transport, run: F)
+    where
+        F: FnOnce(Transport, mpsc::Receiver<(SocketAddr, Bytes)>) -> Fut,
+        Fut: std::future::Future<Output = Result<()>> + Send + 'static,
+    {
+        let (id, ufrag) = (transport.id.clone(), transport.ufrag.clone());
+        let (tx, packets) = mpsc::channel(SESSION_QUEUE);
+        let sessions = self.clone();
+        let session = run(transport, packets);
+        let mut registry = self.registry();
+        let task = tokio::spawn({
+            let id = id.clone();
+            async move {
+                match session.await {
+                    Ok(()) => info!("WebRTC session {} ended", id),
+                    Err(e) => warn!("WebRTC session {} ended: {}", id, e),
+                }
+                let entry = sessions.registry().remove(&id);
+                drop(entry);
+            }
+        });
+        registry.ufrags.insert(ufrag.clone(), id.clone());
+        registry.sessions.insert(
+            id,
+            SessionEntry {
+                ufrag,
+                packets: tx,
+                _task: AbortOnDrop(vec![task]),
+            },
+        );
+    }
+
+    /// Session channel for a datagram from `peer`
+    fn route(&self, peer: SocketAddr, data: &[u8]) -> Option<mpsc::Sender<(SocketAddr, Bytes)>> {
+        let registry = self.registry();
+        let id = match data.first()? {
+            // STUN: by the username fragment of ours that the peer uses
+            0..=3 => {
+                let message = StunMessage::parse(data)?;
+                let username = message.attribute(STUN_USERNAME)?;
+                let ufrag = std::str::from_utf8(username).ok()?.split(':').next()?;
+                registry.ufrags.get(ufrag)?
+            }
+            _ => registry.peers.get(&peer)?,
+        };
+        registry.sessions.get(id).map(|entry| entry.packets.clone())
+    }
+
+    /// Send the session's DTLS and SRTP packets from `peer` from now on
+    fn nominate(&self, id: &str, peer: SocketAddr) {
+        let mut registry = self.registry();
+        if registry.sessions.contains_key(id) {
+            registry.peers.retain(|_, session| session != id);
+            registry.peers.insert(peer, id.to_string());
+        }
+    }
+}
+
+impl Registry {
+    fn remove(&mut self, id: &str) -> Option<SessionEntry> {
+        let entry = self.sessions.remove(id)?;
+        self.ufrags.remove(&entry.ufrag);
+        self.peers.retain(|_, session| session != id);
+        Some(entry)
+    }
+}
+
+/// Self-signed certificate and the DTLS context using it
+fn dtls_context() -> std::result::Result<(SslContext, String), ErrorStack> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, "rtmp-streaming-server")?;
+    let name = name.build();
+    let mut cert = X509::builder()?;
+    cert.set_version(2)?;
+    let serial = BigNum::from_u32(rand::random::<u32>() >> 1)?.to_asn1_integer()?;
+    cert.set_serial_number(&serial)?;
+    cert.set_subject_name(&name)?;
+    cert.set_issuer_name(&name)?;
+    cert.set_pubkey(&key)?;
+    let (not_before, not_after) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(365)?);
+    cert.set_not_before(&not_before)?;
+    cert.set_not_after(&not_after)?;
+    cert.sign(&key, MessageDigest::sha256())?;
+    let cert = cert.build();
+
+    let mut context = SslContext::builder(SslMethod::dtls())?;
+    context.set_certificate(&cert)?;
+    context.set_private_key(&key)?;
+    context.set_tlsext_use_srtp(SRTP_PROFILE)?;
+    // The custom BIO cannot report a path MTU; records are sized by DTLS_MTU
+    context.set_options(SslOptions::NO_QUERY_MTU);
+    // Browsers use self-signed certificates too; the fingerprint from the
+    // offer is checked once the handshake is done
+    context.set_verify_callback(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT, |_, _| true);
+    Ok((context.build(), fingerprint(&cert)?))
+}
+
+/// SHA-256 certificate fingerprint as written in SDP
+fn fingerprint(cert: &X509Ref) -> std::result::Result<String, ErrorStack> {
+    let digest = cert.digest(MessageDigest::sha256())?;
+    Ok(digest.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":"))
+}
+
+/// Datagrams between the DTLS state machine and the socket
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Bytes>,
+    outgoing: Vec<Bytes>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let Some(datagram) = self.incoming.pop_front() else {
+            return Err(std::io::ErrorKind::WouldBlock.into());
+        };
+        let len = datagram.len().min(buf.len());
+        buf[..len].copy_from_slice(&datagram[..len]);
+        Ok(len)
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.push(Bytes::copy_from_slice(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// What a datagram from the peer amounted to
+enum Received {
+    Nothing,
+    /// DTLS is done and SRTP keyed
+    Connected,
+    Rtp(Bytes),
+    Rtcp,
+    /// The peer closed DTLS
+    Closed,
+}
+
+/// One session's ICE, DTLS and SRTP state
+struct Transport {
+    id: String,
+    /// Our ICE credentials
+    ufrag: String,
+    pwd: String,
+    /// ICE username the peer's binding requests carry
+    username: String,
+    /// Certificate fingerprint from the offer
+    remote_fingerprint: String,
+    socket: Arc<UdpSocket>,
+    sessions: WebRtcSessions,
+    /// Address nominated by the peer's binding requests
+    peer: Option<SocketAddr>,
+    dtls: SslStream<Datagrams>,
+    /// Inbound and outbound contexts, once DTLS is done
+    srtp: Option<(SrtpContext, SrtpContext)>,
+    last_seen: Instant,
+}
+
+impl Transport {
+    async fn receive(&mut self, from: SocketAddr, data: Bytes) -> Result<Received> {
+        match data[0] {
+            0..=3 => return self.stun(from, &data).await,
+            _ if self.peer != Some(from) => return Ok(Received::Nothing),
+            20..=63 => return self.dtls(data).await,
+            128..=191 => {}
+            _ => return Ok(Received::Nothing),
+        }
+        self.last_seen = Instant::now();
+        let Some((inbound, _)) = self.srtp.as_mut() else {
+            return Ok(Received::Nothing);
+        };
+        // RTCP payload types 192-223 with the marker bit read as RTP (RFC 5761)
+        let result = match data.get(1).map(|pt| pt & 0x7f) {
+            Some(64..=95) => inbound.unprotect_rtcp(&data).map(|_| Received::Rtcp),
+            _ => inbound.unprotect_rtp(&data).map(Received::Rtp),
+        };
+        Ok(result.unwrap_or_else(|e| {
+            debug!("WebRTC session {}: {}", self.id, e);
+            Received::Nothing
+        }))
+    }
+
+    /// Answer a binding request carrying our credentials
+    async fn stun(&mut self, from: SocketAddr, data: &[u8]) -> Result<Received> {
+        let Some(request) = StunMessage::parse(data) else {
+            return Ok(Received::Nothing);
+        };
+        if request.kind != STUN_BINDING_REQUEST
+            || request.attribute(STUN_USERNAME) != Some(self.username.as_bytes())
+            || !request.verify(self.pwd.as_bytes())
+        {
+            debug!("WebRTC session {}: ignoring STUN message from {}", self.id, from);
+            return Ok(Received::Nothing);
+        }
+        self.last_seen = Instant::now();
+        let response = StunMessage::binding_success(&request.transaction, from, self.pwd.as_bytes())?;
+        self.socket.send_to(&response, from).await?;
+
+        if self.peer != Some(from) && (self.peer.is_none() || request.attribute(STUN_USE_CANDIDATE).is_some()) {
+            debug!("WebRTC session {}: peer at {}", self.id, from);
+            self.peer = Some(from);
+            self.sessions.nominate(&self.id, from);
+        }
+        Ok(Received::Nothing)
+    }
+
+    /// Feed a DTLS record to the handshake or the established connection
+    async fn dtls(&mut self, data: Bytes) -> Result<Received> {
+        self.last_seen = Instant::now();
+        self.dtls.get_mut().incoming.push_back(data);
+        let mut received = Received::Nothing;
+        if self.srtp.is_none() {
+            match self.dtls.do_handshake() {
+                Ok(()) => {
+                    self.srtp = Some(self.srtp_keys()?);
+                    info!("WebRTC session {}: DTLS connected", self.id);
+                    received = Received::Connected;
+                }
+                Err(e) if e.code() == ErrorCode::WANT_READ => {}
+                Err(e) => return Err(Error::Protocol(format!("DTLS handshake failed: {}", e))),
+            }
+        } else {
+            // Only alerts and retransmitted handshake records are expected
+            let mut buf = [0u8; MAX_PACKET_SIZE];
+            match self.dtls.ssl_read(&mut buf) {
+                Ok(_) => {}
+                Err(e) if e.code() == ErrorCode::ZERO_RETURN => received = Received::Closed,
+                Err(e) if e.code() == ErrorCode::WANT_READ => {}
+                Err(e) => return Err(Error::Protocol(format!("DTLS error: {}", e))),
+            }
+        }
+        let outgoing = std::mem::take(&mut self.dtls.get_mut().outgoing);
+        if let Some(peer) = self.peer {
+            for datagram in outgoing {
+                self.socket.send_to(&datagram, peer).await?;
+            }
+        }
+        Ok(received)
+    }
+
+    /// Check the peer's certificate and derive the SRTP contexts
+    fn srtp_keys(&self) -> Result<(SrtpContext, SrtpContext)> {
+        let ssl = self.dtls.ssl();
+        let certificate = ssl
+            .peer_certificate()
+            .ok_or_else(|| Error::Protocol("DTLS peer sent no certificate".to_string()))?;
+        let actual = fingerprint(&certificate).map_err(|e| Error::Internal(e.to_string()))?;
+        if !actual.eq_ignore_ascii_case(&self.remote_fingerprint) {
+            return Err(Error::Protocol("DTLS certificate does not match the offer's fingerprint".to_string()));
+        }
+        if ssl.selected_srtp_profile().is_none() {
+            return Err(Error::Protocol("Peer did not negotiate an SRTP profile".to_string()));
+        }
+
+        // client key | server key | client salt | server salt; we are the server
+        let mut material = [0u8; 60];
+        ssl.export_keying_material(&mut material, SRTP_EXPORTER_LABEL, None)
+            .map_err(|e| Error::Internal(format!("DTLS-SRTP exporter: {}", e)))?;
+        let inbound = SrtpContext::new(&material[..16], &material[32..46])?;
+        let outbound = SrtpContext::new(&material[16..32], &material[46..60])?;
+        Ok((inbound, outbound))
+    }
+
+    async fn send_rtp(&mut self, packet: &[u8]) -> Result<()> {
+        if let (Some(peer), Some((_, outbound))) = (self.peer, self.srtp.as_mut()) {
+            let packet = outbound.protect_rtp(packet)?;
+            self.socket.send_to(&packet, peer).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_rtcp(&mut self, packet: &[u8]) -> Result<()> {
+        if let (Some(peer), Some((_, outbound))) = (self.peer, self.srtp.as_mut()) {
+            let packet = outbound.protect_rtcp(packet)?;
+            self.socket.send_to(&packet, peer).await?;
+        }
+        Ok(())
+    }
+
+    fn expired(&self) -> bool {
+        self.last_seen.elapsed() > SESSION_TIMEOUT
+    }
+}
+
+/// WHIP: publish what the peer sends
+async fn publish(
+    mut transport: Transport,
+    mut packets: mpsc::Receiver<(SocketAddr, Bytes)>,
+    mut publisher: Publisher,
+    tracks: Vec<(u8, u32, Codec)>,
+) -> Result<()> {
+    let mut receiver = RtpReceiver::new(tracks.iter().map(|(_, clock_rate, codec)| (*clock_rate, codec.clone())));
+    let video_pt = tracks
+        .iter()
+        .find(|(_, _, codec)| matches!(codec, Codec::H264 { .. }))
+        .map(|(pt, _, _)| *pt);
+    let ssrc: u32 = rand::random();
+    let mut video_ssrc = None;
+    let mut keyframe = false;
+    let mut subscribers = 0;
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some((from, data)) = packet else {
+                    break;
+                };
+                let packet = match transport.receive(from, data).await? {
+                    Received::Rtp(packet) => packet,
+                    Received::Closed => break,
+                    _ => continue,
+                };
+                let pt = packet[1] & 0x7f;
+                let Some(track) = tracks.iter().position(|(track_pt, _, _)| *track_pt == pt) else {
+                    continue;
+                };
+                if Some(pt) == video_pt && video_ssrc.is_none() {
+                    video_ssrc = Some(u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]));
+                }
+                for tag in receiver.receive(track, packet) {
+                    keyframe |= tag.is_video() && tag.is_keyframe() && !tag.is_sequence_header();
+                    publisher.publish(tag).await?;
+                }
+            }
+            _ = tick.tick() => {
+                if transport.expired() {
+                    return Err(Error::Network("WebRTC peer timed out".to_string()));
+                }
+                let count = publisher.stream().subscriber_count();
+                if let Some(media_ssrc) = video_ssrc {
+                    if !keyframe || count > subscribers {
+                        transport.send_rtcp(&picture_loss_indication(ssrc, media_ssrc)).await?;
+                    }
+                }
+                subscribers = count;
+            }
+            _ = publisher.evicted() => {
+                info!("WHIP session {}: replaced by a new publisher", transport.id);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// RTCP PLI asking the sender of `media_ssrc` for a keyframe (RFC 4585)
+fn picture_loss_indication(sender_ssrc: u32, media_ssrc: u32) -> [u8; 12] {
+    let mut packet = [0u8; 12];
+    packet[0] = 0x80 | RTCP_FMT_PLI;
+    packet[1] = RTCP_PSFB;
+    packet[2..4].copy_from_slice(&2u16.to_be_bytes());
+    packet[4..8].copy_from_slice(&sender_ssrc.to_be_bytes());
+    packet[8..12].copy_from_slice(&media_ssrc.to_be_bytes());
+    packet
+}
+
+/// WHEP: send the stream to the peer once DTLS is up
+async fn play(
+    mut transport: Transport,
+    mut packets: mpsc::Receiver<(SocketAddr, Bytes)>,
+    streams: StreamManager,
+    app: String,
+    name: String,
+    packetizers: Vec<Packetizer>,
+) -> Result<()> {
+    let mut packetizers = Some(packetizers);
+    let mut playing: Option<(Subscription, RtpSource)> = None;
+    let mut tick = tokio::time::interval(TICK_INTERVAL);
+
+    loop {
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some((from, data)) = packet else {
+                    break;
+                };
+                match transport.receive(from, data).await? {
+                    Received::Connected => {
+                        let stream = streams.get_or_pull(&app, &name).await;
+                        let subscription = stream
+                            .subscribe(format!("webrtc-{}", transport.id))
+                            .await
+                            .with_lag_policy(streams.config().app(&app).lag_policy());
+                        let label = format!("{}/webrtc-{}", name, transport.id);
+                        let source = RtpSource::new(packetizers.take().unwrap_or_default(), label);
+                        playing = Some((subscription, source));
+                    }
+                    Received::Closed => break,
+                    // Receiver reports and feedback: SRTP keeps the session alive
+                    _ => {}
+                }
+            }
+            tag = next_tag(&mut playing) => {
+                let Some(tag) = tag? else {
+                    info!("WHEP session {}: stream '{}' ended", transport.id, name);
+                    break;
+                };
+                let Some((_, source)) = playing.as_mut() else {
+                    continue;
+                };
+                for (_, packet) in source.packetize(&tag) {
+                    transport.send_rtp(&packet).await?;
+                }
+            }
+            _ = tick.tick() => {
+                if transport.expired() {
+                    return Err(Error::Network("WebRTC peer timed out".to_string()));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Next tag of the subscription, never ready before playback starts
+async fn next_tag(playing: &mut Option<(Subscription, RtpSource)>) -> Result<Option<FlvTag>> {
+    match playing {
+        Some((subscription, _)) => subscription.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The parts of an SDP offer we use
+struct Offer {
+    /// The peer's ICE username fragment
+    ufrag: String,
+    /// SHA-256 fingerprint of the peer's DTLS certificate
+    fingerprint: String,
+    media: Vec<OfferMedia>,
+}
+
+struct OfferMedia {
+    /// `audio`, `video` or `application`
+    kind: String,
+    protocol: String,
+    /// Format list of the m-line, as offered
+    format_list: String,
+    mid: Option<String>,
+    formats: Vec<Format>,
+}
+
+/// An `a=rtpmap` of the offer with its `a=fmtp`
+struct Format {
+    payload_type: u8,
+    /// Encoding name, upper case
+    encoding: String,
+    rtpmap: String,
+    clock_rate: u32,
+    channels: u8,
+    fmtp: String,
+}
+
+impl Offer {
+    fn parse(sdp: &str) -> Result<Self> {
+        let mut ufrag = None;
+        let mut fingerprint = None;
+        let mut media: Vec<OfferMedia> = Vec::new();
+        for line in sdp.lines().map(str::trim) {
+            if let Some(m) = line.strip_prefix("m=") {
+                let mut parts = m.splitn(4, ' ');
+                let kind = parts.next().unwrap_or_default().to_string();
+                let protocol = parts.nth(1).unwrap_or_default().to_string();
+                media.push(OfferMedia {
+                    kind,
+                    protocol,
+                    format_list: parts.next().unwrap_or_default().to_string(),
+                    mid: None,
+                    formats: Vec::new(),
+                });
+                continue;
+            }
+            let Some(attribute) = line.strip_prefix("a=") else {
+                continue;
+            };
+            let (name, value) = attribute.split_once(':').unwrap_or((attribute, ""));
+            match name {
+                "ice-ufrag" => {
+                    ufrag.get_or_insert_with(|| value.to_string());
+                }
+                "fingerprint" => {
+                    if let Some(("sha-256", hash)) = value.split_once(' ') {
+                        fingerprint.get_or_insert_with(|| hash.trim().to_string());
+                    }
+                }
+                _ => {}
+            }
+            let Some(current) = media.last_mut() else {
+                continue;
+            };
+            match name {
+                "mid" => current.mid = Some(value.to_string()),
+                "rtpmap" => {
+                    let Some((pt, rtpmap)) = value.split_once(' ') else {
+                        continue;
+                    };
+                    let mut parts = rtpmap.split('/');
+                    let encoding = parts.next().unwrap_or_default().to_ascii_uppercase();
+                    let clock_rate = parts.next().and_then(|rate| rate.parse().ok());
+                    let (Ok(payload_type), Some(clock_rate)) = (pt.parse(), clock_rate) else {
+                        continue;
+                    };
+                    current.formats.push(Format {
+                        payload_type,
+                        encoding,
+                        rtpmap: rtpmap.to_string(),
+                        clock_rate,
+                        channels: parts.next().and_then(|channels| channels.parse().ok()).unwrap_or(1),
+                        fmtp: String::new(),
+                    });
+                }
+                "fmtp" => {
+                    let Some((pt, fmtp)) = value.split_once(' ') else {
+                        continue;
+                    };
+                    let pt: Option<u8> = pt.parse().ok();
+                    if let Some(format) = current.formats.iter_mut().find(|format| Some(format.payload_type) == pt) {
+                        format.fmtp = fmtp.to_string();
+                    }
+                }
+                _ => {}
+            }
+        }
+        let ufrag = ufrag.ok_or_else(|| Error::InvalidInput("Offer has no ICE credentials".to_string()))?;
+        let fingerprint =
+            fingerprint.ok_or_else(|| Error::InvalidInput("Offer has no sha-256 fingerprint".to_string()))?;
+        if media.is_empty() {
+            return Err(Error::InvalidInput("Offer has no media".to_string()));
+        }
+        Ok(Self {
+            ufrag,
+            fingerprint,
+            media,
+        })
+    }
+}
+
+/// The format we accept for one m-line of the offer
+#[derive(Clone)]
+struct Answer {
+    payload_type: u8,
+    rtpmap: String,
+    fmtp: String,
+    feedback: Vec<String>,
+    /// SSRC of the track we send
+    ssrc: Option<u32>,
+}
+
+impl Answer {
+    fn new(payload_type: u8, rtpmap: String, fmtp: String) -> Self {
+        Self {
+            payload_type,
+            rtpmap,
+            fmtp,
+            feedback: Vec::new(),
+            ssrc: None,
+        }
+    }
+
+    /// Accept an offered format as it is
+    fn echo(format: &Format) -> Self {
+        Self::new(format.payload_type, format.rtpmap.clone(), format.fmtp.clone())
+    }
+
+    fn feedback(mut self, feedback: String) -> Self {
+        self.feedback.push(feedback);
+        self
+    }
+}
+
+/// SDP answer: one section per offered m-line, unused ones declined
+fn answer(
+    offer: &Offer,
+    transport: &Transport,
+    fingerprint: &str,
+    candidate: SocketAddr,
+    accepted: &[Option<Answer>],
+    direction: &str,
+) -> String {
+    let family = if candidate.is_ipv4() { "IP4" } else { "IP6" };
+    let mids: Vec<&str> = offer
+        .media
+        .iter()
+        .zip(accepted)
+        .filter(|(_, answer)| answer.is_some())
+        .filter_map(|(media, _)| media.mid.as_deref())
+        .collect();
+    let mut sdp = format!(
+        "v=0\r\no=- {} 2 IN {} {}\r\ns=-\r\nt=0 0\r\n",
+        rand::random::<u32>(),
+        family,
+        candidate.ip()
+    );
+    if !mids.is_empty() {
+        sdp.push_str(&format!("a=group:BUNDLE {}\r\n", mids.join(" ")));
+    }
+    sdp.push_str("a=ice-lite\r\n");
+
+    for (media, answer) in offer.media.iter().zip(accepted) {
+        let Some(answer) = answer else {
+            sdp.push_str(&format!("m={} 0 {} {}\r\nc=IN {} 0.0.0.0\r\n", media.kind, media.protocol, media.format_list, family));
+            if let Some(mid) = &media.mid {
+                sdp.push_str(&format!("a=mid:{}\r\n", mid));
+            }
+            sdp.push_str("a=inactive\r\n");
+            continue;
+        };
+        let pt = answer.payload_type;
+        sdp.push_str(&format!(
+            "m={} {} {} {}\r\nc=IN {} {}\r\n",
+            media.kind,
+            candidate.port(),
+            media.protocol,
+            pt,
+            family,
+            candidate.ip()
+        ));
+        if let Some(mid) = &media.mid {
+            sdp.push_str(&format!("a=mid:{}\r\n", mid));
+        }
+        sdp.push_str(&format!(
+            "a=ice-ufrag:{}\r\na=ice-pwd:{}\r\na=fingerprint:sha-256 {}\r\na=setup:passive\r\na=rtcp-mux\r\na={}\r\n",
+            transport.ufrag, transport.pwd, fingerprint, direction
+        ));
+        sdp.push_str(&format!("a=rtpmap:{} {}\r\n", pt, answer.rtpmap));
+        if !answer.fmtp.is_empty() {
+            sdp.push_str(&format!("a=fmtp:{} {}\r\n", pt, answer.fmtp));
+        }
+        for feedback in &answer.feedback {
+            sdp.push_str(&format!("a=rtcp-fb:{} {}\r\n", pt, feedback));
+        }
+        if let Some(ssrc) = answer.ssrc {
+            sdp.push_str(&format!("a=ssrc:{} cname:rtmp-streaming-server\r\n", ssrc));
+        }
+        sdp.push_str(&format!(
+            "a=candidate:1 1 UDP 2130706431 {} {} typ host\r\na=end-of-candidates\r\n",
+            candidate.ip(),
+            candidate.port()
+        ));
+    }
+    sdp
+}
+
+/// A STUN message
+struct StunMessage {
+    kind: u16,
+    transaction: [u8; 12],
+    /// Type, value and offset of every attribute
+    attributes: Vec<(u16, Bytes, usize)>,
+    raw: Bytes,
+}
+
+impl StunMessage {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 20 || data[0] & 0xc0 != 0 {
+            return None;
+        }
+        let kind = u16::from_be_bytes([data[0], data[1]]);
+        let length = u16::from_be_bytes([data[2], data[3]]) as usize;
+        if u32::from_be_bytes([data[4], data[5], data[6], data[7]]) != STUN_MAGIC_COOKIE || data.len() != 20 + length {
+            return None;
+        }
+        let raw = Bytes::copy_from_slice(data);
+        let mut attributes = Vec::new();
+        let mut offset = 20;
+        while offset + 4 <= raw.len() {
+            let kind = u16::from_be_bytes([raw[offset], raw[offset + 1]]);
+            let len = u16::from_be_bytes([raw[offset + 2], raw[offset + 3]]) as usize;
+            let value = raw.get(offset + 4..offset + 4 + len)?;
+            attributes.push((kind, raw.slice_ref(value), offset));
+            offset += 4 + len.div_ceil(4) * 4;
+        }
+        Some(Self {
+            kind,
+            transaction: data[8..20].try_into().ok()?,
+            attributes,
+            raw,
+        })
+    }
+
+    fn attribute(&self, kind: u16) -> Option<&[u8]> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _, _)| *attribute == kind)
+            .map(|(_, value, _)| &value[..])
+    }
+
+    /// Check MESSAGE-INTEGRITY with a short-term credential
+    fn verify(&self, key: &[u8]) -> bool {
+        let Some((_, value, offset)) = self.attributes.iter().find(|(kind, _, _)| *kind == STUN_MESSAGE_INTEGRITY) else {
+            return false;
+        };
+        // The length field covers everything up to and including the attribute
+        let mut covered = self.raw[..*offset].to_vec();
+        covered[2..4].copy_from_slice(&((*offset + 24 - 20) as u16).to_be_bytes());
+        hmac_sha1(key, &covered).is_ok_and(|expected| openssl::memcmp::eq(&expected, value))
+    }
+
+    /// Binding success response telling the peer its reflexive address
+    fn binding_success(transaction: &[u8; 12], peer: SocketAddr, key: &[u8]) -> Result<Vec<u8>> {
+        let mut address = BytesMut::new();
+        address.put_u8(0);
+        address.put_u8(if peer.is_ipv4() { 0x01 } else { 0x02 });
+        address.put_u16(peer.port() ^ (STUN_MAGIC_COOKIE >> 16) as u16);
+        let mut mask = STUN_MAGIC_COOKIE.to_be_bytes().to_vec();
+        mask.extend_from_slice(transaction);
+        let octets = match peer.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        address.extend(octets.iter().zip(&mask).map(|(byte, mask)| byte ^ mask));
+
+        let mut message = BytesMut::new();
+        message.put_u16(STUN_BINDING_SUCCESS);
+        message.put_u16(0);
+        message.put_u32(STUN_MAGIC_COOKIE);
+        message.put_slice(transaction);
+        put_attribute(&mut message, STUN_XOR_MAPPED_ADDRESS, &address);
+
+        let length = (message.len() - 20 + 24) as u16;
+        message[2..4].copy_from_slice(&length.to_be_bytes());
+        let integrity = hmac_sha1(key, &message).map_err(|e| Error::Internal(format!("HMAC-SHA1: {}", e)))?;
+        put_attribute(&mut message, STUN_MESSAGE_INTEGRITY, &integrity);
+
+        let length = (message.len() - 20 + 8) as u16;
+        message[2..4].copy_from_slice(&length.to_be_bytes());
+        let fingerprint = crc32(&message) ^ STUN_FINGERPRINT_XOR;
+        put_attribute(&mut message, STUN_FINGERPRINT, &fingerprint.to_be_bytes());
+        Ok(message.to_vec())
+    }
+}
+
+fn put_attribute(message: &mut BytesMut, kind: u16, value: &[u8]) {
+    message.put_u16(kind);
+    message.put_u16(value.len() as u16);
+    message.put_slice(value);
+    message.put_bytes(0, value.len().div_ceil(4) * 4 - value.len());
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> std::result::Result<Vec<u8>, ErrorStack> {
+    let key = PKey::hmac(key)?;
+    let mut signer = Signer::new(MessageDigest::sha1(), &key)?;
+    signer.update(data)?;
+    signer.sign_to_vec()
+}
+
+/// CRC-32 (ISO-HDLC) of the STUN FINGERPRINT attribute
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use amf::amf0::Value;
+use bytes::Bytes;
+use common::{start_rtmp, video, with_app, Peer};
+use rtmp_streaming_server::config::AppConfig;
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::protocol::{Message, MessageType};
+use rtmp_streaming_server::StreamManager;
+use std::time::Duration;
+
+/// Video message on stream 1
+fn video_message(timestamp: u32, first: u8) -> Message {
+    Message::from_tag(&video(timestamp, first, 0x01), 1)
+}
+
+#[test]
+fn test_split_and_build() {
+    let messages = [video_message(500, 0x17), video_message(540, 0x27), video_message(580, 0x27)];
+    let aggregate = Message::aggregate(&messages, 1);
+    assert_eq!(aggregate.message_type, MessageType::Aggregate);
+    assert_eq!(aggregate.timestamp, 500);
+    // Tag header, payload and back pointer for each message
+    assert_eq!(aggregate.payload.len(), 3 * (11 + 6 + 4));
+    assert_eq!(&aggregate.payload[17..21], &17u32.to_be_bytes());
+
+    // Sub-messages are timed relative to the aggregate itself
+    let mut shifted = aggregate.clone();
+    shifted.timestamp = 1000;
+    let split = shifted.split_aggregate().unwrap();
+    let timestamps: Vec<u32> = split.iter().map(|m| m.timestamp).collect();
+    assert_eq!(timestamps, [1000, 1040, 1080]);
+    for (split, message) in split.iter().zip(&messages) {
+        assert_eq!(split.message_type, MessageType::Video);
+        assert_eq!(split.message_stream_id, 1);
+        assert_eq!(split.payload, message.payload);
+    }
+
+    // A body cut short is rejected
+    let truncated = Message::new(MessageType::Aggregate, 1, 0, aggregate.payload.slice(..30));
+    assert!(truncated.split_aggregate().is_err());
+}
+
+#[tokio::test]
+async fn test_publish_aggregate() {
+    let streams = StreamManager::new();
+    let addr = start_rtmp(streams.clone()).await;
+    let mut peer = Peer::connected(addr).await;
+    peer.create_stream().await;
+    peer.command(
+        1,
+        &[
+            Value::String("publish".to_string()),
+            Value::Number(0.0),
+            Value::Null,
+            Value::String("s".to_string()),
+            Value::String("live".to_string()),
+        ],
+    )
+    .await;
+    peer.status("NetStream.Publish.Start").await;
+    let mut subscription = streams.subscribe("live", "s", "viewer".to_string()).await.unwrap();
+
+    let messages = [video_message(0, 0x17), video_message(40, 0x27), video_message(80, 0x27)];
+    let mut aggregate = Message::aggregate(&messages, 1);
+    aggregate.timestamp = 2000;
+    peer.send(&aggregate).await;
+
+    for (timestamp, first) in [(2000, 0x17), (2040, 0x27), (2080, 0x27)] {
+        let tag = tokio::time::timeout(Duration::from_secs(2), subscription.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(tag, video(timestamp, first, 0x01));
+    }
+}
+
+#[tokio::test]
+async fn test_play_aggregated() {
+    let streams = with_app(AppConfig {
+        aggregate_ms: 100,
+        ..AppConfig::default()
+    });
+    let addr = start_rtmp(streams.clone()).await;
+    let publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+
+    let mut peer = Peer::connected(addr).await;
+    peer.create_stream().await;
+    peer.command(
+        1,
+        &[Value::String("play".to_string()), Value::Number(0.0), Value::Null, Value::String("s".to_string())],
+    )
+    .await;
+    peer.status("NetStream.Play.Start").await;
+
+    // The sequence header goes out on its own, the frames in batches
+    publisher
+        .publish(FlvTag::new(TagType::Video, 0, Bytes::from_static(&[0x17, 0x00, 0, 0, 0, 0x01])))
+        .await
+        .unwrap();
+    for i in 0..5 {
+        let first = if i == 0 { 0x17 } else { 0x27 };
+        publisher.publish(video(i * 40, first, 0x01)).await.unwrap();
+    }
+    assert_eq!(peer.expect(MessageType::Video).await.payload[1], 0x00);
+
+    // 0 to 120 ms fills the window; 160 ms is sent when the time is up
+    let aggregate = peer.expect(MessageType::Aggregate).await;
+    let frames = aggregate.split_aggregate().unwrap();
+    assert_eq!(frames.len(), 4);
+    let offsets: Vec<u32> = frames.iter().map(|m| m.timestamp - frames[0].timestamp).collect();
+    assert_eq!(offsets, [0, 40, 80, 120]);
+    assert!(frames.iter().all(|m| m.message_type == MessageType::Video));
+    let last = peer.expect(MessageType::Video).await;
+    assert_eq!(last.timestamp, frames[0].timestamp + 160);
+}
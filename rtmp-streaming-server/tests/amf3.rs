@@ -0,0 +1,147 @@
+mod common;
+
+use amf::amf0::Value;
+use amf::{amf3, Pair};
+use bytes::Bytes;
+use common::{start_rtmp, Peer};
+use rtmp_streaming_server::flv::TagType;
+use rtmp_streaming_server::protocol::{Message, MessageType};
+use rtmp_streaming_server::StreamManager;
+use std::time::Duration;
+
+fn pair(key: &str, value: Value) -> Pair<String, Value> {
+    Pair {
+        key: key.to_string(),
+        value,
+    }
+}
+
+fn object(entries: Vec<Pair<String, Value>>) -> Value {
+    Value::Object {
+        class_name: None,
+        entries,
+    }
+}
+
+fn decode(mut payload: &[u8]) -> Vec<Value> {
+    let mut values = Vec::new();
+    while !payload.is_empty() {
+        values.push(Value::read_from(&mut payload).unwrap());
+    }
+    values
+}
+
+/// Wait for an AMF3 command message; returns its values
+async fn expect_command(peer: &mut Peer) -> Vec<Value> {
+    loop {
+        let message = peer.recv().await.expect("message");
+        assert_ne!(message.message_type, MessageType::CommandAmf0, "AMF0 command to an AMF3 client");
+        if message.message_type == MessageType::CommandAmf3 {
+            assert_eq!(message.payload[0], 0);
+            return decode(&message.payload[1..]);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_amf3_session() {
+    let streams = StreamManager::new();
+    let addr = start_rtmp(streams.clone()).await;
+    let mut peer = Peer::connect(addr).await;
+
+    // connect negotiates AMF3 and is answered in kind
+    let properties = Value::AvmPlus(amf3::Value::Object {
+        class_name: None,
+        sealed_count: 0,
+        entries: vec![
+            Pair {
+                key: "app".to_string(),
+                value: amf3::Value::String("live".to_string()),
+            },
+            Pair {
+                key: "objectEncoding".to_string(),
+                value: amf3::Value::Integer(3),
+            },
+        ],
+    });
+    peer.command_amf3(0, &[Value::String("connect".to_string()), Value::Number(1.0), properties]).await;
+    let result = expect_command(&mut peer).await;
+    assert_eq!(result[0], Value::String("_result".to_string()));
+    assert_eq!(result[1], Value::Number(1.0));
+    // The reply's objects are real AMF3 objects
+    let Value::AvmPlus(amf3::Value::Object { entries, .. }) = &result[3] else {
+        panic!("AMF3 connect info expected, got {:?}", result[3]);
+    };
+    let code = amf3::Value::String("NetConnection.Connect.Success".to_string());
+    assert!(entries.contains(&Pair {
+        key: "code".to_string(),
+        value: code
+    }));
+    assert!(entries.contains(&Pair {
+        key: "objectEncoding".to_string(),
+        value: amf3::Value::Integer(3)
+    }));
+
+    peer.command_amf3(0, &[Value::String("createStream".to_string()), Value::Number(2.0), Value::Null]).await;
+    assert_eq!(expect_command(&mut peer).await[3], Value::AvmPlus(amf3::Value::Integer(1)));
+
+    // Arguments switched to AMF3 are understood like AMF0 ones
+    let name = Value::AvmPlus(amf3::Value::String("s".to_string()));
+    peer.command_amf3(1, &[Value::String("publish".to_string()), Value::Number(0.0), Value::Null, name]).await;
+    let status = expect_command(&mut peer).await;
+    assert_eq!(status[0], Value::String("onStatus".to_string()));
+    assert!(format!("{:?}", status[3]).contains("NetStream.Publish.Start"));
+    let mut subscription = streams.subscribe("live", "s", "viewer".to_string()).await.unwrap();
+
+    // AMF3 metadata with string, trait and object references
+    let mut metadata = vec![0u8];
+    Value::String("@setDataFrame".to_string()).write_to(&mut metadata).unwrap();
+    Value::String("onMetaData".to_string()).write_to(&mut metadata).unwrap();
+    metadata.extend_from_slice(&[0x11, 0x09, 0x01]);
+    metadata.extend_from_slice(b"\x0dauthor\x06\x07lee");
+    metadata.extend_from_slice(b"\x0dartist\x06\x02");
+    metadata.extend_from_slice(b"\x09size\x0a\x0b\x01\x03w\x04\x10\x01");
+    metadata.extend_from_slice(b"\x09crop\x0a\x01\x08\x04\x08\x01");
+    metadata.extend_from_slice(b"\x09copy\x0a\x02");
+    metadata.extend_from_slice(b"\x01");
+    peer.send(&Message::new(MessageType::DataAmf3, 1, 0, Bytes::from(metadata))).await;
+
+    let tag = tokio::time::timeout(Duration::from_secs(2), subscription.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(tag.tag_type, TagType::Script);
+    let size = object(vec![pair("w", Value::Number(16.0))]);
+    assert_eq!(
+        decode(&tag.data),
+        [
+            Value::String("onMetaData".to_string()),
+            Value::EcmaArray {
+                entries: vec![
+                    pair("author", Value::String("lee".to_string())),
+                    pair("artist", Value::String("lee".to_string())),
+                    pair("size", size.clone()),
+                    pair("crop", object(vec![pair("w", Value::Number(8.0))])),
+                    pair("copy", size),
+                ],
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_amf0_client_unchanged() {
+    let addr = start_rtmp(StreamManager::new()).await;
+    let mut peer = Peer::connect(addr).await;
+    peer.connect_app().await;
+    loop {
+        let message = peer.recv().await.expect("message");
+        assert_ne!(message.message_type, MessageType::CommandAmf3);
+        if message.message_type == MessageType::CommandAmf0 {
+            let result = decode(&message.payload);
+            assert!(format!("{:?}", result[3]).contains("objectEncoding\", value: Number(0.0)"));
+            break;
+        }
+    }
+}
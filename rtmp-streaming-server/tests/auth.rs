@@ -0,0 +1,83 @@
+mod common;
+
+use common::{start_http, start_rtmp, with_tenant, TENANT};
+use hyper::{Body, Client, Request};
+use rtmp_streaming_server::config::{self, Access, AppConfig, Config};
+use rtmp_streaming_server::{Error, RtmpClient, RtmpUrl, StreamManager};
+
+fn config() -> Config {
+    let mut config = Config::default();
+    config.apps.insert(
+        "live".to_string(),
+        AppConfig {
+            publish_token: Some("pub".to_string()),
+            ..AppConfig::default()
+        },
+    );
+    with_tenant(
+        config,
+        AppConfig {
+            play_token: Some("view".to_string()),
+            ..AppConfig::default()
+        },
+    )
+}
+
+#[test]
+fn test_tokens() {
+    let config = config();
+    let live = config.app("live");
+    assert!(live.authorize(Access::Publish, Some("pub")).is_ok());
+    assert!(matches!(live.authorize(Access::Publish, None), Err(Error::AuthDenied(_))));
+    assert!(live.authorize(Access::Publish, Some("view")).is_err());
+    assert!(live.authorize(Access::Play, None).is_ok());
+    // The vhost's app has settings of its own
+    let tenant = config.app(&config.app_id(Some(TENANT), "live").unwrap());
+    assert!(tenant.authorize(Access::Publish, None).is_ok());
+    assert!(tenant.authorize(Access::Play, None).is_err());
+
+    assert_eq!(config::token("s?token=abc"), Some("abc"));
+    assert_eq!(config::token("offset=-5&token=abc"), Some("abc"));
+    assert_eq!(config::token("s"), None);
+}
+
+#[tokio::test]
+async fn test_rtmp_publish_needs_token() {
+    let streams = StreamManager::new().with_config(config());
+    let addr = start_rtmp(streams.clone()).await;
+
+    let target = RtmpUrl::parse(&format!("rtmp://{}/live/s", addr)).unwrap();
+    let mut client = RtmpClient::connect(&target).await.unwrap();
+    match client.publish("s?token=wrong").await {
+        Err(Error::Stream(message)) => assert!(message.contains("NetStream.Publish.Denied"), "{}", message),
+        other => panic!("publish should fail, got {:?}", other.map(|_| ())),
+    }
+    assert!(streams.get_stream("live", "s").await.is_none());
+
+    let mut client = RtmpClient::connect(&target).await.unwrap();
+    client.publish("s?token=pub").await.unwrap();
+    assert!(streams.get_stream("live", "s").await.unwrap().is_published());
+}
+
+#[tokio::test]
+async fn test_http_play_needs_token() {
+    let streams = StreamManager::new().with_config(config());
+    let addr = start_http(streams.clone()).await;
+
+    let client = Client::new();
+    let play = |host: &str, path: &str| {
+        Request::get(format!("http://{}{}", addr, path))
+            .header("Host", host)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let denied = client.request(play(TENANT, "/live/s")).await.unwrap();
+    assert_eq!(denied.status(), 403);
+    let wrong = client.request(play(TENANT, "/live/s?token=pub")).await.unwrap();
+    assert_eq!(wrong.status(), 403);
+    let allowed = client.request(play(TENANT, "/live/s?token=view")).await.unwrap();
+    assert_eq!(allowed.status(), 200);
+    // The default host's live app lets anyone play
+    let open = client.request(play("localhost", "/live/s")).await.unwrap();
+    assert_eq!(open.status(), 200);
+}
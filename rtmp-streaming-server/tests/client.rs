@@ -0,0 +1,131 @@
+mod common;
+
+use amf::amf0::Value;
+use bytes::Bytes;
+use common::{start_rtmp, video};
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::{Error, RtmpClient, RtmpSession, RtmpUrl, StreamManager};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn url(addr: SocketAddr, stream: &str) -> RtmpUrl {
+    RtmpUrl::parse(&format!("rtmp://{}/live/{}", addr, stream)).unwrap()
+}
+
+fn metadata() -> FlvTag {
+    let mut data = Vec::new();
+    Value::String("onMetaData".to_string()).write_to(&mut data).unwrap();
+    let properties = Value::EcmaArray {
+        entries: vec![amf::Pair {
+            key: "width".to_string(),
+            value: Value::Number(1280.0),
+        }],
+    };
+    properties.write_to(&mut data).unwrap();
+    FlvTag::new(TagType::Script, 0, Bytes::from(data))
+}
+
+async fn read(client: &mut RtmpClient) -> Option<FlvTag> {
+    tokio::time::timeout(Duration::from_secs(2), client.read_tag()).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn test_push_and_pull() {
+    let streams = StreamManager::new();
+    let addr = start_rtmp(streams.clone()).await;
+
+    let target = url(addr, "s");
+    let mut publisher = RtmpClient::connect(&target).await.unwrap();
+    publisher.publish(&target.stream).await.unwrap();
+    publisher.send_tag(&metadata()).await.unwrap();
+    publisher.send_tag(&video(0, 0x17, 0x00)).await.unwrap();
+    let stream = streams.get_stream("live", "s").await.unwrap();
+    for _ in 0..100 {
+        if stream.sequence_headers().await.len() == 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    drop(stream);
+
+    // A late player gets the headers first, then the frames
+    let mut player = RtmpClient::connect(&target).await.unwrap();
+    player.play(&target.stream).await.unwrap();
+    let header = read(&mut player).await.unwrap();
+    assert!(header.is_script());
+    assert_eq!(header.data, metadata().data);
+    let header = read(&mut player).await.unwrap();
+    assert!(header.is_video() && header.is_sequence_header());
+
+    publisher.send_tag(&video(40, 0x17, 0x01)).await.unwrap();
+    publisher.send_tag(&video(80, 0x27, 0x01)).await.unwrap();
+    let keyframe = read(&mut player).await.unwrap();
+    assert!(keyframe.is_keyframe());
+    assert_eq!(keyframe.data, video(40, 0x17, 0x01).data);
+    let frame = read(&mut player).await.unwrap();
+    assert_eq!(frame.data, video(80, 0x27, 0x01).data);
+    assert_eq!(frame.timestamp - keyframe.timestamp, 40);
+
+    // Closing the publisher ends the player's stream
+    publisher.close().await.unwrap();
+    assert!(read(&mut player).await.is_none());
+    player.close().await.unwrap();
+}
+
+#[tokio::test]
+async fn test_publish_rejected() {
+    let streams = StreamManager::new();
+    let addr = start_rtmp(streams.clone()).await;
+    let _publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+
+    let target = url(addr, "s");
+    let mut client = RtmpClient::connect(&target).await.unwrap();
+    match client.publish(&target.stream).await {
+        Err(Error::Stream(message)) => assert!(message.contains("NetStream.Publish.BadName"), "{}", message),
+        other => panic!("publish should fail, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn test_connect_over_any_stream() {
+    let streams = StreamManager::new();
+    let (client_side, server_side) = tokio::io::duplex(64 * 1024);
+    let mut session = RtmpSession::new(server_side, "127.0.0.1:1".parse().unwrap(), streams.clone());
+    tokio::spawn(async move { session.handle().await });
+
+    let target = RtmpUrl::parse("rtmp://example.com/live/s").unwrap();
+    let mut client = RtmpClient::connect_with(client_side, &target).await.unwrap();
+    client.publish("s").await.unwrap();
+    assert!(streams.get_stream("live", "s").await.unwrap().is_published());
+}
+
+#[test]
+fn test_url_parsing() {
+    let url = RtmpUrl::parse("rtmp://example.com/live/s?token=abc").unwrap();
+    assert_eq!((url.host.as_str(), url.port, url.app.as_str()), ("example.com", 1935, "live"));
+    assert_eq!(url.stream, "s?token=abc");
+    assert_eq!(url.tc_url(), "rtmp://example.com:1935/live");
+    assert_eq!(RtmpUrl::parse("rtmpt://example.com/live/s").unwrap().port, 80);
+
+    // IPv6 hosts keep their colons, with or without a port
+    let url = RtmpUrl::parse("rtmp://[::1]/live/s").unwrap();
+    assert_eq!((url.host.as_str(), url.port), ("::1", 1935));
+    assert_eq!(url.tc_url(), "rtmp://[::1]:1935/live");
+    let url = RtmpUrl::parse("rtmp://[2001:db8::1]:1936/live/s").unwrap();
+    assert_eq!((url.host.as_str(), url.port), ("2001:db8::1", 1936));
+
+    let url = RtmpUrl::parse("rtmps://example.com/live/s").unwrap();
+    assert!(url.tls && !url.tunneled);
+    assert_eq!(url.port, 443);
+    assert_eq!(url.to_string(), "rtmps://example.com:443/live/s");
+
+    for bad in [
+        "http://example.com/live/s",
+        "rtmp://[::1/live/s",
+        "rtmp://[::1]x/live/s",
+        "rtmp://example.com:x/live/s",
+        "rtmp://example.com/live",
+    ] {
+        assert!(matches!(RtmpUrl::parse(bad), Err(Error::InvalidInput(_))), "{}", bad);
+    }
+}
@@ -0,0 +1,341 @@
+//! Fixtures shared by the integration tests
+//!
+//! Each test binary compiles this module and uses only part of it.
+#![allow(dead_code)]
+
+use amf::amf0::Value;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use rtmp_streaming_server::config::{AppConfig, Config, VhostConfig};
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::protocol::{Message, MessageType};
+use rtmp_streaming_server::{HttpFlvServer, RtmpServer, Stream, StreamManager, Subscription};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+pub const SPS: &[u8] = &[0x67, 0x42, 0xc0, 0x1e, 0xda, 0x02, 0x80];
+pub const PPS: &[u8] = &[0x68, 0xce, 0x3c, 0x80];
+pub const SLICE: &[u8] = &[0x41, 0x9a, 0x22, 0x6c];
+
+/// Virtual host configured by [`with_tenant`]
+pub const TENANT: &str = "tenant.example.com";
+
+/// IDR slice large enough to be sent as FU-A fragments
+pub fn idr() -> Vec<u8> {
+    let mut idr = vec![0x65];
+    idr.extend((0..3000u32).map(|i| (i % 251) as u8 | 1));
+    idr
+}
+
+/// RTMP server for `streams` on a free port
+pub async fn start_rtmp(streams: StreamManager) -> SocketAddr {
+    start_rtmp_server(RtmpServer::new("127.0.0.1:0".parse().unwrap()).with_stream_manager(streams)).await
+}
+
+/// `server` on a free port
+pub async fn start_rtmp_server(server: RtmpServer) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(server.serve(listener));
+    addr
+}
+
+/// HTTP server for `streams` on a free port
+pub async fn start_http(streams: StreamManager) -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(HttpFlvServer::new(addr).with_stream_manager(streams).serve(listener));
+    addr
+}
+
+/// Stream manager whose `live` app has the settings of `app`
+pub fn with_app(app: AppConfig) -> StreamManager {
+    let mut config = Config::default();
+    config.apps.insert("live".to_string(), app);
+    StreamManager::new().with_config(config)
+}
+
+/// Add a [`TENANT`] vhost whose `live` app has the settings of `live`
+pub fn with_tenant(mut config: Config, live: AppConfig) -> Config {
+    let mut vhost = VhostConfig::default();
+    vhost.apps.insert("live".to_string(), live);
+    config.vhosts.insert(TENANT.to_string(), vhost);
+    config
+}
+
+/// Directory holding an executable fake `ffmpeg` that logs its args and
+/// runs `body`
+pub fn fake_ffmpeg(name: &str, body: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rtmp-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("ffmpeg");
+    std::fs::write(&path, format!("#!/bin/sh\necho \"fake ffmpeg $*\" >&2\n{}\n", body)).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    dir
+}
+
+/// Wait until `name` is published on `streams`
+pub async fn published(streams: &StreamManager, name: &str) -> Stream {
+    for _ in 0..100 {
+        if let Some(stream) = streams.get_stream("live", name).await {
+            if stream.is_published() {
+                return stream;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("{} was never published", name);
+}
+
+/// Next tag of `subscription`
+pub async fn next_tag(subscription: &mut Subscription) -> FlvTag {
+    tokio::time::timeout(Duration::from_secs(5), subscription.recv())
+        .await
+        .expect("timed out waiting for tags")
+        .unwrap()
+        .expect("stream ended")
+}
+
+/// Video tag starting with `first` (frame type and codec) and `second`
+/// (AVC packet type)
+pub fn video(timestamp: u32, first: u8, second: u8) -> FlvTag {
+    FlvTag::new(TagType::Video, timestamp, Bytes::from(vec![first, second, 0, 0, 0, 0x65]))
+}
+
+/// AVC sequence header carrying [`SPS`] and [`PPS`]
+pub fn avc_sequence_header(timestamp: u32) -> FlvTag {
+    let mut data = vec![0x17, 0x00, 0, 0, 0, 0x01, SPS[1], SPS[2], SPS[3], 0xff, 0xe1, 0, SPS.len() as u8];
+    data.extend_from_slice(SPS);
+    data.extend_from_slice(&[0x01, 0, PPS.len() as u8]);
+    data.extend_from_slice(PPS);
+    FlvTag::new(TagType::Video, timestamp, Bytes::from(data))
+}
+
+/// AVC frame with 4-byte length prefixed NAL units
+pub fn avc_frame(timestamp: u32, composition: u32, keyframe: bool, nals: &[&[u8]]) -> FlvTag {
+    let mut data = vec![if keyframe { 0x17 } else { 0x27 }, 0x01];
+    data.extend_from_slice(&composition.to_be_bytes()[1..]);
+    for nal in nals {
+        data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+        data.extend_from_slice(nal);
+    }
+    FlvTag::new(TagType::Video, timestamp, Bytes::from(data))
+}
+
+/// Big-endian u32 at `offset`
+pub fn u32_at(payload: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(payload[offset..offset + 4].try_into().unwrap())
+}
+
+/// Bare-bones RTMP peer, for the messages [`rtmp_streaming_server::RtmpClient`]
+/// never sends or hides: raw control messages, aggregates, AMF3 commands
+/// and shared objects
+pub struct Peer {
+    socket: TcpStream,
+    buf: BytesMut,
+    /// Bytes received after the handshake
+    pub received: u32,
+    chunk_size: usize,
+    /// Length, type, stream ID, timestamp and partial payload per chunk
+    /// stream
+    chunks: HashMap<u8, (usize, u8, u32, u32, BytesMut)>,
+}
+
+impl Peer {
+    /// Connect and complete the handshake
+    pub async fn connect(addr: SocketAddr) -> Self {
+        let mut socket = TcpStream::connect(addr).await.unwrap();
+        let mut c0c1 = vec![3u8];
+        c0c1.extend_from_slice(&[0u8; 1536]);
+        socket.write_all(&c0c1).await.unwrap();
+        let mut s0s1s2 = vec![0u8; 1 + 2 * 1536];
+        socket.read_exact(&mut s0s1s2).await.unwrap();
+        socket.write_all(&s0s1s2[1..1537]).await.unwrap();
+        Self {
+            socket,
+            buf: BytesMut::new(),
+            received: 0,
+            chunk_size: 128,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Connect, complete the handshake and connect to the `live` app
+    pub async fn connected(addr: SocketAddr) -> Self {
+        let mut peer = Self::connect(addr).await;
+        peer.connect_app().await;
+        peer.status("NetConnection.Connect.Success").await;
+        peer
+    }
+
+    /// Send `connect` for the `live` app, without waiting for the result
+    pub async fn connect_app(&mut self) {
+        let properties = Value::Object {
+            class_name: None,
+            entries: vec![amf::Pair {
+                key: "app".to_string(),
+                value: Value::String("live".to_string()),
+            }],
+        };
+        self.command(0, &[Value::String("connect".to_string()), Value::Number(1.0), properties]).await;
+    }
+
+    /// Send `createStream` and wait for its result
+    pub async fn create_stream(&mut self) {
+        self.command(0, &[Value::String("createStream".to_string()), Value::Number(2.0), Value::Null]).await;
+        self.expect(MessageType::CommandAmf0).await;
+    }
+
+    /// Send a message as type 0 chunks of 128 bytes on stream 3
+    pub async fn send(&mut self, message: &Message) {
+        let mut out = BytesMut::new();
+        out.put_u8(3);
+        out.put_uint(message.timestamp as u64, 3);
+        out.put_uint(message.payload.len() as u64, 3);
+        out.put_u8(message.message_type as u8);
+        out.put_u32_le(message.message_stream_id);
+        for (i, chunk) in message.payload.chunks(128).enumerate() {
+            if i > 0 {
+                out.put_u8(0xc3);
+            }
+            out.put_slice(chunk);
+        }
+        self.socket.write_all(&out).await.unwrap();
+    }
+
+    /// Send a protocol control message or user control event on stream 0
+    pub async fn control(&mut self, message_type: MessageType, payload: &[u8]) {
+        self.send(&Message::new(message_type, 0, 0, Bytes::copy_from_slice(payload))).await;
+    }
+
+    /// Send an AMF0 command message
+    pub async fn command(&mut self, stream_id: u32, values: &[Value]) {
+        let mut payload = Vec::new();
+        for value in values {
+            value.write_to(&mut payload).unwrap();
+        }
+        self.send(&Message::new(MessageType::CommandAmf0, stream_id, 0, Bytes::from(payload))).await;
+    }
+
+    /// Send an AMF3 command message: format byte, then the values
+    pub async fn command_amf3(&mut self, stream_id: u32, values: &[Value]) {
+        let mut payload = vec![0u8];
+        for value in values {
+            value.write_to(&mut payload).unwrap();
+        }
+        self.send(&Message::new(MessageType::CommandAmf3, stream_id, 0, Bytes::from(payload))).await;
+    }
+
+    /// Send a user control event
+    pub async fn event(&mut self, event: u16, value: u32) {
+        let mut payload = event.to_be_bytes().to_vec();
+        payload.extend_from_slice(&value.to_be_bytes());
+        self.control(MessageType::UserControl, &payload).await;
+    }
+
+    /// Wait for a user control event of type `event`; returns its value
+    pub async fn expect_event(&mut self, event: u16) -> u32 {
+        loop {
+            let message = self.expect(MessageType::UserControl).await;
+            if u16::from_be_bytes([message.payload[0], message.payload[1]]) == event {
+                return u32_at(&message.payload, 2);
+            }
+        }
+    }
+
+    /// Wait for an AMF0 command message containing `code`
+    pub async fn status(&mut self, code: &str) {
+        loop {
+            let status = self.expect(MessageType::CommandAmf0).await;
+            if String::from_utf8_lossy(&status.payload).contains(code) {
+                return;
+            }
+        }
+    }
+
+    /// Wait for a message of `message_type`
+    pub async fn expect(&mut self, message_type: MessageType) -> Message {
+        loop {
+            let message = self.recv().await.expect("message");
+            if message.message_type == message_type {
+                return message;
+            }
+        }
+    }
+
+    /// Whether the server closes the connection within `wait`
+    pub async fn closed_within(&mut self, wait: Duration) -> bool {
+        let mut buf = [0u8; 4096];
+        tokio::time::timeout(wait, async {
+            while !matches!(self.socket.read(&mut buf).await, Ok(0) | Err(_)) {}
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Next complete message, or `None` if nothing arrives for a while
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            if let Some(message) = self.decode() {
+                return Some(message);
+            }
+            let read = tokio::time::timeout(Duration::from_millis(500), self.socket.read_buf(&mut self.buf))
+                .await
+                .ok()?
+                .unwrap();
+            assert!(read > 0, "connection closed");
+            self.received += read as u32;
+        }
+    }
+
+    fn decode(&mut self) -> Option<Message> {
+        loop {
+            let mut peek = &self.buf[..];
+            if peek.is_empty() {
+                return None;
+            }
+            let first = peek.get_u8();
+            let (format, csid) = (first >> 6, first & 0x3f);
+            let header_len = [11, 7, 3, 0][format as usize];
+            if peek.len() < header_len {
+                return None;
+            }
+            let (mut length, mut message_type, mut stream_id, mut timestamp, partial) =
+                self.chunks.get(&csid).cloned().unwrap_or((0, 0, 0, 0, BytesMut::new()));
+            if format <= 2 {
+                let field = peek.get_uint(3) as u32;
+                timestamp = if format == 0 { field } else { timestamp.wrapping_add(field) };
+            }
+            if format <= 1 {
+                length = peek.get_uint(3) as usize;
+                message_type = peek.get_u8();
+                if format == 0 {
+                    stream_id = peek.get_u32_le();
+                }
+            }
+            let take = (length - partial.len()).min(self.chunk_size);
+            if peek.len() < take {
+                return None;
+            }
+            let mut partial = partial;
+            partial.extend_from_slice(&peek[..take]);
+            self.buf.advance(1 + header_len + take);
+            if partial.len() < length {
+                self.chunks.insert(csid, (length, message_type, stream_id, timestamp, partial));
+                continue;
+            }
+            self.chunks.insert(csid, (length, message_type, stream_id, timestamp, BytesMut::new()));
+            let payload = partial.freeze();
+            if message_type == 1 {
+                self.chunk_size = u32_at(&payload, 0) as usize;
+            }
+            let message_type = MessageType::try_from(message_type).unwrap();
+            return Some(Message::new(message_type, stream_id, timestamp, payload));
+        }
+    }
+}
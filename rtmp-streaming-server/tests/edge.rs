@@ -0,0 +1,149 @@
+mod common;
+
+use common::{start_http, start_rtmp, video};
+use futures::StreamExt;
+use hyper::{Body, Client};
+use rtmp_streaming_server::config::{AppConfig, Config, EdgeConfig};
+use rtmp_streaming_server::flv::{FlvDemuxer, FlvTag};
+use rtmp_streaming_server::StreamManager;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn edge(origin: String) -> StreamManager {
+    let mut config = Config::default();
+    config.apps.insert(
+        "live".to_string(),
+        AppConfig {
+            edge: Some(EdgeConfig {
+                origin,
+                idle_timeout_ms: 200,
+            }),
+            ..AppConfig::default()
+        },
+    );
+    StreamManager::new().with_config(config)
+}
+
+async fn play(addr: SocketAddr, name: &str) -> Body {
+    let response = Client::new()
+        .get(format!("http://{}/live/{}", addr, name).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    response.into_body()
+}
+
+/// Read tags until one with the given payload prefix arrives
+async fn read_until(body: &mut Body, demuxer: &mut FlvDemuxer, first: u8, second: u8) -> FlvTag {
+    loop {
+        while let Some(tag) = demuxer.next_tag().unwrap() {
+            if tag.data[..2] == [first, second] {
+                return tag;
+            }
+        }
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("timed out waiting for tags")
+            .expect("body ended early")
+            .unwrap();
+        demuxer.push(&chunk);
+    }
+}
+
+/// Poll `check` for up to five seconds
+async fn eventually<F, Fut>(mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    for _ in 0..100 {
+        if check().await {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("condition not reached");
+}
+
+#[tokio::test]
+async fn test_pull_from_rtmp_origin() {
+    let origin = StreamManager::new();
+    let origin_addr = start_rtmp(origin.clone()).await;
+    let streams = edge(format!("rtmp://{}/live/{{stream}}", origin_addr));
+    let addr = start_http(streams.clone()).await;
+
+    let publisher = origin.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+
+    // Two viewers share a single upstream connection
+    let mut first = play(addr, "cam").await;
+    let mut first_demuxer = FlvDemuxer::new();
+    read_until(&mut first, &mut first_demuxer, 0x17, 0x00).await;
+    let mut second = play(addr, "cam").await;
+    let mut second_demuxer = FlvDemuxer::new();
+    read_until(&mut second, &mut second_demuxer, 0x17, 0x00).await;
+    assert!(streams.edge().is_pulling("live", "cam"));
+    assert_eq!(publisher.stream().subscriber_count(), 1);
+
+    publisher.publish(video(40, 0x17, 0x01)).await.unwrap();
+    read_until(&mut first, &mut first_demuxer, 0x17, 0x01).await;
+    read_until(&mut second, &mut second_demuxer, 0x17, 0x01).await;
+
+    // After the last viewer leaves and the idle timeout, the pull stops
+    drop(first);
+    drop(second);
+    let feed = tokio::spawn({
+        let publisher = publisher.stream().clone();
+        async move {
+            for i in 0.. {
+                publisher.publish(video(80 + i * 40, 0x27, 0x01)).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+        }
+    });
+    eventually(|| async { !streams.edge().is_pulling("live", "cam") }).await;
+    eventually(|| async { streams.get_stream("live", "cam").await.is_none() }).await;
+    eventually(|| async { publisher.stream().subscriber_count() == 0 }).await;
+    feed.abort();
+}
+
+#[tokio::test]
+async fn test_pull_from_http_origin() {
+    let origin = StreamManager::new();
+    let origin_addr = start_http(origin.clone()).await;
+    let streams = edge(format!("http://{}/live/{{stream}}", origin_addr));
+    let addr = start_http(streams.clone()).await;
+
+    let publisher = origin.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+
+    let mut body = play(addr, "cam").await;
+    let mut demuxer = FlvDemuxer::new();
+    read_until(&mut body, &mut demuxer, 0x17, 0x00).await;
+    publisher.publish(video(40, 0x17, 0x01)).await.unwrap();
+    read_until(&mut body, &mut demuxer, 0x17, 0x01).await;
+
+    // The origin stream ending ends the edge stream and its viewers
+    drop(publisher);
+    let rest = tokio::time::timeout(Duration::from_secs(5), async {
+        while let Some(chunk) = body.next().await {
+            chunk.unwrap();
+        }
+    })
+    .await;
+    assert!(rest.is_ok());
+    eventually(|| async { !streams.edge().is_pulling("live", "cam") }).await;
+}
+
+#[tokio::test]
+async fn test_local_publisher_is_not_pulled() {
+    let streams = edge("rtmp://127.0.0.1:1/live/{stream}".to_string());
+    let addr = start_http(streams.clone()).await;
+    let publisher = streams.start_publish("live", "cam", "local".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+
+    let mut body = play(addr, "cam").await;
+    let mut demuxer = FlvDemuxer::new();
+    read_until(&mut body, &mut demuxer, 0x17, 0x00).await;
+    assert!(!streams.edge().is_pulling("live", "cam"));
+}
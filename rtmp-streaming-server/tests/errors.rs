@@ -0,0 +1,44 @@
+use rtmp_streaming_server::protocol::CommandType;
+use rtmp_streaming_server::{Error, StreamManager};
+
+#[test]
+fn test_http_status() {
+    let cases = [
+        (Error::Handshake("bad version".to_string()), 400),
+        (Error::BadChunk("chunk size 0".to_string()), 400),
+        (Error::AuthDenied("wrong key".to_string()), 403),
+        (Error::NotFound("s".to_string()), 404),
+        (Error::StreamBusy("s".to_string()), 409),
+        (Error::ResourceLimit("viewers".to_string()), 429),
+        (Error::Internal("bug".to_string()), 500),
+    ];
+    for (error, status) in cases {
+        assert_eq!(error.http_status(), status, "{}", error);
+    }
+}
+
+#[test]
+fn test_rtmp_status() {
+    let busy = Error::StreamBusy("s".to_string());
+    assert_eq!(busy.rtmp_status(&CommandType::Publish), "NetStream.Publish.BadName");
+
+    let denied = Error::AuthDenied("wrong key".to_string());
+    assert_eq!(denied.rtmp_status(&CommandType::Connect), "NetConnection.Connect.Rejected");
+    assert_eq!(denied.rtmp_status(&CommandType::Publish), "NetStream.Publish.Denied");
+    assert_eq!(denied.rtmp_status(&CommandType::Play), "NetStream.Play.Failed");
+
+    let missing = Error::NotFound("s".to_string());
+    assert_eq!(missing.rtmp_status(&CommandType::Play), "NetStream.Play.StreamNotFound");
+
+    let unknown = Error::UnknownCommand("fancy".to_string());
+    assert_eq!(unknown.rtmp_status(&CommandType::Unknown), "NetConnection.Call.Failed");
+}
+
+#[tokio::test]
+async fn test_stream_errors_have_kinds() {
+    let streams = StreamManager::new();
+    let _publisher = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    let busy = streams.start_publish("live", "s", "b".to_string()).await.err().unwrap();
+    assert!(matches!(busy, Error::StreamBusy(_)));
+    assert!(matches!(streams.subscribe("live", "other", "v".to_string()).await.err(), Some(Error::NotFound(_))));
+}
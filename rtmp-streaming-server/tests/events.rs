@@ -0,0 +1,107 @@
+mod common;
+
+use bytes::Bytes;
+use common::start_http;
+use futures::StreamExt;
+use hyper::Client;
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::{Event, StreamManager};
+use std::time::Duration;
+use tokio::sync::broadcast::Receiver;
+
+async fn next(events: &mut Receiver<Event>) -> Event {
+    tokio::time::timeout(Duration::from_secs(1), events.recv()).await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn test_lifecycle_events() {
+    let streams = StreamManager::new();
+    let mut events = streams.events().subscribe();
+
+    let publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+    assert_eq!(
+        next(&mut events).await,
+        Event::PublishStarted {
+            app: "live".to_string(),
+            stream: "s".to_string(),
+            publisher: "encoder".to_string(),
+        }
+    );
+
+    let subscription = publisher.stream().subscribe("viewer".to_string()).await;
+    assert_eq!(
+        next(&mut events).await,
+        Event::SubscriberJoined {
+            app: "live".to_string(),
+            stream: "s".to_string(),
+            subscriber: "viewer".to_string(),
+        }
+    );
+
+    // Only a different sequence header is a change
+    let header = FlvTag::new(TagType::Video, 0, Bytes::from_static(&[0x17, 0x00, 0, 0, 0, 0x01, 0x64]));
+    publisher.publish(header.clone()).await.unwrap();
+    publisher.publish(header).await.unwrap();
+    assert_eq!(
+        next(&mut events).await,
+        Event::CodecChanged {
+            app: "live".to_string(),
+            stream: "s".to_string(),
+            media: "video".to_string(),
+            codec: "Avc".to_string(),
+        }
+    );
+
+    drop(subscription);
+    assert_eq!(
+        next(&mut events).await,
+        Event::SubscriberLeft {
+            app: "live".to_string(),
+            stream: "s".to_string(),
+            subscriber: "viewer".to_string(),
+        }
+    );
+    drop(publisher);
+    assert_eq!(
+        next(&mut events).await,
+        Event::PublishStopped {
+            app: "live".to_string(),
+            stream: "s".to_string(),
+            publisher: "encoder".to_string(),
+        }
+    );
+}
+
+#[tokio::test]
+async fn test_server_sent_events() {
+    let streams = StreamManager::new();
+    let addr = start_http(streams.clone()).await;
+
+    let response = Client::new()
+        .get(format!("http://{}/api/events?stream=s", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+
+    let other_app = Client::new()
+        .get(format!("http://{}/api/events?app=other&stream=s", addr).parse().unwrap())
+        .await
+        .unwrap();
+
+    // Streams of the same name in other apps are other streams
+    let _other = streams.start_publish("live", "other", "a".to_string()).await.unwrap();
+    let _same_name = streams.start_publish("other", "s", "c".to_string()).await.unwrap();
+    let _publisher = streams.start_publish("live", "s", "b".to_string()).await.unwrap();
+
+    let mut body = response.into_body();
+    let chunk = tokio::time::timeout(Duration::from_secs(1), body.next()).await.unwrap().unwrap().unwrap();
+    let text = String::from_utf8_lossy(&chunk);
+    assert_eq!(
+        text,
+        "event: publish_started\ndata: {\"type\":\"publish_started\",\"app\":\"live\",\"stream\":\"s\",\"publisher\":\"b\"}\n\n"
+    );
+    let mut body = other_app.into_body();
+    let chunk = tokio::time::timeout(Duration::from_secs(1), body.next()).await.unwrap().unwrap().unwrap();
+    assert!(String::from_utf8_lossy(&chunk).contains("\"app\":\"other\",\"stream\":\"s\",\"publisher\":\"c\""));
+}
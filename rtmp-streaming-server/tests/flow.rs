@@ -0,0 +1,158 @@
+mod common;
+
+use amf::amf0::Value;
+use bytes::Bytes;
+use common::{start_rtmp, u32_at, Peer};
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::protocol::{Message, MessageType};
+use rtmp_streaming_server::{RtmpClient, RtmpSession, RtmpUrl, StreamManager};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn test_windows_and_acknowledgements() {
+    let addr = start_rtmp(StreamManager::new()).await;
+    let mut peer = Peer::connect(addr).await;
+    peer.connect_app().await;
+
+    let window = peer.expect(MessageType::WindowAcknowledgementSize).await.payload;
+    assert_eq!(u32_at(&window, 0), 2_500_000);
+    let bandwidth = peer.expect(MessageType::SetPeerBandwidth).await.payload;
+    assert_eq!(u32_at(&bandwidth, 0), 2_500_000);
+    assert_eq!(bandwidth[4], 2);
+    peer.expect(MessageType::CommandAmf0).await;
+
+    // A smaller window is acknowledged as soon as it fills up
+    peer.control(MessageType::WindowAcknowledgementSize, &4096u32.to_be_bytes()).await;
+    for _ in 0..3 {
+        peer.send(&Message::new(MessageType::Audio, 1, 0, Bytes::from_static(&[0xaf; 2000]))).await;
+    }
+    let ack = peer.expect(MessageType::Acknowledgement).await.payload;
+    assert!(u32_at(&ack, 0) >= 4096);
+}
+
+#[tokio::test]
+async fn test_peer_bandwidth_holds_back_playback() {
+    let streams = StreamManager::new();
+    let addr = start_rtmp(streams.clone()).await;
+    let publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+
+    let mut peer = Peer::connect(addr).await;
+    peer.connect_app().await;
+    peer.expect(MessageType::CommandAmf0).await;
+    peer.control(MessageType::SetPeerBandwidth, &[0, 0, 0x4e, 0x20, 0]).await;
+    assert_eq!(u32_at(&peer.expect(MessageType::WindowAcknowledgementSize).await.payload, 0), 20_000);
+
+    peer.create_stream().await;
+    peer.command(1, &[Value::String("play".to_string()), Value::Number(0.0), Value::Null, Value::String("s".to_string())])
+        .await;
+    while peer.recv().await.is_some() {}
+
+    let mut payload = vec![0x17, 0x01];
+    payload.resize(1000, 0x65);
+    for i in 0..60 {
+        publisher.publish(FlvTag::new(TagType::Video, i * 40, Bytes::from(payload.clone()))).await.unwrap();
+    }
+
+    // Playback stops once 20 kB are unacknowledged...
+    let mut frames = 0;
+    while let Some(message) = peer.recv().await {
+        frames += usize::from(message.message_type == MessageType::Video);
+    }
+    assert!(frames > 0 && frames < 30, "{} frames before the window filled", frames);
+
+    // ...and resumes with the acknowledgement
+    let received = peer.received;
+    peer.control(MessageType::Acknowledgement, &received.to_be_bytes()).await;
+    let mut more = 0;
+    while let Some(message) = peer.recv().await {
+        more += usize::from(message.message_type == MessageType::Video);
+    }
+    assert!(more > 0);
+}
+
+/// Client end of a session behind a proxy that counts the client's bytes
+/// and lets the test slip its own chunks to the client
+fn proxied_session(streams: StreamManager) -> (DuplexStream, Arc<AtomicU32>, mpsc::UnboundedSender<Vec<u8>>) {
+    let (client_side, proxy_client) = tokio::io::duplex(64 * 1024);
+    let (proxy_server, server_side) = tokio::io::duplex(64 * 1024);
+    let mut session = RtmpSession::new(server_side, "127.0.0.1:1".parse().unwrap(), streams);
+    tokio::spawn(async move { session.handle().await });
+
+    let forwarded = Arc::new(AtomicU32::new(0));
+    let (inject, mut injected) = mpsc::unbounded_channel::<Vec<u8>>();
+    let (mut from_client, mut to_client) = tokio::io::split(proxy_client);
+    let (mut from_server, mut to_server) = tokio::io::split(proxy_server);
+    let counter = forwarded.clone();
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        while let Ok(read @ 1..) = from_client.read(&mut buf).await {
+            counter.fetch_add(read as u32, Ordering::SeqCst);
+            to_server.write_all(&buf[..read]).await.unwrap();
+        }
+    });
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 16 * 1024];
+        loop {
+            tokio::select! {
+                read = from_server.read(&mut buf) => match read {
+                    Ok(read @ 1..) => to_client.write_all(&buf[..read]).await.unwrap(),
+                    _ => break,
+                },
+                Some(chunk) = injected.recv() => to_client.write_all(&chunk).await.unwrap(),
+            }
+        }
+    });
+    (client_side, forwarded, inject)
+}
+
+/// A protocol control message as one type 0 chunk on stream 2
+fn control_chunk(message_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut chunk = vec![0x02, 0, 0, 0, 0, 0, payload.len() as u8, message_type, 0, 0, 0, 0];
+    chunk.extend_from_slice(payload);
+    chunk
+}
+
+#[tokio::test]
+async fn test_client_waits_for_acknowledgements() {
+    let streams = StreamManager::new();
+    let (stream, forwarded, inject) = proxied_session(streams.clone());
+    let target = RtmpUrl::parse("rtmp://example.com/live/s").unwrap();
+    let mut client = RtmpClient::connect_with(stream, &target).await.unwrap();
+    client.publish("s").await.unwrap();
+
+    // A hard limit of 10 kB unacknowledged
+    inject.send(control_chunk(6, &[0, 0, 0x27, 0x10, 0])).unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let before = forwarded.load(Ordering::SeqCst);
+
+    let mut payload = vec![0x17, 0x01];
+    payload.resize(1000, 0x65);
+    let mut frames = 0;
+    loop {
+        let tag = FlvTag::new(TagType::Video, frames * 40, Bytes::from(payload.clone()));
+        if tokio::time::timeout(Duration::from_millis(200), client.send_tag(&tag)).await.is_err() {
+            break;
+        }
+        frames += 1;
+        assert!(frames < 30, "the window never filled");
+    }
+    let sent = forwarded.load(Ordering::SeqCst) - before;
+    assert!(sent < 12_000, "{} bytes sent into a 10 kB window", sent);
+
+    // The acknowledgement frees the window again
+    let acknowledged = forwarded.load(Ordering::SeqCst);
+    inject.send(control_chunk(3, &acknowledged.to_be_bytes())).unwrap();
+    let tag = FlvTag::new(TagType::Video, frames * 40, Bytes::from(payload));
+    tokio::time::timeout(Duration::from_secs(2), client.send_tag(&tag)).await.unwrap().unwrap();
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while forwarded.load(Ordering::SeqCst) <= acknowledged {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
@@ -0,0 +1,158 @@
+mod common;
+
+use bytes::Bytes;
+use common::{avc_frame, avc_sequence_header, idr, SLICE};
+use rtmp_streaming_server::flv::{
+    AudioCodec, FlvDemuxer, FlvHeader, FlvMuxer, FlvTag, FrameType, PacketType, TagType, VideoCodec,
+};
+use rtmp_streaming_server::Error;
+
+fn aac_frame(timestamp: u32, seq: bool) -> FlvTag {
+    let packet_type = if seq { 0x00 } else { 0x01 };
+    FlvTag::new(
+        TagType::Audio,
+        timestamp,
+        Bytes::from(vec![0xaf, packet_type, 0x12, 0x10]),
+    )
+}
+
+fn sample_tags() -> Vec<FlvTag> {
+    vec![
+        FlvTag::new(TagType::Script, 0, Bytes::from_static(b"\x02\x00\x0aonMetaData\x05")),
+        avc_sequence_header(0),
+        aac_frame(0, true),
+        avc_frame(0, 0, true, &[&idr()]),
+        aac_frame(23, false),
+        avc_frame(40, 0, false, &[SLICE]),
+        avc_frame(0x0123_4567, 0, false, &[SLICE]),
+    ]
+}
+
+fn sample_stream() -> Bytes {
+    let mut muxer = FlvMuxer::new();
+    let mut out = muxer.header(&FlvHeader::new(true, true)).to_vec();
+    out.extend_from_slice(&muxer.tags(&sample_tags()));
+    Bytes::from(out)
+}
+
+fn demux_all(demuxer: &mut FlvDemuxer) -> Vec<FlvTag> {
+    let mut tags = Vec::new();
+    while let Some(tag) = demuxer.next_tag().unwrap() {
+        tags.push(tag);
+    }
+    tags
+}
+
+#[test]
+fn test_roundtrip() {
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(&sample_stream());
+
+    assert_eq!(demux_all(&mut demuxer), sample_tags());
+    assert_eq!(demuxer.header(), Some(&FlvHeader::new(true, true)));
+    assert_eq!(demuxer.buffered(), 0);
+}
+
+#[test]
+fn test_incremental_push() {
+    let data = sample_stream();
+    for step in [1, 3, 7, 13, 64] {
+        let mut demuxer = FlvDemuxer::new();
+        let mut tags = Vec::new();
+        for chunk in data.chunks(step) {
+            demuxer.push(chunk);
+            tags.extend(demux_all(&mut demuxer));
+        }
+        assert_eq!(tags, sample_tags(), "chunk size {}", step);
+    }
+}
+
+#[test]
+fn test_header_needs_full_prefix() {
+    let data = sample_stream();
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(&data[..12]);
+    assert!(demuxer.read_header().unwrap().is_none());
+    assert!(demuxer.next_tag().unwrap().is_none());
+    demuxer.push(&data[12..13]);
+    assert_eq!(demuxer.read_header().unwrap(), Some(FlvHeader::new(true, true)));
+}
+
+#[test]
+fn test_invalid_signature() {
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(b"NOTANFLVFILE!");
+    assert!(demuxer.next_tag().is_err());
+}
+
+#[test]
+fn test_oversized_header_rejected() {
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(b"FLV\x01\x05\xff\xff\xff\xff\x00\x00\x00\x00");
+    assert!(matches!(demuxer.read_header(), Err(Error::InvalidInput(_))));
+
+    // Extension bytes within the bound are skipped
+    let mut data = b"FLV\x01\x05\x00\x00\x00\x0b\xaa\xbb\x00\x00\x00\x00".to_vec();
+    data.extend_from_slice(&FlvMuxer::new().tag(&aac_frame(7, false)));
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(&data);
+    assert_eq!(demux_all(&mut demuxer), vec![aac_frame(7, false)]);
+}
+
+#[test]
+fn test_unknown_tag_type_skipped() {
+    let mut muxer = FlvMuxer::new();
+    let mut data = muxer.header(&FlvHeader::new(false, true)).to_vec();
+    // Tag type 15 with a 2 byte body
+    data.extend_from_slice(&[15, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0xde, 0xad, 0, 0, 0, 13]);
+    data.extend_from_slice(&muxer.tag(&avc_frame(5, 0, true, &[&idr()])));
+
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(&data);
+    assert_eq!(demux_all(&mut demuxer), vec![avc_frame(5, 0, true, &[&idr()])]);
+}
+
+#[test]
+fn test_without_header() {
+    let mut muxer = FlvMuxer::new();
+    let mut demuxer = FlvDemuxer::without_header();
+    demuxer.push(&muxer.tag(&aac_frame(7, false)));
+    assert_eq!(demux_all(&mut demuxer), vec![aac_frame(7, false)]);
+}
+
+#[test]
+fn test_extended_timestamp() {
+    let tag = avc_frame(0x0123_4567, 0, false, &[SLICE]);
+    let encoded = FlvMuxer::new().tag(&tag);
+    // Lower 24 bits first, then the extension byte
+    assert_eq!(&encoded[4..8], &[0x23, 0x45, 0x67, 0x01]);
+    assert_eq!(encoded.len(), tag.encoded_len());
+    let prev_tag_size = u32::from_be_bytes(encoded[encoded.len() - 4..].try_into().unwrap());
+    assert_eq!(prev_tag_size as usize, tag.encoded_len() - 4);
+}
+
+#[test]
+fn test_tag_info() {
+    let seq = avc_sequence_header(0);
+    assert!(seq.is_video() && seq.is_keyframe() && seq.is_sequence_header());
+    assert_eq!(seq.video_codec(), Some(VideoCodec::Avc));
+    assert_eq!(seq.audio_codec(), None);
+
+    let inter = avc_frame(40, 0, false, &[SLICE]);
+    assert_eq!(inter.frame_type(), Some(FrameType::Inter));
+    assert_eq!(inter.packet_type(), Some(PacketType::Data));
+    assert!(!inter.is_keyframe());
+
+    let aac = aac_frame(0, true);
+    assert!(aac.is_audio() && aac.is_sequence_header());
+    assert_eq!(aac.audio_codec(), Some(AudioCodec::Aac));
+    assert_eq!(aac.frame_type(), None);
+
+    let mp3 = FlvTag::new(TagType::Audio, 0, Bytes::from_static(&[0x2f, 0x00]));
+    assert_eq!(mp3.audio_codec(), Some(AudioCodec::Mp3));
+    assert_eq!(mp3.packet_type(), None);
+
+    let script = FlvTag::new(TagType::Script, 0, Bytes::new());
+    assert!(script.is_script());
+    assert_eq!(script.packet_type(), None);
+}
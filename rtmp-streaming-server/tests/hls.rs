@@ -0,0 +1,93 @@
+mod common;
+
+use common::{avc_frame, avc_sequence_header, start_http, with_app};
+use hyper::body::to_bytes;
+use hyper::{Body, Client, Response};
+use rtmp_streaming_server::config::AppConfig;
+use rtmp_streaming_server::flv::FlvTag;
+use rtmp_streaming_server::StreamManager;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn manager() -> StreamManager {
+    with_app(AppConfig {
+        hls: true,
+        hls_segment_ms: 1000,
+        play_token: Some("view".to_string()),
+        ..AppConfig::default()
+    })
+}
+
+fn frame(timestamp: u32, keyframe: bool) -> FlvTag {
+    let nal: &[u8] = if keyframe { &[0x65, 0x88, 0x84] } else { &[0x41, 0x9a, 0x22] };
+    avc_frame(timestamp, 0, keyframe, &[nal])
+}
+
+async fn get(addr: SocketAddr, path: &str) -> Response<Body> {
+    Client::new()
+        .get(format!("http://{}{}", addr, path).parse().unwrap())
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_hls_playlist_and_segments() {
+    let streams = manager();
+    let addr = start_http(streams.clone()).await;
+
+    let publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+    publisher.publish(avc_sequence_header(3000)).await.unwrap();
+    publisher.publish(frame(3000, true)).await.unwrap();
+    publisher.publish(frame(3040, false)).await.unwrap();
+    // Cuts the first segment, which is long enough
+    publisher.publish(frame(4000, true)).await.unwrap();
+
+    let playlist = loop {
+        let response = get(addr, "/hls/live/s.m3u8?token=view").await;
+        if response.status() == 200 {
+            assert_eq!(response.headers()["content-type"], "application/vnd.apple.mpegurl");
+            break String::from_utf8(to_bytes(response.into_body()).await.unwrap().to_vec()).unwrap();
+        }
+        assert_eq!(response.status(), 404);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    assert!(playlist.starts_with("#EXTM3U\n"), "{}", playlist);
+    assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:0\n"), "{}", playlist);
+    assert!(playlist.contains("#EXTINF:1.000,\ns/0.ts?token=view\n"), "{}", playlist);
+
+    let response = get(addr, "/hls/live/s/0.ts?token=view").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "video/mp2t");
+    let segment = to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(segment.len() % 188, 0);
+    assert!(segment.chunks(188).all(|packet| packet[0] == 0x47));
+
+    // Segments share the playlist's authorization
+    assert_eq!(get(addr, "/hls/live/s.m3u8").await.status(), 403);
+    assert_eq!(get(addr, "/hls/live/s/0.ts").await.status(), 403);
+    assert_eq!(get(addr, "/hls/live/s/1.ts?token=view").await.status(), 404);
+
+    // The playlist goes away with the stream
+    drop(publisher);
+    tokio::time::timeout(Duration::from_secs(2), async {
+        while get(addr, "/hls/live/s.m3u8?token=view").await.status() != 404 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .unwrap();
+}
+
+#[tokio::test]
+async fn test_hls_needs_enabling() {
+    let streams = manager();
+    let addr = start_http(streams.clone()).await;
+
+    let publisher = streams.start_publish("event", "s", "encoder".to_string()).await.unwrap();
+    publisher.publish(avc_sequence_header(0)).await.unwrap();
+    publisher.publish(frame(0, true)).await.unwrap();
+    publisher.publish(frame(5000, true)).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(get(addr, "/hls/event/s.m3u8").await.status(), 404);
+    assert_eq!(get(addr, "/hls/event/s/0.ts").await.status(), 404);
+}
@@ -0,0 +1,127 @@
+mod common;
+
+use bytes::Bytes;
+use common::{start_http, video};
+use futures::StreamExt;
+use hyper::{Body, Client, Method, Request};
+use rtmp_streaming_server::flv::{FlvDemuxer, FlvHeader, FlvMuxer, FlvTag, TagType};
+use rtmp_streaming_server::StreamManager;
+use std::time::Duration;
+
+async fn read_tag(body: &mut Body, demuxer: &mut FlvDemuxer) -> FlvTag {
+    loop {
+        if let Some(tag) = demuxer.next_tag().unwrap() {
+            return tag;
+        }
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("timed out waiting for tags")
+            .expect("body ended early")
+            .unwrap();
+        demuxer.push(&chunk);
+    }
+}
+
+#[tokio::test]
+async fn test_publish_and_play() {
+    let streams = StreamManager::new();
+    let addr = start_http(streams.clone()).await;
+    let client = Client::new();
+
+    // Subscriber first: it waits for the publisher
+    let response = client
+        .get(format!("http://{}/live/test", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-type"], "video/x-flv");
+    let mut body = response.into_body();
+
+    // Publisher sends header, sequence header, audio (dropped) and a frame
+    let mut muxer = FlvMuxer::new();
+    let tags = vec![
+        video(0, 0x17, 0x00),
+        FlvTag::new(TagType::Audio, 0, Bytes::from_static(&[0xaf, 0x01, 0x21])),
+        video(40, 0x17, 0x01),
+    ];
+    let (mut sender, publish_body) = Body::channel();
+    let publish = client.request(
+        Request::builder()
+            .method(Method::POST)
+            .uri(format!("http://{}/live/test", addr))
+            .body(publish_body)
+            .unwrap(),
+    );
+    let publisher = tokio::spawn(async move {
+        sender.send_data(muxer.header(&FlvHeader::new(true, true))).await.unwrap();
+        for tag in &tags {
+            // Split each tag in two to exercise reassembly
+            let encoded = muxer.tag(tag);
+            let (a, b) = encoded.split_at(5);
+            sender.send_data(Bytes::copy_from_slice(a)).await.unwrap();
+            sender.send_data(Bytes::copy_from_slice(b)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    });
+    let publish = tokio::spawn(publish);
+
+    let mut demuxer = FlvDemuxer::new();
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next())
+            .await
+            .expect("timed out waiting for tags")
+            .expect("body ended early")
+            .unwrap();
+        demuxer.push(&chunk);
+        while let Some(tag) = demuxer.next_tag().unwrap() {
+            received.push(tag);
+        }
+    }
+
+    // Audio is dropped, so the header advertises video only
+    assert_eq!(demuxer.header(), Some(&FlvHeader::new(false, true)));
+    assert_eq!(received, vec![video(0, 0x17, 0x00), video(40, 0x17, 0x01)]);
+
+    publisher.await.unwrap();
+    publish.await.unwrap().unwrap();
+
+    // The publisher ended, so the subscriber's body ends too
+    let rest = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap();
+    assert!(rest.is_none());
+    assert!(streams.list_streams().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_late_joiner_gets_sequence_header() {
+    let streams = StreamManager::new();
+    let addr = start_http(streams.clone()).await;
+    let client = Client::new();
+
+    let stream = streams.start_publish("live", "late", "test".to_string()).await.unwrap();
+    stream.publish(video(0, 0x17, 0x00)).await.unwrap();
+    stream.publish(video(40, 0x27, 0x01)).await.unwrap();
+
+    let response = client
+        .get(format!("http://{}/live/late", addr).parse().unwrap())
+        .await
+        .unwrap();
+    let mut body = response.into_body();
+    let mut demuxer = FlvDemuxer::new();
+    assert_eq!(read_tag(&mut body, &mut demuxer).await, video(0, 0x17, 0x00));
+
+    // Live tags are rebased to the subscriber's timeline
+    stream.publish(video(600_000, 0x27, 0x01)).await.unwrap();
+    stream.publish(video(600_040, 0x27, 0x01)).await.unwrap();
+    assert_eq!(read_tag(&mut body, &mut demuxer).await, video(0, 0x27, 0x01));
+    assert_eq!(read_tag(&mut body, &mut demuxer).await, video(40, 0x27, 0x01));
+}
+
+#[tokio::test]
+async fn test_not_found() {
+    let addr = start_http(StreamManager::new()).await;
+    let response = Client::new()
+        .get(format!("http://{}/live/test/extra", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 404);
+}
@@ -0,0 +1,104 @@
+mod common;
+
+use bytes::Bytes;
+use common::video;
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::{LagPolicy, Stream, SubscriberStats};
+
+/// More tags than the per-stream channel holds
+const OVERFLOW: u32 = 2000;
+
+fn audio(timestamp: u32) -> FlvTag {
+    FlvTag::new(TagType::Audio, timestamp, Bytes::from_static(&[0xaf, 0x01, 0x21]))
+}
+
+async fn overflow(stream: &Stream, start: u32) {
+    for i in 0..OVERFLOW {
+        stream.publish(video(start + i, 0x27, 0x01)).await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_lagging_subscriber_resyncs_at_keyframe() {
+    let stream = Stream::new("lag".to_string());
+    stream.publish(video(0, 0x17, 0x00)).await.unwrap();
+    let mut subscription = stream.subscribe("slow".to_string()).await;
+
+    overflow(&stream, 1).await;
+    stream.publish(video(5000, 0x17, 0x01)).await.unwrap();
+    stream.publish(video(5040, 0x27, 0x01)).await.unwrap();
+
+    // Sequence header first, at the keyframe's timestamp
+    assert_eq!(subscription.recv().await.unwrap(), Some(video(5000, 0x17, 0x00)));
+    assert_eq!(subscription.recv().await.unwrap(), Some(video(5000, 0x17, 0x01)));
+    assert_eq!(subscription.recv().await.unwrap(), Some(video(5040, 0x27, 0x01)));
+
+    let stats = subscription.stats();
+    assert_eq!(stats.lag_events, 1);
+    assert!(stats.dropped_tags > 0);
+    assert_eq!(stats.dropped_tags + stats.skipped_tags, OVERFLOW as u64);
+    assert_eq!(stream.subscriber_stats(), vec![stats]);
+}
+
+#[tokio::test]
+async fn test_resync_without_sequence_headers() {
+    let stream = Stream::new("lag".to_string());
+    stream.publish(video(0, 0x17, 0x00)).await.unwrap();
+    let mut subscription = stream
+        .subscribe("slow".to_string())
+        .await
+        .with_lag_policy(LagPolicy {
+            resend_sequence_headers: false,
+            ..LagPolicy::default()
+        });
+
+    overflow(&stream, 1).await;
+    stream.publish(audio(4990)).await.unwrap();
+    stream.publish(video(5000, 0x17, 0x01)).await.unwrap();
+    assert_eq!(subscription.recv().await.unwrap(), Some(video(5000, 0x17, 0x01)));
+}
+
+#[tokio::test]
+async fn test_audio_only_stream_resumes_immediately() {
+    let stream = Stream::new("radio".to_string());
+    let mut subscription = stream.subscribe("slow".to_string()).await;
+    for i in 0..OVERFLOW {
+        stream.publish(audio(i)).await.unwrap();
+    }
+
+    let tag = subscription.recv().await.unwrap().unwrap();
+    assert!(tag.is_audio());
+    assert_eq!(subscription.stats().skipped_tags, 0);
+}
+
+#[tokio::test]
+async fn test_repeated_lag_disconnects() {
+    let stream = Stream::new("lag".to_string());
+    stream.publish(video(0, 0x17, 0x00)).await.unwrap();
+    let mut subscription = stream
+        .subscribe("slow".to_string())
+        .await
+        .with_lag_policy(LagPolicy {
+            max_lag_events: Some(1),
+            ..LagPolicy::default()
+        });
+
+    overflow(&stream, 1).await;
+    stream.publish(video(3000, 0x17, 0x01)).await.unwrap();
+    assert_eq!(subscription.recv().await.unwrap(), Some(video(3000, 0x17, 0x00)));
+    assert_eq!(subscription.recv().await.unwrap(), Some(video(3000, 0x17, 0x01)));
+
+    overflow(&stream, 3001).await;
+    assert!(subscription.recv().await.is_err());
+    assert_eq!(
+        stream.subscriber_stats(),
+        vec![SubscriberStats {
+            id: "slow".to_string(),
+            lag_events: 2,
+            dropped_tags: subscription.stats().dropped_tags,
+            skipped_tags: subscription.stats().skipped_tags,
+        }]
+    );
+    drop(subscription);
+    assert!(stream.subscriber_stats().is_empty());
+}
@@ -0,0 +1,130 @@
+mod common;
+
+use common::{start_http, start_rtmp};
+use hyper::Client;
+use rtmp_streaming_server::config::{AppConfig, Config, LimitsConfig};
+use rtmp_streaming_server::{Error, Limiter, Rejections, StreamManager};
+use std::net::{IpAddr, Ipv4Addr};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+fn manager(limits: LimitsConfig, app: AppConfig) -> StreamManager {
+    let mut config = Config {
+        limits,
+        ..Config::default()
+    };
+    config.apps.insert("live".to_string(), app);
+    StreamManager::new().with_config(config)
+}
+
+#[test]
+fn test_connections_per_ip() {
+    let limiter = Limiter::new(LimitsConfig {
+        max_connections_per_ip: Some(2),
+        ..LimitsConfig::default()
+    });
+    let first = limiter.connect(CLIENT).unwrap();
+    let _second = limiter.connect(CLIENT).unwrap();
+    assert!(matches!(limiter.connect(CLIENT), Err(Error::ResourceLimit(_))));
+    assert_eq!(limiter.connections(CLIENT), 2);
+
+    // Other addresses have their own allowance
+    let _other = limiter.connect(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))).unwrap();
+
+    drop(first);
+    assert_eq!(limiter.connections(CLIENT), 1);
+    let _third = limiter.connect(CLIENT).unwrap();
+    assert_eq!(
+        limiter.rejections(),
+        Rejections {
+            connections_per_ip: 1,
+            ..Rejections::default()
+        }
+    );
+}
+
+#[test]
+fn test_connection_rate() {
+    let limiter = Limiter::new(LimitsConfig {
+        connections_per_second: Some(0.5),
+        connection_burst: Some(2),
+        ..LimitsConfig::default()
+    });
+    // The burst is available at once, closed connections do not refund it
+    drop(limiter.connect(CLIENT).unwrap());
+    drop(limiter.connect(CLIENT).unwrap());
+    assert!(matches!(limiter.connect(CLIENT), Err(Error::ResourceLimit(_))));
+    assert!(limiter.connect(CLIENT).is_err());
+    assert_eq!(limiter.rejections().connection_rate, 2);
+}
+
+#[tokio::test]
+async fn test_publishers_per_app() {
+    let streams = manager(
+        LimitsConfig::default(),
+        AppConfig {
+            max_publishers: Some(1),
+            ..AppConfig::default()
+        },
+    );
+    let first = streams.start_publish("live", "a", "p1".to_string()).await.unwrap();
+    let result = streams.start_publish("live", "b", "p2".to_string()).await;
+    assert!(matches!(result, Err(Error::ResourceLimit(_))));
+    assert!(streams.get_stream("live", "b").await.is_none_or(|stream| !stream.is_published()));
+
+    // Other apps are not limited
+    let _other = streams.start_publish("other", "c", "p3".to_string()).await.unwrap();
+
+    drop(first);
+    let _second = streams.start_publish("live", "b", "p4".to_string()).await.unwrap();
+    assert_eq!(streams.limits().rejections().publishers_per_app, 1);
+}
+
+#[tokio::test]
+async fn test_http_subscriber_limit() {
+    let streams = manager(
+        LimitsConfig::default(),
+        AppConfig {
+            max_subscribers: Some(1),
+            ..AppConfig::default()
+        },
+    );
+    let addr = start_http(streams.clone()).await;
+
+    let client = Client::new();
+    let url = format!("http://{}/live/s", addr);
+    let first = client.get(url.parse().unwrap()).await.unwrap();
+    assert_eq!(first.status(), 200);
+    let second = client.get(url.parse().unwrap()).await.unwrap();
+    assert_eq!(second.status(), 429);
+
+    let stats = client
+        .get(format!("http://{}/api/limits", addr).parse().unwrap())
+        .await
+        .unwrap();
+    let body = hyper::body::to_bytes(stats.into_body()).await.unwrap();
+    let stats: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(stats["subscribers_per_stream"], 1);
+}
+
+#[tokio::test]
+async fn test_rtmp_connection_closed() {
+    let streams = manager(
+        LimitsConfig {
+            max_connections_per_ip: Some(1),
+            ..LimitsConfig::default()
+        },
+        AppConfig::default(),
+    );
+    let addr = start_rtmp(streams.clone()).await;
+
+    let _first = TcpStream::connect(addr).await.unwrap();
+    let mut second = TcpStream::connect(addr).await.unwrap();
+    let mut buf = [0u8; 1];
+    let read = tokio::time::timeout(Duration::from_secs(2), second.read(&mut buf)).await.unwrap();
+    assert!(matches!(read, Ok(0) | Err(_)));
+    assert_eq!(streams.limits().rejections().connections_per_ip, 1);
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use common::{video, with_app};
+use rtmp_streaming_server::config::{AppConfig, Config, PublisherPolicy};
+use rtmp_streaming_server::StreamManager;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+
+fn manager(policy: PublisherPolicy, grace_period_ms: u64) -> StreamManager {
+    with_app(AppConfig {
+        publisher_policy: policy,
+        grace_period_ms,
+        ..AppConfig::default()
+    })
+}
+
+#[tokio::test]
+async fn test_reject_second_publisher() {
+    let streams = manager(PublisherPolicy::Reject, 0);
+    let first = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    assert!(streams.start_publish("live", "s", "b".to_string()).await.is_err());
+    assert!(!first.is_evicted());
+
+    // The same name in another app is another stream
+    let other = streams.start_publish("other", "s", "c".to_string()).await.unwrap();
+    assert!(!other.stream().same_as(first.stream()));
+    assert!(!first.is_evicted());
+}
+
+#[tokio::test]
+async fn test_replace_evicts_old_publisher() {
+    let streams = manager(PublisherPolicy::Replace, 0);
+    let mut first = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    first.publish(video(0, 0x17, 0x00)).await.unwrap();
+
+    let second = streams.start_publish("live", "s", "b".to_string()).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(1), first.evicted()).await.unwrap();
+    assert!(first.publish(video(40, 0x27, 0x01)).await.is_err());
+
+    // The new publisher starts without the old sequence header
+    let stream = second.stream().clone();
+    assert!(stream.sequence_headers().await.is_empty());
+    assert_eq!(stream.publisher_count(), 1);
+
+    // Dropping the evicted handle leaves the new publisher in place
+    drop(first);
+    assert_eq!(stream.publisher_count(), 1);
+    assert_eq!(streams.list_streams().await, vec!["live/s".to_string()]);
+}
+
+#[tokio::test]
+async fn test_allow_multiple_publishers() {
+    let streams = manager(PublisherPolicy::Allow, 0);
+    let first = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    let second = streams.start_publish("live", "s", "b".to_string()).await.unwrap();
+    let stream = first.stream().clone();
+    assert_eq!(stream.publisher_count(), 2);
+
+    let mut subscription = stream.subscribe("viewer".to_string()).await;
+    first.publish(video(0, 0x27, 0x01)).await.unwrap();
+    second.publish(video(0, 0x27, 0x02)).await.unwrap();
+    assert_eq!(subscription.receiver.recv().await.unwrap(), video(0, 0x27, 0x01));
+    assert_eq!(subscription.receiver.recv().await.unwrap(), video(0, 0x27, 0x02));
+
+    drop(first);
+    assert!(stream.is_published());
+}
+
+#[tokio::test]
+async fn test_stream_removed_without_grace_period() {
+    let streams = manager(PublisherPolicy::Reject, 0);
+    let publisher = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    let mut subscription = publisher.stream().subscribe("viewer".to_string()).await;
+    drop(publisher);
+
+    let closed = tokio::time::timeout(Duration::from_secs(1), subscription.receiver.recv()).await.unwrap();
+    assert_eq!(closed, Err(RecvError::Closed));
+    assert!(streams.list_streams().await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_republish_races_removal() {
+    let streams = manager(PublisherPolicy::Reject, 0);
+    for i in 0..200 {
+        let publisher = streams.start_publish("live", "s", format!("p{}", i)).await.unwrap();
+        drop(publisher);
+
+        // Publishing again while the old removal is pending keeps the
+        // new publisher's stream reachable
+        let publisher = streams.start_publish("live", "s", format!("q{}", i)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        let current = streams.get_stream("live", "s").await.expect("stream");
+        assert!(current.same_as(publisher.stream()));
+    }
+}
+
+#[tokio::test]
+async fn test_reconnect_within_grace_period() {
+    let streams = manager(PublisherPolicy::Reject, 500);
+    let publisher = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    let mut subscription = publisher.stream().subscribe("viewer".to_string()).await;
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    assert_eq!(subscription.receiver.recv().await.unwrap(), video(0, 0x17, 0x00));
+    drop(publisher);
+
+    // The subscriber keeps waiting while the publisher reconnects
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(streams.list_streams().await, vec!["live/s".to_string()]);
+    let publisher = streams.start_publish("live", "s", "b".to_string()).await.unwrap();
+    assert!(publisher.stream().sequence_headers().await.is_empty());
+
+    // Fresh headers from the new session reach the same subscriber
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    assert_eq!(subscription.receiver.recv().await.unwrap(), video(0, 0x17, 0x00));
+
+    // The first session's grace timer must not remove the resumed stream
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(streams.list_streams().await, vec!["live/s".to_string()]);
+    assert!(publisher.stream().is_published());
+    assert_eq!(publisher.stream().data().await.publisher.as_deref(), Some("b"));
+}
+
+#[tokio::test]
+async fn test_grace_period_expires() {
+    let streams = manager(PublisherPolicy::Reject, 100);
+    let publisher = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    let mut subscription = publisher.stream().subscribe("viewer".to_string()).await;
+    drop(publisher);
+
+    let closed = tokio::time::timeout(Duration::from_secs(2), subscription.receiver.recv()).await.unwrap();
+    assert_eq!(closed, Err(RecvError::Closed));
+    assert!(streams.list_streams().await.is_empty());
+}
+
+#[test]
+fn test_config_file() {
+    let path = std::env::temp_dir().join(format!("rtmp-config-{}.toml", std::process::id()));
+    std::fs::write(
+        &path,
+        "[apps.live]\npublisher_policy = \"replace\"\ngrace_period_ms = 5000\n",
+    )
+    .unwrap();
+    let config = Config::from_file(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(config.app("live").publisher_policy, PublisherPolicy::Replace);
+    assert_eq!(config.app("live").grace_period(), Duration::from_secs(5));
+    assert_eq!(config.app("other"), &AppConfig::default());
+}
@@ -0,0 +1,66 @@
+mod common;
+
+use common::{video, with_app};
+use rtmp_streaming_server::config::AppConfig;
+use rtmp_streaming_server::flv::FlvDemuxer;
+use rtmp_streaming_server::{Event, StreamManager};
+use std::path::Path;
+use std::time::Duration;
+
+fn manager(dir: &Path) -> StreamManager {
+    with_app(AppConfig {
+        record_dir: Some(dir.display().to_string()),
+        ..AppConfig::default()
+    })
+}
+
+#[tokio::test]
+async fn test_published_stream_is_recorded() {
+    let dir = std::env::temp_dir().join(format!("rtmp-recording-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let streams = manager(&dir);
+    let mut events = streams.events().subscribe();
+
+    let publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+    let recording = streams.recordings().statuses();
+    assert_eq!(recording.len(), 1);
+    assert!(recording[0].path.starts_with(&dir.join("live").join("s-").display().to_string()));
+    publisher.publish(video(5000, 0x17, 0x00)).await.unwrap();
+    publisher.publish(video(5000, 0x17, 0x01)).await.unwrap();
+    publisher.publish(video(5040, 0x27, 0x01)).await.unwrap();
+    // Other apps are not recorded
+    let _other = streams.start_publish("event", "s", "other".to_string()).await.unwrap();
+    assert_eq!(streams.recordings().statuses().len(), 1);
+    drop(publisher);
+
+    let path = loop {
+        let event = tokio::time::timeout(Duration::from_secs(2), events.recv()).await.unwrap().unwrap();
+        if let Event::RecordingFinished { app, stream, path } = event {
+            assert_eq!((app.as_str(), stream.as_str()), ("live", "s"));
+            break path;
+        }
+    };
+    assert!(streams.recordings().statuses().is_empty());
+
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(&std::fs::read(&path).unwrap());
+    let mut tags = Vec::new();
+    while let Some(tag) = demuxer.next_tag().unwrap() {
+        tags.push(tag);
+    }
+    assert!(demuxer.header().is_some());
+    assert_eq!(tags.len(), 3);
+    assert!(tags[0].is_sequence_header());
+    // The file starts at 0 whatever the publisher's clock said
+    let timestamps: Vec<u32> = tags.iter().map(|tag| tag.timestamp).collect();
+    assert_eq!(timestamps, vec![0, 0, 40]);
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_recording_stays_in_its_directory() {
+    let dir = std::env::temp_dir().join(format!("rtmp-recording-escape-{}", std::process::id()));
+    let streams = manager(&dir);
+    let _publisher = streams.start_publish("live", "../s", "encoder".to_string()).await.unwrap();
+    assert!(streams.recordings().statuses().is_empty());
+}
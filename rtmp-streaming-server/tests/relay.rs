@@ -0,0 +1,145 @@
+mod common;
+
+use common::{published, start_http, start_rtmp, video};
+use futures::StreamExt;
+use hyper::Client;
+use rtmp_streaming_server::config::{AppConfig, Config, PushTarget};
+use rtmp_streaming_server::{RelayState, RtmpServer, StreamManager};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+/// RTMP server for `streams` on `listener`, to bring a known port back up
+async fn serve_rtmp(streams: StreamManager, listener: TcpListener) -> SocketAddr {
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(RtmpServer::new(addr).with_stream_manager(streams).serve(listener));
+    addr
+}
+
+fn origin(url: String) -> StreamManager {
+    let mut config = Config::default();
+    config.apps.insert(
+        "live".to_string(),
+        AppConfig {
+            push: vec![PushTarget { url, stream: None }],
+            ..AppConfig::default()
+        },
+    );
+    StreamManager::new().with_config(config)
+}
+
+#[test]
+fn test_push_target_url() {
+    let all = PushTarget {
+        url: "rtmp://cdn.example/{app}/{stream}".to_string(),
+        stream: None,
+    };
+    assert_eq!(all.url_for("live", "cam").as_deref(), Some("rtmp://cdn.example/live/cam"));
+
+    let one = PushTarget {
+        url: "rtmp://cdn.example/live/key".to_string(),
+        stream: Some("main".to_string()),
+    };
+    assert_eq!(one.url_for("live", "main").as_deref(), Some("rtmp://cdn.example/live/key"));
+    assert_eq!(one.url_for("live", "other"), None);
+}
+
+#[tokio::test]
+async fn test_push_to_upstream() {
+    let upstream = StreamManager::new();
+    let upstream_addr = start_rtmp(upstream.clone()).await;
+    let streams = origin(format!("rtmp://{}/live/{{stream}}-copy", upstream_addr));
+
+    let publisher = streams.start_publish("live", "cam", "local".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    let copy = published(&upstream, "cam-copy").await;
+    let mut subscription = copy.subscribe("viewer".to_string()).await;
+
+    // Inter frames before the first keyframe are not relayed
+    publisher.publish(video(1000, 0x27, 0x01)).await.unwrap();
+    publisher.publish(video(1040, 0x17, 0x01)).await.unwrap();
+    publisher.publish(video(1080, 0x27, 0x01)).await.unwrap();
+    let mut received = Vec::new();
+    while received.len() < 2 {
+        let tag = tokio::time::timeout(Duration::from_secs(5), subscription.recv())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        if !tag.is_sequence_header() {
+            received.push(tag);
+        }
+    }
+    assert_eq!(received[0].data, video(0, 0x17, 0x01).data);
+    assert_eq!(received[1].data, video(0, 0x27, 0x01).data);
+    assert_eq!(received[1].timestamp - received[0].timestamp, 40);
+    assert!(copy.sequence_headers().await.contains(&video(received[0].timestamp, 0x17, 0x00)));
+
+    // The relay has published, so it reports itself connected
+    let statuses = streams.relays().statuses();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].state, RelayState::Connected);
+    assert_eq!(statuses[0].connects, 1);
+
+    // The relay reads the stream without counting as one of its viewers
+    assert_eq!(publisher.stream().subscriber_count(), 0);
+
+    // The relay stops with the local stream
+    drop(publisher);
+    for _ in 0..100 {
+        if streams.relays().statuses().is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("relay did not stop");
+}
+
+#[tokio::test]
+async fn test_reconnect_with_backoff() {
+    // Reserve a port, then leave it closed until the relay has failed once
+    let reserved = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_addr = reserved.local_addr().unwrap();
+    drop(reserved);
+
+    let streams = origin(format!("rtmp://{}/live/{{stream}}", upstream_addr));
+    let http_addr = start_http(streams.clone()).await;
+
+    let publisher = streams.start_publish("live", "cam", "local".to_string()).await.unwrap();
+    for _ in 0..100 {
+        if streams.relays().statuses()[0].state == RelayState::Backoff {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // Status is reported through the admin API
+    let response = Client::new()
+        .get(format!("http://{}/api/relays", http_addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.headers()["content-type"], "application/json");
+    let body: Vec<u8> = response
+        .into_body()
+        .map(|chunk| chunk.unwrap().to_vec())
+        .concat()
+        .await;
+    let statuses: serde_json::Value = serde_json::from_slice(&body).unwrap();
+    assert_eq!(statuses[0]["stream"], "cam");
+    assert_eq!(statuses[0]["state"], "backoff");
+    assert!(statuses[0]["last_error"].is_string());
+
+    let upstream = StreamManager::new();
+    serve_rtmp(upstream.clone(), TcpListener::bind(upstream_addr).await.unwrap()).await;
+    published(&upstream, "cam").await;
+    for _ in 0..100 {
+        if streams.relays().statuses()[0].state == RelayState::Connected {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let status = &streams.relays().statuses()[0];
+    assert_eq!(status.state, RelayState::Connected);
+    assert_eq!(status.last_error, None);
+    drop(publisher);
+}
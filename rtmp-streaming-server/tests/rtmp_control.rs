@@ -0,0 +1,103 @@
+mod common;
+
+use amf::amf0::Value;
+use common::{start_rtmp, start_rtmp_server, with_app, Peer};
+use rtmp_streaming_server::config::AppConfig;
+use rtmp_streaming_server::protocol::MessageType;
+use rtmp_streaming_server::{RtmpServer, StreamManager};
+use std::time::Duration;
+
+async fn play(peer: &mut Peer, name: &str) {
+    peer.create_stream().await;
+    peer.command(1, &[Value::String("play".to_string()), Value::Number(0.0), Value::Null, Value::String(name.to_string())])
+        .await;
+}
+
+#[tokio::test]
+async fn test_ping_rtt_and_dead_peer() {
+    let streams = StreamManager::new();
+    let server = RtmpServer::new("127.0.0.1:0".parse().unwrap())
+        .with_stream_manager(streams.clone())
+        .with_ping(Duration::from_millis(100), Duration::from_millis(500));
+    let addr = start_rtmp_server(server).await;
+    let mut peer = Peer::connect(addr).await;
+    peer.connect_app().await;
+
+    // The server answers our pings...
+    peer.event(6, 1234).await;
+    assert_eq!(peer.expect_event(7).await, 1234);
+
+    // ...and measures the RTT with its own
+    let timestamp = peer.expect_event(6).await;
+    peer.event(7, timestamp).await;
+    peer.control(MessageType::UserControl, &[0, 3, 0, 0, 0, 1, 0, 0, 0x0b, 0xb8]).await;
+    let mut stats = None;
+    for _ in 0..50 {
+        stats = streams.sessions().list().pop().filter(|s| s.rtt_ms.is_some() && s.buffer_ms.is_some());
+        if stats.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let stats = stats.expect("RTT measured");
+    assert_eq!(stats.app.as_deref(), Some("live"));
+    assert!(stats.rtt_ms.unwrap() < 1000);
+    assert_eq!(stats.buffer_ms, Some(3000));
+
+    // A peer that goes silent is dropped and unregistered
+    assert!(peer.closed_within(Duration::from_secs(2)).await);
+    assert!(streams.sessions().list().is_empty());
+}
+
+#[tokio::test]
+async fn test_stream_events() {
+    let streams = StreamManager::new();
+    let server = RtmpServer::new("127.0.0.1:0".parse().unwrap())
+        .with_stream_manager(streams.clone())
+        .with_ping(Duration::from_millis(100), Duration::from_secs(5));
+    let addr = start_rtmp_server(server).await;
+    let publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+
+    let mut peer = Peer::connect(addr).await;
+    peer.connect_app().await;
+    play(&mut peer, "s").await;
+    assert_eq!(peer.expect_event(0).await, 1);
+    let status = peer.expect(MessageType::CommandAmf0).await.payload;
+    assert!(String::from_utf8_lossy(&status).contains("NetStream.Play.Reset"));
+
+    // No data: the stream runs dry, and begins again with the next tag
+    assert_eq!(peer.expect_event(2).await, 1);
+    publisher.publish(common::video(0, 0x17, 0x01)).await.unwrap();
+    assert_eq!(peer.expect_event(0).await, 1);
+
+    drop(publisher);
+    assert_eq!(peer.expect_event(1).await, 1);
+}
+
+#[tokio::test]
+async fn test_command_errors() {
+    let streams = with_app(AppConfig {
+        max_subscribers: Some(0),
+        ..AppConfig::default()
+    });
+    let addr = start_rtmp(streams).await;
+    let mut peer = Peer::connect(addr).await;
+    peer.connect_app().await;
+    peer.expect(MessageType::CommandAmf0).await;
+
+    // Unknown commands get an error result and the session goes on
+    peer.command(0, &[Value::String("fancyCall".to_string()), Value::Number(7.0), Value::Null]).await;
+    let error = String::from_utf8_lossy(&peer.expect(MessageType::CommandAmf0).await.payload).into_owned();
+    assert!(error.contains("_error") && error.contains("NetConnection.Call.Failed"), "{}", error);
+
+    // A rejected play is answered before the connection closes
+    play(&mut peer, "s").await;
+    loop {
+        let status = String::from_utf8_lossy(&peer.expect(MessageType::CommandAmf0).await.payload).into_owned();
+        if status.contains("onStatus") {
+            assert!(status.contains("NetStream.Play.Failed"), "{}", status);
+            break;
+        }
+    }
+    assert!(peer.closed_within(Duration::from_secs(1)).await);
+}
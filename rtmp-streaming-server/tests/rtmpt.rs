@@ -0,0 +1,122 @@
+mod common;
+
+use bytes::Bytes;
+use common::{published, start_http, video, with_app};
+use hyper::{Body, Client, Request, StatusCode};
+use rtmp_streaming_server::config::{AppConfig, EdgeConfig, PushTarget};
+use rtmp_streaming_server::flv::FlvTag;
+use rtmp_streaming_server::{StreamManager, Subscription};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Next video frame, skipping sequence headers and script data
+async fn next_frame(subscription: &mut Subscription) -> FlvTag {
+    loop {
+        let tag = tokio::time::timeout(Duration::from_secs(5), subscription.recv())
+            .await
+            .expect("timed out waiting for tags")
+            .unwrap()
+            .unwrap();
+        if tag.is_video() && !tag.is_sequence_header() {
+            return tag;
+        }
+    }
+}
+
+/// POST an RTMPT command and return the status and body
+async fn post(addr: SocketAddr, path: &str, body: impl Into<Body>) -> (StatusCode, Bytes) {
+    let request = Request::post(format!("http://{}{}", addr, path))
+        .header("Content-Type", "application/x-fcs")
+        .body(body.into())
+        .unwrap();
+    let response = Client::new().request(request).await.unwrap();
+    let status = response.status();
+    (status, hyper::body::to_bytes(response.into_body()).await.unwrap())
+}
+
+#[tokio::test]
+async fn test_publish_through_tunnel() {
+    let upstream = StreamManager::new();
+    let upstream_addr = start_http(upstream.clone()).await;
+    let streams = with_app(AppConfig {
+        push: vec![PushTarget {
+            url: format!("rtmpt://{}/live/{{stream}}", upstream_addr),
+            stream: None,
+        }],
+        ..AppConfig::default()
+    });
+
+    let publisher = streams.start_publish("live", "cam", "local".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    let copy = published(&upstream, "cam").await;
+    let mut subscription = copy.subscribe("viewer".to_string()).await;
+
+    publisher.publish(video(40, 0x17, 0x01)).await.unwrap();
+    publisher.publish(video(80, 0x27, 0x01)).await.unwrap();
+    assert_eq!(next_frame(&mut subscription).await.data, video(0, 0x17, 0x01).data);
+    assert_eq!(next_frame(&mut subscription).await.data, video(0, 0x27, 0x01).data);
+    assert!(copy.sequence_headers().await.iter().any(|tag| tag.data == video(0, 0x17, 0x00).data));
+}
+
+#[tokio::test]
+async fn test_play_through_tunnel() {
+    let origin = StreamManager::new();
+    let origin_addr = start_http(origin.clone()).await;
+    let streams = with_app(AppConfig {
+        edge: Some(EdgeConfig {
+            origin: format!("rtmpt://{}/live/{{stream}}", origin_addr),
+            idle_timeout_ms: 200,
+        }),
+        ..AppConfig::default()
+    });
+
+    let publisher = origin.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+
+    let stream = streams.get_or_pull("live", "cam").await;
+    let mut subscription = stream.subscribe("viewer".to_string()).await;
+    published(&streams, "cam").await;
+    // Keep feeding keyframes until the pull has caught up with the origin
+    for i in 1..50 {
+        publisher.publish(video(i * 40, 0x17, 0x01)).await.unwrap();
+        if let Ok(tag) = tokio::time::timeout(Duration::from_millis(100), next_frame(&mut subscription)).await {
+            assert_eq!(tag.data, video(0, 0x17, 0x01).data);
+            return;
+        }
+    }
+    panic!("no tags arrived through the tunnel");
+}
+
+#[tokio::test]
+async fn test_tunnel_commands() {
+    let addr = start_http(StreamManager::new()).await;
+
+    let (status, id) = post(addr, "/open/1", "\0").await;
+    assert_eq!(status, 200);
+    let id = String::from_utf8(id.to_vec()).unwrap().trim().to_string();
+    assert!(!id.is_empty());
+
+    // C0 + C1 is answered with S0 + S1 + S2 behind the interval byte
+    let mut c0c1 = vec![3u8];
+    c0c1.extend_from_slice(&[0u8; 1536]);
+    let mut received = Vec::new();
+    let (status, body) = post(addr, &format!("/send/{}/1", id), c0c1).await;
+    assert_eq!(status, 200);
+    received.extend_from_slice(&body[1..]);
+    for seq in 2..50 {
+        if received.len() == 1 + 2 * 1536 {
+            break;
+        }
+        let (status, body) = post(addr, &format!("/idle/{}/{}", id, seq), "").await;
+        assert_eq!(status, 200);
+        assert!((1..=0x21).contains(&body[0]));
+        received.extend_from_slice(&body[1..]);
+    }
+    assert_eq!(received.len(), 1 + 2 * 1536);
+    assert_eq!(received[0], 3);
+
+    // Closed and unknown tunnels are gone
+    assert_eq!(post(addr, &format!("/close/{}/50", id), "").await.0, 200);
+    assert_eq!(post(addr, &format!("/idle/{}/51", id), "").await.0, 404);
+    assert_eq!(post(addr, "/idle/unknown/1", "").await.0, 404);
+}
@@ -0,0 +1,323 @@
+mod common;
+
+use common::{avc_frame, avc_sequence_header, idr, next_tag, PPS, SLICE, SPS};
+use rtmp_streaming_server::config::{AppConfig, CameraConfig, Config, RtspTransport};
+use rtmp_streaming_server::flv::FlvTag;
+use rtmp_streaming_server::{StreamManager, Subscription};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+
+/// A scripted IP camera
+#[derive(Clone)]
+struct Camera {
+    sdp: String,
+    transport: RtspTransport,
+    /// Credentials checked with digest authentication
+    credentials: Option<(&'static str, &'static str)>,
+    /// RTP packets sent after PLAY, by track index
+    media: Vec<(usize, Vec<u8>)>,
+    /// Close the connection after sending the media
+    hang_up: bool,
+}
+
+/// Requests the camera received, as "METHOD uri"
+type Log = Arc<Mutex<Vec<String>>>;
+
+async fn start_camera(camera: Camera) -> (SocketAddr, Log) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let log = Log::default();
+    let requests = log.clone();
+    tokio::spawn(async move {
+        loop {
+            let (socket, _) = listener.accept().await.unwrap();
+            tokio::spawn(serve(camera.clone(), addr, socket, requests.clone()));
+        }
+    });
+    (addr, log)
+}
+
+async fn serve(camera: Camera, addr: SocketAddr, socket: TcpStream, log: Log) {
+    let mut socket = BufReader::new(socket);
+    let mut client_ports = Vec::new();
+    loop {
+        let mut request_line = String::new();
+        if socket.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            socket.read_line(&mut line).await.unwrap();
+            match line.trim_end().split_once(": ") {
+                Some((name, value)) => headers.insert(name.to_ascii_lowercase(), value.to_string()),
+                None => break,
+            };
+        }
+        let mut parts = request_line.split_whitespace();
+        let (method, uri) = (parts.next().unwrap().to_string(), parts.next().unwrap().to_string());
+        log.lock().unwrap().push(format!("{} {}", method, uri));
+
+        let cseq = headers["cseq"].clone();
+        let mut response = format!("RTSP/1.0 200 OK\r\nCSeq: {}\r\n", cseq);
+        let mut body = String::new();
+        if let Some((user, password)) = camera.credentials {
+            let expected = digest(user, password, &method, &uri);
+            if !headers.get("authorization").is_some_and(|auth| auth.contains(&expected)) {
+                response = format!(
+                    "RTSP/1.0 401 Unauthorized\r\nCSeq: {}\r\nWWW-Authenticate: Basic realm=\"cam\"\r\nWWW-Authenticate: Digest realm=\"cam\", nonce=\"n0nce\"\r\n\r\n",
+                    cseq
+                );
+                socket.get_mut().write_all(response.as_bytes()).await.unwrap();
+                continue;
+            }
+        }
+        match method.as_str() {
+            "OPTIONS" => response.push_str("Public: OPTIONS, DESCRIBE, SETUP, PLAY, GET_PARAMETER, TEARDOWN\r\n"),
+            "DESCRIBE" => {
+                response.push_str(&format!("Content-Base: rtsp://{}/cam/\r\nContent-Type: application/sdp\r\n", addr));
+                body = camera.sdp.clone();
+            }
+            "SETUP" => {
+                let transport = &headers["transport"];
+                if let Some(ports) = transport.split("client_port=").nth(1) {
+                    client_ports.push(ports.split('-').next().unwrap().parse::<u16>().unwrap());
+                }
+                response.push_str(&format!("Transport: {}\r\nSession: 4242;timeout=60\r\n", transport));
+            }
+            _ => {}
+        }
+        response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+        socket.get_mut().write_all(response.as_bytes()).await.unwrap();
+
+        if method == "PLAY" {
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            for (track, packet) in &camera.media {
+                match camera.transport {
+                    RtspTransport::Tcp => {
+                        let mut frame = vec![b'$', (2 * track) as u8];
+                        frame.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+                        frame.extend_from_slice(packet);
+                        socket.get_mut().write_all(&frame).await.unwrap();
+                    }
+                    RtspTransport::Udp => {
+                        udp.send_to(packet, ("127.0.0.1", client_ports[*track])).await.unwrap();
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(2)).await;
+            }
+            if camera.hang_up {
+                return;
+            }
+        }
+    }
+}
+
+fn md5_hex(input: &str) -> String {
+    format!("{:x}", md5::compute(input))
+}
+
+fn digest(user: &str, password: &str, method: &str, uri: &str) -> String {
+    let ha1 = md5_hex(&format!("{}:cam:{}", user, password));
+    let ha2 = md5_hex(&format!("{}:{}", method, uri));
+    md5_hex(&format!("{}:n0nce:{}", ha1, ha2))
+}
+
+fn rtp(payload_type: u8, seq: u16, timestamp: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x80, payload_type | if marker { 0x80 } else { 0 }];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&0x1234_5678u32.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// The IDR as FU-A fragments of at most 1200 bytes
+fn fu_a(seq: u16, timestamp: u32) -> Vec<Vec<u8>> {
+    let idr = idr();
+    let chunks: Vec<&[u8]> = idr[1..].chunks(1200).collect();
+    chunks
+        .iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut header = idr[0] & 0x1f;
+            if i == 0 {
+                header |= 0x80;
+            }
+            if i == chunks.len() - 1 {
+                header |= 0x40;
+            }
+            let mut payload = vec![(idr[0] & 0xe0) | 28, header];
+            payload.extend_from_slice(chunk);
+            rtp(96, seq + i as u16, timestamp, i == chunks.len() - 1, &payload)
+        })
+        .collect()
+}
+
+fn with_camera(url: String, transport: RtspTransport) -> StreamManager {
+    let mut app = AppConfig::default();
+    app.cameras.insert(
+        "gate".to_string(),
+        CameraConfig {
+            url,
+            transport,
+            idle_timeout_ms: 200,
+        },
+    );
+    let mut config = Config::default();
+    config.apps.insert("live".to_string(), app);
+    StreamManager::new().with_config(config).with_audio(true)
+}
+
+/// Receive tags until `count` video and audio tags arrived
+async fn collect(subscription: &mut Subscription, video: usize, audio: usize) -> (Vec<FlvTag>, Vec<FlvTag>) {
+    let (mut videos, mut audios) = (Vec::new(), Vec::new());
+    while videos.len() < video || audios.len() < audio {
+        let tag = next_tag(subscription).await;
+        if tag.is_video() {
+            videos.push(tag);
+        } else if tag.is_audio() {
+            audios.push(tag);
+        }
+    }
+    (videos, audios)
+}
+
+#[tokio::test]
+async fn test_pull_interleaved_with_digest_auth() {
+    let sdp = format!(
+        "v=0\r\no=- 0 0 IN IP4 127.0.0.1\r\ns=cam\r\nt=0 0\r\na=control:*\r\n\
+         m=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\na=fmtp:96 packetization-mode=1;sprop-parameter-sets={},{}\r\na=control:trackID=1\r\n\
+         m=audio 0 RTP/AVP 97\r\na=rtpmap:97 MPEG4-GENERIC/44100/2\r\na=fmtp:97 streamtype=5;mode=AAC-hbr;config=1210;sizelength=13;indexlength=3;indexdeltalength=3\r\na=control:trackID=2\r\n",
+        base64_encode(SPS),
+        base64_encode(PPS)
+    );
+    let mut media: Vec<(usize, Vec<u8>)> = fu_a(1, 90_000).into_iter().map(|packet| (0, packet)).collect();
+    media.push((0, rtp(96, 4, 93_600, true, SLICE)));
+    // Two AAC frames of 3 and 2 bytes behind a 32-bit AU header section
+    let aac = [0x00, 0x20, 0x00, 0x18, 0x00, 0x10, 0x21, 0x10, 0x05, 0x21, 0x06];
+    media.push((1, rtp(97, 1, 44_100, true, &aac)));
+    let camera = Camera {
+        sdp,
+        transport: RtspTransport::Tcp,
+        credentials: Some(("admin", "secret")),
+        media,
+        hang_up: false,
+    };
+    let (addr, log) = start_camera(camera).await;
+    let streams = with_camera(format!("rtsp://admin:secret@{}/cam", addr), RtspTransport::Tcp);
+
+    let stream = streams.get_or_pull("live", "gate").await;
+    assert!(streams.rtsp().is_pulling("live", "gate"));
+    let mut subscription = stream.subscribe("viewer".to_string()).await;
+    let (video, audio) = collect(&mut subscription, 3, 3).await;
+
+    assert_eq!(video[0].data, avc_sequence_header(0).data);
+    assert_eq!(video[1].data, avc_frame(0, 0, true, &[&idr()]).data);
+    assert_eq!(video[2].data, avc_frame(0, 0, false, &[SLICE]).data);
+    assert_eq!(video[2].timestamp - video[1].timestamp, 40);
+    assert_eq!(&audio[0].data[..], &[0xaf, 0x00, 0x12, 0x10]);
+    assert_eq!(&audio[1].data[..], &[0xaf, 0x01, 0x21, 0x10, 0x05]);
+    assert_eq!(&audio[2].data[..], &[0xaf, 0x01, 0x21, 0x06]);
+    assert_eq!(audio[2].timestamp - audio[1].timestamp, 23);
+
+    let log = log.lock().unwrap().clone();
+    let setups: Vec<_> = log.iter().filter(|request| request.starts_with("SETUP")).collect();
+    assert_eq!(
+        setups,
+        [&format!("SETUP rtsp://{}/cam/trackID=1", addr), &format!("SETUP rtsp://{}/cam/trackID=2", addr)]
+    );
+    assert!(log.contains(&format!("PLAY rtsp://{}/cam/", addr)));
+}
+
+#[tokio::test]
+async fn test_pull_udp_with_inband_parameter_sets() {
+    let sdp = "v=0\r\ns=cam\r\nt=0 0\r\n\
+               m=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\na=control:trackID=0\r\n\
+               m=audio 0 RTP/AVP 8\r\na=control:trackID=1\r\n"
+        .to_string();
+    // STAP-A with SPS and PPS, then the IDR in a single NAL unit packet
+    let mut stap_a = vec![24];
+    for nal in [SPS, PPS] {
+        stap_a.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        stap_a.extend_from_slice(nal);
+    }
+    let media = vec![
+        (0, rtp(96, 10, 0, false, &stap_a)),
+        (0, rtp(96, 11, 0, true, &idr()[..1000])),
+        (1, rtp(8, 1, 0, false, &[0xd5; 160])),
+        (1, rtp(8, 2, 160, false, &[0x55; 160])),
+    ];
+    let camera = Camera {
+        sdp,
+        transport: RtspTransport::Udp,
+        credentials: None,
+        media,
+        hang_up: false,
+    };
+    let (addr, _) = start_camera(camera).await;
+    let streams = with_camera(format!("rtsp://{}/cam", addr), RtspTransport::Udp);
+
+    let stream = streams.get_or_pull("live", "gate").await;
+    let mut subscription = stream.subscribe("viewer".to_string()).await;
+    let (video, audio) = collect(&mut subscription, 2, 2).await;
+
+    assert_eq!(video[0].data, avc_sequence_header(0).data);
+    assert_eq!(video[1].data, avc_frame(0, 0, true, &[&idr()[..1000]]).data);
+    // G.711 A-law, 8 kHz mono: 160 samples are 20 ms
+    assert_eq!(audio[0].data[0], 0x72);
+    assert_eq!(&audio[0].data[1..], &[0xd5; 160][..]);
+    assert_eq!(audio[1].timestamp - audio[0].timestamp, 20);
+}
+
+#[tokio::test]
+async fn test_reconnect_and_idle_stop() {
+    let sdp = format!(
+        "v=0\r\ns=cam\r\nt=0 0\r\nm=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\na=fmtp:96 sprop-parameter-sets={},{}\r\n",
+        base64_encode(SPS),
+        base64_encode(PPS)
+    );
+    // Every connection sends one keyframe and hangs up
+    let camera = Camera {
+        sdp,
+        transport: RtspTransport::Tcp,
+        credentials: None,
+        media: vec![(0, rtp(96, 1, 0, true, &idr()[..500]))],
+        hang_up: true,
+    };
+    let (addr, log) = start_camera(camera).await;
+    let streams = with_camera(format!("rtsp://{}/cam", addr), RtspTransport::Tcp);
+
+    let stream = streams.get_or_pull("live", "gate").await;
+    let mut subscription = stream.subscribe("viewer".to_string()).await;
+    assert_eq!(next_tag(&mut subscription).await.data, avc_sequence_header(0).data);
+    assert_eq!(next_tag(&mut subscription).await.data, avc_frame(0, 0, true, &[&idr()[..500]]).data);
+    // The viewer stays attached while the camera is reconnected, and the
+    // sequence header is repeated in case the camera changed its settings
+    assert!(stream.is_published());
+    assert_eq!(next_tag(&mut subscription).await.data, avc_sequence_header(0).data);
+    assert_eq!(next_tag(&mut subscription).await.data, avc_frame(0, 0, true, &[&idr()[..500]]).data);
+    let plays = log.lock().unwrap().iter().filter(|request| request.starts_with("PLAY")).count();
+    assert_eq!(plays, 2);
+
+    // Without viewers the pull stops after the idle timeout
+    drop(subscription);
+    for _ in 0..100 {
+        if !streams.rtsp().is_pulling("live", "gate") {
+            assert!(!stream.is_published());
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("camera still pulled without viewers");
+}
+
+fn base64_encode(data: &[u8]) -> String {
+    use base64::Engine;
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
@@ -0,0 +1,357 @@
+mod common;
+
+use bytes::{Buf, Bytes, BytesMut};
+use common::{avc_frame, avc_sequence_header, idr, PPS, SLICE, SPS};
+use rtmp_streaming_server::config::{AppConfig, CameraConfig, Config, RtspTransport};
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::{Publisher, RtspServer, StreamManager};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const AUD: &[u8] = &[0x09, 0xf0];
+
+fn aac(timestamp: u32, packet_type: u8, payload: &[u8]) -> FlvTag {
+    let mut data = vec![0xaf, packet_type];
+    data.extend_from_slice(payload);
+    FlvTag::new(TagType::Audio, timestamp, Bytes::from(data))
+}
+
+async fn start_server(streams: StreamManager) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(RtspServer::new(addr).with_stream_manager(streams).serve(listener));
+    addr
+}
+
+async fn publish_headers(streams: &StreamManager) -> Publisher {
+    let publisher = streams.start_publish("live", "cam", "encoder".to_string()).await.unwrap();
+    publisher.publish(avc_sequence_header(0)).await.unwrap();
+    publisher.publish(aac(0, 0, &[0x12, 0x10])).await.unwrap();
+    publisher
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> &str {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+            .unwrap_or_default()
+    }
+}
+
+/// Minimal RTSP client
+struct Client {
+    socket: TcpStream,
+    buf: BytesMut,
+    cseq: u32,
+}
+
+impl Client {
+    async fn connect(addr: SocketAddr) -> Self {
+        Self {
+            socket: TcpStream::connect(addr).await.unwrap(),
+            buf: BytesMut::new(),
+            cseq: 0,
+        }
+    }
+
+    async fn request(&mut self, method: &str, uri: &str, headers: &[(&str, &str)]) -> Response {
+        self.cseq += 1;
+        let mut request = format!("{} {} RTSP/1.0\r\nCSeq: {}\r\n", method, uri, self.cseq);
+        for (name, value) in headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        self.socket.write_all(request.as_bytes()).await.unwrap();
+
+        loop {
+            if let Some(end) = self.buf.windows(4).position(|window| window == b"\r\n\r\n") {
+                let head = String::from_utf8(self.buf[..end].to_vec()).unwrap();
+                let mut lines = head.split("\r\n");
+                let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+                let headers: Vec<(String, String)> = lines
+                    .map(|line| line.split_once(": ").unwrap())
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect();
+                let response = Response {
+                    status,
+                    headers,
+                    body: String::new(),
+                };
+                let len = response.header("Content-Length").parse::<usize>().unwrap_or(0);
+                if self.buf.len() >= end + 4 + len {
+                    self.buf.advance(end + 4);
+                    let body = String::from_utf8(self.buf.split_to(len).to_vec()).unwrap();
+                    assert_eq!(response.header("CSeq"), self.cseq.to_string());
+                    return Response { body, ..response };
+                }
+            }
+            self.read().await;
+        }
+    }
+
+    /// Next interleaved frame
+    async fn interleaved(&mut self) -> (u8, Bytes) {
+        loop {
+            if self.buf.len() >= 4 {
+                assert_eq!(self.buf[0], b'$');
+                let len = u16::from_be_bytes([self.buf[2], self.buf[3]]) as usize;
+                if self.buf.len() >= 4 + len {
+                    let frame = self.buf.split_to(4 + len).freeze();
+                    return (frame[1], frame.slice(4..));
+                }
+            }
+            self.read().await;
+        }
+    }
+
+    async fn read(&mut self) {
+        let read = tokio::time::timeout(Duration::from_secs(5), self.socket.read_buf(&mut self.buf))
+            .await
+            .expect("timed out reading from the server")
+            .unwrap();
+        assert!(read > 0, "server closed the connection");
+    }
+}
+
+struct Rtp {
+    marker: bool,
+    seq: u16,
+    timestamp: u32,
+    payload: Bytes,
+}
+
+fn parse_rtp(packet: Bytes) -> Rtp {
+    assert_eq!(packet[0], 0x80);
+    Rtp {
+        marker: packet[1] & 0x80 != 0,
+        seq: u16::from_be_bytes([packet[2], packet[3]]),
+        timestamp: u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]]),
+        payload: packet.slice(12..),
+    }
+}
+
+/// `seq` and `rtptime` of a track in an RTP-Info header
+fn rtp_info(header: &str, track: &str) -> (u16, u32) {
+    let info = header.split(',').find(|info| info.contains(track)).unwrap();
+    let param = |name: &str| info.split(';').find_map(|param| param.strip_prefix(name)).unwrap();
+    (param("seq=").parse().unwrap(), param("rtptime=").parse().unwrap())
+}
+
+#[tokio::test]
+async fn test_describe_and_play_interleaved() {
+    let streams = StreamManager::new().with_audio(true);
+    let publisher = publish_headers(&streams).await;
+    let addr = start_server(streams.clone()).await;
+    let url = format!("rtsp://{}/live/cam", addr);
+
+    let mut client = Client::connect(addr).await;
+    let options = client.request("OPTIONS", &url, &[]).await;
+    assert_eq!(options.status, 200);
+    assert!(options.header("Public").contains("DESCRIBE"));
+
+    let describe = client.request("DESCRIBE", &url, &[("Accept", "application/sdp")]).await;
+    assert_eq!(describe.status, 200);
+    assert_eq!(describe.header("Content-Type"), "application/sdp");
+    assert_eq!(describe.header("Content-Base"), format!("{}/", url));
+    let sdp = describe.body;
+    assert!(sdp.contains("m=video 0 RTP/AVP 96\r\na=rtpmap:96 H264/90000\r\n"));
+    assert!(sdp.contains("profile-level-id=42c01e;sprop-parameter-sets=Z0LAHtoCgA==,aM48gA=="));
+    assert!(sdp.contains("a=control:trackID=0"));
+    assert!(sdp.contains("m=audio 0 RTP/AVP 97\r\na=rtpmap:97 MPEG4-GENERIC/44100/2\r\n"));
+    assert!(sdp.contains("sizelength=13;indexlength=3;indexdeltalength=3;config=1210"));
+
+    let video = client
+        .request("SETUP", &format!("{}/trackID=0", url), &[("Transport", "RTP/AVP/TCP;unicast;interleaved=0-1")])
+        .await;
+    assert_eq!(video.status, 200);
+    assert_eq!(video.header("Transport"), "RTP/AVP/TCP;unicast;interleaved=0-1");
+    let session = video.header("Session").split(';').next().unwrap().to_string();
+    let audio = client
+        .request(
+            "SETUP",
+            &format!("{}/trackID=1", url),
+            &[("Transport", "RTP/AVP/TCP;unicast;interleaved=2-3"), ("Session", &session)],
+        )
+        .await;
+    assert_eq!(audio.status, 200);
+    let play = client.request("PLAY", &url, &[("Session", &session), ("Range", "npt=0-")]).await;
+    assert_eq!(play.status, 200);
+    let (video_seq, video_time) = rtp_info(play.header("RTP-Info"), "trackID=0");
+    let (audio_seq, audio_time) = rtp_info(play.header("RTP-Info"), "trackID=1");
+
+    // Playback starts at the keyframe; its parameter sets go first
+    publisher.publish(avc_frame(960, 0, false, &[SLICE])).await.unwrap();
+    publisher.publish(avc_frame(1000, 0, true, &[AUD, SPS, PPS, &idr()])).await.unwrap();
+    publisher.publish(aac(1023, 1, &[0x21, 0x10, 0x05])).await.unwrap();
+    publisher.publish(avc_frame(1040, 80, false, &[SLICE])).await.unwrap();
+
+    let mut packets = Vec::new();
+    for _ in 0..7 {
+        let (channel, packet) = client.interleaved().await;
+        packets.push((channel, parse_rtp(packet)));
+    }
+    let channels: Vec<u8> = packets.iter().map(|(channel, _)| *channel).collect();
+    assert_eq!(channels, [0, 0, 0, 0, 0, 2, 0]);
+
+    let video: Vec<&Rtp> = packets.iter().filter(|(channel, _)| *channel == 0).map(|(_, rtp)| rtp).collect();
+    for (i, rtp) in video.iter().enumerate() {
+        assert_eq!(rtp.seq, video_seq.wrapping_add(i as u16));
+    }
+    assert_eq!(&video[0].payload[..], SPS);
+    assert_eq!(&video[1].payload[..], PPS);
+    // The IDR is split into FU-A fragments, the marker on the last one
+    let mut reassembled = vec![(video[2].payload[0] & 0xe0) | (video[2].payload[1] & 0x1f)];
+    for (i, fragment) in video[2..5].iter().enumerate() {
+        assert_eq!(fragment.payload[0], 0x7c);
+        assert_eq!(fragment.payload[1] & 0x80 != 0, i == 0);
+        assert_eq!(fragment.payload[1] & 0x40 != 0, i == 2);
+        assert_eq!(fragment.marker, i == 2);
+        assert_eq!(fragment.timestamp, video_time);
+        reassembled.extend_from_slice(&fragment.payload[2..]);
+    }
+    assert_eq!(reassembled, idr());
+    assert!(!video[0].marker && !video[1].marker);
+    // Presentation time of the P frame: 40 ms later plus 80 ms composition offset
+    assert_eq!(&video[5].payload[..], SLICE);
+    assert!(video[5].marker);
+    assert_eq!(video[5].timestamp, video_time.wrapping_add(120 * 90));
+
+    let audio = &packets[5].1;
+    assert_eq!(audio.seq, audio_seq);
+    assert!(audio.marker);
+    assert_eq!(audio.timestamp, audio_time.wrapping_add(23 * 44_100 / 1000));
+    assert_eq!(&audio.payload[..], &[0x00, 0x10, 0x00, 0x18, 0x21, 0x10, 0x05]);
+
+    let stream = streams.get_stream("live", "cam").await.unwrap();
+    assert_eq!(stream.subscriber_count(), 1);
+    let teardown = client.request("TEARDOWN", &url, &[("Session", &session)]).await;
+    assert_eq!(teardown.status, 200);
+    for _ in 0..50 {
+        if stream.subscriber_count() == 0 {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("subscriber still attached after TEARDOWN");
+}
+
+#[tokio::test]
+async fn test_play_over_udp() {
+    // The camera puller of a second server plays this one over UDP
+    let origin = StreamManager::new().with_audio(true);
+    let publisher = publish_headers(&origin).await;
+    let addr = start_server(origin).await;
+    tokio::spawn(async move {
+        for i in 0.. {
+            let timestamp = i * 40;
+            if publisher.publish(avc_frame(timestamp, 0, true, &[&idr()[..2000]])).await.is_err() {
+                break;
+            }
+            publisher.publish(aac(timestamp, 1, &[0x21, 0x10, 0x05])).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(40)).await;
+        }
+    });
+
+    let mut app = AppConfig::default();
+    app.cameras.insert(
+        "gate".to_string(),
+        CameraConfig {
+            url: format!("rtsp://{}/live/cam", addr),
+            transport: RtspTransport::Udp,
+            idle_timeout_ms: 1000,
+        },
+    );
+    let mut config = Config::default();
+    config.apps.insert("live".to_string(), app);
+    let edge = StreamManager::new().with_config(config).with_audio(true);
+    let stream = edge.get_or_pull("live", "gate").await;
+    let mut subscription = stream.subscribe("viewer".to_string()).await;
+
+    let (mut video, mut audio) = (Vec::new(), Vec::new());
+    while video.len() < 3 || audio.len() < 3 {
+        let tag = tokio::time::timeout(Duration::from_secs(5), subscription.recv())
+            .await
+            .expect("timed out waiting for tags")
+            .unwrap()
+            .unwrap();
+        if tag.is_video() {
+            video.push(tag);
+        } else {
+            audio.push(tag);
+        }
+    }
+    assert_eq!(video[0].data, avc_sequence_header(0).data);
+    assert_eq!(video[1].data, avc_frame(0, 0, true, &[&idr()[..2000]]).data);
+    assert_eq!(video[2].timestamp - video[1].timestamp, 40);
+    assert_eq!(&audio[0].data[..], &[0xaf, 0x00, 0x12, 0x10]);
+    assert_eq!(&audio[1].data[..], &[0xaf, 0x01, 0x21, 0x10, 0x05]);
+}
+
+#[tokio::test]
+async fn test_request_errors() {
+    let streams = StreamManager::new();
+    let _publisher = publish_headers(&streams).await;
+    let addr = start_server(streams).await;
+    let url = format!("rtsp://{}/live/cam", addr);
+    let mut client = Client::connect(addr).await;
+
+    let missing = client.request("DESCRIBE", &format!("rtsp://{}/live/nobody", addr), &[]).await;
+    assert_eq!(missing.status, 404);
+    assert_eq!(client.request("PLAY", &url, &[]).await.status, 455);
+    assert_eq!(client.request("ANNOUNCE", &url, &[]).await.status, 501);
+    let multicast = client
+        .request("SETUP", &format!("{}/trackID=0", url), &[("Transport", "RTP/AVP;multicast")])
+        .await;
+    assert_eq!(multicast.status, 461);
+
+    // UDP is offered as an alternative to multicast
+    let setup = client
+        .request(
+            "SETUP",
+            &format!("{}/trackID=0", url),
+            &[("Transport", "RTP/AVP;multicast,RTP/AVP;unicast;client_port=5000-5001")],
+        )
+        .await;
+    assert_eq!(setup.status, 200);
+    assert!(setup.header("Transport").starts_with("RTP/AVP;unicast;client_port=5000-5001;server_port="));
+    assert_eq!(client.request("PLAY", &url, &[("Session", "wrong")]).await.status, 454);
+    // Audio is dropped by this stream manager, so there is only a video track
+    let session = setup.header("Session").split(';').next().unwrap().to_string();
+    let audio = client
+        .request("SETUP", &format!("{}/trackID=1", url), &[("Transport", "RTP/AVP/TCP"), ("Session", &session)])
+        .await;
+    assert_eq!(audio.status, 404);
+    let other = client
+        .request("SETUP", &format!("rtsp://{}/live/other/trackID=0", addr), &[("Session", &session)])
+        .await;
+    assert_eq!(other.status, 459);
+    assert_eq!(client.request("GET_PARAMETER", &url, &[("Session", &session)]).await.status, 200);
+}
+
+#[tokio::test]
+async fn test_truncated_sequence_header() {
+    let streams = StreamManager::new();
+    let publisher = streams.start_publish("live", "cam", "encoder".to_string()).await.unwrap();
+    publisher.publish(FlvTag::new(TagType::Video, 0, Bytes::from_static(&[0x17, 0x00, 0x00]))).await.unwrap();
+    let addr = start_server(streams).await;
+    let url = format!("rtsp://{}/live/cam", addr);
+    let mut client = Client::connect(addr).await;
+
+    // DESCRIBE keeps waiting past the truncated header for a usable one
+    let (describe, _) = tokio::join!(client.request("DESCRIBE", &url, &[]), async {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        publisher.publish(avc_sequence_header(40)).await.unwrap();
+    });
+    assert_eq!(describe.status, 200);
+    assert!(describe.body.contains("sprop-parameter-sets=Z0LAHtoCgA==,aM48gA=="));
+}
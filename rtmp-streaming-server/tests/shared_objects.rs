@@ -0,0 +1,162 @@
+mod common;
+
+use amf::amf0::Value;
+use common::{start_rtmp, Peer};
+use rtmp_streaming_server::config::Config;
+use rtmp_streaming_server::protocol::MessageType;
+use rtmp_streaming_server::{SharedObjectEvent, SharedObjectMessage, StreamManager};
+use std::time::Duration;
+
+fn string(value: &str) -> Value {
+    Value::String(value.to_string())
+}
+
+fn change(name: &str, value: Value) -> SharedObjectEvent {
+    SharedObjectEvent::RequestChange {
+        name: name.to_string(),
+        value,
+    }
+}
+
+/// Send events for shared object `name`
+async fn shared_object(peer: &mut Peer, name: &str, persistent: bool, events: Vec<SharedObjectEvent>) {
+    let message = SharedObjectMessage::new(name, 0, persistent, events);
+    peer.send(&message.to_message(false)).await;
+}
+
+/// Next shared object message, or `None` if none arrives for a while
+async fn update(peer: &mut Peer) -> Option<SharedObjectMessage> {
+    loop {
+        let message = peer.recv().await?;
+        if message.message_type == MessageType::SharedObjectAmf0 {
+            return Some(SharedObjectMessage::decode(&message).unwrap());
+        }
+    }
+}
+
+#[test]
+fn test_message_round_trip() {
+    let message = SharedObjectMessage::new(
+        "chat",
+        7,
+        true,
+        vec![
+            SharedObjectEvent::Use,
+            change("topic", string("news")),
+            SharedObjectEvent::SendMessage {
+                values: vec![string("onLine"), Value::Number(1.0)],
+            },
+            SharedObjectEvent::Status {
+                code: "SharedObject.BadPersistence".to_string(),
+                level: "error".to_string(),
+            },
+            SharedObjectEvent::RequestRemove { name: "topic".to_string() },
+        ],
+    );
+    for amf3 in [false, true] {
+        let encoded = message.to_message(amf3);
+        assert_eq!(SharedObjectMessage::decode(&encoded).unwrap(), message);
+    }
+
+    let mut truncated = message.to_message(false);
+    truncated.payload = truncated.payload.slice(..truncated.payload.len() - 1);
+    assert!(SharedObjectMessage::decode(&truncated).is_err());
+}
+
+#[tokio::test]
+async fn test_changes_and_messages() {
+    let streams = StreamManager::new();
+    let addr = start_rtmp(streams.clone()).await;
+    let mut alice = Peer::connected(addr).await;
+    let mut bob = Peer::connected(addr).await;
+
+    shared_object(&mut alice, "chat", false, vec![SharedObjectEvent::Use]).await;
+    let used = update(&mut alice).await.unwrap();
+    assert_eq!(used.events, [SharedObjectEvent::UseSuccess, SharedObjectEvent::Clear]);
+    shared_object(&mut bob, "chat", false, vec![SharedObjectEvent::Use]).await;
+    update(&mut bob).await.unwrap();
+
+    // A change is confirmed to its author and sent to everyone else
+    shared_object(&mut alice, "chat", false, vec![change("topic", string("news"))]).await;
+    let confirmed = update(&mut alice).await.unwrap();
+    assert_eq!(confirmed.events, [SharedObjectEvent::Success { name: "topic".to_string() }]);
+    assert_eq!(confirmed.version, 1);
+    let changed = update(&mut bob).await.unwrap();
+    assert_eq!(changed.name, "chat");
+    assert_eq!(
+        changed.events,
+        [SharedObjectEvent::Change {
+            name: "topic".to_string(),
+            value: string("news"),
+        }]
+    );
+    let properties = streams.shared_objects().properties("live", "chat").unwrap();
+    assert_eq!(properties[0].value, string("news"));
+
+    // Messages go to every client, the sender included
+    let line = SharedObjectEvent::SendMessage {
+        values: vec![string("onLine"), string("hi")],
+    };
+    shared_object(&mut bob, "chat", false, vec![line.clone()]).await;
+    assert_eq!(update(&mut alice).await.unwrap().events, std::slice::from_ref(&line));
+    assert_eq!(update(&mut bob).await.unwrap().events, [line]);
+
+    // Late clients start with the current properties
+    let mut carol = Peer::connected(addr).await;
+    shared_object(&mut carol, "chat", false, vec![SharedObjectEvent::Use]).await;
+    let used = update(&mut carol).await.unwrap();
+    assert_eq!(used.version, 1);
+    assert_eq!(used.events[2], SharedObjectEvent::Change { name: "topic".to_string(), value: string("news") });
+
+    shared_object(&mut bob, "chat", false, vec![SharedObjectEvent::RequestRemove { name: "topic".to_string() }]).await;
+    assert_eq!(update(&mut bob).await.unwrap().events, [SharedObjectEvent::Success { name: "topic".to_string() }]);
+    let removed = SharedObjectEvent::Remove { name: "topic".to_string() };
+    assert_eq!(update(&mut alice).await.unwrap().events, std::slice::from_ref(&removed));
+    assert_eq!(update(&mut carol).await.unwrap().events, [removed]);
+
+    // Requests for objects not in use are ignored
+    shared_object(&mut carol, "other", false, vec![change("x", Value::Number(1.0))]).await;
+    assert!(update(&mut carol).await.is_none());
+    assert!(streams.shared_objects().properties("live", "other").is_none());
+
+    // The object goes away with its last client
+    drop((alice, bob, carol));
+    let mut released = false;
+    for _ in 0..50 {
+        released = streams.shared_objects().properties("live", "chat").is_none();
+        if released {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(released);
+}
+
+#[tokio::test]
+async fn test_persistent_objects() {
+    let dir = std::env::temp_dir().join(format!("rtmp-shared-objects-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let config = Config {
+        shared_objects_dir: Some(dir.to_string_lossy().into_owned()),
+        ..Config::default()
+    };
+
+    let addr = start_rtmp(StreamManager::new().with_config(config.clone())).await;
+    let mut peer = Peer::connected(addr).await;
+    shared_object(&mut peer, "cues/main", true, vec![SharedObjectEvent::Use]).await;
+    update(&mut peer).await.unwrap();
+    shared_object(&mut peer, "cues/main", true, vec![change("cue", Value::Number(42.0))]).await;
+    update(&mut peer).await.unwrap();
+    assert!(dir.join("live").join("cues").join("main.so").exists());
+
+    // A restarted server loads the object again
+    let addr = start_rtmp(StreamManager::new().with_config(config)).await;
+    let mut peer = Peer::connected(addr).await;
+    shared_object(&mut peer, "cues/main", true, vec![SharedObjectEvent::Use]).await;
+    let used = update(&mut peer).await.unwrap();
+    assert_eq!(used.version, 1);
+    assert!(used.persistent);
+    assert_eq!(used.events[2], SharedObjectEvent::Change { name: "cue".to_string(), value: Value::Number(42.0) });
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
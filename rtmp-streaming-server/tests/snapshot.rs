@@ -0,0 +1,102 @@
+mod common;
+
+use common::{fake_ffmpeg, start_http, video};
+use futures::StreamExt;
+use hyper::{Body, Client, Response};
+use rtmp_streaming_server::config::Config;
+use rtmp_streaming_server::flv::FlvDemuxer;
+use rtmp_streaming_server::StreamManager;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Fake ffmpeg that saves its input, counts its runs and prints a "JPEG"
+fn snapshot_ffmpeg(name: &str) -> PathBuf {
+    let body = "dir=$(dirname \"$0\")\ncat > \"$dir/input.flv\"\necho run >> \"$dir/runs\"\nprintf '\\377\\330jpeg'";
+    fake_ffmpeg(&format!("snapshot-{}", name), body)
+}
+
+async fn start(dir: &Path, interval_ms: u64) -> (StreamManager, SocketAddr) {
+    let config = Config {
+        ffmpeg_path: Some(dir.join("ffmpeg").to_string_lossy().into_owned()),
+        snapshot_interval_ms: Some(interval_ms),
+        ..Config::default()
+    };
+    let streams = StreamManager::new().with_config(config);
+    let addr = start_http(streams.clone()).await;
+    (streams, addr)
+}
+
+async fn snapshot(addr: SocketAddr, name: &str) -> Response<Body> {
+    Client::new()
+        .get(format!("http://{}/snapshot/{}.jpg", addr, name).parse().unwrap())
+        .await
+        .unwrap()
+}
+
+async fn body(response: Response<Body>) -> Vec<u8> {
+    response
+        .into_body()
+        .map(|chunk| chunk.unwrap().to_vec())
+        .concat()
+        .await
+}
+
+fn runs(dir: &Path) -> usize {
+    std::fs::read_to_string(dir.join("runs")).map_or(0, |runs| runs.lines().count())
+}
+
+#[tokio::test]
+async fn test_snapshot_of_latest_keyframe() {
+    let dir = snapshot_ffmpeg("latest");
+    let (streams, addr) = start(&dir, 60_000).await;
+    let publisher = streams.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    publisher.publish(video(1000, 0x17, 0x01)).await.unwrap();
+    publisher.publish(video(2000, 0x17, 0x01)).await.unwrap();
+    publisher.publish(video(2040, 0x27, 0x01)).await.unwrap();
+
+    let response = snapshot(addr, "cam").await;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/jpeg");
+    assert_eq!(body(response).await, b"\xff\xd8jpeg");
+
+    // ffmpeg decodes the sequence header and the latest keyframe only
+    let mut demuxer = FlvDemuxer::new();
+    demuxer.push(&std::fs::read(dir.join("input.flv")).unwrap());
+    let mut tags = Vec::new();
+    while let Some(tag) = demuxer.next_tag().unwrap() {
+        tags.push(tag);
+    }
+    assert_eq!(tags, vec![video(0, 0x17, 0x00), video(0, 0x17, 0x01)]);
+}
+
+#[tokio::test]
+async fn test_snapshots_are_throttled() {
+    let dir = snapshot_ffmpeg("throttled");
+    let (streams, addr) = start(&dir, 60_000).await;
+    let publisher = streams.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x01)).await.unwrap();
+
+    let (first, second) = tokio::join!(snapshot(addr, "cam"), snapshot(addr, "cam"));
+    assert_eq!(first.status(), 200);
+    assert_eq!(second.status(), 200);
+    assert_eq!(snapshot(addr, "cam").await.status(), 200);
+    assert_eq!(runs(&dir), 1);
+}
+
+#[tokio::test]
+async fn test_snapshot_needs_keyframe() {
+    let dir = snapshot_ffmpeg("missing");
+    let (streams, addr) = start(&dir, 0).await;
+    assert_eq!(snapshot(addr, "cam").await.status(), 404);
+
+    let publisher = streams.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    publisher.publish(video(40, 0x27, 0x01)).await.unwrap();
+    assert_eq!(snapshot(addr, "cam").await.status(), 404);
+
+    publisher.publish(video(80, 0x17, 0x01)).await.unwrap();
+    assert_eq!(snapshot(addr, "cam").await.status(), 200);
+    assert_eq!(runs(&dir), 1);
+}
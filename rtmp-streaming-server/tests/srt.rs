@@ -0,0 +1,327 @@
+mod common;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use common::{avc_frame, avc_sequence_header, next_tag, PPS, SLICE, SPS};
+use rtmp_streaming_server::config::Config;
+use rtmp_streaming_server::{SrtServer, StreamManager};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const IDR: &[u8] = &[0x65, 0x88, 0x84, 0x21, 0x7f];
+
+const TS_PACKET_SIZE: usize = 188;
+
+const VIDEO_PID: u16 = 0x100;
+
+/// Minimal MPEG-TS muxer for one H.264 stream
+#[derive(Default)]
+struct TsMuxer {
+    out: BytesMut,
+    counters: std::collections::HashMap<u16, u8>,
+}
+
+impl TsMuxer {
+    fn tables(&mut self) {
+        // PAT: program 1 -> PMT on PID 0x1000
+        let pat = [0x00, 0xb0, 0x0d, 0x00, 0x01, 0xc1, 0x00, 0x00, 0x00, 0x01, 0xf0, 0x00, 0, 0, 0, 0];
+        self.packet(0, true, &pat);
+        // PMT: H.264 on 0x100, AAC on 0x101
+        let pmt = [
+            0x02, 0xb0, 0x17, 0x00, 0x01, 0xc1, 0x00, 0x00, 0xe1, 0x00, 0xf0, 0x00, 0x1b, 0xe1, 0x00, 0xf0,
+            0x00, 0x0f, 0xe1, 0x01, 0xf0, 0x00, 0, 0, 0, 0,
+        ];
+        self.packet(0x1000, true, &pmt);
+    }
+
+    /// One access unit of Annex B NAL units
+    fn video(&mut self, dts_ms: u64, cts_ms: u64, nals: &[&[u8]]) {
+        let mut es = vec![0, 0, 0, 1, 0x09, 0xf0];
+        for nal in nals {
+            es.extend_from_slice(&[0, 0, 0, 1]);
+            es.extend_from_slice(nal);
+        }
+        let dts = 900_000 + dts_ms * 90;
+        self.pes(VIDEO_PID, 0xe0, dts + cts_ms * 90, dts, &es);
+    }
+
+    /// Unbounded video PES with a PTS and a DTS
+    fn pes(&mut self, pid: u16, stream_id: u8, pts: u64, dts: u64, es: &[u8]) {
+        let mut pes = vec![0, 0, 1, stream_id, 0, 0, 0x80, 0xc0, 10];
+        pes.extend_from_slice(&timestamp(0x3, pts));
+        pes.extend_from_slice(&timestamp(0x1, dts));
+        pes.extend_from_slice(es);
+        for (i, chunk) in pes.chunks(184).enumerate() {
+            self.packet(pid, i == 0, chunk);
+        }
+    }
+
+    fn packet(&mut self, pid: u16, unit_start: bool, payload: &[u8]) {
+        let counter = self.counters.entry(pid).or_default();
+        let mut packet = vec![0x47, (pid >> 8) as u8 | if unit_start { 0x40 } else { 0 }, pid as u8];
+        let is_psi = pid == 0 || pid == 0x1000;
+        if payload.len() < 184 && !is_psi {
+            // Stuff with an adaptation field
+            packet.push(0x30 | *counter);
+            let stuffing = 183 - payload.len();
+            packet.push(stuffing as u8);
+            if stuffing > 0 {
+                packet.push(0x00);
+                packet.resize(packet.len() + stuffing - 1, 0xff);
+            }
+        } else {
+            packet.push(0x10 | *counter);
+        }
+        if is_psi {
+            packet.push(0); // pointer field
+        }
+        packet.extend_from_slice(payload);
+        packet.resize(TS_PACKET_SIZE, 0xff);
+        *counter = (*counter + 1) & 0x0f;
+        self.out.put_slice(&packet);
+    }
+
+    /// Muxed bytes in SRT-sized payloads of 7 TS packets, padded with
+    /// null packets
+    fn payloads(&mut self) -> Vec<Bytes> {
+        while !self.out.len().is_multiple_of(7 * TS_PACKET_SIZE) {
+            let mut null = vec![0x47, 0x1f, 0xff, 0x10];
+            null.resize(TS_PACKET_SIZE, 0xff);
+            self.out.put_slice(&null);
+        }
+        let mut out = self.out.split().freeze();
+        let mut payloads = Vec::new();
+        while !out.is_empty() {
+            payloads.push(out.split_to(7 * TS_PACKET_SIZE));
+        }
+        payloads
+    }
+}
+
+/// 33-bit PES timestamp with its 4-bit prefix
+fn timestamp(prefix: u8, ts: u64) -> [u8; 5] {
+    [
+        (prefix << 4) | ((ts >> 29) as u8 & 0x0e) | 1,
+        (ts >> 22) as u8,
+        ((ts >> 14) as u8 & 0xfe) | 1,
+        (ts >> 7) as u8,
+        ((ts << 1) as u8 & 0xfe) | 1,
+    ]
+}
+
+/// Two seconds of video: a keyframe every second, P frames in between
+fn muxed_video() -> TsMuxer {
+    let mut muxer = TsMuxer::default();
+    muxer.tables();
+    for i in 0..50 {
+        if i % 25 == 0 {
+            muxer.video(i * 40, 0, &[SPS, PPS, IDR]);
+        } else {
+            muxer.video(i * 40, 80, &[SLICE]);
+        }
+    }
+    muxer
+}
+
+/// Minimal SRT caller: HSv5 handshake, then data packets
+struct Caller {
+    socket: UdpSocket,
+    server_id: u32,
+    isn: u32,
+}
+
+const CALLER_ID: u32 = 0x0102_0304;
+
+impl Caller {
+    /// Connect with `stream_id`; the error is the rejection handshake type
+    async fn connect(addr: SocketAddr, stream_id: &str) -> Result<Self, u32> {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(addr).await.unwrap();
+        let isn = 0x7fff_fff0; // wraps around after a few packets
+        let caller = Self {
+            socket,
+            server_id: 0,
+            isn,
+        };
+
+        // Induction: version 4, UDT_DGRAM
+        caller.send_control(0, 0, &handshake(4, 2, isn, 1, 0, &[])).await;
+        let induction = caller.recv_control(0).await;
+        assert_eq!(induction.get(0..4), Some(&5u32.to_be_bytes()[..]));
+        assert_eq!(induction.get(6..8), Some(&[0x4a, 0x17][..]));
+        let cookie = (&induction[28..32]).get_u32();
+
+        // Conclusion with HSREQ (120 ms latency) and the stream ID
+        let mut hsreq = BytesMut::new();
+        hsreq.put_u32(0x0001_0500);
+        hsreq.put_u32(0x3f);
+        hsreq.put_u32(120);
+        let mut sid = stream_id.as_bytes().to_vec();
+        sid.resize(sid.len().div_ceil(4) * 4, 0);
+        let sid: Vec<u8> = sid.chunks(4).flat_map(|word| word.iter().rev().copied()).collect();
+        let conclusion = handshake(5, 0x05, isn, 0xffff_ffff, cookie, &[(1, &hsreq), (5, &sid)]);
+        caller.send_control(0, 0, &conclusion).await;
+
+        let response = caller.recv_control(0).await;
+        let kind = (&response[20..24]).get_u32();
+        if kind != 0xffff_ffff {
+            return Err(kind);
+        }
+        // HSRSP extension follows the 48-byte handshake
+        assert_eq!((&response[48..50]).get_u16(), 2);
+        let server_id = (&response[24..28]).get_u32();
+        Ok(Self { server_id, ..caller })
+    }
+
+    async fn send_data(&self, index: u32, payload: &[u8]) {
+        let mut packet = BytesMut::new();
+        packet.put_u32(self.isn.wrapping_add(index) & 0x7fff_ffff);
+        packet.put_u32(0xc000_0000 | (index + 1));
+        packet.put_u32(index * 1000);
+        packet.put_u32(self.server_id);
+        packet.put_slice(payload);
+        self.socket.send(&packet).await.unwrap();
+    }
+
+    async fn send_control(&self, kind: u16, info: u32, cif: &[u8]) {
+        let mut packet = BytesMut::new();
+        packet.put_u32(0x8000_0000 | (kind as u32) << 16);
+        packet.put_u32(info);
+        packet.put_u32(0);
+        packet.put_u32(self.server_id);
+        packet.put_slice(cif);
+        self.socket.send(&packet).await.unwrap();
+    }
+
+    /// Next control packet of `kind`, as (type-specific info, CIF)
+    async fn recv_info(&self, kind: u16) -> (u32, Bytes) {
+        let mut buf = vec![0u8; 1500];
+        loop {
+            let len = tokio::time::timeout(Duration::from_secs(5), self.socket.recv(&mut buf))
+                .await
+                .expect("timed out waiting for SRT control packet")
+                .unwrap();
+            let mut packet = Bytes::copy_from_slice(&buf[..len]);
+            let first = packet.get_u32();
+            let info = packet.get_u32();
+            packet.advance(8);
+            if first & 0x8000_0000 != 0 && ((first >> 16) & 0x7fff) as u16 == kind {
+                return (info, packet);
+            }
+        }
+    }
+
+    async fn recv_control(&self, kind: u16) -> Bytes {
+        self.recv_info(kind).await.1
+    }
+}
+
+fn handshake(version: u32, extension: u16, isn: u32, kind: u32, cookie: u32, extensions: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut cif = BytesMut::new();
+    cif.put_u32(version);
+    cif.put_u16(0);
+    cif.put_u16(extension);
+    cif.put_u32(isn);
+    cif.put_u32(1500);
+    cif.put_u32(8192);
+    cif.put_u32(kind);
+    cif.put_u32(CALLER_ID);
+    cif.put_u32(cookie);
+    cif.put_slice(&[0u8; 16]);
+    for (ext_type, content) in extensions {
+        cif.put_u16(*ext_type);
+        cif.put_u16((content.len() / 4) as u16);
+        cif.put_slice(content);
+    }
+    cif.to_vec()
+}
+
+async fn start_srt(streams: StreamManager) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    tokio::spawn(SrtServer::new(addr).with_stream_manager(streams).serve(socket));
+    addr
+}
+
+#[tokio::test]
+async fn test_srt_publish_with_loss() {
+    let streams = StreamManager::new();
+    let addr = start_srt(streams.clone()).await;
+
+    let caller = Caller::connect(addr, "#!::r=live/cam,m=publish").await.unwrap();
+    let stream = streams.get_stream("live", "cam").await.expect("stream published at handshake");
+    assert!(stream.is_published());
+    let mut subscription = stream.subscribe("viewer".to_string()).await;
+
+    let payloads = muxed_video().payloads();
+    assert!(payloads.len() > 4);
+    // Packet 1 is lost on the way; the gap is reported right away
+    caller.send_data(0, &payloads[0]).await;
+    for (i, payload) in payloads.iter().enumerate().skip(2) {
+        caller.send_data(i as u32, payload).await;
+    }
+    let nak = caller.recv_control(3).await;
+    assert_eq!(&nak[..4], &(caller.isn.wrapping_add(1) & 0x7fff_ffff).to_be_bytes());
+    caller.send_data(1, &payloads[1]).await;
+
+    // Everything is acknowledged across the sequence number wraparound
+    let end = caller.isn.wrapping_add(payloads.len() as u32) & 0x7fff_ffff;
+    loop {
+        let (ack_number, ack) = caller.recv_info(2).await;
+        caller.send_control(6, ack_number, &[]).await;
+        if (&ack[..4]).get_u32() == end {
+            break;
+        }
+    }
+
+    assert_eq!(next_tag(&mut subscription).await.data, avc_sequence_header(0).data);
+    assert_eq!(next_tag(&mut subscription).await.data, avc_frame(0, 0, true, &[IDR]).data);
+    for i in 1..25 {
+        let tag = next_tag(&mut subscription).await;
+        assert_eq!(tag.timestamp, i * 40);
+        assert_eq!(tag.data, avc_frame(0, 80, false, &[SLICE]).data);
+    }
+    assert!(stream.sequence_headers().await.iter().any(|tag| tag.data == avc_sequence_header(0).data));
+
+    // Shutdown ends the publish
+    caller.send_control(5, 0, &[]).await;
+    for _ in 0..100 {
+        if !stream.is_published() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("stream still published after shutdown");
+}
+
+#[tokio::test]
+async fn test_srt_rejections() {
+    let streams = StreamManager::new();
+    let addr = start_srt(streams.clone()).await;
+
+    // Only publishing is supported
+    let rejected = Caller::connect(addr, "#!::r=live/cam,m=request").await.err();
+    assert_eq!(rejected, Some(1000 + 1405));
+    assert_eq!(Caller::connect(addr, "#!::m=publish").await.err(), Some(1000 + 1400));
+    assert!(streams.get_stream("live", "cam").await.is_none());
+
+    // A plain resource publishes too; a second publisher conflicts
+    let _first = Caller::connect(addr, "live/cam").await.unwrap();
+    assert!(streams.get_stream("live", "cam").await.unwrap().is_published());
+    let conflict = Caller::connect(addr, "#!::r=live/cam,m=publish").await.err();
+    assert_eq!(conflict, Some(1000 + 1409));
+}
+
+#[tokio::test]
+async fn test_srt_publish_token() {
+    let mut config = Config::default();
+    config.default_app.publish_token = Some("secret".to_string());
+    let streams = StreamManager::new().with_config(config);
+    let addr = start_srt(streams.clone()).await;
+
+    assert_eq!(Caller::connect(addr, "live/cam").await.err(), Some(1000 + 1401));
+    let wrong = Caller::connect(addr, "#!::r=live/cam,token=guess").await.err();
+    assert_eq!(wrong, Some(1000 + 1401));
+    let _caller = Caller::connect(addr, "#!::r=live/cam,token=secret").await.unwrap();
+    assert!(streams.get_stream("live", "cam").await.unwrap().is_published());
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{start_http, video};
+use futures::StreamExt;
+use hyper::{Body, Client};
+use rtmp_streaming_server::config::{AppConfig, Config};
+use rtmp_streaming_server::flv::{FlvDemuxer, FlvTag};
+use rtmp_streaming_server::{Error, Stream, StreamManager, Subscription};
+use std::time::{Duration, Instant};
+
+/// Sequence header, then a keyframe every 200 ms and inter frames every
+/// 20 ms up to 980 ms
+async fn publish_second(stream: &Stream) {
+    stream.publish(video(0, 0x17, 0x00)).await.unwrap();
+    for i in 0..50 {
+        let first = if i % 10 == 0 { 0x17 } else { 0x27 };
+        stream.publish(video(i * 20, first, 0x01)).await.unwrap();
+    }
+}
+
+async fn next(subscription: &mut Subscription) -> FlvTag {
+    tokio::time::timeout(Duration::from_secs(2), subscription.recv())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_start_in_the_past() {
+    let stream = Stream::new("s".to_string()).with_timeshift(Duration::from_secs(60), 1 << 20);
+    publish_second(&stream).await;
+
+    // 300 ms back is closest to the keyframe at 600 ms
+    let mut subscription = stream.subscribe_at("viewer".to_string(), Duration::from_millis(300)).await.unwrap();
+    assert_eq!(next(&mut subscription).await, video(600, 0x17, 0x00));
+    assert_eq!(next(&mut subscription).await, video(600, 0x17, 0x01));
+
+    // The rest of the buffer plays at the speed it was published
+    let started = Instant::now();
+    for timestamp in (620..1000).step_by(20) {
+        assert_eq!(next(&mut subscription).await.timestamp, timestamp);
+    }
+    assert!(started.elapsed() >= Duration::from_millis(300));
+
+    // Then new tags as they arrive
+    stream.publish(video(1000, 0x27, 0x01)).await.unwrap();
+    assert_eq!(next(&mut subscription).await, video(1000, 0x27, 0x01));
+}
+
+#[tokio::test]
+async fn test_buffer_is_bounded() {
+    // Room for about a dozen tags
+    let stream = Stream::new("s".to_string()).with_timeshift(Duration::from_secs(60), 250);
+    publish_second(&stream).await;
+
+    // The sequence header left the buffer but still comes first
+    let mut subscription = stream.subscribe_at("viewer".to_string(), Duration::from_secs(60)).await.unwrap();
+    assert_eq!(next(&mut subscription).await, video(800, 0x17, 0x00));
+    assert_eq!(next(&mut subscription).await, video(800, 0x17, 0x01));
+
+    // The age limit applies as well
+    let stream = Stream::new("s".to_string()).with_timeshift(Duration::from_millis(500), 1 << 20);
+    publish_second(&stream).await;
+    let mut subscription = stream.subscribe_at("viewer".to_string(), Duration::from_secs(60)).await.unwrap();
+    assert_eq!(next(&mut subscription).await, video(600, 0x17, 0x00));
+    assert_eq!(next(&mut subscription).await, video(600, 0x17, 0x01));
+}
+
+#[tokio::test]
+async fn test_timeshift_disabled() {
+    let stream = Stream::new("s".to_string());
+    let result = stream.subscribe_at("viewer".to_string(), Duration::from_secs(1)).await;
+    assert!(matches!(result, Err(Error::InvalidInput(_))));
+    assert_eq!(stream.subscriber_count(), 0);
+}
+
+#[tokio::test]
+async fn test_http_offset() {
+    let mut config = Config::default();
+    config.apps.insert(
+        "live".to_string(),
+        AppConfig {
+            timeshift_secs: 60,
+            ..AppConfig::default()
+        },
+    );
+    let streams = StreamManager::new().with_config(config);
+    let addr = start_http(streams.clone()).await;
+
+    let publisher = streams.start_publish("live", "s", "encoder".to_string()).await.unwrap();
+    publish_second(publisher.stream()).await;
+
+    let client = Client::new();
+    let bad = client
+        .get(format!("http://{}/live/s?offset=5", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(bad.status(), 400);
+    let disabled = client
+        .get(format!("http://{}/other/s?offset=-1", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(disabled.status(), 400);
+    // Further back than any buffer could reach
+    for offset in ["-1e20", "-inf", "NaN", "-61"] {
+        let far = client
+            .get(format!("http://{}/live/s?offset={}", addr, offset).parse().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(far.status(), 400, "offset {}", offset);
+    }
+
+    let response = client
+        .get(format!("http://{}/live/s?offset=-0.3", addr).parse().unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), 200);
+    let mut body: Body = response.into_body();
+    let mut demuxer = FlvDemuxer::new();
+    let mut tags = Vec::new();
+    while tags.len() < 3 {
+        let chunk = tokio::time::timeout(Duration::from_secs(2), body.next()).await.unwrap().unwrap().unwrap();
+        demuxer.push(&chunk);
+        while let Some(tag) = demuxer.next_tag().unwrap() {
+            tags.push(tag);
+        }
+    }
+    // Rebased to start at 0 from the keyframe at 600 ms
+    assert_eq!(tags[..3], [video(0, 0x17, 0x00), video(0, 0x17, 0x01), video(20, 0x27, 0x01)]);
+}
@@ -0,0 +1,61 @@
+use rtmp_streaming_server::timestamp::TimestampRebaser;
+
+fn rebase_all(rebaser: &mut TimestampRebaser, input: &[u32]) -> Vec<u32> {
+    input.iter().map(|&ts| rebaser.rebase(ts)).collect()
+}
+
+#[test]
+fn test_starts_at_zero() {
+    let mut rebaser = TimestampRebaser::new("test");
+    assert_eq!(rebase_all(&mut rebaser, &[600_000, 600_040, 600_080]), vec![0, 40, 80]);
+    assert_eq!(rebaser.discontinuities(), 0);
+}
+
+#[test]
+fn test_interleaving_jitter_preserved() {
+    let mut rebaser = TimestampRebaser::new("test");
+    // Video and audio interleaved with small backward steps
+    let out = rebase_all(&mut rebaser, &[1_000, 1_040, 1_023, 1_080, 1_046]);
+    assert_eq!(out, vec![0, 40, 23, 80, 46]);
+    assert_eq!(rebaser.discontinuities(), 0);
+}
+
+#[test]
+fn test_publisher_restart_stays_monotonic() {
+    let mut rebaser = TimestampRebaser::new("test");
+    let out = rebase_all(&mut rebaser, &[50_000, 50_040, 50_080, 0, 40, 80]);
+    assert_eq!(out, vec![0, 40, 80, 120, 160, 200]);
+    assert_eq!(rebaser.discontinuities(), 1);
+}
+
+#[test]
+fn test_large_gap_is_bridged() {
+    let mut rebaser = TimestampRebaser::new("test").with_limits(1_000, 5_000);
+    let out = rebase_all(&mut rebaser, &[0, 33, 66, 3_600_066, 3_600_099]);
+    assert_eq!(out, vec![0, 33, 66, 99, 132]);
+    assert_eq!(rebaser.discontinuities(), 1);
+}
+
+#[test]
+fn test_24bit_wraparound() {
+    let mut rebaser = TimestampRebaser::new("test");
+    let out = rebase_all(&mut rebaser, &[0xFF_FFB0, 0xFF_FFD8, 0x00_0010, 0x00_0038]);
+    assert_eq!(out, vec![0, 40, 96, 136]);
+    assert_eq!(rebaser.discontinuities(), 0);
+}
+
+#[test]
+fn test_32bit_wraparound() {
+    let mut rebaser = TimestampRebaser::new("test");
+    let out = rebase_all(&mut rebaser, &[0xFFFF_FFB0, 0xFFFF_FFD8, 0x0000_0010]);
+    assert_eq!(out, vec![0, 40, 96]);
+    assert_eq!(rebaser.discontinuities(), 0);
+}
+
+#[test]
+fn test_position_for_cached_headers() {
+    let mut rebaser = TimestampRebaser::new("test");
+    assert_eq!(rebaser.position(), 0);
+    rebase_all(&mut rebaser, &[10_000, 10_040, 10_020]);
+    assert_eq!(rebaser.position(), 40);
+}
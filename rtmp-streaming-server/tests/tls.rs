@@ -0,0 +1,193 @@
+mod common;
+
+use assert_matches::assert_matches;
+use common::{start_rtmp_server, video};
+use futures::StreamExt;
+use hyper::{Body, Request};
+use rtmp_streaming_server::config::TlsConfig;
+use rtmp_streaming_server::flv::FlvDemuxer;
+use rtmp_streaming_server::{Error, HttpFlvServer, RtmpClient, RtmpServer, RtmpUrl, StreamManager, TlsAcceptor};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use tokio_rustls::TlsConnector;
+
+/// Write a fresh self-signed certificate for `localhost` into `dir`
+fn self_signed(dir: &std::path::Path) -> (TlsConfig, CertificateDer<'static>) {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+    let config = TlsConfig {
+        cert_path: dir.join("cert.pem").to_string_lossy().into_owned(),
+        key_path: dir.join("key.pem").to_string_lossy().into_owned(),
+    };
+    std::fs::write(&config.cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&config.key_path, certified.key_pair.serialize_pem()).unwrap();
+    (config, certified.cert.der().clone())
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rtmp-tls-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// TLS connection trusting only `roots`
+async fn connect(addr: SocketAddr, roots: &[CertificateDer<'static>]) -> TlsStream<TcpStream> {
+    let mut store = RootCertStore::empty();
+    for root in roots {
+        store.add(root.clone()).unwrap();
+    }
+    let config = ClientConfig::builder().with_root_certificates(store).with_no_client_auth();
+    let socket = TcpStream::connect(addr).await.unwrap();
+    TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from("localhost").unwrap(), socket)
+        .await
+        .unwrap()
+}
+
+/// Whether the server closes `stream` within two seconds
+async fn closed(stream: &mut (impl AsyncRead + Unpin)) -> bool {
+    let mut buf = [0u8; 64];
+    let read = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut buf)).await;
+    matches!(read, Ok(Ok(0) | Err(_)))
+}
+
+#[tokio::test]
+async fn test_silent_clients_dropped() {
+    let (config, cert) = self_signed(&temp_dir("silent"));
+    let deadline = Duration::from_millis(200);
+
+    let server = RtmpServer::new("127.0.0.1:0".parse().unwrap())
+        .with_tls(TlsAcceptor::load(&config).unwrap())
+        .with_handshake_timeout(deadline);
+    let rtmps = start_rtmp_server(server).await;
+    // Nothing sent: the TLS handshake times out
+    assert!(closed(&mut TcpStream::connect(rtmps).await.unwrap()).await);
+    // TLS done, but no RTMP handshake follows
+    assert!(closed(&mut connect(rtmps, std::slice::from_ref(&cert)).await).await);
+
+    let server = RtmpServer::new("127.0.0.1:0".parse().unwrap()).with_handshake_timeout(deadline);
+    let rtmp = start_rtmp_server(server).await;
+    assert!(closed(&mut TcpStream::connect(rtmp).await.unwrap()).await);
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let https = listener.local_addr().unwrap();
+    let server = HttpFlvServer::new(https)
+        .with_tls(TlsAcceptor::load(&config).unwrap())
+        .with_handshake_timeout(deadline);
+    tokio::spawn(server.serve(listener));
+    assert!(closed(&mut TcpStream::connect(https).await.unwrap()).await);
+}
+
+#[tokio::test]
+async fn test_https_play() {
+    let (config, cert) = self_signed(&temp_dir("https"));
+    let streams = StreamManager::new();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = HttpFlvServer::new(addr)
+        .with_stream_manager(streams.clone())
+        .with_tls(TlsAcceptor::load(&config).unwrap());
+    tokio::spawn(server.serve(listener));
+
+    let publisher = streams.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+
+    let (mut sender, connection) = hyper::client::conn::handshake(connect(addr, &[cert]).await).await.unwrap();
+    tokio::spawn(connection);
+    let request = Request::get("/live/cam").header("Host", "localhost").body(Body::empty()).unwrap();
+    let response = sender.send_request(request).await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "video/x-flv");
+
+    let mut body = response.into_body();
+    let mut demuxer = FlvDemuxer::new();
+    let tag = loop {
+        if let Some(tag) = demuxer.next_tag().unwrap() {
+            break tag;
+        }
+        let chunk = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap().unwrap().unwrap();
+        demuxer.push(&chunk);
+    };
+    assert_eq!(tag.data, video(0, 0x17, 0x00).data);
+}
+
+#[tokio::test]
+async fn test_rtmps_handshake() {
+    let (config, cert) = self_signed(&temp_dir("rtmps"));
+    let server = RtmpServer::new("127.0.0.1:0".parse().unwrap()).with_tls(TlsAcceptor::load(&config).unwrap());
+    let addr = start_rtmp_server(server).await;
+
+    // C0 + C1, then S0 + S1 + S2 come back through the TLS tunnel
+    let mut stream = connect(addr, &[cert]).await;
+    let mut c0c1 = vec![3u8];
+    c0c1.extend_from_slice(&[0u8; 1536]);
+    stream.write_all(&c0c1).await.unwrap();
+    let mut response = vec![0u8; 1 + 2 * 1536];
+    tokio::time::timeout(Duration::from_secs(5), stream.read_exact(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(response[0], 3);
+    stream.write_all(&response[1..1537]).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_rtmps_client() {
+    let (config, cert) = self_signed(&temp_dir("rtmps-client"));
+    let streams = StreamManager::new();
+    let server = RtmpServer::new("127.0.0.1:0".parse().unwrap())
+        .with_stream_manager(streams.clone())
+        .with_tls(TlsAcceptor::load(&config).unwrap());
+    let addr = start_rtmp_server(server).await;
+
+    let target = RtmpUrl::parse(&format!("rtmps://localhost:{}/live/s", addr.port())).unwrap();
+    assert!(target.tls);
+    // The crate brings no trust roots: a plain connect is refused
+    assert!(matches!(RtmpClient::connect(&target).await, Err(Error::InvalidInput(_))));
+    let mut client = RtmpClient::connect_with(connect(addr, &[cert]).await, &target).await.unwrap();
+    client.publish(&target.stream).await.unwrap();
+    client.send_tag(&video(0, 0x17, 0x00)).await.unwrap();
+    assert!(streams.get_stream("live", "s").await.unwrap().is_published());
+}
+
+#[tokio::test]
+async fn test_certificate_reload() {
+    let dir = temp_dir("reload");
+    let (config, first) = self_signed(&dir);
+    let acceptor = TlsAcceptor::load(&config).unwrap();
+    acceptor.watch();
+    let addr = start_rtmp_server(RtmpServer::new("127.0.0.1:0".parse().unwrap()).with_tls(acceptor.clone())).await;
+
+    let peer = |stream: &TlsStream<TcpStream>| stream.get_ref().1.peer_certificates().unwrap()[0].clone();
+    let old_connection = connect(addr, std::slice::from_ref(&first)).await;
+    assert_eq!(peer(&old_connection), first);
+
+    // Replacing the files is picked up by the watcher
+    let (_, second) = self_signed(&dir);
+    let roots = [first.clone(), second.clone()];
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if peer(&connect(addr, &roots).await) == second {
+            reloaded = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "certificate was not reloaded");
+
+    // A broken key is rejected and the last good certificate stays in use
+    std::fs::write(&config.key_path, "not a key").unwrap();
+    assert_matches!(acceptor.reload(), Err(Error::Config(_)));
+    assert_eq!(peer(&connect(addr, &roots).await), second);
+    let missing = TlsConfig {
+        cert_path: dir.join("missing.pem").to_string_lossy().into_owned(),
+        key_path: config.key_path.clone(),
+    };
+    assert_matches!(TlsAcceptor::load(&missing).err(), Some(Error::Config(_)));
+}
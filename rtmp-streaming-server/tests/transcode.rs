@@ -0,0 +1,120 @@
+mod common;
+
+use common::{fake_ffmpeg, video};
+use rtmp_streaming_server::config::{AppConfig, Config, TranscodeProfile};
+use rtmp_streaming_server::{Publisher, StreamManager, WorkerState};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Manager with a 720p rendition for `live`, run by the fake ffmpeg in `dir`
+fn manager(dir: PathBuf) -> StreamManager {
+    let mut config = Config {
+        ffmpeg_path: Some(dir.join("ffmpeg").to_string_lossy().into_owned()),
+        ..Config::default()
+    };
+    config.apps.insert(
+        "live".to_string(),
+        AppConfig {
+            transcode: vec![TranscodeProfile {
+                name: "720p".to_string(),
+                args: vec!["-c:v".to_string(), "copy".to_string()],
+            }],
+            ..AppConfig::default()
+        },
+    );
+    StreamManager::new().with_config(config)
+}
+
+/// Feed keyframes into the source until the rendition delivers one
+async fn wait_for_rendition(streams: &StreamManager, publisher: &Publisher) {
+    let rendition = loop {
+        if let Some(stream) = streams.get_stream("live", "cam_720p").await {
+            break stream;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    };
+    let mut subscription = rendition.subscribe("viewer".to_string()).await;
+    drop(rendition);
+
+    for i in 0..250 {
+        publisher.publish(video(40 * i, 0x17, 0x01)).await.unwrap();
+        if let Ok(tag) = tokio::time::timeout(Duration::from_millis(20), subscription.recv()).await {
+            let tag = tag.unwrap().unwrap();
+            assert!(tag.is_video());
+            return;
+        }
+    }
+    panic!("rendition never produced a tag");
+}
+
+/// Poll until `check` holds for the worker status, up to five seconds
+async fn wait_for_status(streams: &StreamManager, check: impl Fn(&rtmp_streaming_server::TranscodeStatus) -> bool) {
+    for _ in 0..250 {
+        if streams.transcodes().statuses().first().is_some_and(&check) {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("worker status not reached: {:?}", streams.transcodes().statuses());
+}
+
+#[tokio::test]
+async fn test_rendition_published() {
+    let streams = manager(fake_ffmpeg("transcode-copy", "exec cat"));
+    let publisher = streams.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    wait_for_rendition(&streams, &publisher).await;
+
+    let statuses = streams.transcodes().statuses();
+    assert_eq!(statuses.len(), 1);
+    assert_eq!(statuses[0].output, "cam_720p");
+    assert_eq!(statuses[0].state, WorkerState::Running);
+    assert!(statuses[0].pid.is_some());
+    assert_eq!(statuses[0].restarts, 0);
+
+    // The worker is not one of the source's viewers
+    assert_eq!(publisher.stream().subscriber_count(), 0);
+
+    // Unpublishing stops the worker and its rendition
+    drop(publisher);
+    for _ in 0..250 {
+        if streams.transcodes().statuses().is_empty() && streams.list_streams().await.is_empty() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("worker did not stop");
+}
+
+#[tokio::test]
+async fn test_restart_after_crash() {
+    let marker = std::env::temp_dir().join(format!("rtmp-transcode-crashed-{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let script = format!(
+        "if [ ! -f {0} ]; then touch {0}; echo crashing >&2; exit 3; fi\nexec cat",
+        marker.display()
+    );
+    let streams = manager(fake_ffmpeg("transcode-crash", &script));
+    let publisher = streams.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+
+    wait_for_status(&streams, |status| status.restarts == 1).await;
+    let status = &streams.transcodes().statuses()[0];
+    assert!(status.last_exit.as_deref().unwrap().contains('3'));
+
+    // The rendition stays published across the restart
+    assert!(streams.get_stream("live", "cam_720p").await.unwrap().is_published());
+    wait_for_rendition(&streams, &publisher).await;
+    wait_for_status(&streams, |status| status.state == WorkerState::Running).await;
+    std::fs::remove_file(&marker).unwrap();
+}
+
+#[tokio::test]
+async fn test_renditions_are_not_transcoded_again() {
+    let streams = manager(fake_ffmpeg("transcode-nested", "exec cat"));
+    let publisher = streams.start_publish("live", "cam", "camera".to_string()).await.unwrap();
+    publisher.publish(video(0, 0x17, 0x00)).await.unwrap();
+    wait_for_rendition(&streams, &publisher).await;
+    assert_eq!(streams.transcodes().statuses().len(), 1);
+    assert!(streams.get_stream("live", "cam_720p_720p").await.is_none());
+}
@@ -0,0 +1,117 @@
+mod common;
+
+use common::{start_http, start_rtmp, video, with_tenant, TENANT};
+use hyper::{Body, Client, Request};
+use rtmp_streaming_server::config::{AppConfig, Config, PublisherPolicy};
+use rtmp_streaming_server::{Error, RtmpClient, RtmpUrl, StreamManager};
+use std::time::Duration;
+
+fn config() -> Config {
+    let mut config = Config::default();
+    config.apps.insert(
+        "event".to_string(),
+        AppConfig {
+            audio: Some(true),
+            ..AppConfig::default()
+        },
+    );
+    with_tenant(
+        config,
+        AppConfig {
+            publisher_policy: PublisherPolicy::Replace,
+            ..AppConfig::default()
+        },
+    )
+}
+
+#[test]
+fn test_app_ids() {
+    let config = config();
+    let app_id = |host, app| config.app_id(host, app).unwrap();
+    assert_eq!(app_id(Some("Tenant.Example.com:8080"), "live"), "tenant.example.com/live");
+    assert_eq!(app_id(Some("other.example.com"), "live"), "live");
+    assert_eq!(app_id(None, "live"), "live");
+    // A client can't name another host's app itself
+    assert!(matches!(config.app_id(None, "tenant.example.com/live"), Err(Error::InvalidInput(_))));
+    assert!(config.app_id(Some(TENANT), "a/b").is_err());
+
+    let tenant_live = app_id(Some(TENANT), "live");
+    assert_eq!(config.app(&tenant_live).publisher_policy, PublisherPolicy::Replace);
+    assert_eq!(config.app_name(&tenant_live), "live");
+    assert_eq!(config.app("live").publisher_policy, PublisherPolicy::Reject);
+    // Apps missing in the vhost use the server's default app
+    assert_eq!(config.app(&app_id(Some(TENANT), "event")).audio, None);
+}
+
+#[tokio::test]
+async fn test_apps_are_isolated() {
+    let streams = StreamManager::new().with_config(config());
+    let live = streams.start_publish("live", "s", "a".to_string()).await.unwrap();
+    let event = streams.start_publish("event", "s", "b".to_string()).await.unwrap();
+    assert!(!live.stream().has_audio());
+    assert!(event.stream().has_audio());
+
+    let mut subscription = streams.subscribe("event", "s", "viewer".to_string()).await.unwrap();
+    live.publish(video(0, 0x17, 0x01)).await.unwrap();
+    event.publish(video(40, 0x17, 0x01)).await.unwrap();
+    let tag = tokio::time::timeout(Duration::from_secs(1), subscription.recv()).await.unwrap().unwrap();
+    assert_eq!(tag.map(|tag| tag.timestamp), Some(40));
+
+    let mut names = streams.list_streams().await;
+    names.sort();
+    assert_eq!(names, vec!["event/s".to_string(), "live/s".to_string()]);
+}
+
+#[tokio::test]
+async fn test_http_host_picks_vhost() {
+    let streams = StreamManager::new().with_config(config());
+    let addr = start_http(streams.clone()).await;
+
+    let client = Client::new();
+    let play = |host: &'static str, path: &'static str| {
+        Request::get(format!("http://{}{}", addr, path))
+            .header("Host", host)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let tenant = client.request(play(TENANT, "/live/s")).await.unwrap();
+    assert_eq!(tenant.status(), 200);
+    let default = client.request(play("localhost", "/internal/s")).await.unwrap();
+    assert_eq!(default.status(), 200);
+
+    let mut names = streams.list_streams().await;
+    names.sort();
+    assert_eq!(names, vec!["internal/s".to_string(), "tenant.example.com/live/s".to_string()]);
+    assert!(streams.get_stream("live", "s").await.is_none());
+}
+
+#[tokio::test]
+async fn test_rtmp_app_cannot_name_vhost() {
+    let streams = StreamManager::new().with_config(config());
+    let addr = start_rtmp(streams.clone()).await;
+
+    let mut target = RtmpUrl::parse(&format!("rtmp://{}/live/s", addr)).unwrap();
+    target.app = format!("{}/live", TENANT);
+    match RtmpClient::connect(&target).await {
+        Err(Error::Protocol(message)) => assert!(message.contains("NetConnection.Connect.InvalidApp"), "{}", message),
+        other => panic!("connect should fail, got {:?}", other.map(|_| ())),
+    }
+    assert!(streams.list_streams().await.is_empty());
+}
+
+#[tokio::test]
+async fn test_http_app_cannot_name_vhost() {
+    let streams = StreamManager::new().with_config(config());
+    let _publisher = streams.start_publish(&format!("{}/live", TENANT), "s", "a".to_string()).await.unwrap();
+    let addr = start_http(streams.clone()).await;
+
+    let client = Client::new();
+    for path in ["/snapshot/tenant.example.com/live/s.jpg", "/api/events?app=tenant.example.com/live"] {
+        let request = Request::get(format!("http://{}{}", addr, path))
+            .header("Host", "localhost")
+            .body(Body::empty())
+            .unwrap();
+        let response = client.request(request).await.unwrap();
+        assert_eq!(response.status(), 400, "{}", path);
+    }
+}
@@ -0,0 +1,601 @@
+mod common;
+
+use bytes::Bytes;
+use common::{avc_sequence_header, idr, next_tag, start_http, with_tenant, PPS, SLICE, SPS, TENANT};
+use hyper::{Body, Client, Method, Request, StatusCode};
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use openssl::ssl::{ErrorCode, Ssl, SslContext, SslMethod, SslOptions, SslStream, SslVerifyMode};
+use openssl::symm::{encrypt, Cipher};
+use openssl::x509::{X509NameBuilder, X509Ref, X509};
+use rtmp_streaming_server::config::{AppConfig, Config};
+use rtmp_streaming_server::flv::{FlvTag, TagType};
+use rtmp_streaming_server::{HttpFlvServer, StreamManager, WebRtcServer};
+use std::collections::{HashMap, VecDeque};
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+
+const OPUS_FRAME: &[u8] = &[0xfc, 0x01, 0x02, 0x03];
+
+const PEER_UFRAG: &str = "peer";
+const PEER_PWD: &str = "peerpasswordpeerpassword";
+
+async fn start_servers(streams: StreamManager) -> SocketAddr {
+    let webrtc = WebRtcServer::new("127.0.0.1:0".parse().unwrap()).unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let http = HttpFlvServer::new(addr)
+        .with_stream_manager(streams)
+        .with_webrtc(webrtc.sessions());
+    tokio::spawn(http.serve(listener));
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    tokio::spawn(webrtc.serve(socket));
+    // Let the listener register its socket
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    addr
+}
+
+fn certificate() -> (PKey<Private>, X509) {
+    let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap())
+        .unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, "peer").unwrap();
+    let name = name.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    (key, cert.build())
+}
+
+fn fingerprint(cert: &X509Ref) -> String {
+    let digest = cert.digest(MessageDigest::sha256()).unwrap();
+    digest.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+/// Browser-like offer: VP8 and H.264 video, Opus audio and a data channel
+fn offer(fingerprint: &str, direction: &str) -> String {
+    let credentials = format!(
+        "a=ice-ufrag:{}\r\na=ice-pwd:{}\r\na=fingerprint:sha-256 {}\r\na=setup:actpass\r\na=rtcp-mux\r\na={}\r\n",
+        PEER_UFRAG, PEER_PWD, fingerprint, direction
+    );
+    format!(
+        "v=0\r\no=- 1 2 IN IP4 127.0.0.1\r\ns=-\r\nt=0 0\r\na=group:BUNDLE 0 1 2\r\n\
+         m=video 9 UDP/TLS/RTP/SAVPF 96 102\r\nc=IN IP4 0.0.0.0\r\na=mid:0\r\n{}\
+         a=rtpmap:96 VP8/90000\r\na=rtpmap:102 H264/90000\r\n\
+         a=fmtp:102 level-asymmetry-allowed=1;packetization-mode=1;profile-level-id=42e01f\r\n\
+         m=audio 9 UDP/TLS/RTP/SAVPF 111\r\nc=IN IP4 0.0.0.0\r\na=mid:1\r\n{}\
+         a=rtpmap:111 opus/48000/2\r\na=fmtp:111 minptime=10;useinbandfec=1\r\n\
+         m=application 9 UDP/DTLS/SCTP webrtc-datachannel\r\nc=IN IP4 0.0.0.0\r\na=mid:2\r\n{}",
+        credentials, credentials, credentials
+    )
+}
+
+async fn post(addr: SocketAddr, path: &str, sdp: &str) -> (StatusCode, String, String) {
+    post_to(addr, &addr.to_string(), path, sdp).await
+}
+
+/// POST an offer addressed to `host`
+async fn post_to(addr: SocketAddr, host: &str, path: &str, sdp: &str) -> (StatusCode, String, String) {
+    let response = Client::new()
+        .request(
+            Request::builder()
+                .method(Method::POST)
+                .uri(format!("http://{}{}", addr, path))
+                .header("Host", host)
+                .header("Content-Type", "application/sdp")
+                .body(Body::from(sdp.to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let status = response.status();
+    let location = response
+        .headers()
+        .get("location")
+        .map(|value| value.to_str().unwrap().to_string())
+        .unwrap_or_default();
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    (status, location, String::from_utf8_lossy(&body).to_string())
+}
+
+async fn delete(addr: SocketAddr, path: &str) -> StatusCode {
+    let request = Request::builder()
+        .method(Method::DELETE)
+        .uri(format!("http://{}{}", addr, path))
+        .body(Body::empty())
+        .unwrap();
+    Client::new().request(request).await.unwrap().status()
+}
+
+/// First value of an SDP attribute
+fn attribute<'a>(sdp: &'a str, name: &str) -> &'a str {
+    sdp.lines()
+        .find_map(|line| line.strip_prefix(&format!("a={}:", name)))
+        .unwrap_or_else(|| panic!("answer without a={}", name))
+        .trim()
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { crc >> 1 ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn hmac_sha1(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let key = PKey::hmac(key).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha1(), &key).unwrap();
+    signer.update(data).unwrap();
+    signer.sign_to_vec().unwrap()
+}
+
+fn put_attribute(message: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    message.extend_from_slice(&kind.to_be_bytes());
+    message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    message.extend_from_slice(value);
+    message.resize(message.len() + (4 - value.len() % 4) % 4, 0);
+}
+
+/// Binding request with USE-CANDIDATE, MESSAGE-INTEGRITY and FINGERPRINT
+fn binding_request(username: &str, key: &[u8], transaction: &[u8; 12]) -> Vec<u8> {
+    let mut message = vec![0x00, 0x01, 0, 0, 0x21, 0x12, 0xa4, 0x42];
+    message.extend_from_slice(transaction);
+    put_attribute(&mut message, 0x0006, username.as_bytes());
+    put_attribute(&mut message, 0x0025, &[]);
+    let length = (message.len() - 20 + 24) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+    let integrity = hmac_sha1(key, &message);
+    put_attribute(&mut message, 0x0008, &integrity);
+    let length = (message.len() - 20 + 8) as u16;
+    message[2..4].copy_from_slice(&length.to_be_bytes());
+    let fingerprint = crc32(&message) ^ 0x5354_554e;
+    put_attribute(&mut message, 0x8028, &fingerprint.to_be_bytes());
+    message
+}
+
+fn rtp(payload_type: u8, seq: u16, timestamp: u32, ssrc: u32, marker: bool, payload: &[u8]) -> Vec<u8> {
+    let mut packet = vec![0x80, payload_type | if marker { 0x80 } else { 0 }];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+/// One direction of the peer's SRTP session (RFC 3711)
+struct Srtp {
+    rtp: [Vec<u8>; 3],
+    rtcp: [Vec<u8>; 3],
+    /// Rollover counter and highest sequence number per SSRC
+    rollover: HashMap<u32, (u32, u16)>,
+}
+
+impl Srtp {
+    fn new(master_key: &[u8], master_salt: &[u8]) -> Self {
+        // Cipher key, auth key and salt, by their key derivation labels
+        let derive = |label: u8, len: usize| {
+            let mut iv = [0u8; 16];
+            iv[..14].copy_from_slice(master_salt);
+            iv[7] ^= label;
+            encrypt(Cipher::aes_128_ctr(), master_key, Some(&iv), &vec![0; len]).unwrap()
+        };
+        Self {
+            rtp: [derive(0, 16), derive(1, 20), derive(2, 14)],
+            rtcp: [derive(3, 16), derive(4, 20), derive(5, 14)],
+            rollover: HashMap::new(),
+        }
+    }
+
+    /// Encrypt a packet of the tests, whose sequence numbers never wrap
+    fn protect_rtp(&self, packet: &[u8]) -> Vec<u8> {
+        let ssrc = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+        let seq = u16::from_be_bytes([packet[2], packet[3]]);
+        let mut out = packet[..12].to_vec();
+        out.extend(keystream(&self.rtp, ssrc, seq as u64, &packet[12..]));
+        let mut authenticated = out.clone();
+        authenticated.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&hmac_sha1(&self.rtp[1], &authenticated)[..10]);
+        out
+    }
+
+    /// Decrypt a packet of the server, which starts at a random sequence
+    /// number and sends in order
+    fn unprotect_rtp(&mut self, packet: &[u8]) -> Vec<u8> {
+        let (body, tag) = packet.split_at(packet.len() - 10);
+        let ssrc = u32::from_be_bytes(body[8..12].try_into().unwrap());
+        let seq = u16::from_be_bytes([body[2], body[3]]);
+        let (roc, highest) = self.rollover.entry(ssrc).or_insert((0, seq));
+        if seq < *highest && *highest - seq > 0x8000 {
+            *roc += 1;
+        }
+        *highest = seq;
+        let index = (*roc as u64) << 16 | seq as u64;
+
+        let mut authenticated = body.to_vec();
+        authenticated.extend_from_slice(&roc.to_be_bytes());
+        assert_eq!(&hmac_sha1(&self.rtp[1], &authenticated)[..10], tag, "SRTP tag");
+        let header_len = 12 + 4 * (body[0] & 0x0f) as usize;
+        let mut out = body[..header_len].to_vec();
+        out.extend(keystream(&self.rtp, ssrc, index, &body[header_len..]));
+        out
+    }
+
+    fn unprotect_rtcp(&self, packet: &[u8]) -> Vec<u8> {
+        let (authenticated, tag) = packet.split_at(packet.len() - 10);
+        assert_eq!(&hmac_sha1(&self.rtcp[1], authenticated)[..10], tag, "SRTCP tag");
+        let (body, trailer) = authenticated.split_at(authenticated.len() - 4);
+        let index = u32::from_be_bytes(trailer.try_into().unwrap());
+        assert!(index & 0x8000_0000 != 0, "SRTCP packet not encrypted");
+        let ssrc = u32::from_be_bytes(body[4..8].try_into().unwrap());
+        let mut out = body[..8].to_vec();
+        out.extend(keystream(&self.rtcp, ssrc, (index & 0x7fff_ffff) as u64, &body[8..]));
+        out
+    }
+}
+
+/// AES-CM with the packet IV of `ssrc` and `index`
+fn keystream(keys: &[Vec<u8>; 3], ssrc: u32, index: u64, data: &[u8]) -> Vec<u8> {
+    let mut iv = [0u8; 16];
+    iv[..14].copy_from_slice(&keys[2]);
+    for (byte, value) in iv[4..8].iter_mut().zip(ssrc.to_be_bytes()) {
+        *byte ^= value;
+    }
+    for (byte, value) in iv[8..14].iter_mut().zip(&index.to_be_bytes()[2..]) {
+        *byte ^= value;
+    }
+    encrypt(Cipher::aes_128_ctr(), &keys[0], Some(&iv), data).unwrap()
+}
+
+#[derive(Default)]
+struct Datagrams {
+    incoming: VecDeque<Vec<u8>>,
+    outgoing: Vec<Vec<u8>>,
+}
+
+impl Read for Datagrams {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let datagram = self.incoming.pop_front().ok_or(std::io::ErrorKind::WouldBlock)?;
+        buf[..datagram.len()].copy_from_slice(&datagram);
+        Ok(datagram.len())
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.outgoing.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Loopback WebRTC peer: ICE, DTLS client and SRTP
+struct Peer {
+    socket: UdpSocket,
+    inbound: Srtp,
+    outbound: Srtp,
+}
+
+impl Peer {
+    /// Run ICE and DTLS against the answer of an offer made with `key`/`cert`
+    async fn connect(answer: &str, key: &PKey<Private>, cert: &X509) -> Self {
+        let candidate: Vec<&str> = attribute(answer, "candidate").split(' ').collect();
+        let server: SocketAddr = format!("{}:{}", candidate[4], candidate[5]).parse().unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        socket.connect(server).await.unwrap();
+
+        // ICE: the server answers with our address, signed with its password
+        let username = format!("{}:{}", attribute(answer, "ice-ufrag"), PEER_UFRAG);
+        let server_pwd = attribute(answer, "ice-pwd");
+        let transaction = [7u8; 12];
+        socket.send(&binding_request(&username, b"wrong", &transaction)).await.unwrap();
+        socket
+            .send(&binding_request(&username, server_pwd.as_bytes(), &transaction))
+            .await
+            .unwrap();
+        let response = recv(&socket).await;
+        assert_eq!(&response[0..2], &[0x01, 0x01], "binding success");
+        assert_eq!(&response[8..20], &transaction);
+        let port = socket.local_addr().unwrap().port() ^ 0x2112;
+        let mapped = [0x00, 0x20, 0x00, 0x08, 0x00, 0x01];
+        let at = response.windows(6).position(|window| window == mapped).expect("XOR-MAPPED-ADDRESS");
+        assert_eq!(&response[at + 6..at + 8], &port.to_be_bytes());
+        assert_eq!(&response[at + 8..at + 12], &[127 ^ 0x21, 0x12, 0xa4, 1 ^ 0x42]);
+        let integrity = response.len() - 8 - 24;
+        let mut signed = response[..integrity].to_vec();
+        signed[2..4].copy_from_slice(&((integrity + 24 - 20) as u16).to_be_bytes());
+        assert_eq!(&response[integrity + 4..integrity + 24], &hmac_sha1(server_pwd.as_bytes(), &signed)[..]);
+
+        // DTLS as the active side
+        let mut context = SslContext::builder(SslMethod::dtls()).unwrap();
+        context.set_certificate(cert).unwrap();
+        context.set_private_key(key).unwrap();
+        context.set_tlsext_use_srtp("SRTP_AES128_CM_SHA1_80").unwrap();
+        context.set_options(SslOptions::NO_QUERY_MTU);
+        context.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+        let mut ssl = Ssl::new(&context.build()).unwrap();
+        ssl.set_mtu(1200).unwrap();
+        ssl.set_connect_state();
+        let mut dtls = SslStream::new(ssl, Datagrams::default()).unwrap();
+        loop {
+            let result = dtls.do_handshake();
+            for datagram in std::mem::take(&mut dtls.get_mut().outgoing) {
+                socket.send(&datagram).await.unwrap();
+            }
+            match result {
+                Ok(()) => break,
+                Err(e) if e.code() == ErrorCode::WANT_READ => {
+                    let datagram = recv(&socket).await;
+                    dtls.get_mut().incoming.push_back(datagram);
+                }
+                Err(e) => panic!("DTLS handshake failed: {}", e),
+            }
+        }
+        let server_cert = dtls.ssl().peer_certificate().unwrap();
+        assert_eq!(fingerprint(&server_cert), attribute(answer, "fingerprint").trim_start_matches("sha-256 "));
+
+        let mut material = [0u8; 60];
+        dtls.ssl()
+            .export_keying_material(&mut material, "EXTRACTOR-dtls_srtp", None)
+            .unwrap();
+        Self {
+            socket,
+            outbound: Srtp::new(&material[..16], &material[32..46]),
+            inbound: Srtp::new(&material[16..32], &material[46..60]),
+        }
+    }
+
+    async fn send_rtp(&mut self, packet: &[u8]) {
+        let packet = self.outbound.protect_rtp(packet);
+        self.socket.send(&packet).await.unwrap();
+    }
+
+    async fn recv(&mut self) -> Vec<u8> {
+        recv(&self.socket).await
+    }
+}
+
+async fn recv(socket: &UdpSocket) -> Vec<u8> {
+    let mut buf = vec![0u8; 2048];
+    let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .expect("timed out waiting for the server")
+        .unwrap();
+    buf.truncate(len);
+    buf
+}
+
+#[tokio::test]
+async fn test_whip_publish() {
+    let streams = StreamManager::new().with_audio(true);
+    let addr = start_servers(streams.clone()).await;
+    let (key, cert) = certificate();
+
+    let (status, location, answer) = post(addr, "/whip/cam", &offer(&fingerprint(&cert), "sendonly")).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", answer);
+    assert!(location.starts_with("/whip/cam/"));
+    assert!(answer.contains("a=ice-lite"));
+    assert!(answer.contains("a=group:BUNDLE 0 1\r\n"));
+    assert!(answer.contains("a=rtpmap:102 H264/90000"));
+    assert!(answer.contains("a=rtpmap:111 opus/48000/2"));
+    assert!(answer.contains("a=recvonly"));
+    assert!(answer.contains("a=setup:passive"));
+    assert!(answer.contains("m=application 0 UDP/DTLS/SCTP webrtc-datachannel"));
+
+    // A second publisher is refused while the session holds the stream
+    let (status, _, _) = post(addr, "/whip/cam", &offer(&fingerprint(&cert), "sendonly")).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let mut subscription = streams.subscribe("live", "cam", "viewer".to_string()).await.unwrap();
+    let mut peer = Peer::connect(&answer, &key, &cert).await;
+
+    // Browsers wait for a PLI before sending a keyframe
+    let video_ssrc = 0x1111;
+    peer.send_rtp(&rtp(102, 1, 0, video_ssrc, true, SLICE)).await;
+    let rtcp = peer.recv().await;
+    let pli = peer.inbound.unprotect_rtcp(&rtcp);
+    assert_eq!(pli[0] & 0x1f, 1);
+    assert_eq!(pli[1], 206);
+    assert_eq!(&pli[8..12], &video_ssrc.to_be_bytes());
+
+    // SPS/PPS in a STAP-A, the IDR in FU-A fragments
+    let mut stap = vec![24];
+    for nal in [SPS, PPS] {
+        stap.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+        stap.extend_from_slice(nal);
+    }
+    peer.send_rtp(&rtp(102, 2, 3000, video_ssrc, false, &stap)).await;
+    let idr = idr();
+    let chunks: Vec<&[u8]> = idr[1..].chunks(1000).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let start = if i == 0 { 0x80 } else { 0 };
+        let end = if i + 1 == chunks.len() { 0x40 } else { 0 };
+        let mut fragment = vec![(idr[0] & 0xe0) | 28, start | end | (idr[0] & 0x1f)];
+        fragment.extend_from_slice(chunk);
+        peer.send_rtp(&rtp(102, 3 + i as u16, 3000, video_ssrc, end != 0, &fragment)).await;
+    }
+    peer.send_rtp(&rtp(111, 1, 960, 0x2222, false, OPUS_FRAME)).await;
+
+    let mut sequence_header = false;
+    let keyframe = loop {
+        let tag = next_tag(&mut subscription).await;
+        if tag.is_video() && tag.is_sequence_header() {
+            assert_eq!(&tag.data[..2], &[0x17, 0x00]);
+            sequence_header = true;
+        } else if tag.is_video() && tag.is_keyframe() {
+            break tag;
+        }
+    };
+    assert!(sequence_header, "sequence header before the keyframe");
+    assert_eq!(&keyframe.data[..2], &[0x17, 0x01]);
+    assert!(keyframe.data.windows(idr.len()).any(|window| window == &idr[..]));
+
+    let opus_head = loop {
+        let tag = next_tag(&mut subscription).await;
+        if tag.tag_type == TagType::Audio {
+            break tag;
+        }
+    };
+    assert_eq!(&opus_head.data[..5], b"\x90Opus");
+    assert_eq!(&opus_head.data[5..13], b"OpusHead");
+    let frame = next_tag(&mut subscription).await;
+    assert_eq!(&frame.data[..5], b"\x91Opus");
+    assert_eq!(&frame.data[5..], OPUS_FRAME);
+
+    // Ending the session ends the stream for its viewers
+    assert_eq!(delete(addr, &location).await, StatusCode::OK);
+    assert_eq!(delete(addr, &location).await, StatusCode::NOT_FOUND);
+    let ended = tokio::time::timeout(Duration::from_secs(5), async {
+        while subscription.recv().await.unwrap().is_some() {}
+    });
+    ended.await.expect("stream did not end");
+}
+
+#[tokio::test]
+async fn test_whep_play() {
+    let streams = StreamManager::new().with_audio(true);
+    let addr = start_servers(streams.clone()).await;
+    let (key, cert) = certificate();
+
+    let publisher = streams.start_publish("live", "show", "encoder".to_string()).await.unwrap();
+    publisher.publish(avc_sequence_header(0)).await.unwrap();
+    let head = b"\x90OpusOpusHead\x01\x02\x38\x01\x80\xbb\x00\x00\x00\x00\x00".to_vec();
+    publisher.publish(FlvTag::new(TagType::Audio, 0, Bytes::from(head))).await.unwrap();
+
+    let (status, location, answer) = post(addr, "/whep/show", &offer(&fingerprint(&cert), "recvonly")).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", answer);
+    assert!(location.starts_with("/whep/show/"));
+    assert!(answer.contains("a=sendonly"));
+    assert!(answer.contains("a=rtpmap:102 H264/90000"));
+    assert!(answer.contains("a=rtpmap:111 opus/48000/2"));
+    let ssrcs: Vec<u32> = answer
+        .lines()
+        .filter_map(|line| line.strip_prefix("a=ssrc:"))
+        .map(|ssrc| ssrc.split(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(ssrcs.len(), 2);
+
+    let mut peer = Peer::connect(&answer, &key, &cert).await;
+
+    // Keep the stream going until the peer got what it needs
+    let feeder = tokio::spawn(async move {
+        let length_prefixed = |nal: &[u8], data: &mut Vec<u8>| {
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        };
+        for i in 0..200u32 {
+            let mut frame = vec![0x17, 0x01, 0, 0, 0];
+            length_prefixed(&idr(), &mut frame);
+            publisher.publish(FlvTag::new(TagType::Video, i * 40, Bytes::from(frame))).await.unwrap();
+            let mut audio = b"\x91Opus".to_vec();
+            audio.extend_from_slice(OPUS_FRAME);
+            publisher.publish(FlvTag::new(TagType::Audio, i * 40, Bytes::from(audio))).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(40)).await;
+        }
+    });
+
+    let (mut sps, mut fragments, mut opus) = (false, false, false);
+    while !(sps && fragments && opus) {
+        let packet = peer.recv().await;
+        let packet = peer.inbound.unprotect_rtp(&packet);
+        let ssrc = u32::from_be_bytes([packet[8], packet[9], packet[10], packet[11]]);
+        assert!(ssrcs.contains(&ssrc));
+        match packet[1] & 0x7f {
+            102 => {
+                sps |= &packet[12..] == SPS;
+                fragments |= packet[12] & 0x1f == 28;
+            }
+            111 => {
+                assert_eq!(&packet[12..], OPUS_FRAME);
+                opus = true;
+            }
+            pt => panic!("unexpected payload type {}", pt),
+        }
+    }
+    // Before the publisher goes away, which ends the session on its own
+    assert_eq!(delete(addr, &location).await, StatusCode::OK);
+    feeder.abort();
+}
+
+#[tokio::test]
+async fn test_apps_and_vhosts() {
+    let tenant = AppConfig {
+        publish_token: Some("s3cret".to_string()),
+        ..AppConfig::default()
+    };
+    let streams = StreamManager::new().with_config(with_tenant(Config::default(), tenant));
+    let addr = start_servers(streams.clone()).await;
+    let (_, cert) = certificate();
+    let publish = offer(&fingerprint(&cert), "sendonly");
+
+    // The app is taken from the path
+    let (status, location, answer) = post(addr, "/whip/studio/cam", &publish).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", answer);
+    assert!(location.starts_with("/whip/studio/cam/"));
+    assert!(streams.get_stream("studio", "cam").await.unwrap().is_published());
+    assert!(streams.get_stream("live", "cam").await.is_none());
+
+    // The tenant's `live` app has a token, the default host's has none
+    let (status, _, _) = post_to(addr, TENANT, "/whip/cam", &publish).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, _, answer) = post_to(addr, TENANT, "/whip/live/cam?token=s3cret", &publish).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", answer);
+    let tenant_app = format!("{}/live", TENANT);
+    assert!(streams.get_stream(&tenant_app, "cam").await.unwrap().is_published());
+    assert!(streams.get_stream("live", "cam").await.is_none());
+
+    // Playback finds the stream of the same app
+    let publisher = streams.start_publish("studio", "show", "encoder".to_string()).await.unwrap();
+    publisher.publish(avc_sequence_header(0)).await.unwrap();
+    let play = offer(&fingerprint(&cert), "recvonly");
+    assert_eq!(post(addr, "/whep/show", &play).await.0, StatusCode::NOT_FOUND);
+    let (status, location, answer) = post(addr, "/whep/studio/show", &play).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", answer);
+    assert_eq!(delete(addr, &location).await, StatusCode::OK);
+
+    assert_eq!(post(addr, "/whip//cam", &publish).await.0, StatusCode::NOT_FOUND);
+    assert_eq!(post(addr, "/whip/a/b/cam", &publish).await.0, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_offer_errors() {
+    let streams = StreamManager::new();
+    let addr = start_servers(streams.clone()).await;
+    let (_, cert) = certificate();
+    let offer = offer(&fingerprint(&cert), "recvonly");
+
+    // Not published
+    let (status, _, _) = post(addr, "/whep/missing", &offer).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    // Not SDP, or nothing we can receive
+    let (status, _, _) = post(addr, "/whip/cam", "hello").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let vp8_only = offer.replace("a=rtpmap:102 H264/90000", "").replace("a=rtpmap:111 opus/48000/2", "");
+    let (status, _, _) = post(addr, "/whip/cam", &vp8_only).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    assert_eq!(delete(addr, "/whip/cam/unknown").await, StatusCode::NOT_FOUND);
+
+    // An HTTP server without WebRTC
+    let plain = start_http(streams).await;
+    let (status, _, _) = post(plain, "/whip/cam", &offer).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+}